@@ -7,7 +7,9 @@ use shush_rs::SecretString;
 
 use rencfs::crypto::Cipher;
 use rencfs::encryptedfs::write_all_string_to_fs;
-use rencfs::encryptedfs::{CreateFileAttr, EncryptedFs, FileType, PasswordProvider};
+use rencfs::encryptedfs::{
+    AtimeMode, CreateFileAttr, EncryptedFs, FileType, OpenFlags, PasswordProvider,
+};
 
 const ROOT_INODE: u64 = 1;
 
@@ -32,18 +34,19 @@ async fn main() -> Result<()> {
         Box::new(PasswordProviderImpl {}),
         cipher,
         false,
+        AtimeMode::default(),
     )
     .await?;
 
     let file1 = SecretString::from_str("file1").unwrap();
     let (fh, attr) = fs
-        .create(ROOT_INODE, &file1, file_attr(), false, true)
+        .create(ROOT_INODE, &file1, file_attr(), OpenFlags::new(false, true))
         .await?;
     let data = "Hello, world!";
     write_all_string_to_fs(&fs, attr.ino, 0, data, fh).await?;
     fs.flush(fh).await?;
     fs.release(fh).await?;
-    let fh = fs.open(attr.ino, true, false).await?;
+    let fh = fs.open(attr.ino, OpenFlags::new(true, false)).await?;
     let mut buf = vec![0; data.len()];
     fs.read(attr.ino, 0, &mut buf, fh).await?;
     fs.release(fh).await?;
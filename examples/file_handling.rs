@@ -4,7 +4,8 @@ use anyhow::Result;
 use rencfs::{
     crypto::Cipher,
     encryptedfs::{
-        write_all_string_to_fs, CreateFileAttr, EncryptedFs, FileType, PasswordProvider,
+        write_all_string_to_fs, AtimeMode, CreateFileAttr, EncryptedFs, FileType, OpenFlags,
+        PasswordProvider,
     },
 };
 use shush_rs::SecretString;
@@ -36,12 +37,18 @@ async fn main() -> Result<()> {
         Box::new(PasswordProviderImpl),
         cipher,
         false,
+        AtimeMode::default(),
     )
     .await?;
 
     let file_name = SecretString::from_str("file1").unwrap();
     let (file_handle, attr) = fs
-        .create(ROOT_INODE, &file_name, file_attributes(), false, true)
+        .create(
+            ROOT_INODE,
+            &file_name,
+            file_attributes(),
+            OpenFlags::new(false, true),
+        )
         .await?;
 
     let data = "Hello, world!";
@@ -50,7 +57,7 @@ async fn main() -> Result<()> {
     fs.flush(file_handle).await?;
     fs.release(file_handle).await?;
 
-    let file_handle = fs.open(attr.ino, true, false).await?;
+    let file_handle = fs.open(attr.ino, OpenFlags::new(true, false)).await?;
     let mut buffer = vec![0; data.len()];
     fs.read(attr.ino, 0, &mut buffer, file_handle).await?;
     fs.release(file_handle).await?;
@@ -8,7 +8,7 @@ use shush_rs::SecretString;
 use tracing::info;
 
 use rencfs::crypto::Cipher;
-use rencfs::encryptedfs::PasswordProvider;
+use rencfs::encryptedfs::{AtimeMode, PasswordProvider};
 use rencfs::mount::create_mount_point;
 use rencfs::mount::MountPoint;
 
@@ -38,6 +38,7 @@ async fn main() -> Result<()> {
         false,
         false,
         false,
+        AtimeMode::default(),
     );
     let handle = mount_point.mount().await?;
     let mut buffer = String::new();
@@ -10,7 +10,7 @@ use jni::objects::{JClass, JString};
 use jni::sys::{jboolean, jint, jstring};
 use jni::JNIEnv;
 use rencfs::crypto::Cipher;
-use rencfs::encryptedfs::PasswordProvider;
+use rencfs::encryptedfs::{AtimeMode, PasswordProvider};
 use rencfs::log::log_init;
 use rencfs::mount::{create_mount_point, umount, MountHandle};
 use shush_rs::SecretString;
@@ -186,6 +186,7 @@ pub extern "system" fn Java_RustLibrary_mount(
         false,
         false,
         false,
+        AtimeMode::default(),
     );
 
     let handle = match RT.block_on(async {
@@ -14,7 +14,9 @@ use hex::FromHexError;
 use num_format::{Locale, ToFormattedString};
 use rand_chacha::rand_core::{CryptoRng, RngCore, SeedableRng};
 use rand_chacha::ChaCha20Rng;
-use ring::aead::{AES_256_GCM, CHACHA20_POLY1305};
+use ring::aead::{
+    Aad, Algorithm, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN,
+};
 use serde::{Deserialize, Serialize};
 use shush_rs::{ExposeSecret, SecretString, SecretVec};
 use strum_macros::{Display, EnumIter, EnumString};
@@ -22,12 +24,20 @@ use thiserror::Error;
 use tracing::{debug, error, instrument};
 use write::CryptoInnerWriter;
 
+use crate::crypto::chunks::{ChunkId, SharedChunkRefs};
+use crate::crypto::compress::Compression;
+use crate::crypto::counters::SharedCounters;
+use crate::crypto::holes::SharedHoles;
 use crate::crypto::read::{CryptoRead, CryptoReadSeek, RingCryptoRead};
 use crate::crypto::write::{CryptoWrite, CryptoWriteSeek, RingCryptoWrite};
 use crate::encryptedfs::FsResult;
 use crate::{fs_util, stream_util};
 
 pub mod buf_mut;
+pub mod chunks;
+pub mod compress;
+pub mod counters;
+pub mod holes;
 pub mod read;
 pub mod write;
 
@@ -61,6 +71,17 @@ impl Cipher {
             Cipher::Aes256Gcm => (2_usize.pow(39) - 256) / 8,
         }
     }
+
+    /// Length (in bytes) of an encrypted block in the content files, nonce and tag included.
+    #[must_use]
+    #[allow(clippy::use_self)]
+    pub fn ciphertext_block_size(&self) -> usize {
+        let tag_len = match self {
+            Cipher::ChaCha20Poly1305 => CHACHA20_POLY1305.tag_len(),
+            Cipher::Aes256Gcm => AES_256_GCM.tag_len(),
+        };
+        NONCE_LEN + write::BLOCK_SIZE + tag_len
+    }
 }
 
 #[derive(Debug, Error)]
@@ -175,6 +196,67 @@ fn create_ring_read_seek<R: Read + Seek + Send + Sync>(
     RingCryptoRead::new_seek(reader, algorithm, key)
 }
 
+/// Creates an encrypted writer with seek for a sparse file,
+/// seeking after the end records the skipped blocks in `holes` instead of writing encrypted zeros.
+pub fn create_write_seek_with_holes<W: CryptoInnerWriter + Seek + Read + Send + Sync + 'static>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    holes: SharedHoles,
+) -> impl CryptoWriteSeek<W> {
+    create_ring_write_seek(writer, cipher, key).with_holes(holes)
+}
+
+/// Creates an encrypted reader with seek for a sparse file, blocks in `holes` read as zeros.
+pub fn create_read_seek_with_holes<R: Read + Seek + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    holes: SharedHoles,
+) -> impl CryptoReadSeek<R> {
+    create_ring_read_seek(reader, cipher, key).with_holes(holes)
+}
+
+/// Like [`create_write_seek_with_holes`], also writing the blocks kept in the chunk store removes them from `chunks`.
+///
+/// Each block written is counted in `counters`. With `compression` the blocks are compressed before they are
+/// encrypted, see [`compress`].
+pub fn create_write_seek_with_chunks<W: CryptoInnerWriter + Seek + Read + Send + Sync + 'static>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    holes: SharedHoles,
+    chunks: SharedChunkRefs,
+    counters: SharedCounters,
+    compression: Option<Compression>,
+) -> impl CryptoWriteSeek<W> {
+    let writer = create_ring_write_seek(writer, cipher, key)
+        .with_holes(holes)
+        .with_chunks(chunks)
+        .with_counters(counters);
+    match compression {
+        Some(compression) => writer.with_compression(compression),
+        None => writer,
+    }
+}
+
+/// Like [`create_read_seek_with_holes`], also the blocks in `chunks` are read from the chunk store.
+///
+/// The blocks are opened as they were last written, according to `counters`.
+pub fn create_read_seek_with_chunks<R: Read + Seek + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    holes: SharedHoles,
+    chunks: SharedChunkRefs,
+    counters: SharedCounters,
+) -> impl CryptoReadSeek<R> {
+    create_ring_read_seek(reader, cipher, key)
+        .with_holes(holes)
+        .with_chunks(chunks)
+        .with_counters(counters)
+}
+
 /// Creates an encrypted reader
 pub fn create_read<R: Read + Send + Sync>(
     reader: R,
@@ -193,6 +275,198 @@ pub fn create_read_seek<R: Read + Seek + Send + Sync>(
     create_ring_read_seek(reader, cipher, key)
 }
 
+/// Encrypts a single block of a content file, the same as [`RingCryptoWrite`] does.
+///
+/// `aad` is the one from [`counters::WriteCounters::next_aad`] for the block, or the block index, in little endian,
+/// for a stream without counters.\
+/// Returns the nonce followed by the ciphertext and the tag, to be written at `block_index * ciphertext_block_size`.
+/// When `compressed`, `plaintext` is a block compressed by [`compress`] and the result is shorter, a block without
+/// counters is never compressed.
+#[allow(clippy::missing_errors_doc)]
+pub fn seal_block(
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    aad: &[u8],
+    plaintext: &[u8],
+    compressed: bool,
+) -> io::Result<Vec<u8>> {
+    seal(cipher, key, aad, plaintext, compressed)
+}
+
+/// Decrypts a single block of a content file written by [`RingCryptoWrite`] or [`seal_block`].
+///
+/// `aad` and `compressed` are the ones from [`counters::WriteCounters::aad`] for the block, or the block index and
+/// `false` like for [`seal_block`].
+#[allow(clippy::missing_errors_doc)]
+pub fn open_block(
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    aad: &[u8],
+    compressed: bool,
+    block: Vec<u8>,
+) -> io::Result<Vec<u8>> {
+    open(cipher, key, aad, compressed, block)
+}
+
+/// Id of the chunk with `plaintext` in the chunk store.
+///
+/// It's keyed with a key derived from the volume key, so the same content has unrelated ids in different volumes.
+#[must_use]
+pub fn chunk_id(key: &SecretVec<u8>, plaintext: &[u8]) -> ChunkId {
+    let mut hash_key = [0; 32];
+    blake3::derive_key("rencfs chunk store id", &key.expose_secret(), &mut hash_key);
+    ChunkId(blake3::keyed_hash(&hash_key, plaintext).into())
+}
+
+/// Encrypts a chunk for the chunk store, like [`seal_block`] but bound to its `id` instead of a block index.
+///
+/// It starts with a byte telling if it's compressed, bound with the id, see [`split_chunk`].
+#[allow(clippy::missing_errors_doc)]
+pub fn seal_chunk(
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    id: ChunkId,
+    plaintext: &[u8],
+    compression: Option<Compression>,
+) -> io::Result<Vec<u8>> {
+    let compressed = compression.and_then(|compression| compress::compress(compression, plaintext));
+    let mut chunk = vec![u8::from(compressed.is_some())];
+    chunk.extend(seal(
+        cipher,
+        key,
+        &chunk_aad(id, compressed.is_some()),
+        compressed.as_deref().unwrap_or(plaintext),
+        compressed.is_some(),
+    )?);
+    Ok(chunk)
+}
+
+/// Decrypts a chunk of the chunk store written by [`seal_chunk`].
+#[allow(clippy::missing_errors_doc)]
+pub fn open_chunk(
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    id: ChunkId,
+    chunk: Vec<u8>,
+) -> io::Result<Vec<u8>> {
+    let (aad, compressed, block) = split_chunk(id, chunk)?;
+    open(cipher, key, &aad, compressed, block)
+}
+
+/// The associated data of a chunk written by [`seal_chunk`], whether it's compressed and the block to open.
+#[allow(clippy::missing_errors_doc)]
+pub fn split_chunk(id: ChunkId, mut chunk: Vec<u8>) -> io::Result<(Vec<u8>, bool, Vec<u8>)> {
+    let compressed = match chunk.first() {
+        Some(0) => false,
+        Some(1) => true,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid chunk header",
+            ))
+        }
+    };
+    chunk.remove(0);
+    Ok((chunk_aad(id, compressed), compressed, chunk))
+}
+
+fn chunk_aad(id: ChunkId, compressed: bool) -> Vec<u8> {
+    let mut aad = id.0.to_vec();
+    aad.push(u8::from(compressed));
+    aad
+}
+
+fn seal(
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    aad: &[u8],
+    plaintext: &[u8],
+    compressed: bool,
+) -> io::Result<Vec<u8>> {
+    let key = less_safe_key(cipher, key)?;
+    let mut nonce = [0; NONCE_LEN];
+    create_rng().fill_bytes(&mut nonce);
+    let (aad, header) = if compressed {
+        (
+            compress::aad(aad, plaintext.len()),
+            compress::len_bytes(plaintext.len()).to_vec(),
+        )
+    } else {
+        (aad.to_vec(), vec![])
+    };
+    let mut block =
+        Vec::with_capacity(NONCE_LEN + header.len() + plaintext.len() + key.algorithm().tag_len());
+    block.extend_from_slice(&nonce);
+    block.extend_from_slice(&header);
+    block.extend_from_slice(plaintext);
+    let tag = key
+        .seal_in_place_separate_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(aad),
+            &mut block[NONCE_LEN + header.len()..],
+        )
+        .map_err(|err| {
+            error!("error sealing in place: {}", err);
+            io::Error::other("error sealing in place")
+        })?;
+    block.extend_from_slice(tag.as_ref());
+    Ok(block)
+}
+
+fn open(
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    aad: &[u8],
+    compressed: bool,
+    mut block: Vec<u8>,
+) -> io::Result<Vec<u8>> {
+    let key = less_safe_key(cipher, key)?;
+    let tag_len = key.algorithm().tag_len();
+    if block.len() < NONCE_LEN + tag_len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "block is too short",
+        ));
+    }
+    if compressed {
+        let len = compress::compressed_len(&block, tag_len).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "invalid compressed length")
+        })?;
+        let start = NONCE_LEN + compress::LEN_LEN;
+        let mut data = block[start..start + len + tag_len].to_vec();
+        let nonce = Nonce::try_assume_unique_for_key(&block[..NONCE_LEN]).expect("nonce length");
+        let plaintext = key
+            .open_in_place(nonce, Aad::from(compress::aad(aad, len)), &mut data)
+            .map_err(|err| {
+                error!("error opening within: {}", err);
+                io::Error::other("error opening within")
+            })?;
+        return compress::decompress(plaintext, write::BLOCK_SIZE);
+    }
+    let (nonce, data) = block.split_at_mut(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).expect("nonce length");
+    let len = key
+        .open_in_place(nonce, Aad::from(aad), data)
+        .map_err(|err| {
+            error!("error opening within: {}", err);
+            io::Error::other("error opening within")
+        })?
+        .len();
+    block.drain(..NONCE_LEN);
+    block.truncate(len);
+    Ok(block)
+}
+
+fn less_safe_key(cipher: Cipher, key: &SecretVec<u8>) -> io::Result<LessSafeKey> {
+    let algorithm: &'static Algorithm = match cipher {
+        Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+        Cipher::Aes256Gcm => &AES_256_GCM,
+    };
+    let key = UnboundKey::new(algorithm, &key.expose_secret())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
 #[allow(clippy::missing_errors_doc)]
 pub fn encrypt(s: &SecretString, cipher: Cipher, key: &SecretVec<u8>) -> Result<String> {
     let mut cursor = io::Cursor::new(vec![]);
@@ -388,7 +662,7 @@ mod tests {
     use shush_rs::{ExposeSecret, SecretString, SecretVec};
     use std::{
         fs::File,
-        io::{self, Write},
+        io::{self, SeekFrom, Write},
         path::{Path, PathBuf},
     };
     use tempfile::{tempdir, TempDir};
@@ -650,4 +924,154 @@ mod tests {
 
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_seal_and_open_block_match_stream() {
+        for &cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+            let key = secret_key(cipher);
+            let block_size = write::BLOCK_SIZE;
+            let ciphertext_block_size = cipher.ciphertext_block_size();
+            let data = vec![42_u8; block_size * 2 + block_size / 2];
+
+            let mut writer = create_write(io::Cursor::new(vec![]), cipher, &key);
+            writer.write_all(&data).unwrap();
+            let mut ciphertext = writer.finish().unwrap().into_inner();
+
+            // a block written by the stream
+            let block = ciphertext[ciphertext_block_size..ciphertext_block_size * 2].to_vec();
+            assert_eq!(
+                open_block(cipher, &key, &1_u64.to_le_bytes(), false, block.clone()).unwrap(),
+                &data[..block_size]
+            );
+            // it's bound to its position
+            assert!(open_block(cipher, &key, &0_u64.to_le_bytes(), false, block).is_err());
+
+            // a block sealed on its own, read by the stream
+            let block = seal_block(
+                cipher,
+                &key,
+                &1_u64.to_le_bytes(),
+                &[7_u8; write::BLOCK_SIZE],
+                false,
+            )
+            .unwrap();
+            ciphertext[ciphertext_block_size..ciphertext_block_size * 2].copy_from_slice(&block);
+            let mut reader = create_read(io::Cursor::new(ciphertext), cipher, &key);
+            let mut plaintext = vec![];
+            reader.read_to_end(&mut plaintext).unwrap();
+            assert_eq!(plaintext.len(), data.len());
+            assert_eq!(
+                &plaintext[block_size..block_size * 2],
+                &[7_u8; write::BLOCK_SIZE]
+            );
+            assert_eq!(&plaintext[block_size * 2..], &data[block_size * 2..]);
+        }
+    }
+
+    #[test]
+    fn test_compressed_blocks() {
+        use std::sync::{Arc, RwLock};
+
+        use strum::IntoEnumIterator;
+
+        use crate::crypto::counters::WriteCounters;
+
+        for &cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+            for compression in Compression::iter() {
+                let dir = tempdir().unwrap();
+                let key = Arc::new(secret_key(cipher));
+                let counters = Arc::new(RwLock::new(WriteCounters::new(
+                    &dir.path().join("1.counters"),
+                    1,
+                    42,
+                    cipher,
+                    key.clone(),
+                )));
+                let block_size = write::BLOCK_SIZE;
+                let ciphertext_block_size = cipher.ciphertext_block_size();
+                #[allow(clippy::cast_possible_truncation)]
+                let data: Vec<u8> = (0..block_size * 3 + block_size / 3)
+                    .map(|i| (i % 7) as u8)
+                    .collect();
+
+                let mut writer = create_ring_write_seek(io::Cursor::new(vec![]), cipher, &key)
+                    .with_counters(counters.clone())
+                    .with_compression(compression);
+                writer.write_all(&data).unwrap();
+                let ciphertext = writer.finish().unwrap().into_inner();
+
+                // the blocks keep their place
+                let mut writer = create_write(io::Cursor::new(vec![]), cipher, &key);
+                writer.write_all(&data).unwrap();
+                assert_eq!(
+                    ciphertext.len(),
+                    writer.finish().unwrap().into_inner().len()
+                );
+                // only the start of a block is written
+                assert!(ciphertext[ciphertext_block_size / 2..ciphertext_block_size]
+                    .iter()
+                    .all(|b| *b == 0));
+                let block = ciphertext[ciphertext_block_size..ciphertext_block_size * 2].to_vec();
+                let aad = counters.read().unwrap().aad(1).unwrap();
+                assert!(aad.compressed);
+                assert_eq!(
+                    open_block(cipher, &key, &aad.aad, true, block.clone()).unwrap(),
+                    &data[block_size..block_size * 2]
+                );
+                // it's known to be compressed, it doesn't open as it is
+                assert!(open_block(cipher, &key, &aad.aad, false, block.clone()).is_err());
+                let aad = counters.read().unwrap().aad(0).unwrap();
+                assert!(open_block(cipher, &key, &aad.aad, true, block).is_err());
+
+                // seeking to a block
+                let mut reader =
+                    create_ring_read_seek(io::Cursor::new(ciphertext.clone()), cipher, &key)
+                        .with_counters(counters.clone());
+                reader
+                    .seek(SeekFrom::Start(block_size as u64 * 2 + 5))
+                    .unwrap();
+                let mut plaintext = vec![];
+                reader.read_to_end(&mut plaintext).unwrap();
+                assert_eq!(plaintext, &data[block_size * 2 + 5..]);
+
+                // the compressed length is authenticated
+                let mut tampered = ciphertext.clone();
+                tampered[NONCE_LEN] ^= 1;
+                let mut reader = create_ring_read(io::Cursor::new(tampered), cipher, &key)
+                    .with_counters(counters.clone());
+                assert!(reader.read_to_end(&mut vec![]).is_err());
+
+                // without counters it's not known, the blocks are written as they are
+                let mut writer = create_ring_write_seek(io::Cursor::new(vec![]), cipher, &key)
+                    .with_compression(compression);
+                writer.write_all(&data).unwrap();
+                let ciphertext = writer.finish().unwrap().into_inner();
+                let mut reader = create_read(io::Cursor::new(ciphertext), cipher, &key);
+                let mut plaintext = vec![];
+                reader.read_to_end(&mut plaintext).unwrap();
+                assert_eq!(plaintext, data);
+
+                // chunks tell it in their first byte, bound with their id
+                let id = chunk_id(&key, &data[..block_size]);
+                let chunk =
+                    seal_chunk(cipher, &key, id, &data[..block_size], Some(compression)).unwrap();
+                assert_eq!(chunk[0], 1);
+                assert_eq!(
+                    open_chunk(cipher, &key, id, chunk.clone()).unwrap(),
+                    &data[..block_size]
+                );
+                let mut tampered = chunk;
+                tampered[0] = 0;
+                assert!(open_chunk(cipher, &key, id, tampered).is_err());
+
+                // kept as it is when it doesn't get shorter
+                let mut random = vec![0; block_size];
+                create_rng().fill_bytes(&mut random);
+                assert!(compress::compress(compression, &random).is_none());
+                let chunk = seal_chunk(cipher, &key, id, &random, Some(compression)).unwrap();
+                assert_eq!(chunk[0], 0);
+                assert_eq!(chunk.len(), 1 + ciphertext_block_size);
+            }
+        }
+    }
 }
@@ -0,0 +1,116 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::{fs, io};
+
+use serde::{Deserialize, Serialize};
+
+/// Chunk references shared between the readers and writers of the same file.
+pub type SharedChunkRefs = Arc<RwLock<ChunkRefs>>;
+
+/// Identifies a chunk in the chunk store by the keyed hash of its plaintext, see [`crate::crypto::chunk_id`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ChunkId(pub [u8; 32]);
+
+impl fmt::Display for ChunkId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+/// Blocks of a file kept once in the chunk store of the volume, with the other blocks having the same plaintext.
+///
+/// The ciphertext at their position in the content file is not used, it's left unwritten when possible. A chunk is
+/// sealed with its id as associated data instead of the block index, so it can be at any position of any file.\
+/// Kept as `block index -> chunk id`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChunkRefs {
+    blocks: BTreeMap<u64, ChunkId>,
+    // where the chunks are
+    #[serde(skip)]
+    store: PathBuf,
+    // not referenced anymore by a block, maybe still by others
+    #[serde(skip)]
+    released: BTreeSet<ChunkId>,
+}
+
+impl ChunkRefs {
+    #[must_use]
+    pub fn new(store: PathBuf) -> Self {
+        Self {
+            store,
+            ..Self::default()
+        }
+    }
+
+    /// Where the chunks are read from, it's not serialized.
+    #[must_use]
+    pub fn with_store(mut self, store: PathBuf) -> Self {
+        self.store = store;
+        self
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    #[must_use]
+    pub fn get(&self, block_index: u64) -> Option<ChunkId> {
+        self.blocks.get(&block_index).copied()
+    }
+
+    /// The chunks referenced by any block.
+    #[must_use]
+    pub fn ids(&self) -> BTreeSet<ChunkId> {
+        self.blocks.values().copied().collect()
+    }
+
+    pub fn insert(&mut self, block_index: u64, id: ChunkId) {
+        if let Some(old) = self.blocks.insert(block_index, id) {
+            self.released.insert(old);
+        }
+    }
+
+    /// Reference the same chunks as `other`, releasing the current ones.
+    pub fn assign(&mut self, other: &Self) {
+        self.truncate(0);
+        self.blocks.clone_from(&other.blocks);
+    }
+
+    /// The block is written in the content file again.
+    pub fn remove(&mut self, block_index: u64) {
+        if let Some(old) = self.blocks.remove(&block_index) {
+            self.released.insert(old);
+        }
+    }
+
+    pub fn remove_range(&mut self, range: Range<u64>) {
+        let removed: Vec<u64> = self.blocks.range(range).map(|(index, _)| *index).collect();
+        for block_index in removed {
+            self.remove(block_index);
+        }
+    }
+
+    /// Remove the blocks from `block_index` onwards.
+    pub fn truncate(&mut self, block_index: u64) {
+        self.remove_range(block_index..u64::MAX);
+    }
+
+    /// The chunks no block references anymore, since the last call.
+    pub fn take_released(&mut self) -> Vec<ChunkId> {
+        let ids = self.ids();
+        std::mem::take(&mut self.released)
+            .into_iter()
+            .filter(|id| !ids.contains(id))
+            .collect()
+    }
+
+    /// Ciphertext of the chunk `id`.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn read_chunk(&self, id: ChunkId) -> io::Result<Vec<u8>> {
+        fs::read(self.store.join(id.to_string()))
+    }
+}
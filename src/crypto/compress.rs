@@ -0,0 +1,95 @@
+//! Blocks compressed before they are encrypted, see [`crate::encryptedfs::EncryptedFs::set_compression`].
+//!
+//! A compressed block is `nonce | compressed length | ciphertext | tag`. The ciphertext is of the algorithm, one byte,
+//! followed by the compressed data, and the length is in the associated data, so it's authenticated with the rest.
+//! The block keeps the place it would have uncompressed in the content file, the rest of it is not read, so seeking
+//! to a block is still only a multiplication. Blocks which don't get shorter are kept as they are.
+//!
+//! Whether a block is compressed is not guessed from it, it's in the authenticated record of its write, see
+//! [`WriteCounters`](crate::crypto::counters::WriteCounters), so blocks without one are never compressed. A chunk of
+//! the chunk store tells it in its first byte, bound with its id, see [`crate::crypto::seal_chunk`].
+
+use std::io;
+
+use ring::aead::NONCE_LEN;
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumIter, EnumString};
+
+/// Bytes of the compressed length, after the nonce.
+pub(crate) const LEN_LEN: usize = 4;
+
+#[derive(
+    Debug, Clone, Copy, EnumIter, EnumString, Display, Serialize, Deserialize, PartialEq, Eq,
+)]
+pub enum Compression {
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    const fn id(self) -> u8 {
+        match self {
+            Self::Zstd => 1,
+            Self::Lz4 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> io::Result<Self> {
+        match id {
+            1 => Ok(Self::Zstd),
+            2 => Ok(Self::Lz4),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown compression",
+            )),
+        }
+    }
+}
+
+/// The algorithm followed by the compressed `plaintext`, [None] if that's not shorter than the block it replaces.
+pub(crate) fn compress(compression: Compression, plaintext: &[u8]) -> Option<Vec<u8>> {
+    let mut data = vec![compression.id()];
+    match compression {
+        Compression::Zstd => data.extend(zstd::bulk::compress(plaintext, 0).ok()?),
+        Compression::Lz4 => data.extend(lz4_flex::block::compress_prepend_size(plaintext)),
+    }
+    (LEN_LEN + data.len() < plaintext.len()).then_some(data)
+}
+
+/// The plaintext of `data` written by [`compress`], at most `max_len` bytes.
+pub(crate) fn decompress(data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    let (id, data) = data
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "empty block"))?;
+    let plaintext = match Compression::from_id(*id)? {
+        Compression::Zstd => zstd::bulk::decompress(data, max_len)?,
+        Compression::Lz4 => lz4_flex::block::decompress_size_prepended(data)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+    };
+    if plaintext.len() > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "block is too long",
+        ));
+    }
+    Ok(plaintext)
+}
+
+/// Associated data of a compressed block, the one of the block followed by the compressed length.
+pub(crate) fn aad(aad: &[u8], len: usize) -> Vec<u8> {
+    let mut aad = aad.to_vec();
+    aad.extend_from_slice(&len_bytes(len));
+    aad
+}
+
+#[allow(clippy::cast_possible_truncation)]
+pub(crate) const fn len_bytes(len: usize) -> [u8; LEN_LEN] {
+    (len as u32).to_le_bytes()
+}
+
+/// The compressed length in `block`, if it fits in it.
+pub(crate) fn compressed_len(block: &[u8], tag_len: usize) -> Option<usize> {
+    let len_bytes = block.get(NONCE_LEN..NONCE_LEN + LEN_LEN)?;
+    let len = u32::from_le_bytes(len_bytes.try_into().unwrap()) as usize;
+    (len > 0 && NONCE_LEN + LEN_LEN + len + tag_len <= block.len()).then_some(len)
+}
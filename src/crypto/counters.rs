@@ -0,0 +1,874 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use rand_chacha::rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+use shush_rs::SecretVec;
+use tracing::{error, warn};
+
+use crate::crypto::{self, Cipher};
+use crate::encryptedfs::snapshot;
+use crate::fs_util;
+
+/// Write counters shared between the readers and writers of the same file.
+pub type SharedCounters = Arc<RwLock<WriteCounters>>;
+
+// counters reserved at once, the reservation is durable before they are used
+const RESERVE: u64 = 1024;
+// records replaced by later ones kept in the log, before it's written from scratch
+const MAX_STALE: u64 = 1024;
+
+/// What a block was sealed with, besides its index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Stamp {
+    file_id: u128,
+    counter: u64,
+    compressed: bool,
+}
+
+/// Associated data a block is opened with, and whether it was compressed, see [`compress`](crate::crypto::compress).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockAad {
+    pub aad: Vec<u8>,
+    pub compressed: bool,
+}
+
+/// Changes of the counters, appended to their log, see [`WriteCounters`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Record {
+    /// First of a log, the other records are bound to its `log_id`.
+    Header { log_id: u128, file_id: u128 },
+    /// Counters below it may be used.
+    Reserve(u64),
+    /// The block is about to be written with the stamp.
+    Write(u64, Stamp),
+    /// Blocks from this one on were removed.
+    Truncate(u64),
+    /// Blocks in the range became holes.
+    RemoveRange(u64, u64),
+    /// The blocks written with a counter below it are durable, counters from this one on are not reserved anymore.
+    Sync(u64),
+    /// The block may have kept an older content, sealed with one of the stamps, `None` when it had none.
+    Doubtful(u64, Vec<Option<Stamp>>),
+    /// Counters of the file with the id which may have been used by writes not recorded.
+    Lost(u128, u64, u64),
+}
+
+/// Binds the blocks of a file to the file and to the last time each of them was written.
+///
+/// A block is sealed with its index, the random id of the file and the value of a counter of the file, increased with
+/// each block written, as associated data. So a block doesn't open in another file, or at another index, and a
+/// previous content of a block doesn't open anymore once the block is written again.\
+/// The id of a block is the one of the file which wrote it, clones keep reading the blocks they share with their source.
+/// A block without a counter doesn't open, only the blocks of a file created before the counters were kept, which has
+/// no id, open with their index only, see [`Self::legacy`].
+///
+/// They are kept as a log of encrypted records, appended as the blocks are written, and written again from scratch
+/// when it gets too long. Counters are reserved in ranges, the reservation is durable before they are used, so they are
+/// never used twice. The record of a write is appended before the block is written but it's durable only once the
+/// counters are [synced](Self::sync). After a crash the blocks written since the last sync may have kept an older
+/// content, or be of a write whose record was lost, they are opened with the [fallbacks](Self::fallback_aads) too.
+#[derive(Debug, Default)]
+pub struct WriteCounters {
+    // of the file they belong to, new blocks are written with it, None for a legacy file
+    file_id: Option<u128>,
+    // never goes back, not even when the blocks are replaced, so a counter is used once
+    next: u64,
+    // counters below it may have been used
+    reserved: u64,
+    blocks: BTreeMap<u64, Stamp>,
+    // blocks written since the last sync, with the stamps they had before
+    unsynced: BTreeMap<u64, Vec<Option<Stamp>>>,
+    // after a crash, blocks which may have kept an older content, with the stamps it may be of
+    doubtful: BTreeMap<u64, Vec<Option<Stamp>>>,
+    // after a crash, counters which may have been used by writes whose record was lost
+    lost: Vec<(u128, Range<u64>)>,
+    // next counter as of the last sync in the log
+    synced: u64,
+    log: Option<Log>,
+}
+
+/// Where the records are appended.
+#[derive(Debug)]
+struct Log {
+    path: PathBuf,
+    // the records are bound to it, so they don't open for another file
+    ino: u64,
+    cipher: Cipher,
+    key: Arc<SecretVec<u8>>,
+    log_id: u128,
+    // opened by the first append
+    file: Option<File>,
+    // in the file, the next one is numbered with it
+    frames: u64,
+    // to be written from scratch before appending, it doesn't exist yet or doesn't end with a whole record
+    rewrite: bool,
+    // appended since the last sync
+    dirty: bool,
+}
+
+impl WriteCounters {
+    /// Counters of a file with the id `file_id` and no block written yet. They are kept in `path` once
+    /// [`save`](Self::save)d or, for the counters of `ino`, once a block is written.
+    #[must_use]
+    pub fn new(
+        path: &Path,
+        ino: u64,
+        file_id: u128,
+        cipher: Cipher,
+        key: Arc<SecretVec<u8>>,
+    ) -> Self {
+        Self {
+            file_id: Some(file_id),
+            next: 1,
+            reserved: 1,
+            log: Some(Log {
+                path: path.to_owned(),
+                ino,
+                cipher,
+                key,
+                log_id: 0,
+                file: None,
+                frames: 0,
+                rewrite: true,
+                dirty: false,
+            }),
+            ..Self::default()
+        }
+    }
+
+    /// Counters of a file created before they were kept, it has no id. Its blocks are bound to their index only and
+    /// are not compressed, nothing is recorded.
+    #[must_use]
+    pub fn legacy() -> Self {
+        Self::default()
+    }
+
+    /// Load the counters of `ino` from `path`, [None] if there are none.
+    ///
+    /// It's up to the caller to check they are of the file and not behind it, see [`Self::file_id`] and
+    /// [`Self::synced`].
+    #[allow(clippy::missing_errors_doc)]
+    pub fn load(
+        path: &Path,
+        ino: u64,
+        cipher: Cipher,
+        key: Arc<SecretVec<u8>>,
+    ) -> io::Result<Option<Self>> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut frames = Frames {
+            data: &data,
+            ino,
+            cipher,
+            key: &key,
+            log_id: 0,
+            frames: 0,
+        };
+        let Some(Record::Header { log_id, file_id }) = frames.next() else {
+            error!(ino, "write counters of another file");
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "write counters of another file",
+            ));
+        };
+        frames.log_id = log_id;
+        let mut counters = Self::new(path, ino, file_id, cipher, key.clone());
+        counters.next = 0;
+        counters.reserved = 0;
+        // counters above it, up to the reservation, may have been used without being recorded
+        let mut recorded = 0;
+        for record in &mut frames {
+            match record {
+                Record::Write(_, stamp) if stamp.file_id == file_id => {
+                    recorded = recorded.max(stamp.counter + 1);
+                }
+                Record::Sync(next) => recorded = recorded.max(next),
+                _ => {}
+            }
+            counters.apply(record);
+        }
+        let log = counters.log.as_mut().unwrap();
+        log.log_id = log_id;
+        log.frames = frames.frames;
+        log.rewrite = !frames.data.is_empty();
+        if !frames.data.is_empty() {
+            warn!(ino, "write counters end with a partial record");
+        }
+        // what wasn't synced may have not reached the disk
+        let lost = recorded.max(counters.reserved.saturating_sub(RESERVE))..counters.reserved;
+        if !lost.is_empty() {
+            counters.lost.push((file_id, lost));
+            counters.log.as_mut().unwrap().rewrite = true;
+        }
+        if !counters.unsynced.is_empty() {
+            for (block_index, stamps) in std::mem::take(&mut counters.unsynced) {
+                counters
+                    .doubtful
+                    .entry(block_index)
+                    .or_default()
+                    .extend(stamps);
+            }
+            counters.log.as_mut().unwrap().rewrite = true;
+        }
+        counters.next = counters.next.max(counters.reserved).max(1);
+        counters.reserved = counters.next;
+        Ok(Some(counters))
+    }
+
+    /// Id of the file they belong to, [None] for a [legacy](Self::legacy) file.
+    #[must_use]
+    pub const fn file_id(&self) -> Option<u128> {
+        self.file_id
+    }
+
+    /// Whether it's of a file created before the counters were kept, see [`Self::legacy`].
+    #[must_use]
+    pub const fn is_legacy(&self) -> bool {
+        self.file_id.is_none()
+    }
+
+    /// A generation for what is saved along the blocks, greater than the ones before, `0` for a legacy file. No block
+    /// is bound to it, it's durable once the counters are [synced](Self::sync).
+    pub fn next_generation(&mut self) -> u64 {
+        let Some(log) = self.log.as_mut() else {
+            return 0;
+        };
+        log.dirty = true;
+        self.next += 1;
+        self.next - 1
+    }
+
+    /// The next counter as of the last sync in the log. It only grows, a log behind what it was once synced to was
+    /// cut.
+    #[must_use]
+    pub const fn synced(&self) -> u64 {
+        self.synced
+    }
+
+    /// Write the counters to `path` from scratch, for the file `ino`. Nothing is written for a legacy file.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn save(
+        &self,
+        path: &Path,
+        ino: u64,
+        cipher: Cipher,
+        key: &SecretVec<u8>,
+    ) -> io::Result<()> {
+        if self.is_legacy() {
+            return Ok(());
+        }
+        self.write_log(path, ino, cipher, key).map(|_| ())
+    }
+
+    /// Associated data of the block at `block_index` as it was last written, [None] if it wasn't with a counter, it
+    /// doesn't open then, unless with a [fallback](Self::fallback_aads).
+    #[must_use]
+    pub fn aad(&self, block_index: u64) -> Option<BlockAad> {
+        if self.is_legacy() {
+            return Some(aad(block_index, None));
+        }
+        self.blocks
+            .get(&block_index)
+            .map(|stamp| aad(block_index, Some(*stamp)))
+    }
+
+    /// Whether the block at `block_index` has [fallbacks](Self::fallback_aads).
+    #[must_use]
+    pub fn has_fallbacks(&self, block_index: u64) -> bool {
+        !self.lost.is_empty() || self.doubtful.contains_key(&block_index)
+    }
+
+    /// Associated data the block at `block_index` may have been written with too, after a crash.
+    ///
+    /// The older contents it may have kept and the counters which may have been used by writes whose record was lost,
+    /// the latest first. The writes whose record was lost may have been compressed or not, both are tried.
+    pub fn fallback_aads(&self, block_index: u64) -> impl Iterator<Item = BlockAad> + '_ {
+        let doubtful = self
+            .doubtful
+            .get(&block_index)
+            .into_iter()
+            .flat_map(|stamps| stamps.iter().rev().flatten().copied());
+        let lost = self.lost.iter().rev().flat_map(|(file_id, range)| {
+            range.clone().rev().flat_map(|counter| {
+                [false, true].map(|compressed| Stamp {
+                    file_id: *file_id,
+                    counter,
+                    compressed,
+                })
+            })
+        });
+        doubtful
+            .chain(lost)
+            .map(move |stamp| aad(block_index, Some(stamp)))
+    }
+
+    /// Count a write of the block at `block_index`, returns the associated data to seal it with.
+    ///
+    /// The write is recorded before returning, so it needs to be done after this, `compressed` if the block is.
+    /// The blocks of a legacy file are bound to their index only.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn next_aad(&mut self, block_index: u64, compressed: bool) -> io::Result<Vec<u8>> {
+        let Some(file_id) = self.file_id else {
+            debug_assert!(!compressed, "blocks of a legacy file are not compressed");
+            return Ok(aad(block_index, None).aad);
+        };
+        if self.next >= self.reserved {
+            let reserved = self.next + RESERVE;
+            self.record(Record::Reserve(reserved), true)?;
+        }
+        let stamp = Stamp {
+            file_id,
+            counter: self.next,
+            compressed,
+        };
+        self.record(Record::Write(block_index, stamp), false)?;
+        self.next += 1;
+        Ok(aad(block_index, Some(stamp)).aad)
+    }
+
+    /// Forget the blocks from `block_index` on, they are removed from the file.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn truncate(&mut self, block_index: u64) -> io::Result<()> {
+        self.record(Record::Truncate(block_index), false)
+    }
+
+    /// Forget the blocks in `range`, they became holes.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn remove_range(&mut self, range: Range<u64>) -> io::Result<()> {
+        self.record(Record::RemoveRange(range.start, range.end), false)
+    }
+
+    /// Open the blocks as `other` does, like when taking its content. The id and the counter are kept.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn assign(&mut self, other: &Self) -> io::Result<()> {
+        self.blocks.clone_from(&other.blocks);
+        self.unsynced.clear();
+        self.doubtful.clone_from(&other.doubtful);
+        for (block_index, stamps) in &other.unsynced {
+            self.doubtful
+                .entry(*block_index)
+                .or_default()
+                .extend(stamps);
+        }
+        self.lost.clone_from(&other.lost);
+        self.next = self.next.max(other.next);
+        self.reserved = self.reserved.max(self.next);
+        if let Some(log) = self.log.as_mut() {
+            log.rewrite = true;
+            log.dirty = true;
+        }
+        self.sync()
+    }
+
+    /// Make the records durable, needs to be called after the blocks written were synced.
+    ///
+    /// The log is written from scratch when it got too long.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn sync(&mut self) -> io::Result<()> {
+        let Some(point) = self.sync_point() else {
+            return Ok(());
+        };
+        let pending = self.start_sync(point)?;
+        pending.sync_data().inspect_err(|_| self.sync_failed())
+    }
+
+    /// The blocks written with a counter below it are the ones to sync before [starting](Self::start_sync) a sync,
+    /// [None] if there is nothing to sync.
+    #[must_use]
+    pub fn sync_point(&self) -> Option<u64> {
+        self.log.as_ref().filter(|log| log.dirty).map(|_| self.next)
+    }
+
+    /// Record that the blocks written with a counter below `point` are durable, the record is durable once
+    /// [`PendingSync::sync_data`] returns, which doesn't need the counters.
+    ///
+    /// The blocks written since `point` stay unsynced. The log is written from scratch when it got too long.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn start_sync(&mut self, point: u64) -> io::Result<PendingSync> {
+        let live = self.blocks.len() + self.unsynced.len() + self.doubtful.len() + self.lost.len();
+        let log = self.log.as_mut().expect("legacy counters are not synced");
+        if log.frames > 2 * live as u64 + MAX_STALE {
+            log.rewrite = true;
+        }
+        self.append(&Record::Sync(point))?;
+        let log = self.log.as_mut().unwrap();
+        log.dirty = false;
+        let file = log.file.as_ref().unwrap().try_clone()?;
+        self.apply(Record::Sync(point));
+        Ok(PendingSync { point, file })
+    }
+
+    /// The log of a [started](Self::start_sync) sync couldn't be synced, it's written from scratch by the next one.
+    pub fn sync_failed(&mut self) {
+        if let Some(log) = self.log.as_mut() {
+            log.rewrite = true;
+            log.dirty = true;
+        }
+    }
+
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Header { .. } => {}
+            Record::Reserve(reserved) => self.reserved = reserved,
+            Record::Write(block_index, stamp) => {
+                let previous = self.blocks.insert(block_index, stamp);
+                self.unsynced.entry(block_index).or_default().push(previous);
+                self.next = self.next.max(stamp.counter + 1);
+            }
+            Record::Truncate(block_index) => {
+                self.blocks.split_off(&block_index);
+                self.unsynced.split_off(&block_index);
+                self.doubtful.split_off(&block_index);
+            }
+            Record::RemoveRange(start, end) => {
+                let range = start..end;
+                self.blocks
+                    .retain(|block_index, _| !range.contains(block_index));
+                self.unsynced
+                    .retain(|block_index, _| !range.contains(block_index));
+                self.doubtful
+                    .retain(|block_index, _| !range.contains(block_index));
+            }
+            Record::Sync(point) => {
+                for (block_index, mut stamps) in std::mem::take(&mut self.unsynced) {
+                    let Some(stamp) = self.blocks.get(&block_index).copied() else {
+                        continue;
+                    };
+                    if stamp.counter < point {
+                        self.doubtful.remove(&block_index);
+                        continue;
+                    }
+                    // written again since, it has the content of its last write before the point, or of a later one
+                    if let Some(synced) = stamps.iter().rposition(|previous| {
+                        previous.is_some_and(|previous| {
+                            previous.file_id == stamp.file_id && previous.counter < point
+                        })
+                    }) {
+                        stamps.drain(..synced);
+                    }
+                    self.unsynced.insert(block_index, stamps);
+                }
+                self.reserved = point;
+                self.synced = self.synced.max(point);
+            }
+            Record::Doubtful(block_index, stamps) => {
+                self.doubtful.entry(block_index).or_default().extend(stamps);
+            }
+            Record::Lost(file_id, start, end) => self.lost.push((file_id, start..end)),
+        }
+    }
+
+    /// Append `record` to the log then apply it, with `sync` the log is synced before applying.
+    fn record(&mut self, record: Record, sync: bool) -> io::Result<()> {
+        if self.log.is_some() {
+            self.append(&record)?;
+            let log = self.log.as_mut().unwrap();
+            if sync {
+                if let Err(err) = log.file.as_ref().unwrap().sync_data() {
+                    log.rewrite = true;
+                    return Err(err);
+                }
+            }
+        }
+        self.apply(record);
+        Ok(())
+    }
+
+    fn append(&mut self, record: &Record) -> io::Result<()> {
+        let log = self.log.as_mut().unwrap();
+        // the log is hard linked by a snapshot, which needs to keep it as it is
+        if !log.rewrite {
+            let meta = match &log.file {
+                Some(file) => file.metadata(),
+                None => std::fs::metadata(&log.path),
+            };
+            log.rewrite = meta.map_or(true, |meta| snapshot::is_shared(&meta));
+        }
+        if log.rewrite {
+            self.rewrite_log()?;
+        }
+        let log = self.log.as_mut().unwrap();
+        if log.file.is_none() {
+            log.file = Some(OpenOptions::new().append(true).open(&log.path)?);
+        }
+        let frame = frame(
+            log.ino, log.cipher, &log.key, log.log_id, log.frames, record,
+        )?;
+        if let Err(err) = log.file.as_mut().unwrap().write_all(&frame) {
+            // may have written part of it
+            log.rewrite = true;
+            return Err(err);
+        }
+        log.frames += 1;
+        log.dirty = true;
+        Ok(())
+    }
+
+    fn rewrite_log(&mut self) -> io::Result<()> {
+        let log = self.log.as_ref().unwrap();
+        let (log_id, frames) = self.write_log(&log.path, log.ino, log.cipher, &log.key)?;
+        let log = self.log.as_mut().unwrap();
+        log.log_id = log_id;
+        log.frames = frames;
+        log.file = None;
+        log.rewrite = false;
+        // the new log ends its synced part with the next counter
+        self.synced = self.synced.max(self.next);
+        Ok(())
+    }
+
+    /// Write the records of the current state to `path` from scratch, returns the id of the new log and the number of
+    /// records in it.
+    fn write_log(
+        &self,
+        path: &Path,
+        ino: u64,
+        cipher: Cipher,
+        key: &SecretVec<u8>,
+    ) -> io::Result<(u128, u64)> {
+        let mut id = [0; 16];
+        crypto::create_rng().fill_bytes(&mut id);
+        let log_id = u128::from_le_bytes(id);
+        let mut records = vec![Record::Header {
+            log_id,
+            file_id: self.file_id.expect("legacy counters are not logged"),
+        }];
+        // as of the last sync, the writes after it follow
+        for (block_index, stamp) in &self.blocks {
+            let stamp = match self.unsynced.get(block_index) {
+                Some(stamps) => stamps[0],
+                None => Some(*stamp),
+            };
+            if let Some(stamp) = stamp {
+                records.push(Record::Write(*block_index, stamp));
+            }
+        }
+        records.push(Record::Sync(self.next));
+        for (block_index, stamps) in &self.doubtful {
+            records.push(Record::Doubtful(*block_index, stamps.clone()));
+        }
+        for (file_id, range) in &self.lost {
+            records.push(Record::Lost(*file_id, range.start, range.end));
+        }
+        for (block_index, stamps) in &self.unsynced {
+            for stamp in stamps.iter().skip(1).flatten() {
+                records.push(Record::Write(*block_index, *stamp));
+            }
+            if let Some(stamp) = self.blocks.get(block_index) {
+                records.push(Record::Write(*block_index, *stamp));
+            }
+        }
+        if self.reserved > self.next {
+            records.push(Record::Reserve(self.reserved));
+        }
+        let mut file = fs_util::open_atomic_write(path)?;
+        for (i, record) in records.iter().enumerate() {
+            let log_id = if i == 0 { 0 } else { log_id };
+            file.write_all(&frame(ino, cipher, key, log_id, i as u64, record)?)?;
+        }
+        file.commit()?;
+        if let Some(parent) = path.parent() {
+            File::open(parent)?.sync_all()?;
+        }
+        Ok((log_id, records.len() as u64))
+    }
+}
+
+/// A sync [started](WriteCounters::start_sync), its record is durable once synced.
+#[derive(Debug)]
+pub struct PendingSync {
+    point: u64,
+    file: File,
+}
+
+impl PendingSync {
+    /// The blocks written with a counter below it are synced, see [`WriteCounters::synced`].
+    #[must_use]
+    pub const fn point(&self) -> u64 {
+        self.point
+    }
+
+    /// Make the record durable, on error the counters need to know, see [`WriteCounters::sync_failed`].
+    #[allow(clippy::missing_errors_doc)]
+    pub fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// Records of a log, up to the first one not whole, what is after it stays in `data`.
+struct Frames<'a> {
+    data: &'a [u8],
+    ino: u64,
+    cipher: Cipher,
+    key: &'a SecretVec<u8>,
+    log_id: u128,
+    frames: u64,
+}
+
+impl Iterator for Frames<'_> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        let len = u32::from_le_bytes(self.data.get(..4)?.try_into().unwrap()) as usize;
+        let sealed = self.data.get(4..4 + len)?;
+        let aad = frame_aad(self.ino, self.log_id, self.frames);
+        let plaintext =
+            crypto::open_block(self.cipher, self.key, &aad, false, sealed.to_vec()).ok()?;
+        let record = bincode::deserialize(&plaintext).ok()?;
+        self.data = &self.data[4 + len..];
+        self.frames += 1;
+        Some(record)
+    }
+}
+
+/// A record as it's in the log, its length then the record sealed with the file, the log and its position.
+fn frame(
+    ino: u64,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    log_id: u128,
+    index: u64,
+    record: &Record,
+) -> io::Result<Vec<u8>> {
+    let plaintext = bincode::serialize(record).map_err(io::Error::other)?;
+    let sealed = crypto::seal_block(
+        cipher,
+        key,
+        &frame_aad(ino, log_id, index),
+        &plaintext,
+        false,
+    )?;
+    #[allow(clippy::cast_possible_truncation)]
+    let mut frame = (sealed.len() as u32).to_le_bytes().to_vec();
+    frame.extend_from_slice(&sealed);
+    Ok(frame)
+}
+
+fn frame_aad(ino: u64, log_id: u128, index: u64) -> Vec<u8> {
+    let mut aad = ino.to_le_bytes().to_vec();
+    aad.extend_from_slice(&log_id.to_le_bytes());
+    aad.extend_from_slice(&index.to_le_bytes());
+    aad
+}
+
+fn aad(block_index: u64, stamp: Option<Stamp>) -> BlockAad {
+    let mut aad = block_index.to_le_bytes().to_vec();
+    if let Some(stamp) = stamp {
+        aad.extend_from_slice(&stamp.file_id.to_le_bytes());
+        aad.extend_from_slice(&stamp.counter.to_le_bytes());
+    }
+    BlockAad {
+        aad,
+        compressed: stamp.is_some_and(|stamp| stamp.compressed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    const FILE_ID: u128 = 42;
+
+    fn create_key() -> Arc<SecretVec<u8>> {
+        let mut key = vec![0; Cipher::ChaCha20Poly1305.key_len()];
+        crypto::create_rng().fill_bytes(&mut key);
+        Arc::new(SecretVec::from(key))
+    }
+
+    fn load(path: &Path, key: &Arc<SecretVec<u8>>) -> WriteCounters {
+        WriteCounters::load(path, 1, Cipher::ChaCha20Poly1305, key.clone())
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_legacy() {
+        let mut counters = WriteCounters::legacy();
+        assert_eq!(
+            Some(BlockAad {
+                aad: 0_u64.to_le_bytes().to_vec(),
+                compressed: false
+            }),
+            counters.aad(0)
+        );
+        assert!(!counters.has_fallbacks(0));
+        // nothing is recorded
+        assert_eq!(
+            1_u64.to_le_bytes().to_vec(),
+            counters.next_aad(1, false).unwrap()
+        );
+        counters.sync().unwrap();
+        assert_eq!(1_u64.to_le_bytes().to_vec(), counters.aad(1).unwrap().aad);
+    }
+
+    #[test]
+    fn test_blocks_without_counter() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("1.counters");
+        let key = create_key();
+        assert!(
+            WriteCounters::load(&path, 1, Cipher::ChaCha20Poly1305, key.clone())
+                .unwrap()
+                .is_none()
+        );
+        let mut counters =
+            WriteCounters::new(&path, 1, FILE_ID, Cipher::ChaCha20Poly1305, key.clone());
+        assert!(!path.exists());
+
+        let aad = counters.next_aad(1, false).unwrap();
+        counters.sync().unwrap();
+        let counters = load(&path, &key);
+        assert_eq!(Some(FILE_ID), counters.file_id());
+        assert_eq!(aad, counters.aad(1).unwrap().aad);
+        // never opened with its index only
+        assert_eq!(None, counters.aad(0));
+        assert_eq!(0, counters.fallback_aads(0).count());
+    }
+
+    #[test]
+    fn test_other_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("1.counters");
+        let key = create_key();
+        let mut counters =
+            WriteCounters::new(&path, 1, FILE_ID, Cipher::ChaCha20Poly1305, key.clone());
+        counters.next_aad(0, false).unwrap();
+        counters.sync().unwrap();
+        assert!(WriteCounters::load(&path, 2, Cipher::ChaCha20Poly1305, key).is_err());
+    }
+
+    #[test]
+    fn test_crash() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("1.counters");
+        let key = create_key();
+        let mut counters =
+            WriteCounters::new(&path, 1, FILE_ID, Cipher::ChaCha20Poly1305, key.clone());
+        let synced = BlockAad {
+            aad: counters.next_aad(0, false).unwrap(),
+            compressed: false,
+        };
+        counters.sync().unwrap();
+        let synced_to = counters.synced();
+        let unsynced = BlockAad {
+            aad: counters.next_aad(0, true).unwrap(),
+            compressed: true,
+        };
+        let len = fs::metadata(&path).unwrap().len();
+        let lost = BlockAad {
+            aad: counters.next_aad(1, true).unwrap(),
+            compressed: true,
+        };
+        drop(counters);
+        // the record of the last write didn't reach the disk, and a part of another one did
+        let mut data = fs::read(&path).unwrap();
+        #[allow(clippy::cast_possible_truncation)]
+        data.truncate(len as usize + 10);
+        fs::write(&path, data).unwrap();
+
+        let mut counters = load(&path, &key);
+        assert_eq!(synced_to, counters.synced());
+        assert_eq!(Some(unsynced), counters.aad(0));
+        // the block may have kept its previous content
+        assert!(counters.fallback_aads(0).any(|aad| aad == synced));
+        // or be of the write whose record was lost
+        assert_eq!(None, counters.aad(1));
+        assert!(counters.has_fallbacks(1));
+        assert!(counters.fallback_aads(1).any(|aad| aad == lost));
+
+        // a lost counter is not used again
+        let written = counters.next_aad(0, false).unwrap();
+        assert!(counters.fallback_aads(0).all(|aad| aad.aad != written));
+        counters.sync().unwrap();
+        assert!(counters.synced() > synced_to);
+        drop(counters);
+        let counters = load(&path, &key);
+        assert_eq!(written, counters.aad(0).unwrap().aad);
+        // written again since, its previous contents are not opened anymore
+        assert!(counters.fallback_aads(0).all(|aad| aad != synced));
+        assert!(counters.fallback_aads(1).any(|aad| aad == lost));
+    }
+
+    #[test]
+    fn test_sync_point() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("1.counters");
+        let key = create_key();
+        let mut counters =
+            WriteCounters::new(&path, 1, FILE_ID, Cipher::ChaCha20Poly1305, key.clone());
+        let synced = BlockAad {
+            aad: counters.next_aad(1, false).unwrap(),
+            compressed: false,
+        };
+        counters.sync().unwrap();
+        let written = counters.next_aad(0, false).unwrap();
+        let point = counters.sync_point().unwrap();
+        // written while the blocks before the point are synced
+        let unsynced = counters.next_aad(1, false).unwrap();
+        let pending = counters.start_sync(point).unwrap();
+        pending.sync_data().unwrap();
+        assert_eq!(point, pending.point());
+        drop(counters);
+
+        let counters = load(&path, &key);
+        assert_eq!(point, counters.synced());
+        assert_eq!(written, counters.aad(0).unwrap().aad);
+        assert!(!counters.has_fallbacks(0));
+        // it may have kept its synced content
+        assert_eq!(unsynced, counters.aad(1).unwrap().aad);
+        assert!(counters.fallback_aads(1).any(|aad| aad == synced));
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("1.counters");
+        let key = create_key();
+        let mut counters =
+            WriteCounters::new(&path, 1, FILE_ID, Cipher::ChaCha20Poly1305, key.clone());
+        let mut max_len = 0;
+        let mut aad = vec![];
+        for _ in 0..2 * MAX_STALE {
+            aad = counters.next_aad(0, false).unwrap();
+            counters.sync().unwrap();
+            max_len = max_len.max(fs::metadata(&path).unwrap().len());
+        }
+        // written from scratch when it gets too long
+        let frame_len = frame(
+            1,
+            Cipher::ChaCha20Poly1305,
+            &key,
+            0,
+            0,
+            &Record::Write(
+                0,
+                Stamp {
+                    file_id: 0,
+                    counter: 0,
+                    compressed: false,
+                },
+            ),
+        )
+        .unwrap()
+        .len() as u64;
+        assert!(max_len < (MAX_STALE + 16) * frame_len);
+        let synced = counters.synced();
+        drop(counters);
+        let counters = load(&path, &key);
+        assert_eq!(synced, counters.synced());
+        assert_eq!(aad, counters.aad(0).unwrap().aad);
+        assert!(!counters.has_fallbacks(0));
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+/// Holes shared between the readers and writers of the same file.
+pub type SharedHoles = Arc<RwLock<Holes>>;
+
+/// Blocks of a sparse file that were never written.
+///
+/// They are not encrypted, the ciphertext at their position is left unwritten (so the underlying file
+/// is sparse too) and they read as zeros. The last block of a file is never a hole.\
+/// Kept as ranges of block indexes, `start -> end` with `end` exclusive.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Holes {
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl Holes {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    #[must_use]
+    pub fn contains(&self, block_index: u64) -> bool {
+        self.ranges
+            .range(..=block_index)
+            .next_back()
+            .is_some_and(|(_, end)| *end > block_index)
+    }
+
+    /// First block at or after `block_index` that is not a hole.
+    #[must_use]
+    pub fn next_data(&self, block_index: u64) -> u64 {
+        self.ranges
+            .range(..=block_index)
+            .next_back()
+            .filter(|(_, end)| **end > block_index)
+            .map_or(block_index, |(_, end)| *end)
+    }
+
+    /// First hole at or after `block_index`, if any.
+    #[must_use]
+    pub fn next_hole(&self, block_index: u64) -> Option<u64> {
+        if self.contains(block_index) {
+            return Some(block_index);
+        }
+        self.ranges
+            .range(block_index..)
+            .next()
+            .map(|(start, _)| *start)
+    }
+
+    /// Mark the blocks in `range` as holes, merging with adjacent ranges.
+    pub fn insert(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+        let mut start = range.start;
+        let mut end = range.end;
+        if let Some((prev_start, prev_end)) = self.ranges.range(..=start).next_back() {
+            if *prev_end >= start {
+                start = *prev_start;
+                end = end.max(*prev_end);
+            }
+        }
+        let overlapping: Vec<(u64, u64)> = self
+            .ranges
+            .range(start..=end)
+            .map(|(s, e)| (*s, *e))
+            .collect();
+        for (s, e) in overlapping {
+            self.ranges.remove(&s);
+            end = end.max(e);
+        }
+        self.ranges.insert(start, end);
+    }
+
+    /// The block was written, it's not a hole anymore.
+    pub fn remove(&mut self, block_index: u64) {
+        let Some((start, end)) = self
+            .ranges
+            .range(..=block_index)
+            .next_back()
+            .map(|(s, e)| (*s, *e))
+        else {
+            return;
+        };
+        if end <= block_index {
+            return;
+        }
+        self.ranges.remove(&start);
+        if start < block_index {
+            self.ranges.insert(start, block_index);
+        }
+        if block_index + 1 < end {
+            self.ranges.insert(block_index + 1, end);
+        }
+    }
+
+    /// Forget the holes from `block_index` onwards, used when the file is truncated.
+    pub fn truncate(&mut self, block_index: u64) {
+        self.ranges.split_off(&block_index);
+        if let Some((_, end)) = self.ranges.iter_mut().next_back() {
+            *end = (*end).min(block_index);
+        }
+    }
+}
@@ -10,6 +10,9 @@ use shush_rs::{ExposeSecret, SecretVec};
 use tracing::{error, instrument, warn};
 
 use crate::crypto::buf_mut::BufMut;
+use crate::crypto::chunks::SharedChunkRefs;
+use crate::crypto::counters::SharedCounters;
+use crate::crypto::holes::SharedHoles;
 use crate::crypto::write::BLOCK_SIZE;
 use crate::stream_util;
 
@@ -25,8 +28,10 @@ pub trait CryptoRead<R: Read + Send + Sync>: Read + Send + Sync {
 /// ring
 #[macro_export]
 macro_rules! decrypt_block {
-    ($block_index:expr, $buf:expr, $input:expr, $last_nonce:expr, $opening_key:expr) => {{
+    ($block_index:expr, $buf:expr, $input:expr, $last_nonce:expr, $opening_key:expr, $holes:expr, $chunks:expr, $counters:expr) => {{
         let len = {
+            // held while reading, so the block and its counter are of the same write
+            let counters = $counters.as_ref().map(|counters| counters.read().unwrap());
             $buf.clear();
             let buffer = $buf.as_mut_remaining();
             let mut len = {
@@ -44,20 +49,118 @@ macro_rules! decrypt_block {
                 }
                 pos
             };
-            if len != 0 {
-                let data = &mut buffer[..len];
-                let aad = Aad::from(($block_index).to_le_bytes());
-                // extract nonce
-                $last_nonce
-                    .lock()
-                    .unwrap()
-                    .replace(data[..NONCE_LEN].to_vec());
-                let data = &mut data[NONCE_LEN..];
-                let plaintext = $opening_key.open_within(aad, data, 0..).map_err(|err| {
-                    error!("error opening within: {}", err);
+            let is_hole = $holes
+                .as_ref()
+                .is_some_and(|holes| holes.read().unwrap().contains($block_index));
+            if len != 0 && is_hole {
+                // never written, the ciphertext is just a gap in the file, it reads as zeros
+                len = len.saturating_sub(NONCE_LEN + $opening_key.algorithm().tag_len());
+                buffer[NONCE_LEN..NONCE_LEN + len].fill(0);
+            } else if len != 0 {
+                // kept in the chunk store, what is in the file is not used
+                let chunk = $chunks
+                    .as_ref()
+                    .and_then(|chunks| {
+                        let chunks = chunks.read().unwrap();
+                        chunks
+                            .get($block_index)
+                            .map(|id| chunks.read_chunk(id).map(|block| (id, block)))
+                    })
+                    .transpose()?;
+                let (aad, fallbacks) = if let Some((id, chunk)) = chunk {
+                    let (aad, compressed, block) = $crate::crypto::split_chunk(id, chunk)?;
+                    if block.len() > buffer.len() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "chunk is too long",
+                        ));
+                    }
+                    len = block.len();
+                    buffer[..len].copy_from_slice(&block);
+                    (
+                        Some($crate::crypto::counters::BlockAad { aad, compressed }),
+                        None,
+                    )
+                } else if let Some(counters) = counters.as_ref() {
+                    (
+                        counters.aad($block_index),
+                        // after a crash it may be of another write
+                        counters
+                            .has_fallbacks($block_index)
+                            .then(|| counters.fallback_aads($block_index)),
+                    )
+                } else {
+                    (
+                        Some($crate::crypto::counters::BlockAad {
+                            aad: ($block_index).to_le_bytes().to_vec(),
+                            compressed: false,
+                        }),
+                        None,
+                    )
+                };
+                // kept to try again with the fallbacks, opening changes it
+                let ciphertext = fallbacks.is_some().then(|| buffer[..len].to_vec());
+                let mut opened = None;
+                let mut first_err = None;
+                // a block without a counter only opens with a fallback
+                for aad in aad.into_iter().chain(fallbacks.into_iter().flatten()) {
+                    if let (Some(ciphertext), Some(_)) = (&ciphertext, &first_err) {
+                        buffer[..len].copy_from_slice(ciphertext);
+                    }
+                    let data = &mut buffer[..len];
+                    // extract nonce
+                    $last_nonce
+                        .lock()
+                        .unwrap()
+                        .replace(data[..NONCE_LEN].to_vec());
+                    if aad.compressed {
+                        // a compressed block, see crate::crypto::compress
+                        let tag_len = $opening_key.algorithm().tag_len();
+                        let Some(compressed_len) =
+                            $crate::crypto::compress::compressed_len(data, tag_len)
+                        else {
+                            first_err.get_or_insert(ring::error::Unspecified);
+                            continue;
+                        };
+                        let start = NONCE_LEN + $crate::crypto::compress::LEN_LEN;
+                        let mut data = data[start..start + compressed_len + tag_len].to_vec();
+                        let aad =
+                            Aad::from($crate::crypto::compress::aad(&aad.aad, compressed_len));
+                        match $opening_key.open_within(aad, &mut data, 0..) {
+                            Ok(compressed) => {
+                                let plaintext = $crate::crypto::compress::decompress(
+                                    compressed,
+                                    $crate::crypto::write::BLOCK_SIZE,
+                                )?;
+                                buffer[NONCE_LEN..NONCE_LEN + plaintext.len()]
+                                    .copy_from_slice(&plaintext);
+                                opened = Some(plaintext.len());
+                                break;
+                            }
+                            Err(err) => {
+                                first_err.get_or_insert(err);
+                                continue;
+                            }
+                        }
+                    }
+                    let data = &mut data[NONCE_LEN..];
+                    match $opening_key.open_within(Aad::from(aad.aad), data, 0..) {
+                        Ok(plaintext) => {
+                            opened = Some(plaintext.len());
+                            break;
+                        }
+                        Err(err) => {
+                            first_err.get_or_insert(err);
+                        }
+                    }
+                }
+                len = opened.ok_or_else(|| {
+                    match first_err {
+                        Some(err) => error!("error opening within: {}", err),
+                        None => error!("block without a write counter"),
+                    }
                     io::Error::new(io::ErrorKind::Other, "error opening within")
                 })?;
-                len = plaintext.len();
             }
             len
         };
@@ -82,6 +185,9 @@ pub struct RingCryptoRead<R: Read> {
     ciphertext_block_size: usize,
     plaintext_block_size: usize,
     block_index: u64,
+    holes: Option<SharedHoles>,
+    chunks: Option<SharedChunkRefs>,
+    counters: Option<SharedCounters>,
 }
 
 impl<R: Read> RingCryptoRead<R> {
@@ -101,8 +207,33 @@ impl<R: Read> RingCryptoRead<R> {
             ciphertext_block_size,
             plaintext_block_size: BLOCK_SIZE,
             block_index: 0,
+            holes: None,
+            chunks: None,
+            counters: None,
         }
     }
+
+    /// Blocks in `holes` are not decrypted, they read as zeros.
+    #[must_use]
+    pub fn with_holes(mut self, holes: SharedHoles) -> Self {
+        self.holes = Some(holes);
+        self
+    }
+
+    /// Blocks in `chunks` are read from the chunk store instead.
+    #[must_use]
+    pub fn with_chunks(mut self, chunks: SharedChunkRefs) -> Self {
+        self.chunks = Some(chunks);
+        self
+    }
+
+    /// Blocks are opened as they were last written according to `counters`, an older content of a block, or one of
+    /// another file, fails to open.
+    #[must_use]
+    pub fn with_counters(mut self, counters: SharedCounters) -> Self {
+        self.counters = Some(counters);
+        self
+    }
 }
 
 impl<R: Read> Read for RingCryptoRead<R> {
@@ -119,7 +250,10 @@ impl<R: Read> Read for RingCryptoRead<R> {
             self.buf,
             self.input.as_mut().unwrap(),
             self.last_nonce,
-            self.opening_key
+            self.opening_key,
+            self.holes,
+            self.chunks,
+            self.counters
         );
         let len = self.buf.read(buf)?;
         Ok(len)
@@ -234,7 +368,10 @@ impl<R: Read + Seek> Seek for RingCryptoRead<R> {
                     self.buf,
                     self.input.as_mut().unwrap(),
                     self.last_nonce,
-                    self.opening_key
+                    self.opening_key,
+                    self.holes,
+                    self.chunks,
+                    self.counters
                 );
             }
             // seek inside new block
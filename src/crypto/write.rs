@@ -1,7 +1,7 @@
 use std::any::Any;
 use std::io;
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 
 use bytes::Buf;
 use rand_chacha::rand_core::RngCore;
@@ -13,6 +13,11 @@ use shush_rs::{ExposeSecret, SecretVec};
 use tracing::error;
 
 use crate::crypto::buf_mut::BufMut;
+use crate::crypto::chunks::SharedChunkRefs;
+use crate::crypto::compress;
+use crate::crypto::compress::Compression;
+use crate::crypto::counters::SharedCounters;
+use crate::crypto::holes::{Holes, SharedHoles};
 use crate::crypto::read::ExistingNonceSequence;
 use crate::{crypto, decrypt_block, stream_util};
 
@@ -78,6 +83,10 @@ pub struct RingCryptoWrite<W: CryptoInnerWriter + Send + Sync> {
     opening_key: Option<OpeningKey<ExistingNonceSequence>>,
     last_nonce: Option<Arc<Mutex<Option<Vec<u8>>>>>,
     decrypt_buf: Option<BufMut>,
+    holes: Option<SharedHoles>,
+    chunks: Option<SharedChunkRefs>,
+    counters: Option<SharedCounters>,
+    compression: Option<Compression>,
 }
 
 impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
@@ -119,12 +128,76 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
             opening_key,
             last_nonce,
             decrypt_buf,
+            holes: None,
+            chunks: None,
+            counters: None,
+            compression: None,
         }
     }
 
+    /// When seeking after the end, instead of encrypting zeros the skipped blocks are recorded in `holes`.
+    #[must_use]
+    pub fn with_holes(mut self, holes: SharedHoles) -> Self {
+        self.holes = Some(holes);
+        self
+    }
+
+    /// Blocks in `chunks` are read from the chunk store, writing them removes them from it.
+    #[must_use]
+    pub fn with_chunks(mut self, chunks: SharedChunkRefs) -> Self {
+        self.chunks = Some(chunks);
+        self
+    }
+
+    /// Each block written is counted in `counters` and bound to it, see [`WriteCounters`](crate::crypto::counters::WriteCounters).
+    #[must_use]
+    pub fn with_counters(mut self, counters: SharedCounters) -> Self {
+        self.counters = Some(counters);
+        self
+    }
+
+    /// Blocks are compressed before they are encrypted, when that makes them shorter, see [`compress`].
+    ///
+    /// Only for a writer which can seek and [counts](Self::with_counters) its blocks, the others write them as they are.
+    #[must_use]
+    pub const fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
     fn encrypt_and_write(&mut self) -> io::Result<()> {
+        // held while writing, so readers get the block and its counter of the same write
+        let counters = self.counters.clone();
+        let mut counters = counters.as_ref().map(|counters| counters.write().unwrap());
+        // it's recorded with the counter whether the block is compressed, so the blocks without one are not
+        let compressed = counters
+            .as_ref()
+            .is_some_and(|counters| !counters.is_legacy())
+            .then(|| self.compress())
+            .flatten();
+        let aad = match counters.as_mut() {
+            Some(counters) => counters.next_aad(self.block_index, compressed.is_some())?,
+            None => self.block_index.to_le_bytes().to_vec(),
+        };
+        if let Some(data) = compressed {
+            self.write_compressed(data, &aad)?;
+        } else {
+            self.write_sealed(&aad)?;
+        }
+        drop(counters);
+        if let Some(holes) = self.holes.as_ref() {
+            holes.write().unwrap().remove(self.block_index);
+        }
+        if let Some(chunks) = self.chunks.as_ref() {
+            chunks.write().unwrap().remove(self.block_index);
+        }
+        self.block_index += 1;
+        Ok(())
+    }
+
+    fn write_sealed(&mut self, aad: &[u8]) -> io::Result<()> {
         let data = self.buf.as_mut();
-        let aad = Aad::from(self.block_index.to_le_bytes());
+        let aad = Aad::from(aad);
         let tag = self
             .sealing_key
             .seal_in_place_separate_tag(aad, data)
@@ -146,7 +219,52 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
         self.buf.clear();
         writer.write_all(tag.as_ref())?;
         writer.flush()?;
-        self.block_index += 1;
+        Ok(())
+    }
+
+    /// The compressed block, if it's shorter. Only when the writer can seek, to skip the rest of the block.
+    fn compress(&mut self) -> Option<Vec<u8>> {
+        let compression = self.compression?;
+        self.writer.as_mut()?.as_write_seek_read()?;
+        compress::compress(compression, self.buf.as_ref())
+    }
+
+    fn write_compressed(&mut self, mut data: Vec<u8>, aad: &[u8]) -> io::Result<()> {
+        let aad = Aad::from(compress::aad(aad, data.len()));
+        let tag = self
+            .sealing_key
+            .seal_in_place_separate_tag(aad, &mut data)
+            .map_err(|err| {
+                error!("error sealing in place: {}", err);
+                io::Error::other(format!("error sealing in place: {err}"))
+            })?;
+        // the block keeps the place it has uncompressed
+        let block_end = self.block_index * self.ciphertext_block_size as u64
+            + (self.ciphertext_block_size - self.plaintext_block_size + self.buf.available())
+                as u64;
+        let nonce_sequence = self.nonce_sequence.lock().unwrap();
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?
+            .as_write_seek_read()
+            .ok_or(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "downcast failed",
+            ))?;
+        writer.write_all(&nonce_sequence.last_nonce)?;
+        writer.write_all(&compress::len_bytes(data.len()))?;
+        writer.write_all(&data)?;
+        writer.write_all(tag.as_ref())?;
+        self.buf.clear();
+        if writer.stream_len()? < block_end {
+            // a gap in the file, without writing the rest
+            writer.seek(SeekFrom::Start(block_end - 1))?;
+            writer.write_all(&[0])?;
+        } else {
+            writer.seek(SeekFrom::Start(block_end))?;
+        }
+        writer.flush()?;
         Ok(())
     }
 
@@ -170,7 +288,10 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
             self.decrypt_buf.as_mut().unwrap(),
             writer,
             self.last_nonce.as_ref().unwrap(),
-            self.opening_key.as_mut().unwrap()
+            self.opening_key.as_mut().unwrap(),
+            self.holes,
+            self.chunks,
+            self.counters
         );
         if old_block_index == self.block_index {
             // no decryption happened
@@ -355,6 +476,46 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
     }
 }
 
+impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
+    /// We are at the end and need to extend until `new_pos`.
+    ///
+    /// Pad the current block with zeros, then record the whole blocks until `new_pos` as holes and move after them.
+    /// The block containing `new_pos`, or the one before if it's on a block boundary,
+    /// is left to be filled with zeros by the caller, so the file never ends with a hole.
+    fn skip_blocks_as_holes(&mut self, new_pos: u64, holes: &RwLock<Holes>) -> io::Result<()> {
+        let plaintext_block_size = self.plaintext_block_size as u64;
+        let offset_in_block = self.pos() % plaintext_block_size;
+        if offset_in_block > 0 {
+            let len = (plaintext_block_size - offset_in_block).min(new_pos - self.pos());
+            stream_util::fill_zeros(self, len)?;
+        }
+        let first_hole = self.pos().div_ceil(plaintext_block_size);
+        let last_block_index = (new_pos - 1) / plaintext_block_size;
+        if first_hole >= last_block_index {
+            return Ok(());
+        }
+        if self.buf.is_dirty() {
+            self.encrypt_and_write()?;
+        }
+        holes.write().unwrap().insert(first_hole..last_block_index);
+        self.buf.clear();
+        self.block_index = last_block_index;
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?
+            .as_write_seek_read()
+            .ok_or(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "downcast failed",
+            ))?;
+        writer.seek(SeekFrom::Start(
+            last_block_index * self.ciphertext_block_size as u64,
+        ))?;
+        Ok(())
+    }
+}
+
 impl<W: CryptoInnerWriter + Send + Sync> Seek for RingCryptoWrite<W> {
     #[allow(clippy::cast_possible_wrap)]
     #[allow(clippy::cast_sign_loss)]
@@ -448,6 +609,9 @@ impl<W: CryptoInnerWriter + Send + Sync> Seek for RingCryptoWrite<W> {
         }
         // if we couldn't seek until new pos, write zeros until new position
         if self.pos() < new_pos {
+            if let Some(holes) = self.holes.clone() {
+                self.skip_blocks_as_holes(new_pos, &holes)?;
+            }
             let len = new_pos - self.pos();
             stream_util::fill_zeros(self, len)?;
         }
@@ -1381,3 +1381,57 @@ fn writer_with_seeks() {
     writer.seek(SeekFrom::Start(42)).unwrap();
     assert_eq!(writer.stream_position().unwrap(), 42);
 }
+
+#[test]
+#[traced_test]
+fn writer_seek_after_end_leaves_holes() {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    use std::sync::RwLock;
+
+    use crate::crypto::holes::Holes;
+    use crate::crypto::read::CryptoRead;
+    use crate::crypto::write::{CryptoWrite, BLOCK_SIZE};
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let key = create_secret_key(cipher.key_len());
+    let holes = Arc::new(RwLock::new(Holes::default()));
+
+    let cursor = Cursor::new(vec![0; 0]);
+    let mut writer = crypto::create_write_seek_with_holes(cursor, cipher, &key, holes.clone());
+    writer.write_all(b"start").unwrap();
+    writer
+        .seek(SeekFrom::Start(BLOCK_SIZE as u64 * 5 + 2))
+        .unwrap();
+    writer.write_all(b"end").unwrap();
+    let mut cursor = writer.finish().unwrap();
+    cursor.seek(SeekFrom::Start(0)).unwrap();
+
+    // only the first and last blocks are encrypted
+    let ciphertext_block_size = cipher.ciphertext_block_size();
+    assert!((1..5).all(|i| holes.read().unwrap().contains(i)));
+    assert!(!holes.read().unwrap().contains(0));
+    assert!(!holes.read().unwrap().contains(5));
+    assert!(
+        cursor.get_ref()[ciphertext_block_size..ciphertext_block_size * 5]
+            .iter()
+            .all(|b| *b == 0)
+    );
+
+    let mut reader = crypto::create_read_seek_with_holes(cursor, cipher, &key, holes.clone());
+    let mut plaintext = vec![];
+    reader.read_to_end(&mut plaintext).unwrap();
+    let mut expected = b"start".to_vec();
+    expected.resize(BLOCK_SIZE * 5 + 2, 0);
+    expected.extend_from_slice(b"end");
+    assert_eq!(expected, plaintext);
+
+    // writing inside a hole encrypts that block
+    let mut cursor = reader.into_inner();
+    cursor.seek(SeekFrom::Start(0)).unwrap();
+    let mut writer = crypto::create_write_seek_with_holes(cursor, cipher, &key, holes.clone());
+    writer.seek(SeekFrom::Start(BLOCK_SIZE as u64 * 2)).unwrap();
+    writer.write_all(b"middle").unwrap();
+    writer.finish().unwrap();
+    assert!(!holes.read().unwrap().contains(2));
+    assert!(holes.read().unwrap().contains(3));
+}
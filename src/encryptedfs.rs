@@ -3,14 +3,17 @@ use async_trait::async_trait;
 use futures_util::TryStreamExt;
 use lru::LruCache;
 use num_format::{Locale, ToFormattedString};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use shush_rs::{ExposeSecret, SecretBox, SecretString, SecretVec};
 use std::backtrace::Backtrace;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::ffi::OsString;
 use std::fmt::Debug;
-use std::fs::{DirEntry, File, OpenOptions, ReadDir};
+use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::num::{NonZeroUsize, ParseIntError};
+use std::ops::{Range, RangeBounds};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::atomic::{AtomicU64, Ordering};
@@ -19,34 +22,122 @@ use std::time::{Duration, SystemTime};
 use std::{fs, io};
 use thiserror::Error;
 use tokio::runtime::Runtime;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{Mutex, OwnedRwLockReadGuard, RwLock};
 use tokio::task::{JoinError, JoinSet};
 use tokio_stream::wrappers::ReadDirStream;
 use tracing::{debug, error, info, instrument, warn, Level};
 
 use crate::arc_hashmap::ArcHashMap;
+use crate::crypto::chunks::{ChunkRefs, SharedChunkRefs};
+use crate::crypto::compress::Compression;
+use crate::crypto::counters::{SharedCounters, WriteCounters};
+use crate::crypto::holes::{Holes, SharedHoles};
 use crate::crypto::read::{CryptoRead, CryptoReadSeek};
-use crate::crypto::write::{CryptoInnerWriter, CryptoWrite, CryptoWriteSeek};
+use crate::crypto::write::{CryptoInnerWriter, CryptoWrite, CryptoWriteSeek, BLOCK_SIZE};
 use crate::crypto::Cipher;
+use crate::encryptedfs::acl::{Acl, ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR};
+use crate::encryptedfs::chunk_cache::ChunkCache;
+use crate::encryptedfs::content_file::ContentFile;
+use crate::encryptedfs::dedup::{
+    Allocated, ChunkStore, PhysicalSize, CHUNKS_DIR, CHUNKS_EXT, PHYSICAL_SIZE_FILENAME, REFS_EXT,
+};
+use crate::encryptedfs::lock::{FileLock, LockManager, LockType};
+use crate::encryptedfs::quota::{Quota, QuotaTracker, Usage};
+use crate::encryptedfs::snapshot::{COW_DIR, SNAPSHOTS_DIR};
+use crate::encryptedfs::trash::{Trash, TrashEntry, TrashRecord};
+use crate::encryptedfs::versions::{FileVersion, Versioning};
 use crate::expire_value::{ExpireValue, ValueProvider};
 use crate::{crypto, fs_util, stream_util};
 use bon::bon;
+use strum_macros::{Display, EnumIter, EnumString};
 
+pub mod acl;
 mod bench;
+mod chunk_cache;
+mod content_file;
+mod dedup;
+pub mod lock;
+pub mod quota;
+pub mod snapshot;
 #[cfg(test)]
 mod test;
+pub mod trash;
+pub mod versions;
 
 pub(crate) const INODES_DIR: &str = "inodes";
 pub(crate) const CONTENTS_DIR: &str = "contents";
 pub(crate) const SECURITY_DIR: &str = "security";
 pub(crate) const KEY_ENC_FILENAME: &str = "key.enc";
 pub(crate) const KEY_SALT_FILENAME: &str = "key.salt";
+pub(crate) const QUOTA_FILENAME: &str = "quota.enc";
+pub(crate) const TRASH_FILENAME: &str = "trash.enc";
+pub(crate) const VERSIONING_FILENAME: &str = "versioning.enc";
+pub(crate) const DEDUP_FILENAME: &str = "dedup.enc";
+pub(crate) const COMPRESSION_FILENAME: &str = "compression.enc";
+/// Records of the removed entries, see [`trash`].
+pub(crate) const TRASH_DIR: &str = ".trash";
 
 pub(crate) const LS_DIR: &str = "ls";
 pub(crate) const HASH_DIR: &str = "hash";
+/// Encrypted names too long for an `ls/` entry, see [`ls_entry_name`].
+pub(crate) const LONG_NAMES_DIR: &str = "names";
+const LONG_NAME_PREFIX: &str = "$long.";
+/// Most `ls/` names a [`DirectoryEntryIterator`] keeps at once.
+const DIR_BATCH: usize = 1024;
+
+/// Longest file name, in bytes, like Linux allows.
+pub const NAME_MAX: usize = 255;
+
+/// Extension of the file next to `inodes/<ino>` keeping the encrypted extended attributes.
+pub(crate) const XATTR_EXT: &str = "xattr";
+/// Extension of the file next to `inodes/<ino>` keeping the encrypted holes of a sparse file.
+pub(crate) const HOLES_EXT: &str = "holes";
+/// Extension of the file next to `inodes/<ino>` keeping the encrypted log of the write counters of a file.
+pub(crate) const COUNTERS_EXT: &str = "counters";
+/// Extension of the directory next to `inodes/<ino>` keeping the previous contents of a file, see [`versions`].
+pub(crate) const VERSIONS_EXT: &str = "versions";
+/// Extension of the file next to a version keeping its size and times.
+const VERSION_META_EXT: &str = "meta";
 
 pub(crate) const ROOT_INODE: u64 = 1;
 
+/// With [`AtimeMode::RelAtime`] the access time is updated at least this often, like Linux does.
+const RELATIME_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often the trash and the file versions are checked for entries older than their retention.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Name of the `ls/` entry for an encrypted name.
+///
+/// Encryption makes names longer, when it doesn't fit the backing filesystem the entry is named by the hash of it
+/// and the encrypted name is kept in `names/` with the same name.
+pub(crate) fn ls_entry_name(encrypted_name: &str) -> String {
+    if encrypted_name.len() <= NAME_MAX {
+        encrypted_name.to_string()
+    } else {
+        format!(
+            "{LONG_NAME_PREFIX}{}",
+            hex::encode(crypto::hash(encrypted_name.as_bytes()))
+        )
+    }
+}
+
+/// Cookie of a directory entry by its name in `ls/`, the listing is ordered by it.
+///
+/// It doesn't change while the entry exists, so a listing continues after the same entry even if others were added
+/// or removed meanwhile. It fits in an `i64` and is above `0`, like FUSE offsets.
+fn dir_entry_cookie(ls_name: &str) -> u64 {
+    match ls_name {
+        "$." => 1,
+        "$.." => 2,
+        _ => {
+            let hash = crypto::hash(ls_name.as_bytes());
+            let cookie = u64::from_le_bytes(hash[..8].try_into().unwrap()) >> 1;
+            cookie.max(3)
+        }
+    }
+}
+
 fn spawn_runtime() -> Runtime {
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -55,7 +146,6 @@ fn spawn_runtime() -> Runtime {
     runtime
 }
 
-static DIR_ENTRIES_RT: LazyLock<Runtime> = LazyLock::new(spawn_runtime);
 static NOD_RT: LazyLock<Runtime> = LazyLock::new(spawn_runtime);
 
 /// File attributes.
@@ -91,25 +181,238 @@ pub struct FileAttr {
     pub blksize: u32,
     /// Flags (macOS only, see chflags(2))
     pub flags: u32,
+    /// Random id the blocks of the file are bound to, see [`WriteCounters`]. [None] for a file created before it was
+    /// kept, its blocks are bound to their index only
+    pub file_id: Option<u128>,
+    /// Its write counters were synced up to this one, a log behind it was cut, see [`WriteCounters::synced`]
+    pub counters_synced: u64,
+    /// Generation of the last holes and chunk references saved, older ones were rolled back
+    pub side_files_generation: u64,
+}
+
+/// An inode as it was written before [`FileAttr::file_id`] was kept.
+#[derive(Deserialize)]
+struct LegacyFileAttr {
+    ino: u64,
+    size: u64,
+    blocks: u64,
+    atime: SystemTime,
+    mtime: SystemTime,
+    ctime: SystemTime,
+    crtime: SystemTime,
+    kind: FileType,
+    perm: u16,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    blksize: u32,
+    flags: u32,
+}
+
+impl From<LegacyFileAttr> for FileAttr {
+    fn from(value: LegacyFileAttr) -> Self {
+        Self {
+            ino: value.ino,
+            size: value.size,
+            blocks: value.blocks,
+            atime: value.atime,
+            mtime: value.mtime,
+            ctime: value.ctime,
+            crtime: value.crtime,
+            kind: value.kind,
+            perm: value.perm,
+            nlink: value.nlink,
+            uid: value.uid,
+            gid: value.gid,
+            rdev: value.rdev,
+            blksize: value.blksize,
+            flags: value.flags,
+            file_id: None,
+            counters_synced: 0,
+            side_files_generation: 0,
+        }
+    }
+}
+
+/// Read an inode, also one written before [`FileAttr::file_id`] was kept.
+fn deserialize_attr(mut reader: impl Read) -> FsResult<FileAttr> {
+    let mut data = vec![];
+    reader.read_to_end(&mut data)?;
+    // the legacy one is shorter, it doesn't read as the current one
+    Ok(bincode::deserialize(&data)
+        .or_else(|_| bincode::deserialize::<LegacyFileAttr>(&data).map(FileAttr::from))?)
+}
+
+/// How [`EncryptedFs::set_xattr`] behaves when the attribute already exists or not.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SetXattrMode {
+    /// Create the attribute or replace the existing value.
+    #[default]
+    Upsert,
+    /// Fail with [`FsError::AlreadyExists`] if the attribute exists (`XATTR_CREATE`).
+    Create,
+    /// Fail with [`FsError::NotFound`] if the attribute doesn't exist (`XATTR_REPLACE`).
+    Replace,
+}
+
+/// How [`EncryptedFs::rename2`] behaves when the new name already exists, mirrors the `renameat2(2)` flags.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RenameMode {
+    /// Overwrite the existing entry.
+    #[default]
+    Replace,
+    /// Fail with [`FsError::AlreadyExists`] if the new name exists (`RENAME_NOREPLACE`).
+    NoReplace,
+    /// Swap the two entries, they can be of different types.
+    /// Fail with [`FsError::NotFound`] if the new name doesn't exist (`RENAME_EXCHANGE`).
+    Exchange,
+}
+
+/// When reads update the access time, mirrors the `noatime`, `relatime` and `strictatime` mount options.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumIter, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum AtimeMode {
+    /// Never update it.
+    NoAtime,
+    /// Only if it's not newer than the modification or change time, or it's older than a day.
+    #[default]
+    RelAtime,
+    /// On every read.
+    StrictAtime,
+}
+
+/// How [`EncryptedFs::open`] and [`EncryptedFs::create`] open a file, mirrors the `open(2)` flags.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenFlags {
+    pub read: bool,
+    pub write: bool,
+    /// Writes always go to the end of the file, ignoring the offset (`O_APPEND`)
+    pub append: bool,
+    /// Truncate the file to zero, needs `write` (`O_TRUNC`)
+    pub truncate: bool,
+    /// [`EncryptedFs::create`] fails with [`FsError::AlreadyExists`] if the file exists (`O_EXCL`),
+    /// else it opens the existing file
+    pub exclusive: bool,
+    /// Writes return only after the data is durable on disk (`O_SYNC` and `O_DSYNC`)
+    pub sync: bool,
+}
+
+impl OpenFlags {
+    #[must_use]
+    pub const fn new(read: bool, write: bool) -> Self {
+        Self {
+            read,
+            write,
+            append: false,
+            truncate: false,
+            exclusive: false,
+            sync: false,
+        }
+    }
+
+    #[must_use]
+    pub const fn with_append(mut self, append: bool) -> Self {
+        self.append = append;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_truncate(mut self, truncate: bool) -> Self {
+        self.truncate = truncate;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_exclusive(mut self, exclusive: bool) -> Self {
+        self.exclusive = exclusive;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+}
+
+/// What [`EncryptedFs::allocate`] does with the range, mirrors the `fallocate(2)` flags.
+///
+/// With no flags set the file is extended to cover the range.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocateMode {
+    /// Don't change the file size (`FALLOC_FL_KEEP_SIZE`)
+    pub keep_size: bool,
+    /// Deallocate the range, it will read as zeros (`FALLOC_FL_PUNCH_HOLE`), needs `keep_size`
+    pub punch_hole: bool,
+    /// Zero the range, extending the file if needed (`FALLOC_FL_ZERO_RANGE`)
+    pub zero_range: bool,
+}
+
+impl AllocateMode {
+    #[must_use]
+    pub const fn with_keep_size(mut self, keep_size: bool) -> Self {
+        self.keep_size = keep_size;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_punch_hole(mut self, punch_hole: bool) -> Self {
+        self.punch_hole = punch_hole;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_zero_range(mut self, zero_range: bool) -> Self {
+        self.zero_range = zero_range;
+        self
+    }
+}
+
+/// Space and inode usage of the filesystem, see [`EncryptedFs::statfs`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatFs {
+    /// Total number of blocks
+    pub blocks: u64,
+    /// Free blocks
+    pub bfree: u64,
+    /// Free blocks available to unprivileged users
+    pub bavail: u64,
+    /// Total number of inodes
+    pub files: u64,
+    /// Free inodes
+    pub ffree: u64,
+    /// Block size, the same as the plaintext size of an encrypted chunk
+    pub bsize: u32,
+    /// Size of the files, set while block deduplication is on
+    pub logical_bytes: Option<u64>,
+    /// Space the contents and the chunk store take in the data directory, set while block deduplication is on
+    pub physical_bytes: Option<u64>,
 }
 
+/// Number of files in the data directory needed at least for each entry:
+/// the inode, the contents and the two files of the entry in the parent directory.
+const FILES_PER_ENTRY: u64 = 4;
+
 /// File types.
+///
+/// New variants need to be added at the end as the variant index is persisted in inodes and directory entries.
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub enum FileType {
-    // /// Named pipe (S_IFIFO)
-    // NamedPipe,
-    // /// Character device (S_IFCHR)
-    // CharDevice,
-    // /// Block device (S_IFBLK)
-    // BlockDevice,
     /// Directory (`S_IFDIR`)
     Directory,
     /// Regular file (`S_IFREG`)
     RegularFile,
-    // /// Symbolic link (S_IFLNK)
-    // Symlink,
-    // /// Unix domain socket (S_IFSOCK)
-    // Socket,
+    /// Symbolic link (`S_IFLNK`)
+    Symlink,
+    /// Named pipe (`S_IFIFO`)
+    NamedPipe,
+    /// Character device (`S_IFCHR`)
+    CharDevice,
+    /// Block device (`S_IFBLK`)
+    BlockDevice,
+    /// Unix domain socket (`S_IFSOCK`)
+    Socket,
 }
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -237,10 +540,19 @@ impl From<CreateFileAttr> for FileAttr {
             rdev: value.rdev,
             blksize: 0,
             flags: value.flags,
+            file_id: Some(new_file_id()),
+            counters_synced: 0,
+            side_files_generation: 0,
         }
     }
 }
 
+fn new_file_id() -> u128 {
+    let mut id = [0; 16];
+    crypto::create_rng().fill_bytes(&mut id);
+    u128::from_le_bytes(id)
+}
+
 #[derive(Error, Debug)]
 pub enum FsError {
     #[error("IO error: {source}")]
@@ -267,6 +579,8 @@ pub enum FsError {
     InvalidFileHandle,
     #[error("already exists")]
     AlreadyExists,
+    /// Not returned anymore, several handles can write to the same file.
+    #[deprecated(note = "files can be opened for write by several handles")]
     #[error("already open for write")]
     AlreadyOpenForWrite,
     #[error("not empty")]
@@ -305,6 +619,14 @@ pub enum FsError {
     MaxFilesizeExceeded(usize),
     #[error("Read only mode is active.")]
     ReadOnly,
+    #[error("locked by another owner")]
+    WouldBlock,
+    #[error("quota exceeded")]
+    QuotaExceeded,
+    #[error("file name too long")]
+    NameTooLong,
+    #[error("tampered with: {0}")]
+    Tampered(&'static str),
 }
 
 #[derive(Debug, Clone)]
@@ -439,31 +761,213 @@ impl PartialEq for DirectoryEntryPlus {
 
 pub type FsResult<T> = Result<T, FsError>;
 
-pub struct DirectoryEntryIterator(VecDeque<FsResult<DirectoryEntry>>);
+/// Lazily decrypts the entries of a directory, so the memory used doesn't depend on its size.
+///
+/// Each entry has a cookie, its position in the listing, see [`DirectoryEntryIterator::cookie`].
+/// [`EncryptedFs::read_dir_from`] continues after a cookie without decrypting the entries before it.\
+/// The names are read in batches of at most [`DIR_BATCH`], each one with the next cookies after the last returned
+/// entry, so only a batch is kept at once.
+pub struct DirectoryEntryIterator {
+    fs: Arc<EncryptedFs>,
+    key: Arc<SecretVec<u8>>,
+    name_cache: Arc<Mutex<LruCache<String, SecretString>>>,
+    meta_cache: Arc<Mutex<DirEntryMetaCache>>,
+    ls_dir: PathBuf,
+    // with their cookies, in their order
+    batch: std::vec::IntoIter<(u64, OsString)>,
+    // the last batch had all the entries left
+    last_batch: bool,
+    cookie: u64,
+}
+
+impl DirectoryEntryIterator {
+    /// Cookie of the last returned entry, `0` before the first one.
+    #[must_use]
+    pub const fn cookie(&self) -> u64 {
+        self.cookie
+    }
+
+    /// Read the names of the next batch, the ones with the lowest cookies after the current one.
+    fn next_batch(&mut self) -> io::Result<()> {
+        // the highest cookie on top, so it's the one dropped when the batch is full
+        let mut batch = BinaryHeap::with_capacity(DIR_BATCH + 1);
+        let mut more = false;
+        for entry in fs::read_dir(&self.ls_dir)? {
+            let name = entry?.file_name();
+            let cookie = dir_entry_cookie(&name.to_string_lossy());
+            if cookie <= self.cookie {
+                continue;
+            }
+            batch.push((cookie, name));
+            if batch.len() > DIR_BATCH {
+                batch.pop();
+                more = true;
+            }
+        }
+        self.batch = batch.into_sorted_vec().into_iter();
+        self.last_batch = !more;
+        Ok(())
+    }
+
+    // caches are only used if they are not locked, so we don't need to wait
+    fn decrypt_entry(&self, name: &OsString) -> FsResult<DirectoryEntry> {
+        let path = self.ls_dir.join(name);
+        let name = name.to_string_lossy().to_string();
+        let long_name_path = name.starts_with(LONG_NAME_PREFIX).then(|| {
+            self.ls_dir
+                .parent()
+                .unwrap()
+                .join(LONG_NAMES_DIR)
+                .join(&name)
+        });
+        let name = {
+            if name == "$." {
+                SecretString::new(Box::new(".".into()))
+            } else if name == "$.." {
+                SecretString::from_str("..").unwrap()
+            } else if let Some(name_cached) = self
+                .name_cache
+                .try_lock()
+                .ok()
+                .and_then(|mut cache| cache.get(&name).cloned())
+            {
+                name_cached
+            } else {
+                let encrypted_name = if let Some(path) = &long_name_path {
+                    bincode::deserialize_from(crypto::create_read(
+                        File::open(path)?,
+                        self.fs.cipher,
+                        &self.key,
+                    ))?
+                } else {
+                    name.clone()
+                };
+                let decrypted_name =
+                    crypto::decrypt_file_name(&encrypted_name, self.fs.cipher, &self.key).map_err(
+                        |err| {
+                            error!(err = %err, "decrypting file name");
+                            FsError::InvalidInput("invalid file name")
+                        },
+                    )?;
+                if let Ok(mut cache) = self.name_cache.try_lock() {
+                    cache.put(name, decrypted_name.clone());
+                }
+                decrypted_name
+            }
+        };
+
+        self.fs.validate_filename(&name)?;
+
+        let file_path = path.to_str().unwrap().to_owned();
+        if let Some((ino, kind)) = self
+            .meta_cache
+            .try_lock()
+            .ok()
+            .and_then(|mut cache| cache.get(&file_path).copied())
+        {
+            return Ok(DirectoryEntry { ino, name, kind });
+        }
+        // entries are written atomically, no need to lock
+        let (ino, kind): (u64, FileType) = bincode::deserialize_from(crypto::create_read(
+            File::open(&path)?,
+            self.fs.cipher,
+            &self.key,
+        ))
+        .map_err(|err| {
+            error!(err = %err, "deserializing directory entry");
+            err
+        })?;
+        if let Ok(mut cache) = self.meta_cache.try_lock() {
+            cache.put(file_path, (ino, kind));
+        }
+        Ok(DirectoryEntry { ino, name, kind })
+    }
+}
 
 impl Iterator for DirectoryEntryIterator {
     type Item = FsResult<DirectoryEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.0.pop_front()
+        loop {
+            let (cookie, name) = match self.batch.next() {
+                Some(next) => next,
+                None if self.last_batch => return None,
+                None => {
+                    if let Err(err) = self.next_batch() {
+                        self.last_batch = true;
+                        return Some(Err(err.into()));
+                    }
+                    continue;
+                }
+            };
+            self.cookie = cookie;
+            match self.decrypt_entry(&name) {
+                // removed since we started listing
+                Err(FsError::Io { source, .. }) if source.kind() == io::ErrorKind::NotFound => {}
+                res => return Some(res),
+            }
+        }
     }
 }
 
-pub struct DirectoryEntryPlusIterator(VecDeque<FsResult<DirectoryEntryPlus>>);
+/// Like [`DirectoryEntryIterator`] but with [`FileAttr`].
+pub struct DirectoryEntryPlusIterator {
+    entries: DirectoryEntryIterator,
+    attr_cache: Arc<RwLock<LruCache<u64, FileAttr>>>,
+}
+
+impl DirectoryEntryPlusIterator {
+    /// Cookie of the last returned entry, `0` before the first one.
+    #[must_use]
+    pub const fn cookie(&self) -> u64 {
+        self.entries.cookie
+    }
+
+    fn attr(&self, ino: u64) -> FsResult<FileAttr> {
+        if let Some(attr) = self
+            .attr_cache
+            .try_write()
+            .ok()
+            .and_then(|mut cache| cache.get(&ino).copied())
+        {
+            return Ok(attr);
+        }
+        // inodes are written atomically, no need to lock
+        let file = File::open(self.entries.fs.ino_file(ino)).map_err(|err| {
+            error!(err = %err, "opening file");
+            FsError::InodeNotFound
+        })?;
+        deserialize_attr(crypto::create_read(
+            file,
+            self.entries.fs.cipher,
+            &self.entries.key,
+        ))
+    }
+}
 
 impl Iterator for DirectoryEntryPlusIterator {
     type Item = FsResult<DirectoryEntryPlus>;
 
     #[instrument(name = "DirectoryEntryPlusIterator::next", skip(self))]
     fn next(&mut self) -> Option<Self::Item> {
-        self.0.pop_front()
+        let entry = self.entries.next()?;
+        Some(entry.and_then(|entry| {
+            Ok(DirectoryEntryPlus {
+                attr: self.attr(entry.ino)?,
+                ino: entry.ino,
+                name: entry.name,
+                kind: entry.kind,
+            })
+        }))
     }
 }
 
 struct ReadHandleContext {
     ino: u64,
     attr: TimesFileAttr,
-    reader: Option<Box<dyn CryptoReadSeek<File>>>,
+    reader: Option<Box<dyn CryptoReadSeek<ContentFile>>>,
+    // the access time changed and needs to be saved
+    atime_changed: bool,
 }
 
 enum ReadHandleContextOperation {
@@ -479,7 +983,7 @@ impl ReadHandleContextOperation {
 }
 
 enum WriteHandleContextOperation {
-    Create { ino: u64 },
+    Create { ino: u64, flags: OpenFlags },
 }
 
 impl WriteHandleContextOperation {
@@ -493,7 +997,8 @@ impl WriteHandleContextOperation {
 struct WriteHandleContext {
     ino: u64,
     attr: TimesAndSizeFileAttr,
-    writer: Option<Box<dyn CryptoWriteSeek<File>>>,
+    cache: Arc<ChunkCache>,
+    flags: OpenFlags,
 }
 
 struct KeyProvider {
@@ -553,7 +1058,7 @@ pub struct EncryptedFs {
     cipher: Cipher,
     // (ino, fh)
     opened_files_for_read: RwLock<HashMap<u64, HashSet<u64>>>,
-    opened_files_for_write: RwLock<HashMap<u64, u64>>,
+    opened_files_for_write: RwLock<HashMap<u64, HashSet<u64>>>,
     // used for rw ops of actual serialization
     // use std::sync::RwLock instead of tokio::sync::RwLock because we need to use it also in sync code in `DirectoryEntryIterator` and `DirectoryEntryPlusIterator`
     serialize_inode_locks: Arc<ArcHashMap<u64, RwLock<bool>>>,
@@ -562,6 +1067,21 @@ pub struct EncryptedFs {
     // use std::sync::RwLock instead of tokio::sync::RwLock because we need to use it also in sync code in `DirectoryEntryIterator` and `DirectoryEntryPlusIterator`
     serialize_dir_entries_ls_locks: Arc<ArcHashMap<String, RwLock<bool>>>,
     serialize_dir_entries_hash_locks: Arc<ArcHashMap<String, RwLock<bool>>>,
+    // used for rw ops of extended attributes
+    serialize_xattr_locks: ArcHashMap<u64, RwLock<bool>>,
+    // holes of the sparse files, shared by all readers and writers of a file while they are opened
+    holes: Mutex<HashMap<u64, Weak<std::sync::RwLock<Holes>>>>,
+    // blocks of the files kept in the chunk store, shared the same way
+    chunk_refs: Mutex<HashMap<u64, Weak<std::sync::RwLock<ChunkRefs>>>>,
+    // write counters of the blocks of the files, shared the same way
+    counters: Mutex<HashMap<u64, Weak<std::sync::RwLock<WriteCounters>>>>,
+    // chunks being written, shared by all writers of a file while they are opened
+    chunk_caches: Mutex<HashMap<u64, Weak<ChunkCache>>>,
+    locks: LockManager,
+    // flock(2) locks, they don't conflict with the byte-range ones
+    flocks: LockManager,
+    // held for read by the operations changing the volume, for write while taking a snapshot
+    mutations: Arc<RwLock<()>>,
     read_write_locks: ArcHashMap<u64, RwLock<bool>>,
     key: ExpireValue<SecretVec<u8>, FsError, KeyProvider>,
     self_weak: std::sync::Mutex<Option<Weak<Self>>>,
@@ -574,6 +1094,17 @@ pub struct EncryptedFs {
     sizes_read: Mutex<HashMap<u64, AtomicU64>>,
     requested_read: Mutex<HashMap<u64, AtomicU64>>,
     read_only: bool,
+    atime_mode: AtimeMode,
+    // set only when there is a quota, or deduplication is on to count the logical size
+    quota: std::sync::Mutex<Option<QuotaTracker>>,
+    // set only when the trash is on
+    trash: std::sync::Mutex<Option<Trash>>,
+    // set only when versions are kept
+    versioning: std::sync::Mutex<Option<Versioning>>,
+    dedup: std::sync::atomic::AtomicBool,
+    // set only when deduplication is on
+    physical_size: std::sync::Mutex<Option<Arc<PhysicalSize>>>,
+    compression: std::sync::Mutex<Option<Compression>>,
 }
 
 impl EncryptedFs {
@@ -584,7 +1115,13 @@ impl EncryptedFs {
         password_provider: Box<dyn PasswordProvider>,
         cipher: Cipher,
         read_only: bool,
+        atime_mode: AtimeMode,
     ) -> FsResult<Arc<Self>> {
+        if !read_only && data_dir.join(COW_DIR).is_dir() {
+            return Err(FsError::InvalidInput(
+                "a snapshot can only be opened read-only",
+            ));
+        }
         let key_provider = KeyProvider {
             key_path: data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
             salt_path: data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
@@ -608,6 +1145,14 @@ impl EncryptedFs {
             serialize_update_inode_locks: ArcHashMap::default(),
             serialize_dir_entries_ls_locks: Arc::new(ArcHashMap::default()),
             serialize_dir_entries_hash_locks: Arc::new(ArcHashMap::default()),
+            serialize_xattr_locks: ArcHashMap::default(),
+            holes: Mutex::default(),
+            chunk_refs: Mutex::default(),
+            counters: Mutex::default(),
+            chunk_caches: Mutex::default(),
+            locks: LockManager::default(),
+            flocks: LockManager::default(),
+            mutations: Arc::default(),
             key,
             self_weak: std::sync::Mutex::new(None),
             read_write_locks: ArcHashMap::default(),
@@ -627,6 +1172,13 @@ impl EncryptedFs {
             sizes_read: Mutex::default(),
             requested_read: Mutex::default(),
             read_only,
+            atime_mode,
+            quota: std::sync::Mutex::default(),
+            trash: std::sync::Mutex::default(),
+            versioning: std::sync::Mutex::default(),
+            dedup: std::sync::atomic::AtomicBool::new(false),
+            physical_size: std::sync::Mutex::default(),
+            compression: std::sync::Mutex::default(),
         };
 
         let arc = Arc::new(fs);
@@ -636,6 +1188,12 @@ impl EncryptedFs {
             .replace(Arc::downgrade(&arc));
 
         arc.ensure_root_exists().await?;
+        arc.load_quota().await?;
+        arc.load_trash().await?;
+        arc.load_versioning().await?;
+        arc.load_dedup().await?;
+        arc.load_compression().await?;
+        Self::spawn_expiry(Arc::downgrade(&arc));
 
         Ok(arc)
     }
@@ -659,7 +1217,9 @@ impl EncryptedFs {
 
     fn validate_filename(&self, secret_filename: &SecretBox<String>) -> FsResult<()> {
         let filename = secret_filename.expose_secret().to_string();
-        if filename.contains('/') {
+        if filename.len() > NAME_MAX {
+            Err(FsError::NameTooLong)
+        } else if filename.contains('/') {
             Err(FsError::InvalidInput("'/' not allowed in the filename"))
         } else if filename.contains('\\') {
             Err(FsError::InvalidInput("'\\' not allowed in the filename"))
@@ -669,16 +1229,83 @@ impl EncryptedFs {
     }
 
     /// Create a new node in the filesystem
+    ///
+    /// If the parent has a default ACL, it's inherited and the permissions are limited by it.\
+    /// For symbolic links use [`EncryptedFs::create_symlink`].\
+    /// If `flags` asks to read or write the file is also opened, returning the handle. In that case an existing file
+    /// is opened instead, unless [`OpenFlags::exclusive`] is set.
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
-    #[allow(clippy::too_many_lines)]
     pub async fn create(
         &self,
         parent: u64,
         name: &SecretString,
         create_attr: CreateFileAttr,
-        read: bool,
-        write: bool,
+        flags: OpenFlags,
+    ) -> FsResult<(u64, FileAttr)> {
+        let _mutation = self.mutation().await;
+        if create_attr.kind == FileType::Symlink {
+            return Err(FsError::InvalidInput(
+                "symbolic links need to be created with create_symlink",
+            ));
+        }
+        self.create2(parent, name, create_attr, flags, None).await
+    }
+
+    /// Create a symbolic link named `name` in `parent` pointing to `target`.
+    ///
+    /// The target is stored encrypted in the contents file of the new inode.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_symlink(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        target: &SecretString,
+        mut create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr> {
+        let _mutation = self.mutation().await;
+        if target.expose_secret().is_empty() {
+            return Err(FsError::InvalidInput(
+                "symbolic link target cannot be empty",
+            ));
+        }
+        create_attr.kind = FileType::Symlink;
+        let (_, attr) = self
+            .create2(
+                parent,
+                name,
+                create_attr,
+                OpenFlags::default(),
+                Some(target.clone()),
+            )
+            .await?;
+        Ok(attr)
+    }
+
+    /// Read the target of a symbolic link.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_link(&self, ino: u64) -> FsResult<SecretString> {
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        if attr.kind != FileType::Symlink {
+            return Err(FsError::InvalidInodeType);
+        }
+        let target: String = bincode::deserialize_from(crypto::create_read(
+            File::open(self.contents_path(ino))?,
+            self.cipher,
+            &*self.key.get().await?,
+        ))?;
+        Ok(SecretString::new(Box::new(target)))
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::too_many_lines)]
+    async fn create2(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        mut create_attr: CreateFileAttr,
+        flags: OpenFlags,
+        link_target: Option<SecretString>,
     ) -> FsResult<(u64, FileAttr)> {
         if self.read_only {
             return Err(FsError::ReadOnly);
@@ -689,7 +1316,8 @@ impl EncryptedFs {
         if !self.exists(parent) {
             return Err(FsError::InodeNotFound);
         }
-        if self.exists_by_name(parent, name)? {
+        let exists = self.exists_by_name(parent, name)?;
+        if exists && (flags.exclusive || !(flags.read || flags.write)) {
             return Err(FsError::AlreadyExists);
         }
         self.validate_filename(name)?;
@@ -706,8 +1334,38 @@ impl EncryptedFs {
         let name_clone = name.clone();
         NOD_RT
             .spawn(async move {
+                if exists {
+                    // open the existing file
+                    let attr = self_clone
+                        .find_by_name(parent, &name_clone)
+                        .await?
+                        .ok_or(FsError::NotFound("name not found"))?;
+                    if attr.kind != FileType::RegularFile {
+                        return Err(FsError::AlreadyExists);
+                    }
+                    let fh = self_clone.open(attr.ino, flags).await?;
+                    return Ok((fh, self_clone.get_attr(attr.ino).await?));
+                }
+
+                // inherit default ACL of the parent, symbolic links don't have ACLs
+                let mut xattrs = BTreeMap::new();
+                if create_attr.kind != FileType::Symlink {
+                    if let Some(default_acl) = self_clone.get_default_acl(parent).await? {
+                        let (acl, perm) = default_acl.inherit(create_attr.perm);
+                        create_attr.perm = perm;
+                        xattrs.insert(ACL_ACCESS_XATTR.to_owned(), acl.to_bytes());
+                        if create_attr.kind == FileType::Directory {
+                            xattrs.insert(ACL_DEFAULT_XATTR.to_owned(), default_acl.to_bytes());
+                        }
+                    }
+                }
+
                 let mut attr: FileAttr = create_attr.into();
                 attr.ino = self_clone.generate_next_inode();
+                if let Some(target) = &link_target {
+                    attr.size = target.expose_secret().len() as u64;
+                }
+                self_clone.with_quota(|quota| quota.add_inode(attr.ino, attr.size))?;
 
                 let fs = self_clone;
                 let mut join_set = JoinSet::new();
@@ -715,6 +1373,7 @@ impl EncryptedFs {
                 // write inode
                 let self_clone = fs.clone();
                 self_clone.write_inode_to_storage(&attr).await?;
+                self_clone.write_xattrs(attr.ino, &xattrs).await?;
 
                 match attr.kind {
                     FileType::RegularFile => {
@@ -773,6 +1432,27 @@ impl EncryptedFs {
                             Ok::<(), FsError>(())
                         });
                     }
+                    FileType::NamedPipe
+                    | FileType::CharDevice
+                    | FileType::BlockDevice
+                    | FileType::Socket => {
+                        // special files have no contents, the kernel handles them based on kind and rdev
+                    }
+                    FileType::Symlink => {
+                        let self_clone = fs.clone();
+                        let target = link_target
+                            .ok_or(FsError::InvalidInput("symbolic link target is missing"))?;
+                        join_set.spawn(async move {
+                            // keep the target encrypted in contents directory
+                            crypto::atomic_serialize_encrypt_into(
+                                &self_clone.contents_path(attr.ino),
+                                &*target.expose_secret(),
+                                self_clone.cipher,
+                                &*self_clone.key.get().await?,
+                            )?;
+                            Ok::<(), FsError>(())
+                        });
+                    }
                 }
 
                 // edd entry in parent directory, used for listing
@@ -796,7 +1476,7 @@ impl EncryptedFs {
                 join_set.spawn(async move {
                     let now = SystemTime::now();
                     self_clone
-                        .set_attr(
+                        .set_attr_inner(
                             parent,
                             SetFileAttr::default()
                                 .with_mtime(now)
@@ -814,8 +1494,8 @@ impl EncryptedFs {
 
                 let self_clone = fs.clone();
                 let handle = if attr.kind == FileType::RegularFile {
-                    if read || write {
-                        self_clone.open(attr.ino, read, write).await?
+                    if flags.read || flags.write {
+                        self_clone.open(attr.ino, flags).await?
                     } else {
                         // we don't create a handle for files that are not opened
                         0
@@ -843,24 +1523,40 @@ impl EncryptedFs {
         if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
+        let lock = self
+            .serialize_dir_entries_hash_locks
+            .get_or_insert_with(self.dir_entry_key(parent, name), || RwLock::new(false));
+        let guard = lock.read().await;
+        let Some((ino, _, _)) = self.dir_entry(parent, name).await? else {
+            return Ok(None);
+        };
+        drop(guard);
+        self.get_inode_from_cache_or_storage(ino).await.map(Some)
+    }
+
+    /// Key of the lock of the entry `name` in `parent`, held while reading or changing it.
+    fn dir_entry_key(&self, parent: u64, name: &SecretString) -> String {
+        let hash = crypto::hash_file_name(name);
+        let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
+        hash_path.to_str().unwrap().to_owned()
+    }
+
+    /// Read the entry `name` in `parent`, with its encrypted name. The caller holds its lock.
+    async fn dir_entry(
+        &self,
+        parent: u64,
+        name: &SecretString,
+    ) -> FsResult<Option<(u64, FileType, String)>> {
         let hash = crypto::hash_file_name(name);
         let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
         if !hash_path.is_file() {
             return Ok(None);
         }
-        let lock = self
-            .serialize_dir_entries_hash_locks
-            .get_or_insert_with(hash_path.to_str().unwrap().to_owned(), || {
-                RwLock::new(false)
-            });
-        let guard = lock.read().await;
-        let (ino, _, _): (u64, FileType, String) = bincode::deserialize_from(crypto::create_read(
+        Ok(Some(bincode::deserialize_from(crypto::create_read(
             File::open(hash_path)?,
             self.cipher,
             &*self.key.get().await?,
-        ))?;
-        drop(guard);
-        self.get_inode_from_cache_or_storage(ino).await.map(Some)
+        ))?))
     }
 
     /// Count children of a directory. This **EXCLUDES** "." and "..".
@@ -884,6 +1580,7 @@ impl EncryptedFs {
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
     pub async fn remove_dir(&self, parent: u64, name: &SecretString) -> FsResult<()> {
+        let mutation = self.mutation().await;
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
@@ -917,33 +1614,20 @@ impl EncryptedFs {
         let name_clone = name.clone();
         NOD_RT
             .spawn(async move {
-                // remove inode file
-                {
-                    let lock = self_clone
-                        .serialize_inode_locks
-                        .get_or_insert_with(attr.ino, || RwLock::new(false));
-                    let _guard = lock.write();
-                    fs::remove_file(self_clone.ino_file(attr.ino))?;
-                }
-
-                // remove contents directory
-                fs::remove_dir_all(self_clone.contents_path(attr.ino))?;
-                // remove from parent directory
-                self_clone
-                    .remove_directory_entry(parent, &name_clone)
-                    .await?;
-                // remove from cache
-                self_clone
-                    .attr_cache
-                    .get()
-                    .await?
-                    .write()
-                    .await
-                    .demote(&attr.ino);
+                // kept until done, even if the caller stops waiting
+                let _mutation = mutation;
+                let lock = self_clone
+                    .serialize_dir_entries_hash_locks
+                    .get_or_insert_with(self_clone.dir_entry_key(parent, &name_clone), || {
+                        RwLock::new(false)
+                    });
+                let guard = lock.write().await;
+                self_clone.remove_entry(parent, &name_clone, &attr).await?;
+                drop(guard);
 
                 let now = SystemTime::now();
                 self_clone
-                    .set_attr(
+                    .set_attr_inner(
                         parent,
                         SetFileAttr::default()
                             .with_mtime(now)
@@ -961,6 +1645,7 @@ impl EncryptedFs {
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
     pub async fn remove_file(&self, parent: u64, name: &SecretString) -> FsResult<()> {
+        let mutation = self.mutation().await;
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
@@ -975,7 +1660,7 @@ impl EncryptedFs {
             .find_by_name(parent, name)
             .await?
             .ok_or(FsError::NotFound("name not found"))?;
-        if !matches!(attr.kind, FileType::RegularFile) {
+        if attr.kind == FileType::Directory {
             return Err(FsError::InvalidInodeType);
         }
         // todo move to method
@@ -990,33 +1675,20 @@ impl EncryptedFs {
         let name_clone = name.clone();
         NOD_RT
             .spawn(async move {
-                // remove inode file
-                {
-                    let lock = self_clone
-                        .serialize_inode_locks
-                        .get_or_insert_with(attr.ino, || RwLock::new(false));
-                    let _guard = lock.write();
-                    fs::remove_file(self_clone.ino_file(attr.ino))?;
-                }
-
-                // remove from contents directory
-                fs::remove_file(self_clone.contents_path(attr.ino))?;
-                // remove from parent directory
-                self_clone
-                    .remove_directory_entry(parent, &name_clone)
-                    .await?;
-                // remove from cache
-                self_clone
-                    .attr_cache
-                    .get()
-                    .await?
-                    .write()
-                    .await
-                    .demote(&attr.ino);
+                // kept until done, even if the caller stops waiting
+                let _mutation = mutation;
+                let lock = self_clone
+                    .serialize_dir_entries_hash_locks
+                    .get_or_insert_with(self_clone.dir_entry_key(parent, &name_clone), || {
+                        RwLock::new(false)
+                    });
+                let guard = lock.write().await;
+                self_clone.remove_entry(parent, &name_clone, &attr).await?;
+                drop(guard);
 
                 let now = SystemTime::now();
                 self_clone
-                    .set_attr(
+                    .set_attr_inner(
                         parent,
                         SetFileAttr::default()
                             .with_mtime(now)
@@ -1030,210 +1702,239 @@ impl EncryptedFs {
             .await?
     }
 
-    #[allow(clippy::missing_panics_doc)]
-    #[allow(clippy::missing_errors_doc)]
-    pub fn exists_by_name(&self, parent: u64, name: &SecretString) -> FsResult<bool> {
-        if !self.exists(parent) {
-            return Err(FsError::InodeNotFound);
-        }
-        if !self.is_dir(parent) {
-            return Err(FsError::InvalidInodeType);
+    /// Remove the entry `name` in `parent` linking to `attr`, dropping that link of the inode.
+    ///
+    /// With the last link, or for a directory, the inode and everything kept for it are removed, or kept in the trash
+    /// when it's on. The caller holds the lock of the entry.
+    async fn remove_entry(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        attr: &FileAttr,
+    ) -> FsResult<()> {
+        if self.goes_to_trash(attr) {
+            // the record first, so the inode is never left with neither
+            self.move_to_trash(parent, name, attr).await?;
+            self.remove_directory_entry(parent, name).await?;
+        } else {
+            // remove from parent directory
+            self.remove_directory_entry(parent, name).await?;
+            self.unlink_inode(attr).await?;
         }
-        let hash = crypto::hash_file_name(name);
-        let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
-        Ok(hash_path.is_file())
+        Ok(())
     }
 
+    /// If removing the last entry of the inode keeps it in the trash, see [`EncryptedFs::move_to_trash`].
+    fn goes_to_trash(&self, attr: &FileAttr) -> bool {
+        let last_link = attr.kind == FileType::Directory || attr.nlink <= 1;
+        last_link && self.trash().is_some()
+    }
+
+    /// Drop a link of the inode, after its entry was removed or replaced and it's not kept in the trash.
+    async fn unlink_inode(&self, attr: &FileAttr) -> FsResult<()> {
+        if attr.kind == FileType::Directory {
+            self.remove_inode_files(attr).await?;
+        } else {
+            // only remove the inode and contents when the last link is gone
+            let attr = self.add_nlink(attr.ino, -1).await?;
+            if attr.nlink == 0 {
+                self.remove_inode_files(&attr).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the inode and everything kept for it, after its last entry was removed.
+    async fn remove_inode_files(&self, attr: &FileAttr) -> FsResult<()> {
+        // remove inode file
+        {
+            let lock = self
+                .serialize_inode_locks
+                .get_or_insert_with(attr.ino, || RwLock::new(false));
+            let _guard = lock.write();
+            fs::remove_file(self.ino_file(attr.ino))?;
+        }
+        self.remove_xattrs_file(attr.ino).await?;
+        self.with_quota(|quota| {
+            quota.remove_inode(attr.ino);
+            Ok(())
+        })?;
+        // remove from contents directory
+        match attr.kind {
+            FileType::Directory => fs::remove_dir_all(self.contents_path(attr.ino))?,
+            FileType::RegularFile | FileType::Symlink => {
+                let path = self.contents_path(attr.ino);
+                let freed = dedup::freed_by_unlink(&path)?;
+                fs::remove_file(&path)?;
+                let freed = freed + content_file::remove_layers(&path)?;
+                self.count_allocated(Allocated::freed(freed))?;
+            }
+            _ => {}
+        }
+        if self.holes_file(attr.ino).is_file() {
+            fs::remove_file(self.holes_file(attr.ino))?;
+        }
+        if self.chunk_refs_file(attr.ino).is_file() {
+            fs::remove_file(self.chunk_refs_file(attr.ino))?;
+        }
+        if self.counters_file(attr.ino).is_file() {
+            fs::remove_file(self.counters_file(attr.ino))?;
+        }
+        self.chunk_store(attr.ino).release_all()?;
+        if self.versions_dir(attr.ino).is_dir() {
+            // the inode is gone already, the versions are found by their references
+            for entry in fs::read_dir(self.versions_dir(attr.ino))? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == REFS_EXT) {
+                    ChunkStore::new(self.data_dir.join(CHUNKS_DIR), path, self.physical_size())
+                        .release_all()?;
+                }
+            }
+            fs::remove_dir_all(self.versions_dir(attr.ino))?;
+        }
+        // remove from cache
+        self.attr_cache.get().await?.write().await.demote(&attr.ino);
+        Ok(())
+    }
+
+    /// Create a hard link `new_name` in `new_parent` to the existing inode `ino`.
+    ///
+    /// Directories cannot be linked. The inode and its contents are removed only when the last link is removed.
+    #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
-    pub async fn read_dir(&self, ino: u64) -> FsResult<DirectoryEntryIterator> {
-        if !self.is_dir(ino) {
+    pub async fn link(
+        &self,
+        ino: u64,
+        new_parent: u64,
+        new_name: &SecretString,
+    ) -> FsResult<FileAttr> {
+        let _mutation = self.mutation().await;
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if *new_name.expose_secret() == "." || *new_name.expose_secret() == ".." {
+            return Err(FsError::InvalidInput("name cannot be '.' or '..'"));
+        }
+        if !self.exists(ino) || !self.exists(new_parent) {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_dir(new_parent) {
             return Err(FsError::InvalidInodeType);
         }
-        let ls_dir = self.contents_path(ino).join(LS_DIR);
-        if !ls_dir.is_dir() {
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        if attr.kind == FileType::Directory {
             return Err(FsError::InvalidInodeType);
         }
+        if self.exists_by_name(new_parent, new_name)? {
+            return Err(FsError::AlreadyExists);
+        }
+        self.validate_filename(new_name)?;
 
-        let iter = fs::read_dir(ls_dir)?;
-        let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
-        self.set_attr(ino, set_attr).await?;
-        Ok(self.create_directory_entry_iterator(iter).await)
+        self.insert_directory_entry(
+            new_parent,
+            &DirectoryEntry {
+                ino,
+                name: new_name.clone(),
+                kind: attr.kind,
+            },
+        )
+        .await?;
+        let attr = self.add_nlink(ino, 1).await?;
+
+        let now = SystemTime::now();
+        self.set_attr_inner(
+            new_parent,
+            SetFileAttr::default()
+                .with_mtime(now)
+                .with_ctime(now)
+                .with_atime(now),
+        )
+        .await?;
+
+        Ok(attr)
     }
 
-    /// Like [`EncryptedFs::read_dir`] but with [`FileAttr`] so we don't need to query again for those.
-    pub async fn read_dir_plus(&self, ino: u64) -> FsResult<DirectoryEntryPlusIterator> {
-        if !self.is_dir(ino) {
-            return Err(FsError::InvalidInodeType);
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub fn exists_by_name(&self, parent: u64, name: &SecretString) -> FsResult<bool> {
+        if !self.exists(parent) {
+            return Err(FsError::InodeNotFound);
         }
-        let ls_dir = self.contents_path(ino).join(LS_DIR);
-        if !ls_dir.is_dir() {
+        if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
-
-        let iter = fs::read_dir(ls_dir)?;
-        let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
-        self.set_attr(ino, set_attr).await?;
-        Ok(self.create_directory_entry_plus_iterator(iter).await)
+        let hash = crypto::hash_file_name(name);
+        let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
+        Ok(hash_path.is_file())
     }
 
-    async fn create_directory_entry_plus(
-        &self,
-        entry: io::Result<DirEntry>,
-    ) -> FsResult<DirectoryEntryPlus> {
-        let entry = self.create_directory_entry(entry).await?;
-        let lock = self.serialize_inode_locks.clone();
-        let lock_ino = lock.get_or_insert_with(entry.ino, || RwLock::new(false));
-        let _ino_guard = lock_ino.read();
-        let attr = self.get_inode_from_cache_or_storage(entry.ino).await?;
-        Ok(DirectoryEntryPlus {
-            ino: entry.ino,
-            name: entry.name,
-            kind: entry.kind,
-            attr,
-        })
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_dir(&self, ino: u64) -> FsResult<DirectoryEntryIterator> {
+        self.read_dir_from(ino, 0).await
     }
 
-    async fn create_directory_entry_plus_iterator(
-        &self,
-        read_dir: ReadDir,
-    ) -> DirectoryEntryPlusIterator {
-        #[allow(clippy::cast_possible_truncation)]
-        let futures: Vec<_> = read_dir
-            .into_iter()
-            .map(|entry| {
-                let fs = {
-                    self.self_weak
-                        .lock()
-                        .unwrap()
-                        .as_ref()
-                        .unwrap()
-                        .upgrade()
-                        .unwrap()
-                };
-                DIR_ENTRIES_RT.spawn(async move { fs.create_directory_entry_plus(entry).await })
-            })
-            .collect();
-
-        // do these futures in parallel and return them
-        let mut res = VecDeque::with_capacity(futures.len());
-        for f in futures {
-            res.push_back(f.await.unwrap());
-        }
-        DirectoryEntryPlusIterator(res)
+    /// Like [`EncryptedFs::read_dir`] but continues after the entry with `cookie`,
+    /// see [`DirectoryEntryIterator::cookie`].
+    ///
+    /// The entries before it are skipped without being decrypted. Entries are listed in the order of their cookie, so
+    /// adding or removing others meanwhile doesn't change where it continues.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn read_dir_from(&self, ino: u64, cookie: u64) -> FsResult<DirectoryEntryIterator> {
+        let iter = self.dir_entries(ino, cookie).await?;
+        self.touch_atime(ino).await?;
+        Ok(iter)
     }
 
-    async fn create_directory_entry(
-        &self,
-        entry: io::Result<DirEntry>,
-    ) -> FsResult<DirectoryEntry> {
-        if entry.is_err() {
-            return Err(entry.err().unwrap().into());
+    /// Like [`EncryptedFs::read_dir_from`] but without accessing the directory, for our own lookups.
+    async fn dir_entries(&self, ino: u64, cookie: u64) -> FsResult<DirectoryEntryIterator> {
+        if !self.is_dir(ino) {
+            return Err(FsError::InvalidInodeType);
         }
-        if let Err(e) = entry {
-            error!(err = %e, "reading directory entry");
-            return Err(e.into());
+        let ls_dir = self.contents_path(ino).join(LS_DIR);
+        if !ls_dir.is_dir() {
+            return Err(FsError::InvalidInodeType);
         }
-        let entry = entry.unwrap();
-        let name = entry.file_name().to_string_lossy().to_string();
-        let name = {
-            if name == "$." {
-                SecretString::new(Box::new(".".into()))
-            } else if name == "$.." {
-                SecretString::from_str("..").unwrap()
-            } else {
-                // try from cache
-                let lock = self.get_dir_entries_name_cache().await?;
-                let mut cache = lock.lock().await;
-                if let Some(name_cached) = cache.get(&name).cloned() {
-                    name_cached
-                } else {
-                    drop(cache);
-                    if let Ok(decrypted_name) =
-                        crypto::decrypt_file_name(&name, self.cipher, &*self.key.get().await?)
-                            .map_err(|err| {
-                                error!(err = %err, "decrypting file name");
-                                err
-                            })
-                    {
-                        lock.lock().await.put(name.clone(), decrypted_name.clone());
-                        decrypted_name
-                    } else {
-                        return Err(FsError::InvalidInput("invalid file name"));
-                    }
-                }
-            }
-        };
 
-        self.validate_filename(&name)?;
-
-        let file_path = entry.path().to_str().unwrap().to_owned();
-        // try from cache
-        let lock = self.dir_entries_meta_cache.get().await?;
-        let mut cache = lock.lock().await;
-        if let Some((ino, kind)) = cache.get(&file_path) {
-            return Ok(DirectoryEntry {
-                ino: *ino,
-                name,
-                kind: *kind,
-            });
-        }
-        drop(cache);
-        let lock = self
-            .serialize_dir_entries_ls_locks
-            .get_or_insert_with(file_path.clone(), || RwLock::new(false));
-        let guard = lock.read().await;
-        let file = File::open(entry.path())?;
-        let res: bincode::Result<(u64, FileType)> = bincode::deserialize_from(crypto::create_read(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ));
-        drop(guard);
-        if let Err(e) = res {
-            error!(err = %e, "deserializing directory entry");
-            return Err(e.into());
-        }
-        let (ino, kind): (u64, FileType) = res.unwrap();
-        // add to cache
-        self.dir_entries_meta_cache
-            .get()
-            .await?
+        let fs = self
+            .self_weak
             .lock()
-            .await
-            .put(file_path, (ino, kind));
-        Ok(DirectoryEntry { ino, name, kind })
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .upgrade()
+            .unwrap();
+        let mut iter = DirectoryEntryIterator {
+            fs,
+            key: self.key.get().await?,
+            name_cache: self.dir_entries_name_cache.get().await?,
+            meta_cache: self.dir_entries_meta_cache.get().await?,
+            ls_dir,
+            batch: vec![].into_iter(),
+            last_batch: false,
+            cookie,
+        };
+        // only the names are read here, entries are decrypted while iterating
+        iter.next_batch()?;
+        Ok(iter)
     }
 
-    async fn get_dir_entries_name_cache(
-        &self,
-    ) -> FsResult<Arc<Mutex<LruCache<String, SecretString>>>> {
-        self.dir_entries_name_cache.get().await
+    /// Like [`EncryptedFs::read_dir`] but with [`FileAttr`] so we don't need to query again for those.
+    pub async fn read_dir_plus(&self, ino: u64) -> FsResult<DirectoryEntryPlusIterator> {
+        self.read_dir_plus_from(ino, 0).await
     }
 
-    async fn create_directory_entry_iterator(&self, read_dir: ReadDir) -> DirectoryEntryIterator {
-        #[allow(clippy::cast_possible_truncation)]
-        let futures: Vec<_> = read_dir
-            .into_iter()
-            .map(|entry| {
-                let fs = {
-                    self.self_weak
-                        .lock()
-                        .unwrap()
-                        .as_ref()
-                        .unwrap()
-                        .upgrade()
-                        .unwrap()
-                };
-                DIR_ENTRIES_RT.spawn(async move { fs.create_directory_entry(entry).await })
-            })
-            .collect();
-
-        // do these futures in parallel and return them
-        let mut res = VecDeque::with_capacity(futures.len());
-        for f in futures {
-            res.push_back(f.await.unwrap());
-        }
-        DirectoryEntryIterator(res)
+    /// Like [`EncryptedFs::read_dir_from`] but with [`FileAttr`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_dir_plus_from(
+        &self,
+        ino: u64,
+        cookie: u64,
+    ) -> FsResult<DirectoryEntryPlusIterator> {
+        Ok(DirectoryEntryPlusIterator {
+            entries: self.read_dir_from(ino, cookie).await?,
+            attr_cache: self.attr_cache.get().await?,
+        })
     }
 
     #[allow(clippy::missing_errors_doc)]
@@ -1251,11 +1952,11 @@ impl EncryptedFs {
             error!(err = %err, "opening file");
             FsError::InodeNotFound
         })?;
-        Ok(bincode::deserialize_from(crypto::create_read(
+        deserialize_attr(crypto::create_read(
             file,
             self.cipher,
             &*self.key.get().await?,
-        ))?)
+        ))
     }
 
     async fn get_inode_from_cache_or_storage(&self, ino: u64) -> FsResult<FileAttr> {
@@ -1296,25 +1997,52 @@ impl EncryptedFs {
         // merge time info and size with any open write handles
         let open_writes = { self.opened_files_for_write.read().await.contains_key(&ino) };
         if open_writes {
-            let fh = self.opened_files_for_write.read().await.get(&ino).copied();
-            if let Some(fh) = fh {
-                let lock = self.write_handles.read().await;
-                if let Some(ctx) = lock.get(&fh) {
-                    let ctx = ctx.lock().await;
-                    merge_attr(&mut attr, &ctx.attr.clone().into(), false);
+            let fhs = self.opened_files_for_write.read().await.get(&ino).cloned();
+            if let Some(fhs) = fhs {
+                for fh in fhs {
+                    let lock = self.write_handles.read().await;
+                    if let Some(ctx) = lock.get(&fh) {
+                        let ctx = ctx.lock().await;
+                        merge_attr(&mut attr, &ctx.attr.clone().into(), false);
+                    }
                 }
             }
+            // the size also includes what was written and not flushed yet
+            if let Some(cache) = self.opened_chunk_cache(ino).await {
+                attr.size = cache.size();
+            }
         }
 
         Ok(attr)
     }
 
     /// Set metadata
+    ///
+    /// Changing the permissions also updates the access ACL, if any.
     pub async fn set_attr(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
+        let _mutation = self.mutation().await;
+        self.set_attr_inner(ino, set_attr).await
+    }
+
+    /// Like [`Self::set_attr`], with the mutation guard already held.
+    async fn set_attr_inner(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
-        self.set_attr2(ino, set_attr, false).await
+        self.set_attr2(ino, set_attr, false).await?;
+        if let Some(perm) = set_attr.perm {
+            let lock = self
+                .serialize_xattr_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _guard = lock.write().await;
+            let mut xattrs = self.read_xattrs(ino).await?;
+            if let Some(value) = xattrs.get(ACL_ACCESS_XATTR) {
+                let acl = Acl::from_bytes(value)?.with_mode(perm);
+                xattrs.insert(ACL_ACCESS_XATTR.to_owned(), acl.to_bytes());
+                self.write_xattrs(ino, &xattrs).await?;
+            }
+        }
+        Ok(())
     }
 
     async fn set_attr2(
@@ -1339,75 +2067,273 @@ impl EncryptedFs {
         Ok(())
     }
 
-    async fn write_inode_to_storage(&self, attr: &FileAttr) -> Result<(), FsError> {
+    /// Get the value of an extended attribute.
+    ///
+    /// If the attribute doesn't exist it will return an error of type [`FsError::NotFound`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn get_xattr(&self, ino: u64, name: &str) -> FsResult<Vec<u8>> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
         let lock = self
-            .serialize_inode_locks
-            .get_or_insert_with(attr.ino, || RwLock::new(false));
-        let guard = lock.write().await;
-        crypto::atomic_serialize_encrypt_into(
-            &self.ino_file(attr.ino),
-            attr,
-            self.cipher,
-            &*self.key.get().await?,
-        )?;
-        drop(guard);
-        // update cache also
-        {
-            let lock = self.attr_cache.get().await?;
-            let mut guard = lock.write().await;
-            guard.put(attr.ino, *attr);
+            .serialize_xattr_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.read().await;
+        self.read_xattrs(ino)
+            .await?
+            .remove(name)
+            .ok_or(FsError::NotFound("xattr not found"))
+    }
+
+    /// List the names of the extended attributes of an inode.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn list_xattr(&self, ino: u64) -> FsResult<Vec<String>> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
         }
-        Ok(())
+        let lock = self
+            .serialize_xattr_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.read().await;
+        Ok(self.read_xattrs(ino).await?.into_keys().collect())
     }
 
-    /// Read the contents from an `offset`.
-    ///
-    /// If we try to read outside of file size, we return zero bytes.
-    /// If the file is not opened for read, it will return an error of type [FsError::InvalidFileHandle].
-    #[instrument(skip(self, buf), fields(len = %buf.len()), ret(level = Level::DEBUG))]
+    /// Set the value of an extended attribute, `mode` controls what happens if it already exists or not.
     #[allow(clippy::missing_errors_doc)]
-    #[allow(clippy::cast_possible_truncation)]
-    pub async fn read(
+    pub async fn set_xattr(
         &self,
         ino: u64,
-        offset: u64,
-        buf: &mut [u8],
-        handle: u64,
-    ) -> FsResult<usize> {
+        name: &str,
+        value: &[u8],
+        mode: SetXattrMode,
+    ) -> FsResult<()> {
+        let _mutation = self.mutation().await;
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if name.is_empty() {
+            return Err(FsError::InvalidInput("xattr name cannot be empty"));
+        }
         if !self.exists(ino) {
             return Err(FsError::InodeNotFound);
         }
-        if !self.is_file(ino) {
-            return Err(FsError::InvalidInodeType);
+        let mut set_attr = SetFileAttr::default().with_ctime(SystemTime::now());
+        match name {
+            ACL_ACCESS_XATTR => {
+                // the mode follows the access ACL
+                let acl = Acl::from_bytes(value)?;
+                let perm = self.get_inode_from_cache_or_storage(ino).await?.perm;
+                set_attr = set_attr.with_perm(acl.mode(perm));
+            }
+            ACL_DEFAULT_XATTR => {
+                if !self.is_dir(ino) {
+                    return Err(FsError::InvalidInodeType);
+                }
+                Acl::from_bytes(value)?;
+            }
+            _ => {}
         }
-        if !self.read_handles.read().await.contains_key(&handle) {
-            return Err(FsError::InvalidFileHandle);
+        {
+            let lock = self
+                .serialize_xattr_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _guard = lock.write().await;
+            let mut xattrs = self.read_xattrs(ino).await?;
+            match mode {
+                SetXattrMode::Create if xattrs.contains_key(name) => {
+                    return Err(FsError::AlreadyExists);
+                }
+                SetXattrMode::Replace if !xattrs.contains_key(name) => {
+                    return Err(FsError::NotFound("xattr not found"));
+                }
+                _ => {}
+            }
+            xattrs.insert(name.to_owned(), value.to_vec());
+            self.write_xattrs(ino, &xattrs).await?;
         }
+        self.set_attr_inner(ino, set_attr).await
+    }
 
-        let _size = self.get_attr(ino).await?.size;
-
-        let lock = self
-            .read_write_locks
-            .get_or_insert_with(ino, || RwLock::new(false));
-        let _read_guard = lock.read().await;
-
-        let guard = self.read_handles.read().await;
-        let mut ctx = guard.get(&handle).unwrap().lock().await;
-
-        if ctx.ino != ino {
-            return Err(FsError::InvalidFileHandle);
+    /// Remove an extended attribute.
+    ///
+    /// If the attribute doesn't exist it will return an error of type [`FsError::NotFound`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn remove_xattr(&self, ino: u64, name: &str) -> FsResult<()> {
+        let _mutation = self.mutation().await;
+        if self.read_only {
+            return Err(FsError::ReadOnly);
         }
-        if self.is_dir(ino) {
-            return Err(FsError::InvalidInodeType);
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
         }
-        if buf.is_empty() {
-            // no-op
-            return Ok(0);
+        {
+            let lock = self
+                .serialize_xattr_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _guard = lock.write().await;
+            let mut xattrs = self.read_xattrs(ino).await?;
+            if xattrs.remove(name).is_none() {
+                return Err(FsError::NotFound("xattr not found"));
+            }
+            self.write_xattrs(ino, &xattrs).await?;
         }
+        self.set_attr_inner(ino, SetFileAttr::default().with_ctime(SystemTime::now()))
+            .await
+    }
 
-        // read data
-        let (_buf, len) = {
-            let reader = ctx.reader.as_mut().unwrap();
+    /// Get the access ACL of an inode, if it has one.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn get_access_acl(&self, ino: u64) -> FsResult<Option<Acl>> {
+        self.get_acl(ino, ACL_ACCESS_XATTR).await
+    }
+
+    /// Get the default ACL of a directory, if it has one.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn get_default_acl(&self, ino: u64) -> FsResult<Option<Acl>> {
+        self.get_acl(ino, ACL_DEFAULT_XATTR).await
+    }
+
+    async fn get_acl(&self, ino: u64, name: &str) -> FsResult<Option<Acl>> {
+        match self.get_xattr(ino, name).await {
+            Ok(value) => Acl::from_bytes(&value).map(Some),
+            Err(FsError::NotFound(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn remove_xattrs_file(&self, ino: u64) -> FsResult<()> {
+        let lock = self
+            .serialize_xattr_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.write().await;
+        self.write_xattrs(ino, &BTreeMap::new()).await
+    }
+
+    async fn read_xattrs(&self, ino: u64) -> FsResult<BTreeMap<String, Vec<u8>>> {
+        let path = self.xattr_file(ino);
+        if !path.is_file() {
+            return Ok(BTreeMap::new());
+        }
+        Ok(bincode::deserialize_from(crypto::create_read(
+            File::open(path)?,
+            self.cipher,
+            &*self.key.get().await?,
+        ))?)
+    }
+
+    async fn write_xattrs(&self, ino: u64, xattrs: &BTreeMap<String, Vec<u8>>) -> FsResult<()> {
+        let path = self.xattr_file(ino);
+        if xattrs.is_empty() {
+            if path.is_file() {
+                fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+        crypto::atomic_serialize_encrypt_into(&path, xattrs, self.cipher, &*self.key.get().await?)?;
+        Ok(())
+    }
+
+    /// Change the number of hard links of an inode by `delta`, returning the updated attributes.
+    async fn add_nlink(&self, ino: u64, delta: i32) -> FsResult<FileAttr> {
+        let serialize_update_lock = self
+            .serialize_update_inode_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _serialize_update_guard = serialize_update_lock.lock().await;
+
+        let mut attr = self.get_inode_from_cache_or_storage(ino).await?;
+        attr.nlink = attr.nlink.saturating_add_signed(delta);
+        attr.ctime = SystemTime::now();
+        self.write_inode_to_storage(&attr).await?;
+
+        Ok(attr)
+    }
+
+    async fn write_inode_to_storage(&self, attr: &FileAttr) -> Result<(), FsError> {
+        let lock = self
+            .serialize_inode_locks
+            .get_or_insert_with(attr.ino, || RwLock::new(false));
+        let guard = lock.write().await;
+        crypto::atomic_serialize_encrypt_into(
+            &self.ino_file(attr.ino),
+            attr,
+            self.cipher,
+            &*self.key.get().await?,
+        )?;
+        drop(guard);
+        // update cache also
+        {
+            let lock = self.attr_cache.get().await?;
+            let mut guard = lock.write().await;
+            guard.put(attr.ino, *attr);
+        }
+        Ok(())
+    }
+
+    /// Read the contents from an `offset`.
+    ///
+    /// If we try to read outside of file size, we return zero bytes.
+    /// If the file is not opened for read, it will return an error of type [FsError::InvalidFileHandle].
+    #[instrument(skip(self, buf), fields(len = %buf.len()), ret(level = Level::DEBUG))]
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub async fn read(
+        &self,
+        ino: u64,
+        offset: u64,
+        buf: &mut [u8],
+        handle: u64,
+    ) -> FsResult<usize> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_file(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        if !self.read_handles.read().await.contains_key(&handle) {
+            return Err(FsError::InvalidFileHandle);
+        }
+
+        let _size = self.get_attr(ino).await?.size;
+
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _read_guard = lock.read().await;
+
+        let guard = self.read_handles.read().await;
+        let mut ctx = guard.get(&handle).unwrap().lock().await;
+
+        if ctx.ino != ino {
+            return Err(FsError::InvalidFileHandle);
+        }
+        if self.is_dir(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        if buf.is_empty() {
+            // no-op
+            return Ok(0);
+        }
+
+        // keep block size to max the cipher can handle
+        let buf = if offset + buf.len() as u64 > self.cipher.max_plaintext_len() as u64 {
+            warn!("reading more than max block size, truncating");
+            buf.split_at_mut(self.cipher.max_plaintext_len() - offset as usize)
+                .0
+        } else {
+            buf
+        };
+
+        // read data
+        let len = if let Some(cache) = self.opened_chunk_cache(ino).await {
+            // see what was written from any of the write handles, without flushing it
+            cache
+                .read(offset, buf, &*self.key.get().await?)
+                .map_err(|err| {
+                    error!(err = %err, "reading");
+                    err
+                })?
+        } else {
+            let reader = ctx.reader.as_mut().unwrap();
 
             reader.seek(SeekFrom::Start(offset)).map_err(|err| {
                 error!(err = %err, "seeking");
@@ -1421,23 +2347,16 @@ impl EncryptedFs {
                 // we would need to seek after filesize
                 return Ok(0);
             }
-            // keep block size to max the cipher can handle
-            #[allow(clippy::cast_possible_truncation)]
-            let buf = if offset + buf.len() as u64 > self.cipher.max_plaintext_len() as u64 {
-                warn!("reading more than max block size, truncating");
-                buf.split_at_mut(self.cipher.max_plaintext_len() - offset as usize)
-                    .0
-            } else {
-                buf
-            };
-            let len = stream_util::read(reader, buf).map_err(|err| {
+            stream_util::read(reader, buf).map_err(|err| {
                 error!(err = %err, "reading");
                 err
-            })?;
-            (buf, len)
+            })?
         };
 
-        ctx.attr.atime = SystemTime::now();
+        if self.is_atime_stale(ctx.attr.atime, ctx.attr.mtime, ctx.attr.ctime) {
+            ctx.attr.atime = SystemTime::now();
+            ctx.atime_changed = true;
+        }
         drop(ctx);
 
         // self.sizes_read
@@ -1475,6 +2394,7 @@ impl EncryptedFs {
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::too_many_lines)]
     pub async fn release(&self, handle: u64) -> FsResult<()> {
+        let _mutation = self.mutation().await;
         if handle == 0 {
             // in the case of directory or if the file was crated
             // without being opened we don't use a handle
@@ -1502,12 +2422,13 @@ impl EncryptedFs {
                 }
             }
 
-            // write attr only here to avoid serializing it multiple times while reading
-            // it will merge time fields with existing data because it might got change while we kept the handle
-            let set_attr: SetFileAttr = ctx.attr.clone().into();
+            // write atime only here to avoid serializing it multiple times while reading
+            let atime = ctx.atime_changed.then_some(ctx.attr.atime);
             let ino = ctx.ino;
             drop(ctx);
-            self.set_attr(ino, set_attr).await?;
+            if let Some(atime) = atime {
+                self.save_atime(ino, atime).await?;
+            }
 
             valid_fh = true;
         }
@@ -1518,24 +2439,40 @@ impl EncryptedFs {
             if self.read_only {
                 return Err(FsError::ReadOnly);
             }
-            let mut ctx = ctx.lock().await;
-
-            let mut writer = ctx.writer.take().unwrap();
+            let ctx = ctx.into_inner();
+            let ino = ctx.ino;
             let lock = self
                 .read_write_locks
-                .get_or_insert_with(ctx.ino, || RwLock::new(false));
+                .get_or_insert_with(ino, || RwLock::new(false));
             let write_guard = lock.write().await;
-            let file = writer.finish()?;
-            file.sync_all()?;
-            File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+            ctx.cache.flush(&*self.key.get().await?)?;
+            File::open(self.contents_path(ino))?.sync_all()?;
+            File::open(self.contents_path(ino).parent().unwrap())?.sync_all()?;
+            self.save_holes(ino).await?;
             // write attr only here to avoid serializing it multiple times while writing
             // it will merge time fields with existing data because it might got change while we kept the handle
-            let ino = ctx.ino;
-            let attr = ctx.attr.clone();
-            drop(ctx);
-            self.set_attr(ino, attr.into()).await?;
+            let mut attr = ctx.attr;
+            attr.size = ctx.cache.size();
+            // readers use it until they are reset to read the content file
+            let cache = ctx.cache;
+            self.set_attr_inner(ino, attr.into()).await?;
+            if self.versioning().is_some() {
+                self.save_version(ino).await?;
+            }
+            let last_writer = {
+                let mut opened_files_for_write = self.opened_files_for_write.write().await;
+                let handles = opened_files_for_write
+                    .get_mut(&ino)
+                    .expect("handle is missing");
+                handles.remove(&handle);
+                let last_writer = handles.is_empty();
+                if last_writer {
+                    opened_files_for_write.remove(&ino);
+                }
+                last_writer
+            };
             let attr = self.get_attr(ino).await?;
-            {
+            if last_writer {
                 let write_size = self
                     .sizes_write
                     .lock()
@@ -1567,13 +2504,13 @@ impl EncryptedFs {
                         attr.size, requested_read, read
                     );
                 }
+                self.sizes_write.lock().await.remove(&ino);
+                self.sizes_read.lock().await.remove(&ino);
+                self.requested_read.lock().await.remove(&ino);
             }
-            self.sizes_write.lock().await.remove(&ino);
-            self.sizes_read.lock().await.remove(&ino);
-            self.requested_read.lock().await.remove(&ino);
             drop(write_guard);
-            self.opened_files_for_write.write().await.remove(&ino);
             self.reset_handles(ino, Some(handle), true).await?;
+            drop(cache);
 
             valid_fh = true;
         }
@@ -1596,11 +2533,17 @@ impl EncryptedFs {
 
     /// Writes the contents of `buf` to the file with `ino` starting at `offset`.
     ///
-    /// If we write outside file size, we fill up with zeros until the `offset`.
+    /// If we write outside file size, we fill up with zeros until the `offset`,
+    /// whole blocks are not encrypted but kept as holes.
+    /// The file can be written from multiple handles at the same time, writes to different blocks
+    /// proceed in parallel while writes to the same block are serialized.
+    /// If the handle was opened with [`OpenFlags::append`] the data goes to the end of the file, ignoring `offset`,
+    /// and with [`OpenFlags::sync`] it returns after the data is durable on disk.
     /// If the file is not opened for writing,
     /// it will return an error of type [FsError::InvalidFileHandle].
     #[instrument(skip(self, buf), fields(len = %buf.len()), ret(level = Level::DEBUG))]
     pub async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
+        let _mutation = self.mutation().await;
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
@@ -1627,30 +2570,27 @@ impl EncryptedFs {
             return Ok(0);
         }
 
+        // shared, exclusive access is only needed to change the file outside the writers, like when truncating
         let lock = self
             .read_write_locks
             .get_or_insert_with(ino, || RwLock::new(false));
-        let write_guard = lock.write().await;
+        let _read_guard = lock.read().await;
 
         let guard = self.write_handles.read().await;
-        let mut ctx = guard.get(&handle).unwrap().lock().await;
+        let (cache, flags) = {
+            let ctx = guard.get(&handle).unwrap().lock().await;
+            (ctx.cache.clone(), ctx.flags)
+        };
+        // appending writes at the end at the time of writing, which could be changed by other handles meanwhile
+        let offset = if flags.append { cache.size() } else { offset };
 
         // write new data
-        let (pos, len) = {
+        let len = {
             if offset > self.cipher.max_plaintext_len() as u64 {
                 return Err(FsError::MaxFilesizeExceeded(
                     self.cipher.max_plaintext_len(),
                 ));
             }
-            let writer = ctx.writer.as_mut().unwrap();
-            let pos = writer.seek(SeekFrom::Start(offset)).map_err(|err| {
-                error!(err = %err, "seeking");
-                err
-            })?;
-            if offset != pos {
-                // we could not seek to the desired position
-                return Ok(0);
-            }
             // keep block size to max the cipher can handle
             #[allow(clippy::cast_possible_truncation)]
             let buf = if offset + buf.len() as u64 > self.cipher.max_plaintext_len() as u64 {
@@ -1659,27 +2599,47 @@ impl EncryptedFs {
             } else {
                 buf
             };
-            let len = writer.write(buf).map_err(|err| {
+            self.with_quota(|quota| quota.grow(ino, offset + buf.len() as u64))?;
+            let key = self.key.get().await?;
+            if flags.append {
+                cache
+                    .append(buf, &key)
+                    .map(|offset| debug!(offset, "appended"))
+            } else {
+                cache.write(offset, buf, &key)
+            }
+            .map_err(|err| {
                 error!(err = %err, "writing");
                 err
             })?;
-            (writer.stream_position()?, len)
+            if flags.append {
+                // other handles might have appended since we checked
+                self.with_quota(|quota| {
+                    quota.grown(ino, cache.size());
+                    Ok(())
+                })?;
+            }
+            buf.len()
         };
 
-        // let size = ctx.attr.size;
-        if pos > ctx.attr.size {
-            // if we write pass file size set the new size
-            debug!("setting new file size {}", pos);
-            ctx.attr.size = pos;
-        }
+        let mut ctx = guard.get(&handle).unwrap().lock().await;
+        ctx.attr.size = cache.size();
         let now = SystemTime::now();
         ctx.attr.mtime = now;
         ctx.attr.ctime = now;
         ctx.attr.atime = now;
+        let attr = ctx.attr.clone();
         drop(ctx);
+        drop(guard);
 
-        drop(write_guard);
-        self.reset_handles(ino, Some(handle), true).await?;
+        if flags.sync {
+            cache.flush(&*self.key.get().await?)?;
+            File::open(self.contents_path(ino))?.sync_all()?;
+            File::open(self.contents_path(ino).parent().unwrap())?.sync_all()?;
+            self.save_holes(ino).await?;
+            // the size is needed to read the data back
+            self.set_attr_inner(ino, attr.into()).await?;
+        }
 
         self.sizes_write
             .lock()
@@ -1709,6 +2669,7 @@ impl EncryptedFs {
     /// Flush the data to the underlying storage.
     #[allow(clippy::missing_panics_doc)]
     pub async fn flush(&self, handle: u64) -> FsResult<()> {
+        let _mutation = self.mutation().await;
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
@@ -1719,18 +2680,24 @@ impl EncryptedFs {
         let lock = self.read_handles.read().await;
         let mut valid_fh = lock.get(&handle).is_some();
         let lock = self.write_handles.read().await;
-        if let Some(ctx) = lock.get(&handle) {
-            let mut ctx = ctx.lock().await;
+        let ctx = match lock.get(&handle) {
+            Some(ctx) => {
+                let ctx = ctx.lock().await;
+                Some((ctx.ino, ctx.cache.clone()))
+            }
+            None => None,
+        };
+        drop(lock);
+        if let Some((ino, cache)) = ctx {
             let lock = self
                 .read_write_locks
-                .get_or_insert_with(ctx.ino, || RwLock::new(false));
+                .get_or_insert_with(ino, || RwLock::new(false));
             let write_guard = lock.write().await;
-            ctx.writer.as_mut().expect("writer is missing").flush()?;
-            File::open(self.contents_path(ctx.ino))?.sync_all()?;
-            File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+            cache.flush(&*self.key.get().await?)?;
+            File::open(self.contents_path(ino))?.sync_all()?;
+            File::open(self.contents_path(ino).parent().unwrap())?.sync_all()?;
+            self.save_holes(ino).await?;
             drop(write_guard);
-            let ino = ctx.ino;
-            drop(ctx);
             self.reset_handles(ino, Some(handle), true).await?;
             valid_fh = true;
         }
@@ -1742,12 +2709,95 @@ impl EncryptedFs {
         Ok(())
     }
 
+    /// Make the changes of the file durable, like `fsync(2)`.
+    ///
+    /// The content is synced first, then the holes and the inode, so the metadata never describes data that is
+    /// not on disk yet. With `datasync` only the metadata needed to read the data back, like the size,
+    /// is saved, like `fdatasync(2)`.
+    pub async fn fsync(&self, handle: u64, datasync: bool) -> FsResult<()> {
+        let _mutation = self.mutation().await;
+        if handle == 0 {
+            // in the case of directory or if the file was crated without being opened we don't use a handle
+            return Ok(());
+        }
+        let lock = self.read_handles.read().await;
+        let valid_fh = lock.get(&handle).is_some();
+        drop(lock);
+        let handles = self.write_handles.read().await;
+        let Some(ctx_lock) = handles.get(&handle) else {
+            // nothing was written through a read handle, everything else is saved when changed
+            return if valid_fh {
+                Ok(())
+            } else {
+                Err(FsError::InvalidFileHandle)
+            };
+        };
+        let (ino, cache) = {
+            let ctx = ctx_lock.lock().await;
+            (ctx.ino, ctx.cache.clone())
+        };
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let write_guard = lock.write().await;
+        // data
+        cache.flush(&*self.key.get().await?)?;
+        let file = File::open(self.contents_path(ino))?;
+        if datasync {
+            file.sync_data()?;
+        } else {
+            file.sync_all()?;
+        }
+        fs_util::sync_dir(&self.data_dir.join(CONTENTS_DIR))?;
+        // metadata, each one is synced with its directory when saved
+        self.save_holes(ino).await?;
+        let size = cache.size();
+        if !datasync || size != self.get_inode_from_storage(ino).await?.size {
+            let mut attr = ctx_lock.lock().await.attr.clone();
+            attr.size = size;
+            self.set_attr_inner(ino, attr.into()).await?;
+            ctx_lock.lock().await.attr = self.get_inode_from_storage(ino).await?.into();
+        }
+        // the holes file might have been removed
+        fs_util::sync_dir(&self.data_dir.join(INODES_DIR))?;
+        drop(write_guard);
+        drop(handles);
+
+        Ok(())
+    }
+
+    /// Make the entries of a directory durable, like `fsync(2)` on a directory.
+    ///
+    /// Entries are synced when added, this also covers the removed ones.
+    pub async fn fsync_dir(&self, ino: u64) -> FsResult<()> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_dir(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        let contents_dir = self.contents_path(ino);
+        // names are removed from hash before ls, keep the same order
+        fs_util::sync_dir(&contents_dir.join(HASH_DIR))?;
+        fs_util::sync_dir(&contents_dir.join(LS_DIR))?;
+        if contents_dir.join(LONG_NAMES_DIR).is_dir() {
+            fs_util::sync_dir(&contents_dir.join(LONG_NAMES_DIR))?;
+        }
+        fs_util::sync_dir(&contents_dir)?;
+        File::open(self.ino_file(ino))?.sync_all()?;
+        fs_util::sync_dir(&self.data_dir.join(INODES_DIR))?;
+        fs_util::sync_dir(&self.data_dir.join(CONTENTS_DIR))?;
+
+        Ok(())
+    }
+
     /// Helpful when we want to copy just some portions of the file.
     pub async fn copy_file_range(
         &self,
         file_range_req: &CopyFileRangeReq,
         size: usize,
     ) -> FsResult<usize> {
+        let _mutation = self.mutation().await;
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
@@ -1755,6 +2805,29 @@ impl EncryptedFs {
             return Err(FsError::InvalidInodeType);
         }
 
+        if !self.exists(file_range_req.src_ino) || !self.exists(file_range_req.dest_ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        self.check_handle(file_range_req.src_ino, file_range_req.src_fh, false)
+            .await?;
+        self.check_handle(file_range_req.dest_ino, file_range_req.dest_fh, true)
+            .await?;
+
+        // the whole file over all of the destination, share the content instead
+        let src_size = self.get_attr(file_range_req.src_ino).await?.size;
+        if file_range_req.src_offset == 0
+            && file_range_req.dest_offset == 0
+            && src_size > 0
+            && size as u64 >= src_size
+            && file_range_req.src_ino != file_range_req.dest_ino
+            && self.get_attr(file_range_req.dest_ino).await?.size <= src_size
+        {
+            self.clone_file_inner(file_range_req.src_ino, file_range_req.dest_ino)
+                .await?;
+            #[allow(clippy::cast_possible_truncation)]
+            return Ok(src_size as usize);
+        }
+
         let mut buf = vec![0; size];
         let len = self
             .read(
@@ -1786,9 +2859,149 @@ impl EncryptedFs {
         Ok(len)
     }
 
-    /// Open a file. We can open multiple times for read but only one to write at a time.
+    /// Fail with [`FsError::InvalidFileHandle`] if `handle` is not opened on `ino`, for write or else for read.
+    async fn check_handle(&self, ino: u64, handle: u64, write: bool) -> FsResult<()> {
+        let handle_ino = if write {
+            let guard = self.write_handles.read().await;
+            match guard.get(&handle) {
+                Some(ctx) => Some(ctx.lock().await.ino),
+                None => None,
+            }
+        } else {
+            let guard = self.read_handles.read().await;
+            match guard.get(&handle) {
+                Some(ctx) => Some(ctx.lock().await.ino),
+                None => None,
+            }
+        };
+        if handle_ino != Some(ino) {
+            return Err(FsError::InvalidFileHandle);
+        }
+        Ok(())
+    }
+
+    /// Make the content of `dest_ino` the same as the one of `src_ino`, without copying it.
+    ///
+    /// The encrypted blocks are shared by the two files, a block is copied only when one of them writes it.
+    /// Every clone adds a layer to look into for the blocks not written since, after
+    /// [`content_file::MAX_LAYERS`] their blocks are copied into the content file of the source first.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn clone_file(&self, src_ino: u64, dest_ino: u64) -> FsResult<()> {
+        let _mutation = self.mutation().await;
+        self.clone_file_inner(src_ino, dest_ino).await
+    }
+
+    /// Like [`Self::clone_file`], with the mutation guard already held.
+    async fn clone_file_inner(&self, src_ino: u64, dest_ino: u64) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if src_ino == dest_ino {
+            return Err(FsError::InvalidInput("cannot clone a file to itself"));
+        }
+        let src_attr = self.get_attr(src_ino).await?;
+        let dest_attr = self.get_attr(dest_ino).await?;
+        if src_attr.kind != FileType::RegularFile || dest_attr.kind != FileType::RegularFile {
+            return Err(FsError::InvalidInodeType);
+        }
+        self.with_quota(|quota| quota.resize(dest_ino, src_attr.size))?;
+
+        // in the order of the inodes, so two clones between the same files don't deadlock
+        let (first, second) = if src_ino < dest_ino {
+            (src_ino, dest_ino)
+        } else {
+            (dest_ino, src_ino)
+        };
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(first, || RwLock::new(false));
+        let _first_guard = lock.write().await;
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(second, || RwLock::new(false));
+        let _second_guard = lock.write().await;
+
+        // flush writers
+        self.flush_and_reset_writers(src_ino).await?;
+        self.flush_and_reset_writers(dest_ino).await?;
+        let src_attr = self.get_inode_from_storage(src_ino).await?;
+
+        let src_path = self.contents_path(src_ino);
+        let dest_path = self.contents_path(dest_ino);
+        let mut allocated = Allocated::default();
+        if content_file::layers(&src_path).len() >= content_file::MAX_LAYERS {
+            allocated =
+                content_file::flatten(&src_path, self.cipher.ciphertext_block_size() as u64)?;
+        }
+        let len = fs::metadata(&src_path)?.len();
+        // the content of the source becomes its newest layer, the snapshots keep the one they had
+        let index = content_file::layers(&src_path).len();
+        let tmp_path = src_path.with_file_name(format!(".{src_ino}.clone"));
+        File::create(&tmp_path)?.set_len(len)?;
+        fs::hard_link(&src_path, content_file::layer_path(&src_path, index))?;
+        fs::rename(&tmp_path, &src_path)?;
+        // and the destination gets all of them
+        let mut freed = content_file::remove_layers(&dest_path)?;
+        for index in 0..=index {
+            fs::hard_link(
+                content_file::layer_path(&src_path, index),
+                content_file::layer_path(&dest_path, index),
+            )?;
+        }
+        let tmp_path = dest_path.with_file_name(format!(".{dest_ino}.clone"));
+        File::create(&tmp_path)?.set_len(len)?;
+        freed += dedup::freed_by_unlink(&dest_path)?;
+        fs::rename(&tmp_path, &dest_path)?;
+        File::open(src_path.parent().unwrap())?.sync_all()?;
+        // the new content files are empty
+        self.count_allocated(allocated.and(Allocated::freed(freed)))?;
+
+        // kept until saved
+        let holes = self.holes(dest_ino).await?;
+        let src_holes = self.holes(src_ino).await?.read().unwrap().clone();
+        *holes.write().unwrap() = src_holes;
+        // the blocks in the chunk store are shared too
+        let chunks = self.chunk_refs(dest_ino).await?;
+        let src_chunks = self.chunk_refs(src_ino).await?.read().unwrap().clone();
+        chunks.write().unwrap().assign(&src_chunks);
+        self.chunk_store(dest_ino)
+            .share(&self.chunk_store(src_ino))?;
+        // the shared blocks were written by the source
+        let counters = self.counters(dest_ino).await?;
+        let src_counters = self.counters(src_ino).await?;
+        let src_legacy = src_counters.read().unwrap().is_legacy();
+        if src_legacy != counters.read().unwrap().is_legacy() {
+            // bound to their index only like the ones of the source, or not anymore
+            self.reset_file_id(dest_ino, (!src_legacy).then(new_file_id))
+                .await?;
+        }
+        counters
+            .write()
+            .unwrap()
+            .assign(&src_counters.read().unwrap())?;
+        self.save_holes(dest_ino).await?;
+        let now = SystemTime::now();
+        self.set_attr2(
+            dest_ino,
+            SetFileAttr::default()
+                .with_size(src_attr.size)
+                .with_mtime(now)
+                .with_ctime(now),
+            true,
+        )
+        .await?;
+
+        // reset handles because the content files were replaced
+        self.reset_handles(src_ino, None, false).await?;
+        self.reset_handles(dest_ino, None, false).await?;
+        Ok(())
+    }
+
+    /// Open a file. We can open multiple times for read and write.
     #[allow(clippy::missing_panics_doc)]
-    pub async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+    pub async fn open(&self, ino: u64, flags: OpenFlags) -> FsResult<u64> {
+        let _mutation = self.mutation().await;
+        let OpenFlags { read, write, .. } = flags;
         if write && self.read_only {
             return Err(FsError::ReadOnly);
         }
@@ -1797,9 +3010,18 @@ impl EncryptedFs {
                 "read and write cannot be false at the same time",
             ));
         }
+        if flags.truncate && !write {
+            return Err(FsError::InvalidInput("truncate needs write"));
+        }
         if self.is_dir(ino) {
             return Err(FsError::InvalidInodeType);
         }
+        if self.get_inode_from_cache_or_storage(ino).await?.kind != FileType::RegularFile {
+            return Err(FsError::InvalidInodeType);
+        }
+        if flags.truncate {
+            self.set_len_inner(ino, 0).await?;
+        }
 
         let mut handle: Option<u64> = None;
         if read {
@@ -1811,16 +3033,13 @@ impl EncryptedFs {
             .await?;
         }
         if write {
-            if self.opened_files_for_write.read().await.contains_key(&ino) {
-                return Err(FsError::AlreadyOpenForWrite);
-            }
             if handle.is_none() {
                 handle = Some(self.next_handle());
             }
             let res = self
                 .do_with_write_handle(
                     *handle.as_ref().expect("handle is missing"),
-                    WriteHandleContextOperation::Create { ino },
+                    WriteHandleContextOperation::Create { ino, flags },
                 )
                 .await;
             if res.is_err() && read {
@@ -1856,12 +3075,18 @@ impl EncryptedFs {
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::too_many_lines)]
     pub async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
+        let _mutation = self.mutation().await;
+        self.set_len_inner(ino, size).await
+    }
+
+    /// Like [`Self::set_len`], with the mutation guard already held.
+    async fn set_len_inner(&self, ino: u64, size: u64) -> FsResult<()> {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
         info!("truncate {ino} to {size}");
         let attr = self.get_attr(ino).await?;
-        if matches!(attr.kind, FileType::Directory) {
+        if !matches!(attr.kind, FileType::RegularFile) {
             return Err(FsError::InvalidInodeType);
         }
 
@@ -1869,6 +3094,7 @@ impl EncryptedFs {
             // no-op
             return Ok(());
         }
+        self.with_quota(|quota| quota.resize(ino, size))?;
 
         let lock = self
             .read_write_locks
@@ -1879,39 +3105,67 @@ impl EncryptedFs {
         self.flush_and_reset_writers(ino).await?;
 
         let file_path = self.contents_path(ino);
+        let allocated = dedup::allocated_of(&file_path)?;
+        let mut freed = 0;
+        let holes = self.holes(ino).await?;
+        let chunks = self.chunk_refs(ino).await?;
+        let counters = self.counters(ino).await?;
+        // from the block the new size ends in, or the old one when extending
+        let plaintext_block_size = BLOCK_SIZE as u64;
+        self.preserve_blocks(
+            ino,
+            size.min(attr.size).saturating_sub(1) / plaintext_block_size..u64::MAX,
+        )?;
         if size == 0 {
             debug!("truncate to zero");
             // truncate to zero
             let file = File::create(&file_path)?;
             file.set_len(0)?;
             file.sync_all()?;
+            freed = content_file::remove_layers(&file_path)?;
+            holes.write().unwrap().truncate(0);
+            chunks.write().unwrap().truncate(0);
+            counters.write().unwrap().truncate(0)?;
+        } else if size > attr.size {
+            debug!("extend size to {}", size.to_formatted_string(&Locale::en));
+            // seeking after the end leaves holes, only the last block is encrypted
+            // they are saved before the content file grows, so the gap is never read as blocks
+            holes.write().unwrap().insert(
+                attr.size.div_ceil(plaintext_block_size)..(size - 1) / plaintext_block_size,
+            );
+            self.save_holes(ino).await?;
+            let mut writer = self.create_content_write_seek(ino).await?;
+            writer.seek(SeekFrom::Start(size))?;
+            writer.finish()?.sync_all()?;
         } else {
             debug!("truncate size to {}", size.to_formatted_string(&Locale::en));
-
-            let mut file = fs_util::open_atomic_write(&file_path)?;
+            // keep the new last block, we need to encrypt it again with the new length
+            let last_block_index = (size - 1) / plaintext_block_size;
+            #[allow(clippy::cast_possible_truncation)]
+            let mut last_block = vec![0; (size - last_block_index * plaintext_block_size) as usize];
             {
-                // have a new scope, so we drop the reader before moving new content files
-                let mut reader = self.create_read(File::open(file_path.as_path())?).await?;
-
-                let mut writer = self.create_write(file).await?;
-
-                let len = if size > attr.size {
-                    // increase size, copy existing data until existing size
-                    attr.size
-                } else {
-                    // decrease size, copy existing data until new size
-                    size
-                };
-                stream_util::copy_exact(&mut reader, &mut writer, len)?;
-                if size > attr.size {
-                    // increase size, seek to new size will write zeros
-                    stream_util::fill_zeros(&mut writer, size - attr.size)?;
-                }
-                file = writer.finish()?;
+                let mut reader = self.create_content_read_seek(ino).await?;
+                reader.seek(SeekFrom::Start(last_block_index * plaintext_block_size))?;
+                reader.read_exact(&mut last_block)?;
             }
-            file.commit()?;
+            OpenOptions::new()
+                .write(true)
+                .open(&file_path)?
+                .set_len(last_block_index * self.cipher.ciphertext_block_size() as u64)?;
+            holes.write().unwrap().truncate(last_block_index);
+            chunks.write().unwrap().truncate(last_block_index);
+            counters.write().unwrap().truncate(last_block_index)?;
+            let mut writer = self.create_content_write_seek(ino).await?;
+            writer.seek(SeekFrom::Start(last_block_index * plaintext_block_size))?;
+            writer.write_all(&last_block)?;
+            writer.finish()?.sync_all()?;
         }
         File::open(file_path.parent().unwrap())?.sync_all()?;
+        self.count_allocated(
+            Allocated::changed(allocated, dedup::allocated_of(&file_path)?)
+                .and(Allocated::freed(freed)),
+        )?;
+        self.save_holes(ino).await?;
 
         let now = SystemTime::now();
         let set_attr = SetFileAttr::default()
@@ -1941,6 +3195,293 @@ impl EncryptedFs {
         Ok(())
     }
 
+    /// Get the space and inode usage, derived from the filesystem holding the data directory.
+    ///
+    /// Each block of [`StatFs::bsize`] takes more space on disk because of the nonce and tag added by encryption,
+    /// so the block counts are the space of the underlying filesystem divided by the size of an encrypted chunk.\
+    /// With a [`Quota`] the totals are its limits and the free counts what is left of them,
+    /// if the underlying filesystem has that much.
+    #[cfg(unix)]
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::unnecessary_cast)]
+    pub fn statfs(&self) -> FsResult<StatFs> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = std::ffi::CString::new(self.data_dir.as_os_str().as_bytes())
+            .map_err(|_| FsError::InvalidInput("data dir path contains nul"))?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let fragment_size = stat.f_frsize as u64;
+        let ciphertext_block_size = self.cipher.ciphertext_block_size() as u64;
+        let blocks = |count: u64| count * fragment_size / ciphertext_block_size;
+        #[allow(clippy::cast_possible_truncation)]
+        let mut stat_fs = StatFs {
+            blocks: blocks(stat.f_blocks as u64),
+            bfree: blocks(stat.f_bfree as u64),
+            bavail: blocks(stat.f_bavail as u64),
+            files: stat.f_files as u64 / FILES_PER_ENTRY,
+            ffree: stat.f_ffree as u64 / FILES_PER_ENTRY,
+            bsize: BLOCK_SIZE as u32,
+            logical_bytes: None,
+            physical_bytes: None,
+        };
+        if let Some(tracker) = self.quota.lock().unwrap().as_ref() {
+            let (quota, usage) = (tracker.quota(), tracker.usage());
+            if let Some(max_bytes) = quota.max_bytes {
+                let plaintext_block_size = BLOCK_SIZE as u64;
+                let free = max_bytes.saturating_sub(usage.bytes) / plaintext_block_size;
+                stat_fs.blocks = max_bytes / plaintext_block_size;
+                stat_fs.bfree = stat_fs.bfree.min(free);
+                stat_fs.bavail = stat_fs.bavail.min(free);
+            }
+            if let Some(max_inodes) = quota.max_inodes {
+                stat_fs.files = max_inodes;
+                stat_fs.ffree = stat_fs.ffree.min(max_inodes.saturating_sub(usage.inodes));
+            }
+            if self.block_dedup() {
+                stat_fs.logical_bytes = Some(usage.bytes);
+                stat_fs.physical_bytes = self.physical_size().map(|size| size.get());
+            }
+        }
+        Ok(stat_fs)
+    }
+
+    /// Get the space and inode usage, not supported on this platform.
+    #[cfg(not(unix))]
+    #[allow(clippy::missing_errors_doc)]
+    pub fn statfs(&self) -> FsResult<StatFs> {
+        Err(FsError::Other("statfs is not supported on this platform"))
+    }
+
+    /// Allocate, zero or deallocate the range `offset..offset + len` of a file, like `fallocate(2)`.
+    ///
+    /// As content is encrypted on write there is no space to reserve in advance, preallocating just extends the file
+    /// with zeros if needed, and with [`AllocateMode::keep_size`] it's a no-op.\
+    /// [`AllocateMode::punch_hole`] and [`AllocateMode::zero_range`] make the range read as zeros.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn allocate(
+        &self,
+        ino: u64,
+        offset: u64,
+        len: u64,
+        mode: AllocateMode,
+    ) -> FsResult<()> {
+        let _mutation = self.mutation().await;
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if len == 0 {
+            return Err(FsError::InvalidInput("len cannot be zero"));
+        }
+        if mode.punch_hole && (!mode.keep_size || mode.zero_range) {
+            return Err(FsError::InvalidInput(
+                "punch hole needs keep size and cannot be combined with zero range",
+            ));
+        }
+        let end = offset
+            .checked_add(len)
+            .ok_or(FsError::InvalidInput("offset + len overflows"))?;
+        let attr = self.get_attr(ino).await?;
+        if attr.kind != FileType::RegularFile {
+            return Err(FsError::InvalidInodeType);
+        }
+
+        if mode.punch_hole || mode.zero_range {
+            self.write_zeros(ino, offset, end).await?;
+        }
+        if !mode.keep_size && end > self.get_attr(ino).await?.size {
+            self.set_len_inner(ino, end).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Overwrite with zeros the range `offset..end` that is inside the file.
+    async fn write_zeros(&self, ino: u64, offset: u64, end: u64) -> FsResult<()> {
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _write_guard = lock.write().await;
+
+        // flush writers
+        self.flush_and_reset_writers(ino).await?;
+
+        let size = self.get_attr(ino).await?.size;
+        let end = end.min(size);
+        if offset >= end {
+            return Ok(());
+        }
+
+        // whole blocks in the range become holes, except the last block of the file
+        let plaintext_block_size = BLOCK_SIZE as u64;
+        let first_hole = offset.div_ceil(plaintext_block_size);
+        let end_hole = (end / plaintext_block_size).min((size - 1) / plaintext_block_size);
+        let allocated = dedup::allocated_of(&self.contents_path(ino))?;
+        let holes = self.holes(ino).await?;
+        let chunks = self.chunk_refs(ino).await?;
+        let counters = self.counters(ino).await?;
+        self.preserve_blocks(
+            ino,
+            offset / plaintext_block_size..end.div_ceil(plaintext_block_size),
+        )?;
+        let mut writer = self.create_content_write_seek(ino).await?;
+        writer.seek(SeekFrom::Start(offset))?;
+        if first_hole < end_hole {
+            stream_util::fill_zeros(&mut writer, first_hole * plaintext_block_size - offset)?;
+            holes.write().unwrap().insert(first_hole..end_hole);
+            chunks.write().unwrap().remove_range(first_hole..end_hole);
+            counters
+                .write()
+                .unwrap()
+                .remove_range(first_hole..end_hole)?;
+            writer.seek(SeekFrom::Start(end_hole * plaintext_block_size))?;
+            stream_util::fill_zeros(&mut writer, end - end_hole * plaintext_block_size)?;
+        } else {
+            stream_util::fill_zeros(&mut writer, end - offset)?;
+        }
+        writer.finish()?.sync_all()?;
+        File::open(self.contents_path(ino).parent().unwrap())?.sync_all()?;
+        self.count_allocated(Allocated::changed(
+            allocated,
+            dedup::allocated_of(&self.contents_path(ino))?,
+        ))?;
+        self.save_holes(ino).await?;
+
+        let now = SystemTime::now();
+        self.set_attr2(
+            ino,
+            SetFileAttr::default().with_mtime(now).with_ctime(now),
+            false,
+        )
+        .await?;
+
+        // reset handles because the file has changed
+        self.reset_handles(ino, None, false).await?;
+
+        Ok(())
+    }
+
+    /// Test for an advisory lock, returning the first lock of another owner that conflicts with
+    /// `lock_type` over `start..=end`, like `fcntl(2)` with `F_GETLK`.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn get_lock(
+        &self,
+        ino: u64,
+        owner: u64,
+        start: u64,
+        end: u64,
+        lock_type: LockType,
+    ) -> FsResult<Option<FileLock>> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        Ok(self.locks.get(ino, owner, start, end, lock_type).await)
+    }
+
+    /// Acquire, change or release (when `lock_type` is [None]) an advisory lock over `start..=end`,
+    /// like `fcntl(2)` with `F_SETLK` and `F_SETLKW` when `block` is `true`.
+    ///
+    /// If the range is locked by another owner and we don't `block`,
+    /// it will return an error of type [`FsError::WouldBlock`].
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::too_many_arguments)]
+    pub async fn set_lock(
+        &self,
+        ino: u64,
+        owner: u64,
+        pid: u32,
+        start: u64,
+        end: u64,
+        lock_type: Option<LockType>,
+        block: bool,
+    ) -> FsResult<()> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        self.locks
+            .set(ino, owner, pid, start, end, lock_type, block)
+            .await
+    }
+
+    /// Acquire, change or release (when `lock_type` is [None]) an advisory lock over the whole file, like `flock(2)`,
+    /// waiting for it when `block` is `true`.
+    ///
+    /// The locks are kept apart from the ones of [`Self::set_lock`], they don't conflict with them.
+    /// If the file is locked by another owner and we don't `block`, it will return an error of type
+    /// [`FsError::WouldBlock`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_flock(
+        &self,
+        ino: u64,
+        owner: u64,
+        pid: u32,
+        lock_type: Option<LockType>,
+        block: bool,
+    ) -> FsResult<()> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        self.flocks
+            .set(ino, owner, pid, 0, u64::MAX, lock_type, block)
+            .await
+    }
+
+    /// Release all advisory locks of `owner` on a file, should be called when the file is closed.
+    pub async fn release_locks(&self, ino: u64, owner: u64) {
+        self.locks.release(ino, owner).await;
+        self.flocks.release(ino, owner).await;
+    }
+
+    /// Find the first offset at or after `offset` that has data, like `lseek(2)` with `SEEK_DATA`.
+    ///
+    /// Returns [None] if `offset` is at or after the end of the file.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn seek_data(&self, ino: u64, offset: u64) -> FsResult<Option<u64>> {
+        self.seek_holes(ino, offset, true).await
+    }
+
+    /// Find the first offset at or after `offset` that is in a hole, like `lseek(2)` with `SEEK_HOLE`.
+    ///
+    /// The end of the file counts as a hole. Returns [None] if `offset` is at or after the end of the file.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn seek_hole(&self, ino: u64, offset: u64) -> FsResult<Option<u64>> {
+        self.seek_holes(ino, offset, false).await
+    }
+
+    async fn seek_holes(&self, ino: u64, offset: u64, data: bool) -> FsResult<Option<u64>> {
+        let attr = self.get_attr(ino).await?;
+        if attr.kind != FileType::RegularFile {
+            return Err(FsError::InvalidInodeType);
+        }
+        if offset >= attr.size {
+            return Ok(None);
+        }
+        let holes = self.holes(ino).await?;
+        let holes = holes.read().unwrap();
+        let plaintext_block_size = BLOCK_SIZE as u64;
+        let block_index = offset / plaintext_block_size;
+        let block_index = if data {
+            Some(holes.next_data(block_index))
+        } else {
+            holes.next_hole(block_index)
+        };
+        Ok(Some(match block_index {
+            Some(block_index) if block_index == offset / plaintext_block_size => offset,
+            Some(block_index) => block_index * plaintext_block_size,
+            // no more holes, only the virtual one at the end
+            None => attr.size,
+        }))
+    }
+
+    /// Held while changing the volume, so a snapshot is taken between the operations and not in the middle of one.
+    ///
+    /// Taken once, by the public operations, the internal ones called by them expect it to be held.
+    async fn mutation(&self) -> OwnedRwLockReadGuard<()> {
+        self.mutations.clone().read_owned().await
+    }
+
     /// This will write any dirty data to the file from all writers and reset them.
     /// Timestamps and size will be updated to the storage.
     /// > ⚠️ **Warning**
@@ -1951,182 +3492,1587 @@ impl EncryptedFs {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
-        let opened_files_for_write_guard = self.opened_files_for_write.read().await;
-        let handle = opened_files_for_write_guard.get(&ino);
-        if let Some(handle) = handle {
-            let write_handles_guard = self.write_handles.write().await;
-            let ctx = write_handles_guard.get(handle);
-            if let Some(lock) = ctx {
-                let mut ctx = lock.lock().await;
+        if self.opened_files_for_write.read().await.contains_key(&ino) {
+            self.reset_handles(ino, None, true).await?;
+        }
+        Ok(())
+    }
+
+    /// Rename an entry, overwriting the new name if it exists.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn rename(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        new_parent: u64,
+        new_name: &SecretString,
+    ) -> FsResult<()> {
+        self.rename2(parent, name, new_parent, new_name, RenameMode::Replace)
+            .await
+    }
+
+    /// Rename an entry, `mode` says what happens if the new name exists.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn rename2(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        new_parent: u64,
+        new_name: &SecretString,
+        mode: RenameMode,
+    ) -> FsResult<()> {
+        let _mutation = self.mutation().await;
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if !self.exists(parent) {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_dir(parent) {
+            return Err(FsError::InvalidInodeType);
+        }
+        if !self.exists(new_parent) {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_dir(new_parent) {
+            return Err(FsError::InvalidInodeType);
+        }
+        if !self.exists_by_name(parent, name)? {
+            return Err(FsError::NotFound("name not found"));
+        }
+        self.validate_filename(new_name)?;
+
+        if parent == new_parent && name.expose_secret() == new_name.expose_secret() {
+            // no-op
+            return if mode == RenameMode::NoReplace {
+                Err(FsError::AlreadyExists)
+            } else {
+                Ok(())
+            };
+        }
+
+        // held from the checks until both entries are changed, in the same order by everyone
+        let mut keys = [
+            self.dir_entry_key(parent, name),
+            self.dir_entry_key(new_parent, new_name),
+        ];
+        keys.sort();
+        let locks = keys.map(|key| {
+            self.serialize_dir_entries_hash_locks
+                .get_or_insert_with(key, || RwLock::new(false))
+        });
+        let _guard = locks[0].write().await;
+        let _guard_2 = locks[1].write().await;
+
+        let (ino, kind, encrypted_name) = self
+            .dir_entry(parent, name)
+            .await?
+            .ok_or(FsError::NotFound("name not found"))?;
+        let replaced = self.dir_entry(new_parent, new_name).await?;
+        match mode {
+            RenameMode::NoReplace if replaced.is_some() => {
+                return Err(FsError::AlreadyExists);
+            }
+            RenameMode::Exchange => {
+                let (new_ino, new_kind, new_encrypted_name) =
+                    replaced.ok_or(FsError::NotFound("new name not found"))?;
+                // each entry is changed in place, so it's always found
+                self.replace_directory_entry(parent, name, &encrypted_name, new_ino, new_kind)
+                    .await?;
+                self.replace_directory_entry(new_parent, new_name, &new_encrypted_name, ino, kind)
+                    .await?;
+                return self
+                    .exchanged(parent, ino, kind, new_parent, new_ino, new_kind)
+                    .await;
+            }
+            _ => {}
+        }
+
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        let replaced = if let Some((new_ino, _, new_encrypted_name)) = replaced {
+            if new_ino == ino {
+                // both are links to the same inode
+                return Ok(());
+            }
+            let new_attr = self.get_inode_from_cache_or_storage(new_ino).await?;
+            // Only overwrite an existing directory if it's empty
+            if new_attr.kind == FileType::Directory && self.len(new_ino)? > 0 {
+                return Err(FsError::NotEmpty);
+            }
+            Some((new_attr, new_encrypted_name))
+        } else {
+            None
+        };
+
+        let trashed = match &replaced {
+            Some((new_attr, _)) if self.goes_to_trash(new_attr) => {
+                // before its entry is replaced
+                self.move_to_trash(new_parent, new_name, new_attr).await?;
+                true
+            }
+            _ => false,
+        };
+        // add to new parent contents, the entry it replaces points to it in place
+        if let Some((_, new_encrypted_name)) = &replaced {
+            self.replace_directory_entry(new_parent, new_name, new_encrypted_name, ino, kind)
+                .await?;
+        } else {
+            self.write_directory_entry(
+                new_parent,
+                &DirectoryEntry {
+                    ino,
+                    name: new_name.clone(),
+                    kind,
+                },
+            )
+            .await?;
+        }
+        // remove from parent contents
+        self.remove_directory_entry(parent, name).await?;
+        // the inode it replaced loses that link like when unlinking it
+        if let Some((new_attr, _)) = replaced.filter(|_| !trashed) {
+            self.unlink_inode(&new_attr).await?;
+        }
+
+        if attr.kind == FileType::Directory {
+            // add the parent link to the new directory
+            self.insert_directory_entry(
+                attr.ino,
+                &DirectoryEntry {
+                    ino: new_parent,
+                    name: SecretBox::new(Box::new("$..".to_owned())),
+                    kind: FileType::Directory,
+                },
+            )
+            .await?;
+        }
+
+        let now = SystemTime::now();
+        let set_attr = SetFileAttr::default()
+            .with_mtime(now)
+            .with_ctime(now)
+            .with_atime(now);
+        self.set_attr_inner(parent, set_attr).await?;
+
+        let set_attr = SetFileAttr::default()
+            .with_mtime(now)
+            .with_ctime(now)
+            .with_atime(now);
+        self.set_attr_inner(new_parent, set_attr).await?;
+
+        let set_attr = SetFileAttr::default().with_ctime(now).with_atime(now);
+        self.set_attr_inner(attr.ino, set_attr).await?;
+
+        Ok(())
+    }
+
+    /// Finish a [`RenameMode::Exchange`] after the entries in `parent` and `new_parent` were swapped.
+    async fn exchanged(
+        &self,
+        parent: u64,
+        ino: u64,
+        kind: FileType,
+        new_parent: u64,
+        new_ino: u64,
+        new_kind: FileType,
+    ) -> FsResult<()> {
+        // update the parent link of the directories that moved to another parent
+        if parent != new_parent {
+            for (ino, kind, parent) in [(ino, kind, new_parent), (new_ino, new_kind, parent)] {
+                if kind == FileType::Directory {
+                    self.insert_directory_entry(
+                        ino,
+                        &DirectoryEntry {
+                            ino: parent,
+                            name: SecretBox::new(Box::new("$..".to_owned())),
+                            kind: FileType::Directory,
+                        },
+                    )
+                    .await?;
+                }
+            }
+        }
+
+        let now = SystemTime::now();
+        for ino in [parent, new_parent] {
+            let set_attr = SetFileAttr::default()
+                .with_mtime(now)
+                .with_ctime(now)
+                .with_atime(now);
+            self.set_attr_inner(ino, set_attr).await?;
+        }
+        for ino in [ino, new_ino] {
+            let set_attr = SetFileAttr::default().with_ctime(now).with_atime(now);
+            self.set_attr_inner(ino, set_attr).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Create a crypto writer using internal encryption info.
+    pub async fn create_write<W: CryptoInnerWriter + Seek + Send + Sync + 'static>(
+        &self,
+        file: W,
+    ) -> FsResult<impl CryptoWrite<W>> {
+        Ok(crypto::create_write(
+            file,
+            self.cipher,
+            &*self.key.get().await?,
+        ))
+    }
+
+    /// Create a crypto writer with seek using internal encryption info.
+    pub async fn create_write_seek<W: Write + Seek + Read + Send + Sync + 'static>(
+        &self,
+        file: W,
+    ) -> FsResult<impl CryptoWriteSeek<W>> {
+        Ok(crypto::create_write_seek(
+            file,
+            self.cipher,
+            &*self.key.get().await?,
+        ))
+    }
+
+    /// Create a crypto reader using internal encryption info.
+    pub async fn create_read<R: Read + Send + Sync>(
+        &self,
+        reader: R,
+    ) -> FsResult<impl CryptoRead<R>> {
+        Ok(crypto::create_read(
+            reader,
+            self.cipher,
+            &*self.key.get().await?,
+        ))
+    }
+
+    /// Create a crypto reader with seek using internal encryption info.
+    pub async fn create_read_seek<R: Read + Seek + Send + Sync>(
+        &self,
+        reader: R,
+    ) -> FsResult<impl CryptoReadSeek<R>> {
+        Ok(crypto::create_read_seek(
+            reader,
+            self.cipher,
+            &*self.key.get().await?,
+        ))
+    }
+
+    /// Create a crypto reader with seek for the contents of a file, aware of its holes.
+    async fn create_content_read_seek(
+        &self,
+        ino: u64,
+    ) -> FsResult<impl CryptoReadSeek<ContentFile>> {
+        let holes = self.holes(ino).await?;
+        let chunks = self.chunk_refs(ino).await?;
+        let counters = self.counters(ino).await?;
+        Ok(crypto::create_read_seek_with_chunks(
+            ContentFile::open(
+                &self.contents_path(ino),
+                Some(&self.data_dir.join(COW_DIR)),
+                self.cipher.ciphertext_block_size() as u64,
+            )?,
+            self.cipher,
+            &*self.key.get().await?,
+            holes,
+            chunks,
+            counters,
+        ))
+    }
+
+    /// Create a crypto writer with seek for the contents of a file, extending it leaves holes.
+    async fn create_content_write_seek(
+        &self,
+        ino: u64,
+    ) -> FsResult<impl CryptoWriteSeek<ContentFile>> {
+        let holes = self.holes(ino).await?;
+        let chunks = self.chunk_refs(ino).await?;
+        let counters = self.counters(ino).await?;
+        Ok(crypto::create_write_seek_with_chunks(
+            ContentFile::open_write(
+                &self.contents_path(ino),
+                self.cipher.ciphertext_block_size() as u64,
+            )?,
+            self.cipher,
+            &*self.key.get().await?,
+            holes,
+            chunks,
+            counters,
+            self.compression(),
+        ))
+    }
+
+    /// If a read needs to update the access time, depending on [`AtimeMode`].
+    fn is_atime_stale(&self, atime: SystemTime, mtime: SystemTime, ctime: SystemTime) -> bool {
+        if self.read_only {
+            return false;
+        }
+        match self.atime_mode {
+            AtimeMode::NoAtime => false,
+            AtimeMode::RelAtime => {
+                atime <= mtime
+                    || atime <= ctime
+                    || SystemTime::now()
+                        .duration_since(atime)
+                        .is_ok_and(|age| age >= RELATIME_MAX_AGE)
+            }
+            AtimeMode::StrictAtime => true,
+        }
+    }
+
+    /// Save the access time after a read without a handle, like listing a directory.
+    async fn touch_atime(&self, ino: u64) -> FsResult<()> {
+        let attr = self.get_attr(ino).await?;
+        if self.is_atime_stale(attr.atime, attr.mtime, attr.ctime) {
+            self.save_atime(ino, SystemTime::now()).await?;
+        }
+        Ok(())
+    }
+
+    /// Unlike [`EncryptedFs::set_attr`] it keeps the change time, reading doesn't change the file.
+    async fn save_atime(&self, ino: u64, atime: SystemTime) -> FsResult<()> {
+        let serialize_update_lock = self
+            .serialize_update_inode_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _serialize_update_guard = serialize_update_lock.lock().await;
+
+        let mut attr = self.get_attr(ino).await?;
+        if atime <= attr.atime {
+            return Ok(());
+        }
+        attr.atime = atime;
+        self.write_inode_to_storage(&attr).await
+    }
+
+    /// Set limits on the plaintext bytes and number of inodes of the volume, stored encrypted in the data directory.
+    ///
+    /// Operations that would go over them fail with [`FsError::QuotaExceeded`], what is already used is kept.
+    /// Setting a [`Quota::default`] removes the limits.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_quota(&self, quota: Quota) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let path = self.data_dir.join(SECURITY_DIR).join(QUOTA_FILENAME);
+        if !quota.is_set() {
+            if path.is_file() {
+                fs::remove_file(path)?;
+            }
+            let mut guard = self.quota.lock().unwrap();
+            if self.block_dedup() {
+                // still counting the logical size
+                if let Some(tracker) = guard.as_mut() {
+                    tracker.set_quota(quota);
+                }
+            } else {
+                guard.take();
+            }
+            return Ok(());
+        }
+        crypto::atomic_serialize_encrypt_into(&path, &quota, self.cipher, &*self.key.get().await?)?;
+        if let Some(tracker) = self.quota.lock().unwrap().as_mut() {
+            tracker.set_quota(quota);
+            return Ok(());
+        }
+        let tracker = QuotaTracker::new(quota, self.scan_sizes().await?);
+        self.quota.lock().unwrap().replace(tracker);
+        Ok(())
+    }
+
+    /// The limits of the volume, see [`EncryptedFs::set_quota`].
+    #[allow(clippy::missing_panics_doc)]
+    pub fn quota(&self) -> Quota {
+        self.quota
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(QuotaTracker::quota)
+            .unwrap_or_default()
+    }
+
+    /// What is counted against the [`Quota`], [None] if there is none and deduplication is off.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn usage(&self) -> Option<Usage> {
+        self.quota.lock().unwrap().as_ref().map(QuotaTracker::usage)
+    }
+
+    async fn load_quota(&self) -> FsResult<()> {
+        let path = self.data_dir.join(SECURITY_DIR).join(QUOTA_FILENAME);
+        if !path.is_file() {
+            return Ok(());
+        }
+        let quota: Quota = bincode::deserialize_from(crypto::create_read(
+            File::open(path)?,
+            self.cipher,
+            &*self.key.get().await?,
+        ))?;
+        let tracker = QuotaTracker::new(quota, self.scan_sizes().await?);
+        self.quota.lock().unwrap().replace(tracker);
+        Ok(())
+    }
+
+    /// Size of each inode, from storage.
+    async fn scan_sizes(&self) -> FsResult<HashMap<u64, u64>> {
+        let mut sizes = HashMap::new();
+        for entry in fs::read_dir(self.data_dir.join(INODES_DIR))? {
+            // the other files next to inodes have an extension
+            let Ok(ino) = entry?.file_name().to_string_lossy().parse::<u64>() else {
+                continue;
+            };
+            let attr = self.get_inode_from_storage(ino).await?;
+            let size = match attr.kind {
+                FileType::RegularFile | FileType::Symlink => attr.size,
+                _ => 0,
+            };
+            sizes.insert(ino, size);
+        }
+        Ok(sizes)
+    }
+
+    /// Apply `f` to the quota, if there is one.
+    fn with_quota(&self, f: impl FnOnce(&mut QuotaTracker) -> FsResult<()>) -> FsResult<()> {
+        self.quota.lock().unwrap().as_mut().map_or(Ok(()), f)
+    }
+
+    /// Turn the trash on, or off with [None]. The setting is stored encrypted in the data directory.
+    ///
+    /// While on, removed files and directories are kept in the trash, see [`EncryptedFs::trash_entries`].
+    /// Turning it off keeps the entries already there.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_trash(&self, trash: Option<Trash>) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let path = self.data_dir.join(SECURITY_DIR).join(TRASH_FILENAME);
+        match trash {
+            Some(trash) => crypto::atomic_serialize_encrypt_into(
+                &path,
+                &trash,
+                self.cipher,
+                &*self.key.get().await?,
+            )?,
+            None if path.is_file() => fs::remove_file(path)?,
+            None => {}
+        }
+        *self.trash.lock().unwrap() = trash;
+        Ok(())
+    }
+
+    /// The settings of the trash, [None] if it's off.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn trash(&self) -> Option<Trash> {
+        *self.trash.lock().unwrap()
+    }
+
+    async fn load_trash(&self) -> FsResult<()> {
+        let path = self.data_dir.join(SECURITY_DIR).join(TRASH_FILENAME);
+        if !path.is_file() {
+            return Ok(());
+        }
+        let trash: Trash = bincode::deserialize_from(crypto::create_read(
+            File::open(path)?,
+            self.cipher,
+            &*self.key.get().await?,
+        ))?;
+        self.trash.lock().unwrap().replace(trash);
+        Ok(())
+    }
+
+    /// Turn block deduplication on or off. The setting is stored encrypted in the data directory.
+    ///
+    /// While on, each written block is kept once in the chunk store of the volume, with the other blocks of the
+    /// same content, see [`dedup`]. It's fixed-size deduplication, not content-defined chunking: blocks are compared
+    /// whole, at their place in the file, so they are found in copies of files and in files changed in place, but
+    /// inserting or removing data in the middle of a file shifts the blocks after it and they are stored again.\
+    /// Turning it off keeps the blocks already in the store, they are moved out as they are written again.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_block_dedup(&self, dedup: bool) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let path = self.data_dir.join(SECURITY_DIR).join(DEDUP_FILENAME);
+        if dedup {
+            crypto::atomic_serialize_encrypt_into(
+                &path,
+                &dedup,
+                self.cipher,
+                &*self.key.get().await?,
+            )?;
+        } else if path.is_file() {
+            fs::remove_file(path)?;
+        }
+        self.dedup.store(dedup, Ordering::SeqCst);
+        self.track_sizes().await
+    }
+
+    /// If block deduplication is on, see [`EncryptedFs::set_block_dedup`].
+    pub fn block_dedup(&self) -> bool {
+        self.dedup.load(Ordering::SeqCst)
+    }
+
+    async fn load_dedup(&self) -> FsResult<()> {
+        let path = self.data_dir.join(SECURITY_DIR).join(DEDUP_FILENAME);
+        if !path.is_file() {
+            return Ok(());
+        }
+        let dedup: bool = bincode::deserialize_from(crypto::create_read(
+            File::open(path)?,
+            self.cipher,
+            &*self.key.get().await?,
+        ))?;
+        self.dedup.store(dedup, Ordering::SeqCst);
+        self.track_sizes().await
+    }
+
+    /// The size of the files, and the space they take, is counted while deduplication is on, for
+    /// [`EncryptedFs::statfs`], even without a quota.
+    async fn track_sizes(&self) -> FsResult<()> {
+        let path = self
+            .data_dir
+            .join(SECURITY_DIR)
+            .join(PHYSICAL_SIZE_FILENAME);
+        if self.block_dedup() {
+            if self.quota.lock().unwrap().is_none() {
+                let tracker = QuotaTracker::new(Quota::default(), self.scan_sizes().await?);
+                self.quota.lock().unwrap().replace(tracker);
+            }
+            if self.physical_size().is_none() {
+                let size = match PhysicalSize::open(&path)? {
+                    Some(size) => size,
+                    None => PhysicalSize::create(
+                        (!self.read_only).then_some(path.as_path()),
+                        &self.data_dir,
+                    )?,
+                };
+                self.physical_size.lock().unwrap().replace(Arc::new(size));
+            }
+        } else {
+            // not counted anymore, it's measured again when turned back on
+            self.physical_size.lock().unwrap().take();
+            if path.is_file() {
+                fs::remove_file(path)?;
+            }
+            let mut guard = self.quota.lock().unwrap();
+            if guard
+                .as_ref()
+                .is_some_and(|tracker| !tracker.quota().is_set())
+            {
+                guard.take();
+            }
+        }
+        Ok(())
+    }
+
+    /// Compress the content of the files before it's encrypted, or stop with [None]. The setting is stored encrypted in
+    /// the data directory.
+    ///
+    /// Each block is compressed on its own and kept only if it gets shorter, see [`crate::crypto::compress`]. It keeps
+    /// its place in the content file, so seeking is not slower, and the rest of it is a gap in the file. Blocks are
+    /// compressed when they are written, changing it doesn't rewrite the ones already written.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_compression(&self, compression: Option<Compression>) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let path = self.data_dir.join(SECURITY_DIR).join(COMPRESSION_FILENAME);
+        match compression {
+            Some(compression) => crypto::atomic_serialize_encrypt_into(
+                &path,
+                &compression,
+                self.cipher,
+                &*self.key.get().await?,
+            )?,
+            None if path.is_file() => fs::remove_file(path)?,
+            None => {}
+        }
+        *self.compression.lock().unwrap() = compression;
+        Ok(())
+    }
+
+    /// How blocks are compressed, [None] if they are not.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn compression(&self) -> Option<Compression> {
+        *self.compression.lock().unwrap()
+    }
+
+    async fn load_compression(&self) -> FsResult<()> {
+        let path = self.data_dir.join(SECURITY_DIR).join(COMPRESSION_FILENAME);
+        if !path.is_file() {
+            return Ok(());
+        }
+        let compression: Compression = bincode::deserialize_from(crypto::create_read(
+            File::open(path)?,
+            self.cipher,
+            &*self.key.get().await?,
+        ))?;
+        self.compression.lock().unwrap().replace(compression);
+        Ok(())
+    }
+
+    /// Purge the expired trash entries and file versions periodically, for as long as the filesystem is used.
+    fn spawn_expiry(fs: Weak<Self>) {
+        tokio::spawn(async move {
+            while let Some(fs) = fs.upgrade() {
+                if let Err(err) = fs.expire_trash().await {
+                    error!(err = %err, "expiring trash");
+                }
+                if let Err(err) = fs.expire_versions().await {
+                    error!(err = %err, "expiring versions");
+                }
+                drop(fs);
+                tokio::time::sleep(EXPIRE_INTERVAL).await;
+            }
+        });
+    }
+
+    /// The removed files and directories, the oldest first.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn trash_entries(&self) -> FsResult<Vec<TrashEntry>> {
+        let dir = self.data_dir.join(TRASH_DIR);
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut entries = vec![];
+        for file in fs::read_dir(dir)? {
+            // skip the temporary files of atomic writes
+            let Ok(ino) = file?.file_name().to_string_lossy().parse::<u64>() else {
+                continue;
+            };
+            let record = self.trash_record(ino).await?;
+            entries.push(TrashEntry {
+                ino,
+                kind: record.kind,
+                path: SecretString::from_str(&record.path).unwrap(),
+                deleted: record.deleted,
+            });
+        }
+        entries.sort_by_key(|entry| entry.deleted);
+        Ok(entries)
+    }
+
+    /// Put a trash entry back where it was removed from.
+    ///
+    /// Fails if its parent directory was removed or there is another entry with the same name.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn restore_from_trash(&self, ino: u64) -> FsResult<()> {
+        let _mutation = self.mutation().await;
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let record = self.trash_record(ino).await?;
+        if !self.is_dir(record.parent) || self.trash_file(record.parent).is_file() {
+            return Err(FsError::NotFound("parent not found"));
+        }
+        let name = SecretString::from_str(&record.name).unwrap();
+        if self.exists_by_name(record.parent, &name)? {
+            return Err(FsError::AlreadyExists);
+        }
+        self.insert_directory_entry(
+            record.parent,
+            &DirectoryEntry {
+                ino,
+                name,
+                kind: record.kind,
+            },
+        )
+        .await?;
+        fs::remove_file(self.trash_file(ino))?;
+
+        let now = SystemTime::now();
+        self.set_attr_inner(
+            record.parent,
+            SetFileAttr::default().with_mtime(now).with_ctime(now),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Remove a trash entry for good.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn purge_from_trash(&self, ino: u64) -> FsResult<()> {
+        let _mutation = self.mutation().await;
+        self.purge_from_trash_inner(ino).await
+    }
+
+    /// Like [`Self::purge_from_trash`], with the mutation guard already held.
+    async fn purge_from_trash_inner(&self, ino: u64) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let path = self.trash_file(ino);
+        if !path.is_file() {
+            return Err(FsError::NotFound("not in trash"));
+        }
+        // the record goes last, so an interrupted purge can be done again
+        if self.exists(ino) {
+            let attr = self.get_inode_from_cache_or_storage(ino).await?;
+            self.remove_inode_files(&attr).await?;
+        }
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    /// Purge the trash entries older than its retention, this is also done periodically.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn expire_trash(&self) -> FsResult<()> {
+        let _mutation = self.mutation().await;
+        let Some(retention) = self.trash().and_then(|trash| trash.retention) else {
+            return Ok(());
+        };
+        if self.read_only {
+            return Ok(());
+        }
+        let now = SystemTime::now();
+        for entry in self.trash_entries().await? {
+            if now.duration_since(entry.deleted).unwrap_or_default() >= retention {
+                self.purge_from_trash_inner(entry.ino).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep an inode whose last entry, `name` in `parent`, was removed.
+    async fn move_to_trash(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        attr: &FileAttr,
+    ) -> FsResult<()> {
+        let record = TrashRecord {
+            parent,
+            name: name.expose_secret().to_string(),
+            path: self.path_of(parent, name).await?,
+            kind: attr.kind,
+            deleted: SystemTime::now(),
+        };
+        fs::create_dir_all(self.data_dir.join(TRASH_DIR))?;
+        crypto::atomic_serialize_encrypt_into(
+            &self.trash_file(attr.ino),
+            &record,
+            self.cipher,
+            &*self.key.get().await?,
+        )?;
+        Ok(())
+    }
+
+    async fn trash_record(&self, ino: u64) -> FsResult<TrashRecord> {
+        let path = self.trash_file(ino);
+        if !path.is_file() {
+            return Err(FsError::NotFound("not in trash"));
+        }
+        Ok(bincode::deserialize_from(crypto::create_read(
+            File::open(path)?,
+            self.cipher,
+            &*self.key.get().await?,
+        ))?)
+    }
+
+    fn trash_file(&self, ino: u64) -> PathBuf {
+        self.data_dir.join(TRASH_DIR).join(ino.to_string())
+    }
+
+    /// Keep previous contents of regular files, or stop with [None]. The setting is stored encrypted in the data
+    /// directory.
+    ///
+    /// While on, a version of the file is saved each time a write handle is released, see
+    /// [`EncryptedFs::list_versions`]. Turning it off keeps the versions already saved.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_versioning(&self, versioning: Option<Versioning>) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let path = self.data_dir.join(SECURITY_DIR).join(VERSIONING_FILENAME);
+        match versioning {
+            Some(versioning) => crypto::atomic_serialize_encrypt_into(
+                &path,
+                &versioning,
+                self.cipher,
+                &*self.key.get().await?,
+            )?,
+            None if path.is_file() => fs::remove_file(path)?,
+            None => {}
+        }
+        *self.versioning.lock().unwrap() = versioning;
+        Ok(())
+    }
+
+    /// How many versions are kept, [None] if it's off.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn versioning(&self) -> Option<Versioning> {
+        *self.versioning.lock().unwrap()
+    }
+
+    async fn load_versioning(&self) -> FsResult<()> {
+        let path = self.data_dir.join(SECURITY_DIR).join(VERSIONING_FILENAME);
+        if !path.is_file() {
+            return Ok(());
+        }
+        let versioning: Versioning = bincode::deserialize_from(crypto::create_read(
+            File::open(path)?,
+            self.cipher,
+            &*self.key.get().await?,
+        ))?;
+        self.versioning.lock().unwrap().replace(versioning);
+        Ok(())
+    }
+
+    /// The saved versions of a file, the oldest first.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn list_versions(&self, ino: u64) -> FsResult<Vec<FileVersion>> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        let dir = self.versions_dir(ino);
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut versions = vec![];
+        for file in fs::read_dir(dir)? {
+            // the content files, the others have an extension
+            let Ok(id) = file?.file_name().to_string_lossy().parse::<u64>() else {
+                continue;
+            };
+            versions.push(self.version(ino, id).await?);
+        }
+        versions.sort_by_key(|version| version.id);
+        Ok(versions)
+    }
+
+    /// Read from a version of a file at `offset`, like [`EncryptedFs::read`].
+    ///
+    /// Returns the number of bytes read, `0` after its end.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_version(
+        &self,
+        ino: u64,
+        id: u64,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> FsResult<usize> {
+        let version = self.version(ino, id).await?;
+        if offset >= version.size {
+            return Ok(0);
+        }
+        let path = self.versions_dir(ino).join(id.to_string());
+        let holes = self.version_holes(ino, id).await?;
+        let chunks = self.version_chunk_refs(ino, id).await?;
+        let counters = self.version_counters(ino, id).await?;
+        let mut reader = crypto::create_read_seek_with_chunks(
+            File::open(path)?,
+            self.cipher,
+            &*self.key.get().await?,
+            Arc::new(std::sync::RwLock::new(holes)),
+            Arc::new(std::sync::RwLock::new(chunks)),
+            Arc::new(std::sync::RwLock::new(counters)),
+        );
+        reader.seek(SeekFrom::Start(offset))?;
+        #[allow(clippy::cast_possible_truncation)]
+        let len = buf.len().min((version.size - offset) as usize);
+        reader.read_exact(&mut buf[..len])?;
+        Ok(len)
+    }
+
+    /// Replace the content of a file with one of its versions.
+    ///
+    /// The current content is saved as a version first, so this can be undone.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn restore_version(&self, ino: u64, id: u64) -> FsResult<()> {
+        let _mutation = self.mutation().await;
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let version = self.version(ino, id).await?;
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _write_guard = lock.write().await;
+
+        // flush writers
+        self.flush_and_reset_writers(ino).await?;
+        self.save_version(ino).await?;
+        self.with_quota(|quota| quota.resize(ino, version.size))?;
+
+        // replaced, so the snapshots keep the content they had
+        let file_path = self.contents_path(ino);
+        let tmp_path = file_path.with_file_name(format!(".{ino}.{VERSIONS_EXT}"));
+        fs::copy(self.versions_dir(ino).join(id.to_string()), &tmp_path)?;
+        File::open(&tmp_path)?.sync_all()?;
+        let allocated = Allocated {
+            added: dedup::allocated_of(&tmp_path)?,
+            freed: dedup::freed_by_unlink(&file_path)?,
+        };
+        fs::rename(tmp_path, &file_path)?;
+        // the version has all the blocks
+        let freed = content_file::remove_layers(&file_path)?;
+        File::open(file_path.parent().unwrap())?.sync_all()?;
+        self.count_allocated(allocated.and(Allocated::freed(freed)))?;
+        // kept until saved
+        let holes = self.holes(ino).await?;
+        let version_holes = self.version_holes(ino, id).await?;
+        *holes.write().unwrap() = version_holes;
+        let chunks = self.chunk_refs(ino).await?;
+        let version_chunks = self.version_chunk_refs(ino, id).await?;
+        chunks.write().unwrap().assign(&version_chunks);
+        self.chunk_store(ino)
+            .share(&self.version_chunk_store(ino, id))?;
+        let counters = self.counters(ino).await?;
+        let version_counters = self.version_counters(ino, id).await?;
+        counters.write().unwrap().assign(&version_counters)?;
+        self.save_holes(ino).await?;
+
+        let now = SystemTime::now();
+        self.set_attr2(
+            ino,
+            SetFileAttr::default()
+                .with_size(version.size)
+                .with_mtime(now)
+                .with_ctime(now),
+            true,
+        )
+        .await?;
+
+        // reset handles because the file has changed
+        self.reset_handles(ino, None, false).await?;
+        Ok(())
+    }
+
+    /// Save the current content of a regular file as a version, if it changed since the last one.
+    ///
+    /// Needs to be called with the file flushed and locked for writing.
+    async fn save_version(&self, ino: u64) -> FsResult<()> {
+        let Some(versioning) = self.versioning() else {
+            return Ok(());
+        };
+        let attr = self.get_inode_from_storage(ino).await?;
+        if attr.kind != FileType::RegularFile {
+            return Ok(());
+        }
+        let versions = self.list_versions(ino).await?;
+        if versions
+            .last()
+            .is_some_and(|last| last.size == attr.size && last.mtime == attr.mtime)
+        {
+            return Ok(());
+        }
+        let id = versions.last().map_or(1, |last| last.id + 1);
+        let dir = self.versions_dir(ino);
+        fs::create_dir_all(&dir)?;
+        let key = self.key.get().await?;
+        let counters = self.counters(ino).await?;
+        // of the version, so they are not taken from another one
+        let binding = SideFileBinding::version(ino, &counters.read().unwrap(), id);
+        let holes = self.holes(ino).await?.read().unwrap().clone();
+        if !holes.is_empty() {
+            save_side_file(
+                &dir.join(format!("{id}.{HOLES_EXT}")),
+                binding,
+                &holes,
+                self.cipher,
+                &key,
+            )?;
+        }
+        counters.read().unwrap().save(
+            &dir.join(format!("{id}.{COUNTERS_EXT}")),
+            ino,
+            self.cipher,
+            &key,
+        )?;
+        let chunks = self.chunk_refs(ino).await?.read().unwrap().clone();
+        if !chunks.is_empty() {
+            self.version_chunk_store(ino, id)
+                .share(&self.chunk_store(ino))?;
+            save_side_file(
+                &dir.join(format!("{id}.{CHUNKS_EXT}")),
+                binding,
+                &chunks,
+                self.cipher,
+                &key,
+            )?;
+        }
+        crypto::atomic_serialize_encrypt_into(
+            &dir.join(format!("{id}.{VERSION_META_EXT}")),
+            &FileVersion {
+                id,
+                size: attr.size,
+                mtime: attr.mtime,
+                saved: SystemTime::now(),
+            },
+            self.cipher,
+            &*self.key.get().await?,
+        )?;
+        // the content last, listing only finds complete versions
+        let tmp_path = dir.join(format!(".{id}"));
+        content_file::copy(
+            &self.contents_path(ino),
+            &tmp_path,
+            self.cipher.ciphertext_block_size() as u64,
+        )?;
+        File::open(&tmp_path)?.sync_all()?;
+        fs::rename(tmp_path, dir.join(id.to_string()))?;
+        fs_util::sync_dir(&dir)?;
+
+        self.prune_versions(ino, &versioning).await
+    }
+
+    /// Remove the versions over the count or older than the age of `versioning`.
+    async fn prune_versions(&self, ino: u64, versioning: &Versioning) -> FsResult<()> {
+        let versions = self.list_versions(ino).await?;
+        let over = versions.len().saturating_sub(versioning.max_versions);
+        let now = SystemTime::now();
+        for (i, version) in versions.iter().enumerate() {
+            let expired = versioning.max_age.is_some_and(|max_age| {
+                now.duration_since(version.saved).unwrap_or_default() >= max_age
+            });
+            if i < over || expired {
+                let dir = self.versions_dir(ino);
+                fs::remove_file(dir.join(version.id.to_string()))?;
+                fs::remove_file(dir.join(format!("{}.{VERSION_META_EXT}", version.id)))?;
+                let holes_file = dir.join(format!("{}.{HOLES_EXT}", version.id));
+                if holes_file.is_file() {
+                    fs::remove_file(holes_file)?;
+                }
+                let counters_file = dir.join(format!("{}.{COUNTERS_EXT}", version.id));
+                if counters_file.is_file() {
+                    fs::remove_file(counters_file)?;
+                }
+                let chunks_file = dir.join(format!("{}.{CHUNKS_EXT}", version.id));
+                if chunks_file.is_file() {
+                    fs::remove_file(chunks_file)?;
+                }
+                self.version_chunk_store(ino, version.id).release_all()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the versions older than the age of the versioning setting, this is also done periodically.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn expire_versions(&self) -> FsResult<()> {
+        let _mutation = self.mutation().await;
+        let Some(versioning) = self.versioning() else {
+            return Ok(());
+        };
+        if versioning.max_age.is_none() || self.read_only {
+            return Ok(());
+        }
+        for file in fs::read_dir(self.data_dir.join(INODES_DIR))? {
+            let name = file?.file_name().to_string_lossy().to_string();
+            let Some(Ok(ino)) = name
+                .strip_suffix(&format!(".{VERSIONS_EXT}"))
+                .map(str::parse::<u64>)
+            else {
+                continue;
+            };
+            self.prune_versions(ino, &versioning).await?;
+        }
+        Ok(())
+    }
+
+    async fn version(&self, ino: u64, id: u64) -> FsResult<FileVersion> {
+        let path = self
+            .versions_dir(ino)
+            .join(format!("{id}.{VERSION_META_EXT}"));
+        if !self.versions_dir(ino).join(id.to_string()).is_file() || !path.is_file() {
+            return Err(FsError::NotFound("version not found"));
+        }
+        Ok(bincode::deserialize_from(crypto::create_read(
+            File::open(path)?,
+            self.cipher,
+            &*self.key.get().await?,
+        ))?)
+    }
+
+    async fn version_counters(&self, ino: u64, id: u64) -> FsResult<WriteCounters> {
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        // written at once, never cut by a crash
+        let path = self.versions_dir(ino).join(format!("{id}.{COUNTERS_EXT}"));
+        self.load_counters(&attr, &path, 0).await
+    }
+
+    async fn version_chunk_refs(&self, ino: u64, id: u64) -> FsResult<ChunkRefs> {
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        let path = self.versions_dir(ino).join(format!("{id}.{CHUNKS_EXT}"));
+        self.load_chunk_refs(&path, &attr, id..=id).await
+    }
+
+    async fn version_holes(&self, ino: u64, id: u64) -> FsResult<Holes> {
+        let path = self.versions_dir(ino).join(format!("{id}.{HOLES_EXT}"));
+        if !path.is_file() {
+            return Ok(Holes::default());
+        }
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        load_side_file(&path, &attr, id..=id, self.cipher, &*self.key.get().await?)
+    }
+
+    /// Freeze the current state of the volume into the snapshot `name`, see [`snapshot`].
+    ///
+    /// It waits for the operations changing the volume in progress and holds the next ones until everything is linked,
+    /// so the snapshot is of the volume as it was between two of them. Pending writes are flushed first.\
+    /// Use [`snapshot::snapshot_data_dir`] to open it.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_snapshot(&self, name: &str) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if !cfg!(unix) {
+            return Err(FsError::Other(
+                "snapshots are not supported on this platform",
+            ));
+        }
+        Self::validate_snapshot_name(name)?;
+        let dir = snapshot::snapshot_data_dir(&self.data_dir, name);
+        if dir.exists() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        // held until linked, so nothing is changed meanwhile
+        let barrier = self.mutations.write().await;
+        let inos: Vec<u64> = self
+            .opened_files_for_write
+            .read()
+            .await
+            .keys()
+            .copied()
+            .collect();
+        for ino in inos {
+            self.flush_and_reset_writers(ino).await?;
+        }
+
+        // hidden until complete
+        let snapshots_dir = self.data_dir.join(SNAPSHOTS_DIR);
+        let tmp_dir = snapshots_dir.join(format!(".{name}"));
+        if tmp_dir.exists() {
+            fs::remove_dir_all(&tmp_dir)?;
+        }
+        fs::create_dir_all(&tmp_dir)?;
+        // before linking, writes keep the blocks here from then on
+        fs::create_dir(tmp_dir.join(COW_DIR))?;
+        snapshot::link_tree(&self.data_dir.join(INODES_DIR), &tmp_dir.join(INODES_DIR))?;
+        snapshot::link_tree(
+            &self.data_dir.join(CONTENTS_DIR),
+            &tmp_dir.join(CONTENTS_DIR),
+        )?;
+        if self.data_dir.join(CHUNKS_DIR).is_dir() {
+            snapshot::link_tree(&self.data_dir.join(CHUNKS_DIR), &tmp_dir.join(CHUNKS_DIR))?;
+        }
+        // copied, the key is changed in place with the password
+        fs::create_dir(tmp_dir.join(SECURITY_DIR))?;
+        for file in fs::read_dir(self.data_dir.join(SECURITY_DIR))? {
+            let file = file?;
+            // the space is counted for the volume only
+            let name = file.file_name().to_string_lossy().to_string();
+            if !name.starts_with('.') && name != PHYSICAL_SIZE_FILENAME {
+                fs::copy(
+                    file.path(),
+                    tmp_dir.join(SECURITY_DIR).join(file.file_name()),
+                )?;
+            }
+        }
+        fs::rename(tmp_dir, dir)?;
+        fs_util::sync_dir(&snapshots_dir)?;
+        drop(barrier);
+        Ok(())
+    }
+
+    /// Names of the snapshots of the volume.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn snapshots(&self) -> FsResult<Vec<String>> {
+        let dir = self.data_dir.join(SNAPSHOTS_DIR);
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut names = vec![];
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            // the ones not complete are hidden
+            if !name.starts_with('.') {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Remove a snapshot, freeing the blocks only it had.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn remove_snapshot(&self, name: &str) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        Self::validate_snapshot_name(name)?;
+        let dir = snapshot::snapshot_data_dir(&self.data_dir, name);
+        if !dir.is_dir() {
+            return Err(FsError::NotFound("snapshot not found"));
+        }
+        let contents_dir = dir.join(CONTENTS_DIR);
+        if contents_dir.is_dir() {
+            self.count_allocated(Allocated::freed(dedup::freed_by_removing(&contents_dir)?))?;
+        }
+        fs::remove_dir_all(dir)?;
+        dedup::collect_garbage(
+            &self.data_dir.join(CHUNKS_DIR),
+            self.physical_size().as_deref(),
+        )?;
+        Ok(())
+    }
+
+    fn validate_snapshot_name(name: &str) -> FsResult<()> {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(FsError::InvalidInput("invalid snapshot name"));
+        }
+        Ok(())
+    }
+
+    /// Path of `name` in `parent`, from the root of the filesystem.
+    async fn path_of(&self, parent: u64, name: &SecretString) -> FsResult<String> {
+        let mut names = vec![name.expose_secret().to_string()];
+        let mut ino = parent;
+        while ino != ROOT_INODE {
+            let up = self
+                .find_by_name(ino, &SecretString::from_str("..").unwrap())
+                .await?
+                .ok_or(FsError::InodeNotFound)?
+                .ino;
+            let entry = self
+                .dir_entries(up, 0)
+                .await?
+                .filter_map(Result::ok)
+                .find(|entry| entry.ino == ino && entry.kind == FileType::Directory)
+                .ok_or(FsError::InodeNotFound)?;
+            names.push(entry.name.expose_secret().to_string());
+            ino = up;
+        }
+        names.reverse();
+        Ok(format!("/{}", names.join("/")))
+    }
+
+    /// Get the holes of a file, shared with its opened readers and writers, or load them from storage.
+    async fn holes(&self, ino: u64) -> FsResult<SharedHoles> {
+        let mut guard = self.holes.lock().await;
+        if let Some(holes) = guard.get(&ino).and_then(Weak::upgrade) {
+            return Ok(holes);
+        }
+        let path = self.holes_file(ino);
+        let holes: Holes = if path.is_file() {
+            let attr = self.get_inode_from_cache_or_storage(ino).await?;
+            load_side_file(
+                &path,
+                &attr,
+                attr.side_files_generation..,
+                self.cipher,
+                &*self.key.get().await?,
+            )?
+        } else {
+            Holes::default()
+        };
+        let holes = Arc::new(std::sync::RwLock::new(holes));
+        guard.retain(|_, holes| holes.strong_count() > 0);
+        guard.insert(ino, Arc::downgrade(&holes));
+        Ok(holes)
+    }
+
+    /// Persist the holes, the write counters and the chunk references of a file, needs to be called after the writer
+    /// was finished. Then keep in the inode how far they were saved.
+    async fn save_holes(&self, ino: u64) -> FsResult<()> {
+        // durable with the counters, before it's kept in the inode
+        let binding = SideFileBinding::next(ino, &mut self.counters(ino).await?.write().unwrap());
+        let holes = self.holes(ino).await?.read().unwrap().clone();
+        save_holes_file(
+            &self.holes_file(ino),
+            binding,
+            &holes,
+            self.cipher,
+            &*self.key.get().await?,
+        )?;
+        let counters_synced = self.save_counters(ino).await?;
+        self.save_chunk_refs(ino, binding).await?;
+        self.set_saved(
+            ino,
+            counters_synced,
+            binding.map_or(0, |binding| binding.generation),
+        )
+        .await
+    }
+
+    /// Get the write counters of a file, shared with its opened readers and writers, or load them from storage.
+    async fn counters(&self, ino: u64) -> FsResult<SharedCounters> {
+        let mut guard = self.counters.lock().await;
+        if let Some(counters) = guard.get(&ino).and_then(Weak::upgrade) {
+            return Ok(counters);
+        }
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        let counters = self
+            .load_counters(&attr, &self.counters_file(ino), attr.counters_synced)
+            .await?;
+        let counters = Arc::new(std::sync::RwLock::new(counters));
+        guard.retain(|_, counters| counters.strong_count() > 0);
+        guard.insert(ino, Arc::downgrade(&counters));
+        Ok(counters)
+    }
+
+    /// Load the write counters of the file `attr` from `path`, they need to be of its id and synced up to `synced`.
+    ///
+    /// A file without an id has none, its blocks are bound to their index only.
+    async fn load_counters(
+        &self,
+        attr: &FileAttr,
+        path: &Path,
+        synced: u64,
+    ) -> FsResult<WriteCounters> {
+        let Some(file_id) = attr.file_id else {
+            return Ok(WriteCounters::legacy());
+        };
+        let key = self.key.get().await?;
+        let counters = match WriteCounters::load(path, attr.ino, self.cipher, key.clone()) {
+            Ok(counters) => counters,
+            // the first record doesn't open
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => None,
+            Err(err) => return Err(err.into()),
+        };
+        let Some(counters) = counters else {
+            if synced > 0 || path.exists() {
+                error!(ino = attr.ino, "write counters are missing");
+                return Err(FsError::Tampered("write counters are missing"));
+            }
+            return Ok(WriteCounters::new(
+                path,
+                attr.ino,
+                file_id,
+                self.cipher,
+                key,
+            ));
+        };
+        if counters.file_id() != Some(file_id) {
+            error!(ino = attr.ino, "write counters of another file");
+            return Err(FsError::Tampered("write counters of another file"));
+        }
+        if counters.synced() < synced {
+            error!(ino = attr.ino, "write counters are behind the inode");
+            return Err(FsError::Tampered("write counters are behind the inode"));
+        }
+        Ok(counters)
+    }
+
+    /// Make the write counters durable, the blocks they count are synced first. Returns how far they were synced, `0`
+    /// when there was nothing to sync.
+    async fn save_counters(&self, ino: u64) -> FsResult<u64> {
+        let counters = self.counters(ino).await?;
+        // the blocks written are the ones with a counter below it, the ones written while syncing stay unsynced
+        let Some(point) = counters.read().unwrap().sync_point() else {
+            return Ok(0);
+        };
+        let contents = self.contents_path(ino);
+        if contents.is_file() {
+            File::open(contents)?.sync_all()?;
+        }
+        // the log is synced without holding the counters, the blocks are written meanwhile
+        let pending = counters.write().unwrap().start_sync(point)?;
+        if let Err(err) = pending.sync_data() {
+            counters.write().unwrap().sync_failed();
+            return Err(err.into());
+        }
+        Ok(pending.point())
+    }
+
+    /// Give a new id to the file, or none, its write counters start over. Only for a file whose blocks are replaced.
+    async fn reset_file_id(&self, ino: u64, file_id: Option<u128>) -> FsResult<()> {
+        let serialize_update_lock = self
+            .serialize_update_inode_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _serialize_update_guard = serialize_update_lock.lock().await;
 
-                let mut writer = ctx.writer.take().unwrap();
-                let file = writer.finish()?;
-                file.sync_all()?;
-                File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
-                let handle = *handle;
-                let set_attr: SetFileAttr = ctx.attr.clone().into();
-                drop(ctx);
-                drop(opened_files_for_write_guard);
-                drop(write_handles_guard);
-                self.set_attr(ino, set_attr).await?;
-                self.reset_handles(ino, Some(handle), true).await?;
-                let write_handles_guard = self.write_handles.write().await;
-                let mut ctx = write_handles_guard.get(&handle).unwrap().lock().await;
-                let writer = self
-                    .create_write_seek(
-                        OpenOptions::new()
-                            .read(true)
-                            .write(true)
-                            .open(self.contents_path(ino))?,
-                    )
-                    .await?;
-                ctx.writer = Some(Box::new(writer));
-                let attr = self.get_inode_from_storage(ino).await?;
-                ctx.attr = attr.into();
-            }
+        let path = self.counters_file(ino);
+        if path.is_file() {
+            fs::remove_file(&path)?;
         }
-        Ok(())
+        let key = self.key.get().await?;
+        *self.counters(ino).await?.write().unwrap() = match file_id {
+            Some(file_id) => WriteCounters::new(&path, ino, file_id, self.cipher, key),
+            None => WriteCounters::legacy(),
+        };
+        let mut attr = self.get_inode_from_cache_or_storage(ino).await?;
+        attr.file_id = file_id;
+        attr.counters_synced = 0;
+        attr.side_files_generation = 0;
+        self.write_inode_to_storage(&attr).await
     }
 
-    #[allow(clippy::missing_panics_doc)]
-    pub async fn rename(
+    /// Keep in the inode that its write counters were synced up to `counters_synced` and the generation of its side
+    /// files, see [`FileAttr::counters_synced`] and [`FileAttr::side_files_generation`].
+    async fn set_saved(
         &self,
-        parent: u64,
-        name: &SecretString,
-        new_parent: u64,
-        new_name: &SecretString,
+        ino: u64,
+        counters_synced: u64,
+        side_files_generation: u64,
     ) -> FsResult<()> {
-        if self.read_only {
-            return Err(FsError::ReadOnly);
-        }
-        if !self.exists(parent) {
-            return Err(FsError::InodeNotFound);
-        }
-        if !self.is_dir(parent) {
-            return Err(FsError::InvalidInodeType);
-        }
-        if !self.exists(new_parent) {
-            return Err(FsError::InodeNotFound);
-        }
-        if !self.is_dir(new_parent) {
-            return Err(FsError::InvalidInodeType);
-        }
-        if !self.exists_by_name(parent, name)? {
-            return Err(FsError::NotFound("name not found"));
-        }
-        self.validate_filename(new_name)?;
+        let serialize_update_lock = self
+            .serialize_update_inode_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _serialize_update_guard = serialize_update_lock.lock().await;
 
-        if parent == new_parent && name.expose_secret() == new_name.expose_secret() {
-            // no-op
+        let mut attr = match self.get_inode_from_cache_or_storage(ino).await {
+            Ok(attr) => attr,
+            // removed while it was opened
+            Err(FsError::InodeNotFound) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        if attr.file_id.is_none()
+            || (attr.counters_synced >= counters_synced
+                && attr.side_files_generation >= side_files_generation)
+        {
             return Ok(());
         }
+        attr.counters_synced = attr.counters_synced.max(counters_synced);
+        attr.side_files_generation = attr.side_files_generation.max(side_files_generation);
+        self.write_inode_to_storage(&attr).await
+    }
 
-        // Only overwrite an existing directory if it's empty
-        if let Ok(Some(new_attr)) = self.find_by_name(new_parent, new_name).await {
-            if new_attr.kind == FileType::Directory && self.len(new_attr.ino)? > 0 {
-                return Err(FsError::NotEmpty);
-            }
-        }
-
-        let attr = self
-            .find_by_name(parent, name)
-            .await?
-            .ok_or(FsError::NotFound("name not found"))?;
-        // remove from parent contents
-        self.remove_directory_entry(parent, name).await?;
-        // remove from new_parent contents, if exists
-        if self.exists_by_name(new_parent, new_name)? {
-            self.remove_directory_entry(new_parent, new_name).await?;
+    /// Get the chunks of the blocks of a file, shared with its opened readers and writers, or load them from storage.
+    async fn chunk_refs(&self, ino: u64) -> FsResult<SharedChunkRefs> {
+        let mut guard = self.chunk_refs.lock().await;
+        if let Some(chunks) = guard.get(&ino).and_then(Weak::upgrade) {
+            return Ok(chunks);
         }
-        // add to new parent contents
-        self.insert_directory_entry(
-            new_parent,
-            &DirectoryEntry {
-                ino: attr.ino,
-                name: new_name.clone(),
-                kind: attr.kind,
-            },
-        )
-        .await?;
-
-        if attr.kind == FileType::Directory {
-            // add the parent link to the new directory
-            self.insert_directory_entry(
-                attr.ino,
-                &DirectoryEntry {
-                    ino: new_parent,
-                    name: SecretBox::new(Box::new("$..".to_owned())),
-                    kind: FileType::Directory,
-                },
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        let chunks = self
+            .load_chunk_refs(
+                &self.chunk_refs_file(ino),
+                &attr,
+                attr.side_files_generation..,
             )
             .await?;
+        let chunks = Arc::new(std::sync::RwLock::new(chunks));
+        guard.retain(|_, chunks| chunks.strong_count() > 0);
+        guard.insert(ino, Arc::downgrade(&chunks));
+        Ok(chunks)
+    }
+
+    /// Load the chunk references of the file `attr` from `path`, saved with one of the `generations`.
+    async fn load_chunk_refs(
+        &self,
+        path: &Path,
+        attr: &FileAttr,
+        generations: impl RangeBounds<u64>,
+    ) -> FsResult<ChunkRefs> {
+        let store = self.data_dir.join(CHUNKS_DIR);
+        if !path.is_file() {
+            return Ok(ChunkRefs::new(store));
         }
+        let chunks: ChunkRefs = load_side_file(
+            path,
+            attr,
+            generations,
+            self.cipher,
+            &*self.key.get().await?,
+        )?;
+        Ok(chunks.with_store(store))
+    }
 
-        let now = SystemTime::now();
-        let set_attr = SetFileAttr::default()
-            .with_mtime(now)
-            .with_ctime(now)
-            .with_atime(now);
-        self.set_attr(parent, set_attr).await?;
+    /// Persist the chunk references of a file, bound with `binding`, then free the chunks it doesn't reference
+    /// anymore.
+    async fn save_chunk_refs(&self, ino: u64, binding: Option<SideFileBinding>) -> FsResult<()> {
+        let chunks = self.chunk_refs(ino).await?;
+        let (snapshot, released) = {
+            let mut chunks = chunks.write().unwrap();
+            (chunks.clone(), chunks.take_released())
+        };
+        let path = self.chunk_refs_file(ino);
+        if snapshot.is_empty() {
+            remove_side_file(&path)?;
+        } else {
+            save_side_file(
+                &path,
+                binding,
+                &snapshot,
+                self.cipher,
+                &*self.key.get().await?,
+            )?;
+        }
+        self.chunk_store(ino).release(&released)?;
+        Ok(())
+    }
 
-        let set_attr = SetFileAttr::default()
-            .with_mtime(now)
-            .with_ctime(now)
-            .with_atime(now);
-        self.set_attr(new_parent, set_attr).await?;
+    /// The chunk store, with the references of a file.
+    fn chunk_store(&self, ino: u64) -> ChunkStore {
+        ChunkStore::new(
+            self.data_dir.join(CHUNKS_DIR),
+            self.data_dir
+                .join(CONTENTS_DIR)
+                .join(format!("{ino}.{REFS_EXT}")),
+            self.physical_size(),
+        )
+    }
 
-        let set_attr = SetFileAttr::default().with_ctime(now).with_atime(now);
-        self.set_attr(attr.ino, set_attr).await?;
+    /// The chunk store, with the references of a version of a file.
+    fn version_chunk_store(&self, ino: u64, id: u64) -> ChunkStore {
+        ChunkStore::new(
+            self.data_dir.join(CHUNKS_DIR),
+            self.versions_dir(ino).join(format!("{id}.{REFS_EXT}")),
+            self.physical_size(),
+        )
+    }
 
-        Ok(())
+    /// The space the content files and the chunk store take, counted while deduplication is on.
+    fn physical_size(&self) -> Option<Arc<PhysicalSize>> {
+        self.physical_size.lock().unwrap().clone()
     }
 
-    /// Create a crypto writer using internal encryption info.
-    pub async fn create_write<W: CryptoInnerWriter + Seek + Send + Sync + 'static>(
-        &self,
-        file: W,
-    ) -> FsResult<impl CryptoWrite<W>> {
-        Ok(crypto::create_write(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
+    /// Count what a change of the content files took and freed on disk, see [`PhysicalSize`].
+    fn count_allocated(&self, allocated: Allocated) -> FsResult<()> {
+        if let Some(size) = self.physical_size() {
+            size.apply(allocated)?;
+        }
+        Ok(())
     }
 
-    /// Create a crypto writer with seek using internal encryption info.
-    pub async fn create_write_seek<W: Write + Seek + Read + Send + Sync + 'static>(
-        &self,
-        file: W,
-    ) -> FsResult<impl CryptoWriteSeek<W>> {
-        Ok(crypto::create_write_seek(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
+    /// Copy the `blocks` of the content file shared with snapshots to them, before changing it in place.
+    fn preserve_blocks(&self, ino: u64, blocks: Range<u64>) -> FsResult<()> {
+        snapshot::preserve_blocks(
+            &self.data_dir.join(SNAPSHOTS_DIR),
+            &self.contents_path(ino),
+            blocks,
+            self.cipher.ciphertext_block_size() as u64,
+        )?;
+        Ok(())
     }
 
-    /// Create a crypto reader using internal encryption info.
-    pub async fn create_read<R: Read + Send + Sync>(
-        &self,
-        reader: R,
-    ) -> FsResult<impl CryptoRead<R>> {
-        Ok(crypto::create_read(
-            reader,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
+    /// Get the chunk cache of a file, shared with its opened writers, or create it.
+    async fn chunk_cache(&self, ino: u64) -> FsResult<Arc<ChunkCache>> {
+        let mut guard = self.chunk_caches.lock().await;
+        if let Some(cache) = guard.get(&ino).and_then(Weak::upgrade) {
+            return Ok(cache);
+        }
+        let cache = Arc::new(
+            ChunkCache::new(
+                self.contents_path(ino),
+                &self.data_dir.join(SNAPSHOTS_DIR),
+                self.cipher,
+                self.holes(ino).await?,
+                self.chunk_refs(ino).await?,
+                self.block_dedup().then(|| self.chunk_store(ino)),
+                self.get_inode_from_cache_or_storage(ino).await?.size,
+            )
+            .with_holes_file(self.holes_file(ino), ino)
+            .with_counters(self.counters(ino).await?)
+            .with_compression(self.compression()),
+        );
+        guard.retain(|_, cache| cache.strong_count() > 0);
+        guard.insert(ino, Arc::downgrade(&cache));
+        Ok(cache)
     }
 
-    /// Create a crypto reader with seek using internal encryption info.
-    pub async fn create_read_seek<R: Read + Seek + Send + Sync>(
-        &self,
-        reader: R,
-    ) -> FsResult<impl CryptoReadSeek<R>> {
-        Ok(crypto::create_read_seek(
-            reader,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
+    /// Get the chunk cache of a file if it's opened for write.
+    async fn opened_chunk_cache(&self, ino: u64) -> Option<Arc<ChunkCache>> {
+        self.chunk_caches
+            .lock()
+            .await
+            .get(&ino)
+            .and_then(Weak::upgrade)
     }
 
     /// Change the password of the filesystem used to access the encryption key.
@@ -2165,8 +5111,9 @@ impl EncryptedFs {
 
     /// Reset all handles for a file.
     /// Read handles will be recreated.
-    /// Write handles will be flushed and recreated.
-    /// Timestamps and size will be updated to storage.
+    /// Write handles will be flushed and their attributes reloaded.
+    /// Timestamps and size will be updated to storage, unless `save_attr` is `false`
+    /// in which case the size is taken from storage.
     /// > ⚠️ **Warning**
     /// > Need to be called in a context with write lock on `self.read_write_inode.lock().await.get(ino)`.
     /// > That is because we want to make sure caller is holding a lock while all writers flush, and we can't
@@ -2177,58 +5124,63 @@ impl EncryptedFs {
         skip_write_fh: Option<u64>,
         save_attr: bool,
     ) -> FsResult<()> {
-        let path = self.contents_path(ino);
-
         // read
         let lock = self.opened_files_for_read.read().await;
         if let Some(set) = lock.get(&ino) {
             for handle in set.iter().filter(|h| skip_write_fh != Some(**h)) {
                 let guard = self.read_handles.read().await;
                 let ctx = guard.get(handle).unwrap().lock().await;
-                let set_attr: SetFileAttr = ctx.attr.clone().into();
+                let atime = ctx.atime_changed.then_some(ctx.attr.atime);
                 drop(ctx);
-                self.set_attr(ino, set_attr).await?;
+                if let Some(atime) = atime {
+                    self.save_atime(ino, atime).await?;
+                }
                 let attr = self.get_inode_from_storage(ino).await?;
                 let mut ctx = guard.get(handle).unwrap().lock().await;
-                let reader = self.create_read_seek(File::open(&path)?).await?;
+                let reader = self.create_content_read_seek(ino).await?;
                 ctx.reader = Some(Box::new(reader));
                 ctx.attr = attr.into();
+                ctx.atime_changed = false;
             }
         }
+        drop(lock);
 
         // write
-        let lock = self.opened_files_for_write.read().await;
-        if let Some(fh) = lock.get(&ino) {
-            if let Some(handle) = skip_write_fh {
-                if *fh == handle {
-                    return Ok(());
-                }
+        let Some(cache) = self.opened_chunk_cache(ino).await else {
+            return Ok(());
+        };
+        cache.flush(&*self.key.get().await?)?;
+        File::open(self.contents_path(ino))?.sync_all()?;
+        File::open(self.contents_path(ino).parent().unwrap())?.sync_all()?;
+        self.save_holes(ino).await?;
+        let fhs = self.opened_files_for_write.read().await.get(&ino).cloned();
+        for fh in fhs.unwrap_or_default() {
+            if skip_write_fh == Some(fh) {
+                continue;
             }
             let lock = self.write_handles.read().await;
-            if let Some(lock) = lock.get(fh) {
-                let mut ctx = lock.lock().await;
-                let writer = ctx.writer.as_mut().unwrap();
-                let file = writer.finish()?;
-                file.sync_all()?;
-                File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+            if let Some(lock) = lock.get(&fh) {
+                let ctx = lock.lock().await;
                 let set_attr: Option<SetFileAttr> = if save_attr {
-                    Some(ctx.attr.clone().into())
+                    let mut attr = ctx.attr.clone();
+                    attr.size = cache.size();
+                    Some(attr.into())
                 } else {
                     None
                 };
                 drop(ctx);
                 if let Some(set_attr) = set_attr {
-                    self.set_attr(ino, set_attr).await?;
+                    self.set_attr_inner(ino, set_attr).await?;
                 }
-                let writer = self
-                    .create_write_seek(OpenOptions::new().read(true).write(true).open(&path)?)
-                    .await?;
-                let mut ctx = lock.lock().await;
-                ctx.writer = Some(Box::new(writer));
                 let attr = self.get_inode_from_storage(ino).await?;
+                let mut ctx = lock.lock().await;
                 ctx.attr = attr.into();
             }
         }
+        if !save_attr {
+            // the file was changed outside the writers
+            cache.set_size(self.get_inode_from_storage(ino).await?.size);
+        }
 
         Ok(())
     }
@@ -2239,16 +5191,16 @@ impl EncryptedFs {
         op: ReadHandleContextOperation,
     ) -> FsResult<()> {
         let ino = op.get_ino();
-        let path = self.contents_path(ino);
         let attr = self.get_inode_from_storage(ino).await?;
         match op {
             ReadHandleContextOperation::Create { ino } => {
                 let attr: TimesFileAttr = attr.into();
-                let reader = self.create_read_seek(File::open(&path)?).await?;
+                let reader = self.create_content_read_seek(ino).await?;
                 let ctx = ReadHandleContext {
                     ino,
                     attr,
                     reader: Some(Box::new(reader)),
+                    atime_changed: false,
                 };
                 self.read_handles
                     .write()
@@ -2271,17 +5223,15 @@ impl EncryptedFs {
         op: WriteHandleContextOperation,
     ) -> FsResult<()> {
         let ino = op.get_ino();
-        let path = self.contents_path(ino);
         match op {
-            WriteHandleContextOperation::Create { ino } => {
+            WriteHandleContextOperation::Create { flags, .. } => {
                 let attr = self.get_attr(ino).await?.into();
-                let writer = self
-                    .create_write_seek(OpenOptions::new().read(true).write(true).open(&path)?)
-                    .await?;
+                let cache = self.chunk_cache(ino).await?;
                 let ctx = WriteHandleContext {
                     ino,
                     attr,
-                    writer: Some(Box::new(writer)),
+                    cache,
+                    flags,
                 };
                 self.write_handles
                     .write()
@@ -2290,7 +5240,9 @@ impl EncryptedFs {
                 self.opened_files_for_write
                     .write()
                     .await
-                    .insert(ino, handle);
+                    .entry(ino)
+                    .or_insert_with(HashSet::new)
+                    .insert(handle);
             }
         }
 
@@ -2341,6 +5293,21 @@ impl EncryptedFs {
         &self,
         ino_contents_dir: u64,
         entry: &DirectoryEntry,
+    ) -> FsResult<()> {
+        let lock = self
+            .serialize_dir_entries_hash_locks
+            .get_or_insert_with(self.dir_entry_key(ino_contents_dir, &entry.name), || {
+                RwLock::new(false)
+            });
+        let _guard = lock.write().await;
+        self.write_directory_entry(ino_contents_dir, entry).await
+    }
+
+    /// Like [`EncryptedFs::insert_directory_entry`], the caller holds the lock of the entry.
+    async fn write_directory_entry(
+        &self,
+        ino_contents_dir: u64,
+        entry: &DirectoryEntry,
     ) -> FsResult<()> {
         let parent_path = self.contents_path(ino_contents_dir);
         let encrypted_name =
@@ -2359,15 +5326,25 @@ impl EncryptedFs {
         let entry_clone = entry.clone();
         // spawn a task to do concurrently with adding to HASH directory
         let h = tokio::spawn(async move {
-            let file_path = parent_path_clone
-                .join(LS_DIR)
-                .join(encrypted_name_clone.clone());
+            let ls_name = ls_entry_name(&encrypted_name_clone);
+            let file_path = parent_path_clone.join(LS_DIR).join(&ls_name);
             let lock = self_clone
                 .serialize_dir_entries_ls_locks
                 .get_or_insert_with(file_path.to_str().unwrap().to_owned(), || {
                     RwLock::new(false)
                 });
             let _guard = lock.write().await;
+            if ls_name != encrypted_name_clone {
+                // before the entry, so listing never finds one without its name
+                let long_names_dir = parent_path_clone.join(LONG_NAMES_DIR);
+                fs::create_dir_all(&long_names_dir)?;
+                crypto::atomic_serialize_encrypt_into(
+                    &long_names_dir.join(&ls_name),
+                    &encrypted_name_clone,
+                    self_clone.cipher,
+                    &*self_clone.key.get().await?,
+                )?;
+            }
             // write inode and file type
             let entry = (entry_clone.ino, entry_clone.kind);
             crypto::atomic_serialize_encrypt_into(
@@ -2391,12 +5368,6 @@ impl EncryptedFs {
         tokio::spawn(async move {
             let name = crypto::hash_file_name(&entry_hash.name);
             let file_path = parent_path.join(HASH_DIR).join(name);
-            let lock = self_clone
-                .serialize_dir_entries_hash_locks
-                .get_or_insert_with(file_path.to_str().unwrap().to_owned(), || {
-                    RwLock::new(false)
-                });
-            let _guard = lock.write().await;
             // write inode and file type
             // we save the encrypted name also because we need it to remove the entry on [`remove_directory_entry`]
             let entry = (entry_hash.ino, entry_hash.kind, encrypted_name);
@@ -2417,19 +5388,46 @@ impl EncryptedFs {
         self.data_dir.join(INODES_DIR).join(ino.to_string())
     }
 
+    fn xattr_file(&self, ino: u64) -> PathBuf {
+        self.data_dir
+            .join(INODES_DIR)
+            .join(format!("{ino}.{XATTR_EXT}"))
+    }
+
+    fn holes_file(&self, ino: u64) -> PathBuf {
+        self.data_dir
+            .join(INODES_DIR)
+            .join(format!("{ino}.{HOLES_EXT}"))
+    }
+
+    fn counters_file(&self, ino: u64) -> PathBuf {
+        self.data_dir
+            .join(INODES_DIR)
+            .join(format!("{ino}.{COUNTERS_EXT}"))
+    }
+
+    fn chunk_refs_file(&self, ino: u64) -> PathBuf {
+        self.data_dir
+            .join(INODES_DIR)
+            .join(format!("{ino}.{CHUNKS_EXT}"))
+    }
+
+    fn versions_dir(&self, ino: u64) -> PathBuf {
+        self.data_dir
+            .join(INODES_DIR)
+            .join(format!("{ino}.{VERSIONS_EXT}"))
+    }
+
     fn contents_path(&self, ino: u64) -> PathBuf {
         self.data_dir.join(CONTENTS_DIR).join(ino.to_string())
     }
 
+    /// The caller holds the lock of the entry, see [`EncryptedFs::dir_entry_key`].
     async fn remove_directory_entry(&self, parent: u64, name: &SecretString) -> FsResult<()> {
         let parent_path = self.contents_path(parent);
         // remove from HASH
         let name = crypto::hash_file_name(name);
         let path = parent_path.join(HASH_DIR).join(name);
-        let lock = self
-            .serialize_dir_entries_hash_locks
-            .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
-        let guard = lock.write().await;
         let (_, _, name): (u64, FileType, String) =
             bincode::deserialize_from(crypto::create_read(
                 File::open(path.clone())?,
@@ -2437,14 +5435,50 @@ impl EncryptedFs {
                 &*self.key.get().await?,
             ))?;
         fs::remove_file(path)?;
-        drop(guard);
         // remove from LS
-        let path = parent_path.join(LS_DIR).join(name);
+        let ls_name = ls_entry_name(&name);
+        let path = parent_path.join(LS_DIR).join(&ls_name);
         let lock = self
             .serialize_dir_entries_ls_locks
             .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
         let _guard = lock.write().await;
         fs::remove_file(path)?;
+        if ls_name != name {
+            fs::remove_file(parent_path.join(LONG_NAMES_DIR).join(ls_name))?;
+        }
+        Ok(())
+    }
+
+    /// Point the existing entry `name` in `parent` to another inode, the caller holds the lock of the entry.
+    ///
+    /// Its files are replaced, not removed, so the entry is always found.
+    async fn replace_directory_entry(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        encrypted_name: &str,
+        ino: u64,
+        kind: FileType,
+    ) -> FsResult<()> {
+        let parent_path = self.contents_path(parent);
+        let key = self.key.get().await?;
+        // HASH
+        let path = parent_path
+            .join(HASH_DIR)
+            .join(crypto::hash_file_name(name));
+        crypto::atomic_serialize_encrypt_into(
+            &path,
+            &(ino, kind, encrypted_name.to_owned()),
+            self.cipher,
+            &key,
+        )?;
+        // LS
+        let path = parent_path.join(LS_DIR).join(ls_entry_name(encrypted_name));
+        let lock = self
+            .serialize_dir_entries_ls_locks
+            .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
+        let _guard = lock.write().await;
+        crypto::atomic_serialize_encrypt_into(&path, &(ino, kind), self.cipher, &key)?;
         Ok(())
     }
 
@@ -2568,6 +5602,103 @@ async fn ensure_structure_created(data_dir: &PathBuf) -> FsResult<()> {
     Ok(())
 }
 
+/// What the side files of a file, its holes and its chunk references, are bound to. So they are not taken from
+/// another file, or from an older state of the file, the inode keeps the generation of the last ones saved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SideFileBinding {
+    ino: u64,
+    file_id: u128,
+    generation: u64,
+}
+
+impl SideFileBinding {
+    /// Of the side files of `ino` saved now, with a new generation from its write counters. [None] for a file without
+    /// an id, they are not bound.
+    pub(crate) fn next(ino: u64, counters: &mut WriteCounters) -> Option<Self> {
+        let file_id = counters.file_id()?;
+        Some(Self {
+            ino,
+            file_id,
+            generation: counters.next_generation(),
+        })
+    }
+
+    /// Of the side files of the version `id` of `ino`.
+    fn version(ino: u64, counters: &WriteCounters, id: u64) -> Option<Self> {
+        counters.file_id().map(|file_id| Self {
+            ino,
+            file_id,
+            generation: id,
+        })
+    }
+}
+
+/// Write a side file of a file to `path`, bound with `binding`.
+fn save_side_file<T: Serialize>(
+    path: &Path,
+    binding: Option<SideFileBinding>,
+    value: &T,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    match binding {
+        Some(binding) => {
+            crypto::atomic_serialize_encrypt_into(path, &(binding, value), cipher, key)?
+        }
+        None => crypto::atomic_serialize_encrypt_into(path, value, cipher, key)?,
+    }
+    Ok(())
+}
+
+/// Remove a side file of a file, durably so an older one doesn't come back after a crash.
+fn remove_side_file(path: &Path) -> FsResult<()> {
+    if path.is_file() {
+        fs::remove_file(path)?;
+        fs_util::sync_dir(path.parent().unwrap())?;
+    }
+    Ok(())
+}
+
+/// Read a side file of the file `attr` from `path`, saved with one of the `generations`. A file without an id has
+/// them not bound.
+fn load_side_file<T: DeserializeOwned>(
+    path: &Path,
+    attr: &FileAttr,
+    generations: impl RangeBounds<u64>,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<T> {
+    let reader = crypto::create_read(File::open(path)?, cipher, key);
+    let Some(file_id) = attr.file_id else {
+        return Ok(bincode::deserialize_from(reader)?);
+    };
+    let (binding, value): (SideFileBinding, T) = bincode::deserialize_from(reader)?;
+    if binding.ino != attr.ino || binding.file_id != file_id {
+        error!(ino = attr.ino, path = %path.display(), "side file of another file");
+        return Err(FsError::Tampered("side file of another file"));
+    }
+    if !generations.contains(&binding.generation) {
+        error!(ino = attr.ino, path = %path.display(), "side file of another generation");
+        return Err(FsError::Tampered("side file of another generation"));
+    }
+    Ok(value)
+}
+
+/// Write the holes of a file to `path`, bound with `binding`, or remove it when there are none.
+pub(crate) fn save_holes_file(
+    path: &Path,
+    binding: Option<SideFileBinding>,
+    holes: &Holes,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    if holes.is_empty() {
+        remove_side_file(path)
+    } else {
+        save_side_file(path, binding, holes, cipher, key)
+    }
+}
+
 async fn check_structure(data_dir: &Path, ignore_empty: bool) -> FsResult<()> {
     if !data_dir.exists() || !data_dir.is_dir() {
         return Err(FsError::InvalidDataDirStructure);
@@ -2581,6 +5712,8 @@ async fn check_structure(data_dir: &Path, ignore_empty: bool) -> FsResult<()> {
     if vec.is_empty() && ignore_empty {
         return Ok(());
     }
+    // created only when needed, a snapshot has the blocks changed since it was taken
+    vec.retain(|dir| ![TRASH_DIR, SNAPSHOTS_DIR, COW_DIR, CHUNKS_DIR].contains(&dir.as_str()));
     if vec.len() != 3 {
         return Err(FsError::InvalidDataDirStructure);
     }
@@ -2655,7 +5788,7 @@ pub async fn write_all_bytes_to_fs(
 ) -> FsResult<()> {
     let mut pos = 0_usize;
     loop {
-        let len = fs.write(ino, offset, &buf[pos..], fh).await?;
+        let len = fs.write(ino, offset + pos as u64, &buf[pos..], fh).await?;
         pos += len;
         if pos == buf.len() {
             break;
@@ -0,0 +1,243 @@
+//! POSIX access control lists.
+//!
+//! They are kept as extended attributes in the same binary format used by the Linux kernel
+//! (`posix_acl_xattr_header` followed by `posix_acl_xattr_entry`s), so tools like `setfacl` and `getfacl` work unchanged.
+
+use crate::encryptedfs::{FsError, FsResult};
+
+/// Name of the extended attribute holding the access ACL.
+pub const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+/// Name of the extended attribute holding the default ACL of a directory, inherited by new entries.
+pub const ACL_DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+const ACL_XATTR_VERSION: u32 = 2;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+const HEADER_LEN: usize = 4;
+const ENTRY_LEN: usize = 8;
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+/// Who an [`AclEntry`] applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclTag {
+    /// The owner of the file
+    UserObj,
+    /// A specific user
+    User(u32),
+    /// The owning group of the file
+    GroupObj,
+    /// A specific group
+    Group(u32),
+    /// Upper bound of the permissions granted by `User`, `GroupObj` and `Group` entries
+    Mask,
+    /// Everybody else
+    Other,
+}
+
+/// A single ACL entry, `perm` holds the `rwx` bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    pub perm: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl {
+    pub entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    /// Parse the extended attribute value.
+    ///
+    /// It will return an error of type [`FsError::InvalidInput`] if the value is malformed
+    /// or doesn't have exactly one `UserObj`, `GroupObj` and `Other` entry, and a `Mask` when needed.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::missing_panics_doc)]
+    pub fn from_bytes(value: &[u8]) -> FsResult<Self> {
+        if value.len() < HEADER_LEN
+            || !value[HEADER_LEN..]
+                .chunks_exact(ENTRY_LEN)
+                .remainder()
+                .is_empty()
+        {
+            return Err(FsError::InvalidInput("invalid ACL size"));
+        }
+        if u32::from_le_bytes(value[..HEADER_LEN].try_into().unwrap()) != ACL_XATTR_VERSION {
+            return Err(FsError::InvalidInput("unsupported ACL version"));
+        }
+        let mut entries = vec![];
+        for chunk in value[HEADER_LEN..].chunks_exact(ENTRY_LEN) {
+            let tag = u16::from_le_bytes(chunk[0..2].try_into().unwrap());
+            let perm = u16::from_le_bytes(chunk[2..4].try_into().unwrap());
+            let id = u32::from_le_bytes(chunk[4..8].try_into().unwrap());
+            let tag = match tag {
+                ACL_USER_OBJ => AclTag::UserObj,
+                ACL_USER => AclTag::User(id),
+                ACL_GROUP_OBJ => AclTag::GroupObj,
+                ACL_GROUP => AclTag::Group(id),
+                ACL_MASK => AclTag::Mask,
+                ACL_OTHER => AclTag::Other,
+                _ => return Err(FsError::InvalidInput("invalid ACL tag")),
+            };
+            if perm & !0o7 != 0 {
+                return Err(FsError::InvalidInput("invalid ACL permissions"));
+            }
+            entries.push(AclEntry { tag, perm });
+        }
+        let acl = Self { entries };
+        acl.validate()?;
+        Ok(acl)
+    }
+
+    /// Serialize into the extended attribute value.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut value = Vec::with_capacity(HEADER_LEN + self.entries.len() * ENTRY_LEN);
+        value.extend_from_slice(&ACL_XATTR_VERSION.to_le_bytes());
+        for entry in &self.entries {
+            let (tag, id) = match entry.tag {
+                AclTag::UserObj => (ACL_USER_OBJ, ACL_UNDEFINED_ID),
+                AclTag::User(id) => (ACL_USER, id),
+                AclTag::GroupObj => (ACL_GROUP_OBJ, ACL_UNDEFINED_ID),
+                AclTag::Group(id) => (ACL_GROUP, id),
+                AclTag::Mask => (ACL_MASK, ACL_UNDEFINED_ID),
+                AclTag::Other => (ACL_OTHER, ACL_UNDEFINED_ID),
+            };
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&entry.perm.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+        value
+    }
+
+    /// Permission bits of the file mode matching this ACL, special bits from `perm` are kept.
+    #[must_use]
+    pub fn mode(&self, perm: u16) -> u16 {
+        let mut perm = perm & !0o777;
+        let mut group = None;
+        for entry in &self.entries {
+            match entry.tag {
+                AclTag::UserObj => perm |= entry.perm << 6,
+                AclTag::Mask => group = Some(entry.perm),
+                AclTag::GroupObj if group.is_none() => group = Some(entry.perm),
+                AclTag::Other => perm |= entry.perm,
+                _ => {}
+            }
+        }
+        perm | (group.unwrap_or(0) << 3)
+    }
+
+    /// Update the entries that mirror the file mode after a `chmod`.
+    #[must_use]
+    pub fn with_mode(mut self, perm: u16) -> Self {
+        let has_mask = self.has_mask();
+        for entry in &mut self.entries {
+            match entry.tag {
+                AclTag::UserObj => entry.perm = (perm >> 6) & 0o7,
+                AclTag::Mask => entry.perm = (perm >> 3) & 0o7,
+                AclTag::GroupObj if !has_mask => entry.perm = (perm >> 3) & 0o7,
+                AclTag::Other => entry.perm = perm & 0o7,
+                _ => {}
+            }
+        }
+        self
+    }
+
+    /// Create the access ACL of a new entry from this default ACL of its parent and the requested mode.
+    ///
+    /// Returns the new ACL and the resulting mode.
+    #[must_use]
+    pub fn inherit(&self, perm: u16) -> (Self, u16) {
+        let has_mask = self.has_mask();
+        let mut acl = self.clone();
+        for entry in &mut acl.entries {
+            match entry.tag {
+                AclTag::UserObj => entry.perm &= (perm >> 6) & 0o7,
+                AclTag::Mask => entry.perm &= (perm >> 3) & 0o7,
+                AclTag::GroupObj if !has_mask => entry.perm &= (perm >> 3) & 0o7,
+                AclTag::Other => entry.perm &= perm & 0o7,
+                _ => {}
+            }
+        }
+        let perm = acl.mode(perm);
+        (acl, perm)
+    }
+
+    /// Check if `uid`, member of `groups`, is granted `access_mask` (`R_OK`, `W_OK`, `X_OK`).
+    ///
+    /// This follows the POSIX access check algorithm, root is expected to be handled by the caller.
+    #[must_use]
+    pub fn check(
+        &self,
+        #[allow(clippy::similar_names)] file_uid: u32,
+        #[allow(clippy::similar_names)] file_gid: u32,
+        uid: u32,
+        groups: &[u32],
+        access_mask: i32,
+    ) -> bool {
+        #[allow(clippy::cast_possible_truncation)]
+        #[allow(clippy::cast_sign_loss)]
+        let requested = (access_mask & 0o7) as u16;
+        let mask = self
+            .entries
+            .iter()
+            .find(|e| e.tag == AclTag::Mask)
+            .map_or(0o7, |e| e.perm);
+        let granted = |perm: u16| perm & requested == requested;
+
+        if uid == file_uid {
+            return self
+                .entries
+                .iter()
+                .any(|e| e.tag == AclTag::UserObj && granted(e.perm));
+        }
+        if let Some(entry) = self.entries.iter().find(|e| e.tag == AclTag::User(uid)) {
+            return granted(entry.perm & mask);
+        }
+        let mut group_matched = false;
+        for entry in &self.entries {
+            let matches = match entry.tag {
+                AclTag::GroupObj => groups.contains(&file_gid),
+                AclTag::Group(gid) => groups.contains(&gid),
+                _ => false,
+            };
+            if matches {
+                if granted(entry.perm & mask) {
+                    return true;
+                }
+                group_matched = true;
+            }
+        }
+        if group_matched {
+            return false;
+        }
+        self.entries
+            .iter()
+            .any(|e| e.tag == AclTag::Other && granted(e.perm))
+    }
+
+    fn has_mask(&self) -> bool {
+        self.entries.iter().any(|e| e.tag == AclTag::Mask)
+    }
+
+    fn validate(&self) -> FsResult<()> {
+        let count = |tag: AclTag| self.entries.iter().filter(|e| e.tag == tag).count();
+        if count(AclTag::UserObj) != 1 || count(AclTag::GroupObj) != 1 || count(AclTag::Other) != 1
+        {
+            return Err(FsError::InvalidInput("ACL misses required entries"));
+        }
+        let named = self
+            .entries
+            .iter()
+            .any(|e| matches!(e.tag, AclTag::User(_) | AclTag::Group(_)));
+        match count(AclTag::Mask) {
+            0 if named => Err(FsError::InvalidInput("ACL misses mask entry")),
+            0 | 1 => Ok(()),
+            _ => Err(FsError::InvalidInput("ACL has duplicate mask entries")),
+        }
+    }
+}
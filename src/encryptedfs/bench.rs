@@ -9,7 +9,7 @@ use rand::Rng;
 use shush_rs::SecretString;
 
 #[allow(unused_imports)]
-use crate::encryptedfs::{DirectoryEntry, DirectoryEntryPlus, FileType, ROOT_INODE};
+use crate::encryptedfs::{DirectoryEntry, DirectoryEntryPlus, FileType, OpenFlags, ROOT_INODE};
 #[allow(unused_imports)]
 use crate::test_common::{create_attr, get_fs};
 #[allow(unused_imports)]
@@ -31,8 +31,7 @@ fn bench_create(b: &mut Bencher) {
                             ROOT_INODE,
                             &test_file,
                             create_attr(FileType::RegularFile),
-                            false,
-                            false,
+                            OpenFlags::new(false, false),
                         )
                         .await
                         .unwrap();
@@ -77,8 +76,7 @@ fn bench_find_by_name(b: &mut Bencher) {
                     ROOT_INODE,
                     &test_file,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -114,8 +112,7 @@ fn bench_read_dir(b: &mut Bencher) {
                     ROOT_INODE,
                     &test_file,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -144,8 +141,7 @@ fn bench_read_dir_plus(b: &mut Bencher) {
                     ROOT_INODE,
                     &test_file,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
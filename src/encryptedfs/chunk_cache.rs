@@ -0,0 +1,462 @@
+//! Plaintext of the chunks being written, shared by all the write handles of a file.
+//!
+//! A chunk is a block of the content file. Each one has its own lock, so writes to different chunks
+//! proceed in parallel while writes to the same chunk are serialized. Chunks are encrypted and written
+//! to the content file when a write reaches their end, or on [`ChunkCache::flush`]. With deduplication
+//! on they go to the chunk store instead, see [`crate::encryptedfs::dedup`].
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use shush_rs::SecretVec;
+
+use crate::crypto;
+use crate::crypto::chunks::SharedChunkRefs;
+use crate::crypto::compress::{self, Compression};
+use crate::crypto::counters::{BlockAad, SharedCounters};
+use crate::crypto::holes::SharedHoles;
+use crate::crypto::write::BLOCK_SIZE;
+use crate::crypto::Cipher;
+use crate::encryptedfs::content_file::{read_block, ContentFile};
+use crate::encryptedfs::dedup::ChunkStore;
+use crate::encryptedfs::{save_holes_file, snapshot, SideFileBinding};
+
+/// After this many chunks in memory all of them are flushed.
+const MAX_CHUNKS: usize = 64;
+
+#[derive(Default)]
+struct Chunk {
+    // plaintext, `None` until loaded from the content file
+    data: Option<Vec<u8>>,
+    dirty: bool,
+    // removed from the cache after it was written, writers waiting for it need to get it again
+    evicted: bool,
+}
+
+pub(crate) struct ChunkCache {
+    path: PathBuf,
+    // chunks shared with snapshots are copied to them before being overwritten
+    snapshots_dir: PathBuf,
+    cipher: Cipher,
+    holes: SharedHoles,
+    // where the holes of the file are saved, before the content file grows after them
+    holes_file: Option<(PathBuf, u64)>,
+    // holes were added since they were saved
+    holes_changed: AtomicBool,
+    chunk_refs: SharedChunkRefs,
+    // set while deduplication is on, the chunks are written there
+    store: Option<ChunkStore>,
+    counters: Option<SharedCounters>,
+    compression: Option<Compression>,
+    // size of the file, including the chunks not written yet
+    size: Mutex<u64>,
+    chunks: Mutex<HashMap<u64, Arc<Mutex<Chunk>>>>,
+}
+
+impl ChunkCache {
+    pub fn new(
+        path: PathBuf,
+        snapshots_dir: &Path,
+        cipher: Cipher,
+        holes: SharedHoles,
+        chunk_refs: SharedChunkRefs,
+        store: Option<ChunkStore>,
+        size: u64,
+    ) -> Self {
+        Self {
+            path,
+            snapshots_dir: snapshots_dir.to_path_buf(),
+            cipher,
+            holes,
+            holes_file: None,
+            holes_changed: AtomicBool::new(false),
+            chunk_refs,
+            store,
+            counters: None,
+            compression: None,
+            size: Mutex::new(size),
+            chunks: Mutex::default(),
+        }
+    }
+
+    /// Save the holes of the file `ino` to `path` before writing a chunk after them, so the gap is never read as
+    /// blocks. They are bound to the file with its [counters](Self::with_counters).
+    pub fn with_holes_file(mut self, path: PathBuf, ino: u64) -> Self {
+        self.holes_file = Some((path, ino));
+        self
+    }
+
+    /// Count the chunks written in `counters`, see [`crate::crypto::counters::WriteCounters`].
+    pub fn with_counters(mut self, counters: SharedCounters) -> Self {
+        self.counters = Some(counters);
+        self
+    }
+
+    /// Compress the chunks before they are encrypted, see [`crate::crypto::compress`].
+    pub const fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn size(&self) -> u64 {
+        *self.size.lock().unwrap()
+    }
+
+    /// The file was changed without the cache, like when truncating. Needs to be called after [`Self::flush`].
+    pub fn set_size(&self, size: u64) {
+        *self.size.lock().unwrap() = size;
+    }
+
+    /// Write `buf` at `offset`.
+    ///
+    /// If we write after the end, the last chunk is filled up with zeros and the whole chunks until `offset`
+    /// are kept as holes.
+    pub fn write(&self, offset: u64, buf: &[u8], key: &SecretVec<u8>) -> io::Result<()> {
+        self.write_at(Some(offset), buf, key).map(|_| ())
+    }
+
+    /// Write `buf` at the end, returning the offset it was written at.
+    ///
+    /// The range is reserved before writing, so appends from several writers don't overlap.
+    pub fn append(&self, buf: &[u8], key: &SecretVec<u8>) -> io::Result<u64> {
+        self.write_at(None, buf, key)
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn write_at(&self, offset: Option<u64>, buf: &[u8], key: &SecretVec<u8>) -> io::Result<u64> {
+        let plaintext_block_size = BLOCK_SIZE as u64;
+        let offset = self.extend(offset, buf.len() as u64, key)?;
+        let end = offset + buf.len() as u64;
+        let mut pos = offset;
+        while pos < end {
+            let block_index = pos / plaintext_block_size;
+            let start = (pos - block_index * plaintext_block_size) as usize;
+            let len = ((end - pos) as usize).min(BLOCK_SIZE - start);
+            let src = &buf[(pos - offset) as usize..][..len];
+            self.update_chunk(block_index, key, |data| {
+                if data.len() < start + len {
+                    data.resize(start + len, 0);
+                }
+                data[start..start + len].copy_from_slice(src);
+                // most writes are sequential, we won't get back to it soon
+                start + len == BLOCK_SIZE
+            })?;
+            pos += len as u64;
+        }
+        if self.chunks.lock().unwrap().len() > MAX_CHUNKS {
+            self.flush(key)?;
+        }
+        Ok(offset)
+    }
+
+    /// Read into `buf` from `offset`, returning how much was read.
+    ///
+    /// Chunks not written yet are read from the cache, the others from the content file. Chunks loaded only
+    /// for reading are not kept.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn read(&self, offset: u64, buf: &mut [u8], key: &SecretVec<u8>) -> io::Result<usize> {
+        let plaintext_block_size = BLOCK_SIZE as u64;
+        let end = self.size().min(offset + buf.len() as u64);
+        let mut pos = offset;
+        while pos < end {
+            let block_index = pos / plaintext_block_size;
+            let start = (pos - block_index * plaintext_block_size) as usize;
+            let len = ((end - pos) as usize).min(BLOCK_SIZE - start);
+            let dst = &mut buf[(pos - offset) as usize..][..len];
+            self.read_chunk(block_index, key, |data| {
+                // the last chunk can be shorter than the size while it's being extended
+                let available = data.len().saturating_sub(start).min(len);
+                dst[..available].copy_from_slice(&data[start..start + available]);
+                dst[available..].fill(0);
+            })?;
+            pos += len as u64;
+        }
+        Ok(end.saturating_sub(offset) as usize)
+    }
+
+    /// Write the changed chunks to the content file and empty the cache.
+    ///
+    /// Returns `true` if anything was written.
+    pub fn flush(&self, key: &SecretVec<u8>) -> io::Result<bool> {
+        let mut chunks: Vec<(u64, Arc<Mutex<Chunk>>)> = self
+            .chunks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(block_index, chunk)| (*block_index, chunk.clone()))
+            .collect();
+        chunks.sort_by_key(|(block_index, _)| *block_index);
+        let mut written = false;
+        for (block_index, lock) in chunks {
+            let mut chunk = lock.lock().unwrap();
+            if chunk.evicted {
+                continue;
+            }
+            if chunk.dirty {
+                self.write_chunk(block_index, &chunk, key)?;
+                written = true;
+            }
+            self.evict(block_index, &lock, &mut chunk);
+        }
+        Ok(written)
+    }
+
+    /// Grow the size if needed, before writing `len` bytes at `offset`, or at the end if it's [None].
+    ///
+    /// Returns the offset to write at.
+    fn extend(&self, offset: Option<u64>, len: u64, key: &SecretVec<u8>) -> io::Result<u64> {
+        let plaintext_block_size = BLOCK_SIZE as u64;
+        let mut size = self.size.lock().unwrap();
+        let offset = offset.unwrap_or(*size);
+        let end = offset + len;
+        if end <= *size {
+            return Ok(offset);
+        }
+        let blocks = size.div_ceil(plaintext_block_size);
+        if (end - 1) / plaintext_block_size >= blocks && *size < blocks * plaintext_block_size {
+            // the last chunk is not the last anymore, it needs to be whole
+            self.update_chunk(blocks - 1, key, |data| {
+                data.resize(BLOCK_SIZE, 0);
+                false
+            })?;
+        }
+        // chunks between the end and the write are never written
+        if blocks < offset / plaintext_block_size {
+            self.holes
+                .write()
+                .unwrap()
+                .insert(blocks..offset / plaintext_block_size);
+            self.holes_changed.store(true, Ordering::SeqCst);
+        }
+        *size = end;
+        Ok(offset)
+    }
+
+    /// Change the chunk with `f`, if it returns `true` the chunk is also written to the content file.
+    fn update_chunk(
+        &self,
+        block_index: u64,
+        key: &SecretVec<u8>,
+        f: impl FnOnce(&mut Vec<u8>) -> bool,
+    ) -> io::Result<()> {
+        loop {
+            let lock = self
+                .chunks
+                .lock()
+                .unwrap()
+                .entry(block_index)
+                .or_default()
+                .clone();
+            let mut chunk = lock.lock().unwrap();
+            if chunk.evicted {
+                // it was written meanwhile, load it again
+                continue;
+            }
+            if chunk.data.is_none() {
+                chunk.data = Some(self.load(block_index, key)?);
+            }
+            let write = f(chunk.data.as_mut().unwrap());
+            chunk.dirty = true;
+            if write {
+                self.write_chunk(block_index, &chunk, key)?;
+                self.evict(block_index, &lock, &mut chunk);
+            }
+            return Ok(());
+        }
+    }
+
+    /// Give the chunk to `f`, loading it if it's not in the cache.
+    ///
+    /// The chunk is locked while loading, so a writer can't write the block meanwhile.
+    fn read_chunk(
+        &self,
+        block_index: u64,
+        key: &SecretVec<u8>,
+        f: impl FnOnce(&[u8]),
+    ) -> io::Result<()> {
+        loop {
+            let lock = self
+                .chunks
+                .lock()
+                .unwrap()
+                .entry(block_index)
+                .or_default()
+                .clone();
+            let mut chunk = lock.lock().unwrap();
+            if chunk.evicted {
+                continue;
+            }
+            if let Some(data) = &chunk.data {
+                f(data);
+                return Ok(());
+            }
+            let data = self.load(block_index, key);
+            // not changed, no need to keep it
+            self.evict(block_index, &lock, &mut chunk);
+            f(&data?);
+            return Ok(());
+        }
+    }
+
+    fn load(&self, block_index: u64, key: &SecretVec<u8>) -> io::Result<Vec<u8>> {
+        if self.holes.read().unwrap().contains(block_index) {
+            return Ok(vec![0; BLOCK_SIZE]);
+        }
+        let chunk = self.chunk_refs.read().unwrap().get(block_index);
+        if let Some(id) = chunk {
+            let chunk = self.chunk_refs.read().unwrap().read_chunk(id)?;
+            return crypto::open_chunk(self.cipher, key, id, chunk);
+        }
+        let ciphertext_block_size = self.cipher.ciphertext_block_size() as u64;
+        // held while reading, so the block and its counter are of the same write
+        let counters = self
+            .counters
+            .as_ref()
+            .map(|counters| counters.read().unwrap());
+        // through the layers shared with clones
+        let mut file = ContentFile::open(&self.path, None, ciphertext_block_size)?;
+        let block = read_block(
+            &mut file,
+            block_index * ciphertext_block_size,
+            ciphertext_block_size,
+        )?;
+        if block.is_empty() {
+            // after the end
+            return Ok(block);
+        }
+        let Some(counters) = counters else {
+            return crypto::open_block(self.cipher, key, &block_index.to_le_bytes(), false, block);
+        };
+        let open = |aad: BlockAad, block| {
+            crypto::open_block(self.cipher, key, &aad.aad, aad.compressed, block)
+        };
+        // a block without a counter only opens with a fallback
+        let aad = counters.aad(block_index);
+        if !counters.has_fallbacks(block_index) {
+            return open(
+                aad.ok_or_else(|| io::Error::other("block without a write counter"))?,
+                block,
+            );
+        }
+        // after a crash it may be of another write
+        let opened = aad
+            .into_iter()
+            .chain(counters.fallback_aads(block_index))
+            .find_map(|aad| open(aad, block.clone()).ok());
+        opened.ok_or_else(|| io::Error::other("error opening block"))
+    }
+
+    /// Save the holes if some were added, needs to be done before writing chunks, which could be after them.
+    fn save_holes(&self, key: &SecretVec<u8>) -> io::Result<()> {
+        let Some((path, ino)) = &self.holes_file else {
+            return Ok(());
+        };
+        if self.holes_changed.swap(false, Ordering::SeqCst) {
+            let binding = self
+                .counters
+                .as_ref()
+                .and_then(|counters| SideFileBinding::next(*ino, &mut counters.write().unwrap()));
+            let holes = self.holes.read().unwrap().clone();
+            save_holes_file(path, binding, &holes, self.cipher, key).map_err(|err| {
+                self.holes_changed.store(true, Ordering::SeqCst);
+                io::Error::other(err)
+            })?;
+        }
+        Ok(())
+    }
+
+    fn write_chunk(&self, block_index: u64, chunk: &Chunk, key: &SecretVec<u8>) -> io::Result<()> {
+        self.save_holes(key)?;
+        if let Some(store) = &self.store {
+            return self.write_to_store(store, block_index, chunk, key);
+        }
+        let data = chunk.data.as_ref().unwrap();
+        // held while writing, so readers get the block and its counter of the same write
+        let mut counters = self
+            .counters
+            .as_ref()
+            .map(|counters| counters.write().unwrap());
+        // it's recorded with the counter whether the block is compressed, so the blocks without one are not
+        let compressed = counters
+            .as_ref()
+            .filter(|counters| !counters.is_legacy())
+            .and(self.compression)
+            .and_then(|compression| compress::compress(compression, data));
+        let aad = match counters.as_mut() {
+            Some(counters) => counters.next_aad(block_index, compressed.is_some())?,
+            None => block_index.to_le_bytes().to_vec(),
+        };
+        let block = crypto::seal_block(
+            self.cipher,
+            key,
+            &aad,
+            compressed.as_deref().unwrap_or(data),
+            compressed.is_some(),
+        )?;
+        snapshot::preserve_blocks(
+            &self.snapshots_dir,
+            &self.path,
+            block_index..block_index + 1,
+            self.cipher.ciphertext_block_size() as u64,
+        )?;
+        let mut file = OpenOptions::new().write(true).open(&self.path)?;
+        file.seek(SeekFrom::Start(
+            block_index * self.cipher.ciphertext_block_size() as u64,
+        ))?;
+        file.write_all(&block)?;
+        // a compressed block is shorter
+        self.extend_to_block_end(&file, block_index, data.len())?;
+        drop(counters);
+        self.holes.write().unwrap().remove(block_index);
+        self.chunk_refs.write().unwrap().remove(block_index);
+        Ok(())
+    }
+
+    /// Reference the chunk with the content of the block from the store instead of writing it in the content file.
+    fn write_to_store(
+        &self,
+        store: &ChunkStore,
+        block_index: u64,
+        chunk: &Chunk,
+        key: &SecretVec<u8>,
+    ) -> io::Result<()> {
+        let data = chunk.data.as_ref().unwrap();
+        let id = crypto::chunk_id(key, data);
+        store.put(id, || {
+            crypto::seal_chunk(self.cipher, key, id, data, self.compression)
+        })?;
+        let file = OpenOptions::new().write(true).open(&self.path)?;
+        self.extend_to_block_end(&file, block_index, data.len())?;
+        self.holes.write().unwrap().remove(block_index);
+        self.chunk_refs.write().unwrap().insert(block_index, id);
+        Ok(())
+    }
+
+    /// The content file keeps the length it would have with the block written as it is, that's how readers know the
+    /// size.
+    fn extend_to_block_end(&self, file: &File, block_index: u64, len: usize) -> io::Result<()> {
+        let ciphertext_block_size = self.cipher.ciphertext_block_size();
+        let end = block_index * ciphertext_block_size as u64
+            + (len + ciphertext_block_size - BLOCK_SIZE) as u64;
+        if file.metadata()?.len() < end {
+            file.set_len(end)?;
+        }
+        Ok(())
+    }
+
+    fn evict(&self, block_index: u64, lock: &Arc<Mutex<Chunk>>, chunk: &mut Chunk) {
+        let mut chunks = self.chunks.lock().unwrap();
+        if chunks
+            .get(&block_index)
+            .is_some_and(|current| Arc::ptr_eq(current, lock))
+        {
+            chunks.remove(&block_index);
+        }
+        chunk.evicted = true;
+    }
+}
@@ -0,0 +1,247 @@
+//! Content files read through the layers they share with their clones, see
+//! [`crate::encryptedfs::EncryptedFs::clone_file`].
+//!
+//! Cloning a file doesn't copy its content. The content file of the source becomes a layer, `contents/<ino>.base.<n>`,
+//! hard linked by both inodes, and each of them gets a new sparse content file of the same length. A block is read
+//! from the first of the content file and its layers, newest first, that has it. Writes only go to the content file,
+//! so the layers never change and a block is copied only when one side writes it. The hard links count the references
+//! to a layer, its blocks are freed with the last one. After [`MAX_LAYERS`] the blocks of the layers are copied into
+//! the content file.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use crate::encryptedfs::dedup::{self, Allocated};
+use crate::encryptedfs::snapshot;
+
+/// Extension of the layers next to a content file, followed by their index.
+const LAYER_EXT: &str = "base";
+/// A content file with this many layers gets their blocks copied into it before being cloned again, so reading
+/// doesn't look into more.
+pub(crate) const MAX_LAYERS: usize = 16;
+
+/// Path of the layer `index` of the content file `path`.
+pub(crate) fn layer_path(path: &Path, index: usize) -> PathBuf {
+    let mut layer = path.as_os_str().to_owned();
+    layer.push(format!(".{LAYER_EXT}.{index}"));
+    PathBuf::from(layer)
+}
+
+/// The layers of the content file `path`, newest first.
+pub(crate) fn layers(path: &Path) -> Vec<PathBuf> {
+    let mut layers: Vec<PathBuf> = (0..)
+        .map(|index| layer_path(path, index))
+        .take_while(|layer| layer.is_file())
+        .collect();
+    layers.reverse();
+    layers
+}
+
+/// Remove the references of the content file `path` to its layers, returns the bytes freed by the ones it was the
+/// last to reference.
+pub(crate) fn remove_layers(path: &Path) -> io::Result<u64> {
+    let mut freed = 0;
+    for layer in layers(path) {
+        freed += dedup::freed_by_unlink(&layer)?;
+        fs::remove_file(layer)?;
+    }
+    Ok(freed)
+}
+
+/// Copy the ciphertext of the content file `src` to `dst`, with the blocks it reads from its layers.
+pub(crate) fn copy(src: &Path, dst: &Path, ciphertext_block_size: u64) -> io::Result<()> {
+    if layers(src).is_empty() {
+        fs::copy(src, dst)?;
+        return Ok(());
+    }
+    let mut content_file = ContentFile::open(src, None, ciphertext_block_size)?;
+    let mut file = File::create(dst)?;
+    let len = content_file.len;
+    let mut offset = 0;
+    while offset < len {
+        let block = read_block(&mut content_file, offset, ciphertext_block_size)?;
+        // keep it sparse
+        if is_present(&block) {
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&block)?;
+        }
+        offset += ciphertext_block_size;
+    }
+    file.set_len(len)?;
+    Ok(())
+}
+
+/// Copy the blocks the content file `path` reads from its layers into it and drop its references to them.
+///
+/// The content file is replaced, the layers stay for the other files sharing them.
+pub(crate) fn flatten(path: &Path, ciphertext_block_size: u64) -> io::Result<Allocated> {
+    let tmp_path = path.with_file_name(format!(
+        ".{}.flatten",
+        path.file_name().unwrap().to_string_lossy()
+    ));
+    copy(path, &tmp_path, ciphertext_block_size)?;
+    File::open(&tmp_path)?.sync_all()?;
+    let allocated = Allocated {
+        added: dedup::allocated_of(&tmp_path)?,
+        freed: dedup::freed_by_unlink(path)?,
+    };
+    fs::rename(&tmp_path, path)?;
+    Ok(allocated.and(Allocated::freed(remove_layers(path)?)))
+}
+
+/// Content file of an inode, with its layers and, in a snapshot, the blocks from `cow/<ino>`.
+pub(crate) struct ContentFile {
+    // read in order, a block is taken from the first one having it, writes go to the first one
+    files: Vec<File>,
+    len: u64,
+    block_size: u64,
+    pos: u64,
+    // (block index, ciphertext)
+    block: Option<(u64, Vec<u8>)>,
+}
+
+impl ContentFile {
+    /// Open the content file `path`, with the blocks changed since the snapshot from `cow_dir`, if it has them.
+    pub(crate) fn open(
+        path: &Path,
+        cow_dir: Option<&Path>,
+        ciphertext_block_size: u64,
+    ) -> io::Result<Self> {
+        let cow_file = cow_dir.map(|cow_dir| cow_dir.join(path.file_name().unwrap()));
+        match cow_file {
+            Some(cow_file) if cow_file.is_file() => Self::with_layers(
+                path,
+                vec![File::open(&cow_file)?, File::open(path)?],
+                Some(snapshot::cow_len(&cow_file)?),
+                ciphertext_block_size,
+            ),
+            _ => Self::with_layers(path, vec![File::open(path)?], None, ciphertext_block_size),
+        }
+    }
+
+    /// Open the content file `path` for reading and writing.
+    pub(crate) fn open_write(path: &Path, ciphertext_block_size: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Self::with_layers(path, vec![file], None, ciphertext_block_size)
+    }
+
+    fn with_layers(
+        path: &Path,
+        mut files: Vec<File>,
+        len: Option<u64>,
+        ciphertext_block_size: u64,
+    ) -> io::Result<Self> {
+        for layer in layers(path) {
+            files.push(File::open(layer)?);
+        }
+        let len = match len {
+            Some(len) => len,
+            None => files[0].metadata()?.len(),
+        };
+        Ok(Self {
+            files,
+            len,
+            block_size: ciphertext_block_size,
+            pos: 0,
+            block: None,
+        })
+    }
+
+    /// Like [`File::sync_all`] for the content file.
+    pub(crate) fn sync_all(&self) -> io::Result<()> {
+        self.files[0].sync_all()
+    }
+
+    fn is_layered(&self) -> bool {
+        self.files.len() > 1
+    }
+}
+
+impl Read for ContentFile {
+    #[allow(clippy::cast_possible_truncation)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.is_layered() {
+            return self.files[0].read(buf);
+        }
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let block_index = self.pos / self.block_size;
+        if self.block.as_ref().map(|(index, _)| *index) != Some(block_index) {
+            let offset = block_index * self.block_size;
+            let block_len = self.block_size.min(self.len - offset);
+            let mut block = read_block(&mut self.files[0], offset, block_len)?;
+            for file in &mut self.files[1..] {
+                if is_present(&block) {
+                    break;
+                }
+                let layer_block = read_block(file, offset, block_len)?;
+                if is_present(&layer_block) {
+                    block = layer_block;
+                }
+            }
+            self.block = Some((block_index, block));
+        }
+        let block = &self.block.as_ref().unwrap().1;
+        let start = (self.pos - block_index * self.block_size) as usize;
+        if start >= block.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(block.len() - start);
+        buf[..len].copy_from_slice(&block[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for ContentFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.is_layered() {
+            return self.files[0].write(buf);
+        }
+        self.files[0].seek(SeekFrom::Start(self.pos))?;
+        let len = self.files[0].write(buf)?;
+        self.pos += len as u64;
+        self.len = self.len.max(self.pos);
+        self.block = None;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.files[0].flush()
+    }
+}
+
+impl Seek for ContentFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        if !self.is_layered() {
+            return self.files[0].seek(pos);
+        }
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(pos) => self.len.checked_add_signed(pos),
+            SeekFrom::Current(pos) => self.pos.checked_add_signed(pos),
+        };
+        self.pos = new_pos
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek position"))?;
+        Ok(self.pos)
+    }
+}
+
+pub(crate) fn read_block<R: Read + Seek>(
+    file: &mut R,
+    offset: u64,
+    len: u64,
+) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut block = vec![];
+    file.take(len).read_to_end(&mut block)?;
+    Ok(block)
+}
+
+// never written blocks of a sparse file read as zeros, a ciphertext block never is all zeros
+pub(crate) fn is_present(block: &[u8]) -> bool {
+    block.iter().any(|b| *b != 0)
+}
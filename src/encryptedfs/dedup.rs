@@ -0,0 +1,348 @@
+//! Blocks with the same content kept once, see [`crate::encryptedfs::EncryptedFs::set_block_dedup`].
+//!
+//! Blocks are deduplicated at their fixed place in the file, the same blocks the content file is made of, so a block
+//! is still read and written on its own. Data shifted by an insertion doesn't line up with the blocks it was stored in
+//! and is stored again, there is no content-defined chunking.\
+//! While deduplication is on, a written block goes to the chunk store, `chunks/<id>`, named by the keyed hash of its
+//! plaintext, and the file only records which chunk each of its blocks is in, see [`crate::crypto::chunks`].\
+//! Each file, version and snapshot referencing a chunk has a hard link to it in its `refs` directory, so the chunk is
+//! freed when the last of them goes. Only the link of the store is left then, and it's removed too.\
+//! The space the content files and the chunk store take is counted as they change, see [`PhysicalSize`].
+
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{fs, io};
+
+use crate::crypto::chunks::ChunkId;
+use crate::encryptedfs::{snapshot, CONTENTS_DIR, SNAPSHOTS_DIR};
+
+/// Directory of the chunk store in the data directory.
+pub(crate) const CHUNKS_DIR: &str = "chunks";
+/// Extension of the file next to the inode keeping the chunks of its blocks.
+pub(crate) const CHUNKS_EXT: &str = "chunks";
+/// Extension of the directory next to a content file with the links to its chunks.
+pub(crate) const REFS_EXT: &str = "refs";
+/// File in the security directory keeping the [`PhysicalSize`].
+pub(crate) const PHYSICAL_SIZE_FILENAME: &str = "physical.size";
+
+// serializes adding and freeing chunks, so one isn't freed while it's linked again
+static STORE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Bytes taken and freed on disk by a change of the content files, counted in the [`PhysicalSize`].
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Allocated {
+    pub(crate) added: u64,
+    pub(crate) freed: u64,
+}
+
+impl Allocated {
+    pub(crate) const fn added(added: u64) -> Self {
+        Self { added, freed: 0 }
+    }
+
+    pub(crate) const fn freed(freed: u64) -> Self {
+        Self { added: 0, freed }
+    }
+
+    /// A file took `before` bytes and now takes `after`.
+    pub(crate) const fn changed(before: u64, after: u64) -> Self {
+        if after > before {
+            Self::added(after - before)
+        } else {
+            Self::freed(before - after)
+        }
+    }
+
+    #[must_use]
+    pub(crate) const fn and(self, other: Self) -> Self {
+        Self {
+            added: self.added + other.added,
+            freed: self.freed + other.freed,
+        }
+    }
+}
+
+/// Bytes the content files, with their layers, and the chunk store take on disk, for
+/// [`crate::encryptedfs::EncryptedFs::statfs`].
+///
+/// It's measured once, when deduplication is turned on, and then changed with the files, so it's not measured again
+/// each time. A file counts from when it's created until its last link goes, even if that's in a snapshot.\
+/// It's kept in a file of its own, written in place with each change. It's not encrypted, it's only what anyone with
+/// the data directory can measure. After losing power it may be missing the last changes, removing the file while
+/// the volume isn't mounted has it measured again.
+#[derive(Debug)]
+pub(crate) struct PhysicalSize {
+    // the file, `None` on a read-only volume, and the size in it
+    file: Mutex<(Option<File>, u64)>,
+}
+
+impl PhysicalSize {
+    /// Open the size kept in `path`, [None] if it was never measured.
+    #[allow(clippy::missing_errors_doc)]
+    pub(crate) fn open(path: &Path) -> io::Result<Option<Self>> {
+        let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut bytes = [0; 8];
+        if file.read_exact(&mut bytes).is_err() {
+            // not written whole
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            file: Mutex::new((Some(file), u64::from_le_bytes(bytes))),
+        }))
+    }
+
+    /// Measure the size of the data directory `data_dir` and keep it in `path`, if there is one.
+    #[allow(clippy::missing_errors_doc)]
+    pub(crate) fn create(path: Option<&Path>, data_dir: &Path) -> io::Result<Self> {
+        let bytes = measure(data_dir)?;
+        let file = path
+            .map(|path| {
+                let mut file = File::create(path)?;
+                file.write_all(&bytes.to_le_bytes())?;
+                file.sync_all()?;
+                Ok::<_, io::Error>(file)
+            })
+            .transpose()?;
+        Ok(Self {
+            file: Mutex::new((file, bytes)),
+        })
+    }
+
+    pub(crate) fn get(&self) -> u64 {
+        self.file.lock().unwrap().1
+    }
+
+    #[allow(clippy::missing_errors_doc)]
+    pub(crate) fn apply(&self, allocated: Allocated) -> io::Result<()> {
+        if allocated.added == allocated.freed {
+            return Ok(());
+        }
+        let mut guard = self.file.lock().unwrap();
+        let (file, bytes) = &mut *guard;
+        *bytes = (*bytes + allocated.added).saturating_sub(allocated.freed);
+        let Some(file) = file else {
+            return Ok(());
+        };
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&bytes.to_le_bytes())
+    }
+}
+
+/// Bytes the file `path` takes on disk, `0` if it doesn't exist.
+pub(crate) fn allocated_of(path: &Path) -> io::Result<u64> {
+    match fs::metadata(path) {
+        Ok(meta) => Ok(allocated(&meta)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(err),
+    }
+}
+
+/// Bytes freed by removing or replacing the file `path`, none if it's linked somewhere else too.
+pub(crate) fn freed_by_unlink(path: &Path) -> io::Result<u64> {
+    match fs::metadata(path) {
+        Ok(meta) if links(&meta) <= 1 => Ok(allocated(&meta)),
+        Ok(_) => Ok(0),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(err),
+    }
+}
+
+/// The chunk store, as seen by one file or version referencing chunks from it.
+pub(crate) struct ChunkStore {
+    dir: PathBuf,
+    refs_dir: PathBuf,
+    // the chunks added and freed are counted in it
+    size: Option<Arc<PhysicalSize>>,
+}
+
+impl ChunkStore {
+    pub(crate) const fn new(
+        dir: PathBuf,
+        refs_dir: PathBuf,
+        size: Option<Arc<PhysicalSize>>,
+    ) -> Self {
+        Self {
+            dir,
+            refs_dir,
+            size,
+        }
+    }
+
+    /// Reference the chunk `id`, creating it with the ciphertext from `seal` if it's not in the store.
+    pub(crate) fn put(
+        &self,
+        id: ChunkId,
+        seal: impl FnOnce() -> io::Result<Vec<u8>>,
+    ) -> io::Result<()> {
+        let _guard = STORE_LOCK.lock().unwrap();
+        let path = self.dir.join(id.to_string());
+        if !path.is_file() {
+            fs::create_dir_all(&self.dir)?;
+            let tmp_path = self.dir.join(format!(".{id}"));
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&seal()?)?;
+            file.sync_all()?;
+            fs::rename(tmp_path, &path)?;
+            if let Some(size) = &self.size {
+                size.apply(Allocated::added(allocated(&file.metadata()?)))?;
+            }
+        }
+        fs::create_dir_all(&self.refs_dir)?;
+        match fs::hard_link(path, self.refs_dir.join(id.to_string())) {
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+            res => res,
+        }
+    }
+
+    /// Drop the references to the chunks `ids`, freeing the ones nothing else references.
+    pub(crate) fn release(&self, ids: &[ChunkId]) -> io::Result<()> {
+        let _guard = STORE_LOCK.lock().unwrap();
+        for id in ids {
+            match fs::remove_file(self.refs_dir.join(id.to_string())) {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                res => res?,
+            }
+            free_if_unused(&self.dir.join(id.to_string()), self.size.as_deref())?;
+        }
+        Ok(())
+    }
+
+    /// Drop all the references, when the file or version is removed.
+    pub(crate) fn release_all(&self) -> io::Result<()> {
+        if !self.refs_dir.is_dir() {
+            return Ok(());
+        }
+        let _guard = STORE_LOCK.lock().unwrap();
+        for entry in fs::read_dir(&self.refs_dir)? {
+            let entry = entry?;
+            fs::remove_file(entry.path())?;
+            free_if_unused(&self.dir.join(entry.file_name()), self.size.as_deref())?;
+        }
+        fs::remove_dir(&self.refs_dir)
+    }
+
+    /// Reference all the chunks `other` references too.
+    pub(crate) fn share(&self, other: &Self) -> io::Result<()> {
+        if !other.refs_dir.is_dir() {
+            return Ok(());
+        }
+        let _guard = STORE_LOCK.lock().unwrap();
+        fs::create_dir_all(&self.refs_dir)?;
+        for entry in fs::read_dir(&other.refs_dir)? {
+            let entry = entry?;
+            match fs::hard_link(entry.path(), self.refs_dir.join(entry.file_name())) {
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+                res => res?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Free the chunks in the store `dir` nothing references anymore, like after removing a snapshot.
+pub(crate) fn collect_garbage(dir: &Path, size: Option<&PhysicalSize>) -> io::Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    let _guard = STORE_LOCK.lock().unwrap();
+    for entry in fs::read_dir(dir)? {
+        free_if_unused(&entry?.path(), size)?;
+    }
+    Ok(())
+}
+
+/// Bytes the content files and the chunk store of the data directory `data_dir` take on disk, with the content files
+/// only snapshots have. A file linked more than once counts once.
+pub(crate) fn measure(data_dir: &Path) -> io::Result<u64> {
+    let mut dirs = vec![data_dir.join(CONTENTS_DIR), data_dir.join(CHUNKS_DIR)];
+    let snapshots_dir = data_dir.join(SNAPSHOTS_DIR);
+    if snapshots_dir.is_dir() {
+        for entry in fs::read_dir(snapshots_dir)? {
+            let name = entry?.file_name();
+            dirs.push(
+                snapshot::snapshot_data_dir(data_dir, &name.to_string_lossy()).join(CONTENTS_DIR),
+            );
+        }
+    }
+    let mut seen = std::collections::HashSet::new();
+    let mut size = 0;
+    for dir in dirs {
+        if !dir.is_dir() {
+            continue;
+        }
+        // the directories are the entries of the directories and the references to the chunks
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_file() && seen.insert(file_id(&meta, &entry.path())) {
+                size += allocated(&meta);
+            }
+        }
+    }
+    Ok(size)
+}
+
+/// Bytes the files directly in `dir` free once removed, the ones linked somewhere else too don't.
+pub(crate) fn freed_by_removing(dir: &Path) -> io::Result<u64> {
+    let mut freed = 0;
+    for entry in fs::read_dir(dir)? {
+        let meta = entry?.metadata()?;
+        if meta.is_file() && links(&meta) <= 1 {
+            freed += allocated(&meta);
+        }
+    }
+    Ok(freed)
+}
+
+fn free_if_unused(path: &Path, size: Option<&PhysicalSize>) -> io::Result<()> {
+    match fs::metadata(path) {
+        Ok(meta) if links(&meta) <= 1 => {
+            fs::remove_file(path)?;
+            size.map_or(Ok(()), |size| {
+                size.apply(Allocated::freed(allocated(&meta)))
+            })
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res.map(|_| ()),
+    }
+}
+
+#[cfg(unix)]
+fn links(meta: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.nlink()
+}
+
+// without link counts the chunks are never freed
+#[cfg(not(unix))]
+fn links(_meta: &Metadata) -> u64 {
+    u64::MAX
+}
+
+#[cfg(unix)]
+fn file_id(meta: &Metadata, _path: &Path) -> (u64, u64, PathBuf) {
+    use std::os::unix::fs::MetadataExt;
+    (meta.dev(), meta.ino(), PathBuf::new())
+}
+
+#[cfg(not(unix))]
+fn file_id(_meta: &Metadata, path: &Path) -> (u64, u64, PathBuf) {
+    (0, 0, path.to_path_buf())
+}
+
+#[cfg(unix)]
+fn allocated(meta: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn allocated(meta: &Metadata) -> u64 {
+    meta.len()
+}
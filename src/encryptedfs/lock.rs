@@ -0,0 +1,191 @@
+//! Advisory byte-range locks, like `fcntl(2)` `F_GETLK`, `F_SETLK` and `F_SETLKW`, and `flock(2)` locks, which
+//! are the same over the whole file but kept apart.
+//!
+//! Locks are only kept in memory, they are owned by a lock owner (the kernel gives one for each
+//! process, or open file for `flock(2)`) and the process holding them.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+use tokio::sync::{Mutex, Notify};
+
+use crate::encryptedfs::{FsError, FsResult};
+
+// a process which dies doesn't release its locks, blocked waiters check again for it this often
+const RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Kind of lock, many readers or one writer can hold a range at the same time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockType {
+    /// Shared lock
+    Read,
+    /// Exclusive lock
+    Write,
+}
+
+/// A lock over the range `start..=end` of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileLock {
+    pub start: u64,
+    /// Inclusive, [`u64::MAX`] means until the end of the file, however it grows.
+    pub end: u64,
+    pub lock_type: LockType,
+    pub owner: u64,
+    /// The process holding the lock.
+    pub pid: u32,
+}
+
+impl FileLock {
+    const fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflicts(&self, owner: u64, start: u64, end: u64, lock_type: LockType) -> bool {
+        self.owner != owner
+            && self.overlaps(start, end)
+            && (self.lock_type == LockType::Write || lock_type == LockType::Write)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct LockManager {
+    // ino -> locks
+    locks: Mutex<HashMap<u64, Vec<FileLock>>>,
+    changed: Notify,
+}
+
+impl LockManager {
+    /// First lock of another owner that would prevent acquiring `lock_type` over `start..=end`.
+    pub async fn get(
+        &self,
+        ino: u64,
+        owner: u64,
+        start: u64,
+        end: u64,
+        lock_type: LockType,
+    ) -> Option<FileLock> {
+        let mut locks = self.locks.lock().await;
+        Self::find_conflict(&mut locks, ino, owner, start, end, lock_type)
+    }
+
+    /// Acquire, change or release (when `lock_type` is [None]) the lock of `owner` over `start..=end`.
+    ///
+    /// Existing locks of the same owner in the range are replaced. If another owner holds a conflicting lock
+    /// it will wait for it to be released when `block` is `true`,
+    /// else it will return an error of type [`FsError::WouldBlock`].
+    #[allow(clippy::too_many_arguments)]
+    pub async fn set(
+        &self,
+        ino: u64,
+        owner: u64,
+        pid: u32,
+        start: u64,
+        end: u64,
+        lock_type: Option<LockType>,
+        block: bool,
+    ) -> FsResult<()> {
+        if start > end {
+            return Err(FsError::InvalidInput("lock start after end"));
+        }
+        loop {
+            // created before checking, so we don't miss a release happening until we wait
+            let changed = self.changed.notified();
+            {
+                let mut locks = self.locks.lock().await;
+                let conflict = lock_type.and_then(|lock_type| {
+                    Self::find_conflict(&mut locks, ino, owner, start, end, lock_type)
+                });
+                if conflict.is_none() {
+                    let file_locks = locks.entry(ino).or_default();
+                    Self::unlock_range(file_locks, owner, start, end);
+                    if let Some(lock_type) = lock_type {
+                        file_locks.push(FileLock {
+                            start,
+                            end,
+                            lock_type,
+                            owner,
+                            pid,
+                        });
+                    }
+                    if file_locks.is_empty() {
+                        locks.remove(&ino);
+                    }
+                    drop(locks);
+                    self.changed.notify_waiters();
+                    return Ok(());
+                }
+                if !block {
+                    return Err(FsError::WouldBlock);
+                }
+            }
+            // the holder may die without releasing, that isn't notified
+            let _ = tokio::time::timeout(RECHECK_INTERVAL, changed).await;
+            if !is_process_alive(pid) {
+                return Err(FsError::Other("process waiting for lock is gone"));
+            }
+        }
+    }
+
+    /// Release all the locks of `owner` on the file, used when the file is closed.
+    pub async fn release(&self, ino: u64, owner: u64) {
+        let mut locks = self.locks.lock().await;
+        if let Some(file_locks) = locks.get_mut(&ino) {
+            file_locks.retain(|lock| lock.owner != owner);
+            if file_locks.is_empty() {
+                locks.remove(&ino);
+            }
+        }
+        drop(locks);
+        self.changed.notify_waiters();
+    }
+
+    fn find_conflict(
+        locks: &mut HashMap<u64, Vec<FileLock>>,
+        ino: u64,
+        owner: u64,
+        start: u64,
+        end: u64,
+        lock_type: LockType,
+    ) -> Option<FileLock> {
+        let file_locks = locks.get_mut(&ino)?;
+        // the process died without closing the file
+        file_locks.retain(|lock| is_process_alive(lock.pid));
+        file_locks
+            .iter()
+            .find(|lock| lock.conflicts(owner, start, end, lock_type))
+            .copied()
+    }
+
+    /// Remove the range from the locks of `owner`, splitting them if needed.
+    fn unlock_range(file_locks: &mut Vec<FileLock>, owner: u64, start: u64, end: u64) {
+        let mut kept = vec![];
+        file_locks.retain(|lock| {
+            if lock.owner != owner || !lock.overlaps(start, end) {
+                return true;
+            }
+            if lock.start < start {
+                kept.push(FileLock {
+                    end: start - 1,
+                    ..*lock
+                });
+            }
+            if lock.end > end {
+                kept.push(FileLock {
+                    start: end + 1,
+                    ..*lock
+                });
+            }
+            false
+        });
+        file_locks.extend(kept);
+    }
+}
+
+fn is_process_alive(pid: u32) -> bool {
+    if cfg!(target_os = "linux") && pid != 0 {
+        Path::new("/proc").join(pid.to_string()).exists()
+    } else {
+        true
+    }
+}
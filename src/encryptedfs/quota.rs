@@ -0,0 +1,147 @@
+//! Limits on the plaintext bytes and the number of inodes of a volume.
+//!
+//! The limits are kept encrypted in the data directory. While they are set the size of every inode is tracked in
+//! memory, so growing a file is checked against what it already counted, even with several writers.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::encryptedfs::{FsError, FsResult};
+
+/// Limits of a volume, see [`crate::encryptedfs::EncryptedFs::set_quota`]. [None] means no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    /// Total size of the files and symbolic links.
+    pub max_bytes: Option<u64>,
+    /// Number of files, directories and links, hard links count once.
+    pub max_inodes: Option<u64>,
+}
+
+impl Quota {
+    #[must_use]
+    pub const fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_max_inodes(mut self, max_inodes: u64) -> Self {
+        self.max_inodes = Some(max_inodes);
+        self
+    }
+
+    /// If there is any limit.
+    #[must_use]
+    pub const fn is_set(&self) -> bool {
+        self.max_bytes.is_some() || self.max_inodes.is_some()
+    }
+}
+
+/// What is counted against the [`Quota`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub bytes: u64,
+    pub inodes: u64,
+}
+
+pub(crate) struct QuotaTracker {
+    quota: Quota,
+    // ino -> size
+    sizes: HashMap<u64, u64>,
+    usage: Usage,
+}
+
+impl QuotaTracker {
+    pub fn new(quota: Quota, sizes: HashMap<u64, u64>) -> Self {
+        let usage = Usage {
+            bytes: sizes.values().sum(),
+            inodes: sizes.len() as u64,
+        };
+        Self {
+            quota,
+            sizes,
+            usage,
+        }
+    }
+
+    pub const fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    pub fn set_quota(&mut self, quota: Quota) {
+        self.quota = quota;
+    }
+
+    pub const fn usage(&self) -> Usage {
+        self.usage
+    }
+
+    /// Count a new inode, if it fits.
+    pub fn add_inode(&mut self, ino: u64, size: u64) -> FsResult<()> {
+        if self
+            .quota
+            .max_inodes
+            .is_some_and(|max| self.usage.inodes >= max)
+        {
+            return Err(FsError::QuotaExceeded);
+        }
+        self.check_bytes(size)?;
+        self.sizes.insert(ino, size);
+        self.usage.inodes += 1;
+        self.usage.bytes += size;
+        Ok(())
+    }
+
+    pub fn remove_inode(&mut self, ino: u64) {
+        if let Some(size) = self.sizes.remove(&ino) {
+            self.usage.inodes -= 1;
+            self.usage.bytes -= size;
+        }
+    }
+
+    /// Count the new size of a file, growing needs to fit.
+    pub fn resize(&mut self, ino: u64, size: u64) -> FsResult<()> {
+        let old_size = self.size(ino);
+        if size > old_size {
+            self.check_bytes(size - old_size)?;
+        }
+        self.set_size(ino, size);
+        Ok(())
+    }
+
+    /// Like [`Self::resize`], but never shrinks, for writes that might finish in any order.
+    pub fn grow(&mut self, ino: u64, size: u64) -> FsResult<()> {
+        if size <= self.size(ino) {
+            return Ok(());
+        }
+        self.resize(ino, size)
+    }
+
+    /// Count the size a file already grew to, without checking it fits.
+    pub fn grown(&mut self, ino: u64, size: u64) {
+        if size > self.size(ino) {
+            self.set_size(ino, size);
+        }
+    }
+
+    fn size(&self, ino: u64) -> u64 {
+        self.sizes.get(&ino).copied().unwrap_or(0)
+    }
+
+    fn set_size(&mut self, ino: u64, size: u64) {
+        let old_size = self.sizes.insert(ino, size).unwrap_or(0);
+        self.usage.bytes = self.usage.bytes - old_size + size;
+    }
+
+    fn check_bytes(&self, added: u64) -> FsResult<()> {
+        if self
+            .quota
+            .max_bytes
+            .is_some_and(|max| self.usage.bytes + added > max)
+        {
+            return Err(FsError::QuotaExceeded);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+//! Point-in-time copies of the volume, see [`crate::encryptedfs::EncryptedFs::create_snapshot`].
+//!
+//! A snapshot is a data directory of its own in `snapshots/<name>`, so it can be opened read-only like the volume.
+//! Taking one hard links the files of the volume into it, nothing is copied. Inodes, holes and directory entries are
+//! always replaced and never changed in place, so the snapshot keeps the versions it linked.\
+//! Content files are changed in place. Before a block of one shared with a snapshot is overwritten, its ciphertext is
+//! copied to `cow/<ino>` of the snapshot, at the same offset in a sparse file. The extra space is only for the
+//! changed blocks.
+
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use crate::encryptedfs::content_file::{is_present, read_block, ContentFile};
+use crate::encryptedfs::CONTENTS_DIR;
+
+pub(crate) const SNAPSHOTS_DIR: &str = "snapshots";
+/// Blocks of the content files changed since the snapshot was taken.
+pub(crate) const COW_DIR: &str = "cow";
+/// Extension of the file next to `cow/<ino>` keeping the length the content file had.
+const LEN_EXT: &str = "len";
+
+/// Data directory of the snapshot `name` of the volume in `data_dir`.
+///
+/// It can be mounted with [`crate::mount::create_mount_point`], read-only.
+#[must_use]
+pub fn snapshot_data_dir(data_dir: &Path, name: &str) -> PathBuf {
+    data_dir.join(SNAPSHOTS_DIR).join(name)
+}
+
+/// Hard link the files in `src` to `dst`, recreating the directories.
+///
+/// Files starting with `.` are skipped, those are the temporary files of atomic writes.
+pub(crate) fn link_tree(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::create_dir(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            link_tree(&entry.path(), &dst.join(name))?;
+        } else {
+            fs::hard_link(entry.path(), dst.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy the `blocks` of `content_file` to the snapshots sharing it, before they are overwritten.
+///
+/// Blocks already copied are kept, those are the ones the snapshot had.
+pub(crate) fn preserve_blocks(
+    snapshots_dir: &Path,
+    content_file: &Path,
+    blocks: Range<u64>,
+    ciphertext_block_size: u64,
+) -> io::Result<()> {
+    let meta = match fs::metadata(content_file) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        res => res?,
+    };
+    if !is_shared(&meta) {
+        return Ok(());
+    }
+    let name = content_file.file_name().unwrap();
+    for snapshot in fs::read_dir(snapshots_dir)? {
+        let snapshot = snapshot?.path();
+        let Ok(snapshot_meta) = fs::metadata(snapshot.join(CONTENTS_DIR).join(name)) else {
+            continue;
+        };
+        if !is_same_file(&meta, &snapshot_meta) {
+            continue;
+        }
+        let cow_file = snapshot.join(COW_DIR).join(name);
+        let len_file = len_file(&cow_file);
+        let len = if len_file.is_file() {
+            read_len(&len_file)?
+        } else {
+            // first change since the snapshot, the content file is still the one it had
+            let mut file = File::create(&len_file)?;
+            file.write_all(&meta.len().to_le_bytes())?;
+            file.sync_all()?;
+            meta.len()
+        };
+        // through the layers, the snapshot reads them from the content file
+        let mut base = ContentFile::open(content_file, None, ciphertext_block_size)?;
+        let mut cow = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&cow_file)?;
+        let end = blocks.end.min(len.div_ceil(ciphertext_block_size));
+        for block_index in blocks.start..end {
+            let offset = block_index * ciphertext_block_size;
+            let block_len = ciphertext_block_size.min(len - offset);
+            if is_present(&read_block(&mut cow, offset, block_len)?) {
+                continue;
+            }
+            let block = read_block(&mut base, offset, block_len)?;
+            cow.seek(SeekFrom::Start(offset))?;
+            cow.write_all(&block)?;
+        }
+        cow.sync_data()?;
+    }
+    Ok(())
+}
+
+fn len_file(cow_file: &Path) -> PathBuf {
+    let mut path = cow_file.as_os_str().to_owned();
+    path.push(format!(".{LEN_EXT}"));
+    PathBuf::from(path)
+}
+
+/// Length the content file had when the snapshot was taken, for its `cow_file`.
+pub(crate) fn cow_len(cow_file: &Path) -> io::Result<u64> {
+    read_len(&len_file(cow_file))
+}
+
+fn read_len(path: &Path) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    File::open(path)?.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(unix)]
+pub(crate) fn is_shared(meta: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    meta.nlink() > 1
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_shared(_meta: &Metadata) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn is_same_file(a: &Metadata, b: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_a: &Metadata, _b: &Metadata) -> bool {
+    false
+}
@@ -1,21 +1,40 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
 use std::str::FromStr;
 use std::string::ToString;
-use std::time::SystemTime;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use shush_rs::{ExposeSecret, SecretString};
+use strum::IntoEnumIterator;
 use tracing_test::traced_test;
 
+use crate::crypto::compress::Compression;
+use crate::crypto::write::BLOCK_SIZE;
 use crate::crypto::Cipher;
+use crate::encryptedfs::acl::{Acl, AclEntry, AclTag, ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR};
+use crate::encryptedfs::content_file;
+use crate::encryptedfs::dedup::{self, CHUNKS_DIR, PHYSICAL_SIZE_FILENAME};
+use crate::encryptedfs::lock::LockType;
+use crate::encryptedfs::quota::{Quota, Usage};
+use crate::encryptedfs::snapshot::{snapshot_data_dir, COW_DIR};
+use crate::encryptedfs::trash::Trash;
+use crate::encryptedfs::versions::Versioning;
 use crate::encryptedfs::write_all_bytes_to_fs;
+use crate::encryptedfs::HOLES_EXT;
 use crate::encryptedfs::INODES_DIR;
 use crate::encryptedfs::KEY_ENC_FILENAME;
 use crate::encryptedfs::KEY_SALT_FILENAME;
+use crate::encryptedfs::QUOTA_FILENAME;
 use crate::encryptedfs::SECURITY_DIR;
-use crate::encryptedfs::{CopyFileRangeReq, HASH_DIR};
+use crate::encryptedfs::XATTR_EXT;
 use crate::encryptedfs::{
-    DirectoryEntry, DirectoryEntryPlus, EncryptedFs, FileType, FsError, FsResult, SetFileAttr,
-    CONTENTS_DIR, ROOT_INODE,
+    AllocateMode, AtimeMode, CreateFileAttr, DirectoryEntry, DirectoryEntryPlus, EncryptedFs,
+    FileType, FsError, FsResult, OpenFlags, RenameMode, SetFileAttr, SetXattrMode, CONTENTS_DIR,
+    ROOT_INODE,
 };
+use crate::encryptedfs::{CopyFileRangeReq, DIR_BATCH, HASH_DIR, LONG_NAMES_DIR, LS_DIR, NAME_MAX};
 use crate::test_common::run_test;
 use crate::test_common::TestSetup;
 use crate::test_common::{create_attr, get_fs, PasswordProviderImpl};
@@ -41,8 +60,7 @@ async fn test_write() {
                     ROOT_INODE,
                     &test_file,
                     create_attr(FileType::RegularFile),
-                    false,
-                    true,
+                    OpenFlags::new(false, true),
                 )
                 .await
                 .unwrap();
@@ -58,7 +76,10 @@ async fn test_write() {
 
             // offset greater than current position
             let data = "37";
-            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
             write_all_bytes_to_fs(&fs, attr.ino, 5, data.as_bytes(), fh)
                 .await
                 .unwrap();
@@ -71,7 +92,10 @@ async fn test_write() {
 
             // offset after the file end
             let data = "37";
-            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
             write_all_bytes_to_fs(&fs, attr.ino, 42, data.as_bytes(), fh)
                 .await
                 .unwrap();
@@ -89,8 +113,7 @@ async fn test_write() {
                     ROOT_INODE,
                     &test_file_2,
                     create_attr(FileType::RegularFile),
-                    false,
-                    true,
+                    OpenFlags::new(false, true),
                 )
                 .await
                 .unwrap();
@@ -121,8 +144,7 @@ async fn test_write() {
                     ROOT_INODE,
                     &test_file_3,
                     create_attr(FileType::RegularFile),
-                    false,
-                    true,
+                    OpenFlags::new(false, true),
                 )
                 .await
                 .unwrap();
@@ -142,7 +164,10 @@ async fn test_write() {
             assert_eq!("test-37-37-42", new_content);
 
             let buf = [0; 0];
-            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
             assert!(matches!(
                 fs.write(ROOT_INODE, 0, &buf, fh).await,
                 Err(FsError::InvalidInodeType)
@@ -157,8 +182,7 @@ async fn test_write() {
                     ROOT_INODE,
                     &test_dir,
                     create_attr(FileType::Directory),
-                    false,
-                    true,
+                    OpenFlags::new(false, true),
                 )
                 .await
                 .unwrap();
@@ -191,8 +215,7 @@ async fn test_read() {
                     ROOT_INODE,
                     &test_file,
                     create_attr(FileType::RegularFile),
-                    false,
-                    true,
+                    OpenFlags::new(false, true),
                 )
                 .await
                 .unwrap();
@@ -203,7 +226,10 @@ async fn test_read() {
                 .unwrap();
             fs.flush(fh).await.unwrap();
             fs.release(fh).await.unwrap();
-            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(true, false))
+                .await
+                .unwrap();
             test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
             assert_eq!(data, &buf);
 
@@ -214,18 +240,27 @@ async fn test_read() {
             // offset
             let data = b"test-37";
             let mut buf = [0; 2];
-            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
             write_all_bytes_to_fs(&fs, attr.ino, 0, data, fh)
                 .await
                 .unwrap();
             fs.flush(fh).await.unwrap();
             fs.release(fh).await.unwrap();
-            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(true, false))
+                .await
+                .unwrap();
             test_common::read_exact(&fs, attr.ino, 5, &mut buf, fh).await;
             assert_eq!(b"37", &buf);
 
             // offset after file end
-            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(true, false))
+                .await
+                .unwrap();
             let len = fs.read(attr.ino, 42, &mut [0, 1], fh).await.unwrap();
             assert_eq!(len, 0);
 
@@ -236,8 +271,7 @@ async fn test_read() {
                     ROOT_INODE,
                     &test_file_2,
                     create_attr(FileType::RegularFile),
-                    false,
-                    true,
+                    OpenFlags::new(false, true),
                 )
                 .await
                 .unwrap();
@@ -247,9 +281,15 @@ async fn test_read() {
                 .unwrap();
             fs.flush(fh).await.unwrap();
             fs.release(fh).await.unwrap();
-            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(true, false))
+                .await
+                .unwrap();
             test_common::read_exact(&fs, attr.ino, 0, &mut [0_u8; 1], fh).await;
-            let fh_2 = fs.open(attr.ino, false, true).await.unwrap();
+            let fh_2 = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
             let new_data = "37";
             write_all_bytes_to_fs(&fs, attr.ino, 5, new_data.as_bytes(), fh_2)
                 .await
@@ -267,8 +307,7 @@ async fn test_read() {
                     ROOT_INODE,
                     &test_file_3,
                     create_attr(FileType::RegularFile),
-                    false,
-                    true,
+                    OpenFlags::new(false, true),
                 )
                 .await
                 .unwrap();
@@ -278,9 +317,15 @@ async fn test_read() {
                 .unwrap();
             fs.flush(fh).await.unwrap();
             fs.release(fh).await.unwrap();
-            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(true, false))
+                .await
+                .unwrap();
             test_common::read_exact(&fs, attr.ino, 8, &mut [0_u8; 1], fh).await;
-            let fh_2 = fs.open(attr.ino, false, true).await.unwrap();
+            let fh_2 = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
             let new_data = "37";
             write_all_bytes_to_fs(&fs, attr.ino, 5, new_data.as_bytes(), fh_2)
                 .await
@@ -298,8 +343,7 @@ async fn test_read() {
                     ROOT_INODE,
                     &test_file_4,
                     create_attr(FileType::RegularFile),
-                    false,
-                    true,
+                    OpenFlags::new(false, true),
                 )
                 .await
                 .unwrap();
@@ -309,9 +353,15 @@ async fn test_read() {
                 .unwrap();
             fs.flush(fh).await.unwrap();
             fs.release(fh).await.unwrap();
-            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(true, false))
+                .await
+                .unwrap();
             test_common::read_exact(&fs, attr.ino, 7, &mut [0_u8; 1], fh).await;
-            let fh_2 = fs.open(attr.ino, false, true).await.unwrap();
+            let fh_2 = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
             let new_data = "37";
             write_all_bytes_to_fs(&fs, attr.ino, 5, new_data.as_bytes(), fh_2)
                 .await
@@ -338,8 +388,7 @@ async fn test_read() {
                     ROOT_INODE,
                     &test_dir,
                     create_attr(FileType::Directory),
-                    true,
-                    false,
+                    OpenFlags::new(true, false),
                 )
                 .await
                 .unwrap();
@@ -371,8 +420,7 @@ async fn test_set_len() {
                     ROOT_INODE,
                     &test_file,
                     create_attr(FileType::RegularFile),
-                    false,
-                    true,
+                    OpenFlags::new(false, true),
                 )
                 .await
                 .unwrap();
@@ -384,7 +432,10 @@ async fn test_set_len() {
             fs.release(fh).await.unwrap();
 
             // size increase, preserve opened writer content
-            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
             let data = "37";
             write_all_bytes_to_fs(&fs, attr.ino, 5, data.as_bytes(), fh)
                 .await
@@ -406,7 +457,10 @@ async fn test_set_len() {
             );
 
             // size decrease, preserve opened writer content
-            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
             let data = "37";
             write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
                 .await
@@ -446,8 +500,7 @@ async fn test_copy_file_range() {
                     ROOT_INODE,
                     &test_file_1,
                     create_attr(FileType::RegularFile),
-                    true,
-                    true,
+                    OpenFlags::new(true, true),
                 )
                 .await
                 .unwrap();
@@ -457,15 +510,17 @@ async fn test_copy_file_range() {
                 .unwrap();
             fs.flush(fh).await.unwrap();
             fs.release(fh).await.unwrap();
-            let fh = fs.open(attr_1.ino, true, false).await.unwrap();
+            let fh = fs
+                .open(attr_1.ino, OpenFlags::new(true, false))
+                .await
+                .unwrap();
             let test_file_2 = SecretString::from_str("test-file-2").unwrap();
             let (fh2, attr_2) = fs
                 .create(
                     ROOT_INODE,
                     &test_file_2,
                     create_attr(FileType::RegularFile),
-                    true,
-                    true,
+                    OpenFlags::new(true, true),
                 )
                 .await
                 .unwrap();
@@ -475,30 +530,51 @@ async fn test_copy_file_range() {
             fs.flush(fh2).await.unwrap();
             fs.release(fh2).await.unwrap();
             let mut buf = [0; 7];
-            let fh = fs.open(attr_2.ino, true, false).await.unwrap();
+            let fh = fs
+                .open(attr_2.ino, OpenFlags::new(true, false))
+                .await
+                .unwrap();
             test_common::read_exact(&fs, attr_2.ino, 0, &mut buf, fh).await;
             assert_eq!(data, String::from_utf8(buf.to_vec()).unwrap());
 
             // offset
             let data_37 = "37";
-            let fh = fs.open(attr_1.ino, false, true).await.unwrap();
+            let fh = fs
+                .open(attr_1.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
             write_all_bytes_to_fs(&fs, attr_1.ino, 7, data_37.as_bytes(), fh)
                 .await
                 .unwrap();
             fs.flush(fh).await.unwrap();
             fs.release(fh).await.unwrap();
-            let fh = fs.open(attr_1.ino, true, false).await.unwrap();
-            let fh_2 = fs.open(attr_2.ino, false, true).await.unwrap();
+            let fh = fs
+                .open(attr_1.ino, OpenFlags::new(true, false))
+                .await
+                .unwrap();
+            let fh_2 = fs
+                .open(attr_2.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
             test_common::copy_all_file_range(&fs, attr_1.ino, 7, attr_2.ino, 5, 2, fh, fh_2).await;
             fs.flush(fh_2).await.unwrap();
             fs.release(fh_2).await.unwrap();
-            let fh = fs.open(attr_2.ino, true, false).await.unwrap();
+            let fh = fs
+                .open(attr_2.ino, OpenFlags::new(true, false))
+                .await
+                .unwrap();
             test_common::read_exact(&fs, attr_2.ino, 0, &mut buf, fh).await;
             assert_eq!("test-37", String::from_utf8(buf.to_vec()).unwrap());
 
             // out of bounds
-            let fh = fs.open(attr_1.ino, true, false).await.unwrap();
-            let fh_2 = fs.open(attr_2.ino, false, true).await.unwrap();
+            let fh = fs
+                .open(attr_1.ino, OpenFlags::new(true, false))
+                .await
+                .unwrap();
+            let fh_2 = fs
+                .open(attr_2.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
             let file_range_req = CopyFileRangeReq::builder()
                 .src_ino(attr_1.ino)
                 .src_offset(42)
@@ -549,8 +625,7 @@ async fn test_read_dir() {
                     ROOT_INODE,
                     &test_file,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -561,8 +636,7 @@ async fn test_read_dir() {
                     ROOT_INODE,
                     &test_dir,
                     create_attr(FileType::Directory),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -632,8 +706,7 @@ async fn test_read_dir() {
                     parent,
                     &test_file_2,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -644,8 +717,7 @@ async fn test_read_dir() {
                     parent,
                     &test_file_3,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -656,8 +728,7 @@ async fn test_read_dir() {
                     parent,
                     &test_dir_2,
                     create_attr(FileType::Directory),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -745,8 +816,7 @@ async fn test_read_dir_plus() {
                     ROOT_INODE,
                     &test_file,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -757,8 +827,7 @@ async fn test_read_dir_plus() {
                     ROOT_INODE,
                     &test_dir,
                     create_attr(FileType::Directory),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -841,8 +910,7 @@ async fn test_read_dir_plus() {
                     parent,
                     &test_file_2,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -853,8 +921,7 @@ async fn test_read_dir_plus() {
                     parent,
                     &test_dir_2,
                     create_attr(FileType::Directory),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -946,24 +1013,2817 @@ async fn test_find_by_name() {
             let (_fh, file_attr) = fs
                 .create(
                     ROOT_INODE,
-                    &test_file,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(
+                Some(file_attr),
+                fs.find_by_name(ROOT_INODE, &test_file).await.unwrap()
+            );
+            assert_eq!(
+                None,
+                fs.find_by_name(ROOT_INODE, &SecretString::from_str("42").unwrap())
+                    .await
+                    .unwrap()
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_exists_by_name() {
+    run_test(
+        TestSetup {
+            key: "test_exists_by_name",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            for file in ["test-file", "test--file"] {
+                let test_file = SecretString::from_str(file).unwrap();
+                let _ = fs
+                    .create(
+                        ROOT_INODE,
+                        &test_file,
+                        create_attr(FileType::RegularFile),
+                        OpenFlags::new(false, false),
+                    )
+                    .await
+                    .unwrap();
+
+                assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+                assert!(
+                    !(fs.exists_by_name(ROOT_INODE, &SecretString::from_str("42").unwrap())
+                        .unwrap())
+                );
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_remove_dir() {
+    run_test(
+        TestSetup {
+            key: "test_remove_dir",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            for dir in ["test-dir", "test-dir_", "test-dir-"] {
+                let test_dir = SecretString::from_str(dir).unwrap();
+                let _ = fs
+                    .create(
+                        ROOT_INODE,
+                        &test_dir,
+                        create_attr(FileType::Directory),
+                        OpenFlags::new(false, false),
+                    )
+                    .await
+                    .unwrap();
+
+                assert!(fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
+                fs.remove_dir(ROOT_INODE, &test_dir).await.unwrap();
+                assert!(!fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
+                assert_eq!(None, fs.find_by_name(ROOT_INODE, &test_dir).await.unwrap());
+                assert_eq!(
+                    0,
+                    fs.read_dir(ROOT_INODE)
+                        .await
+                        .unwrap()
+                        .filter(|entry| {
+                            entry.as_ref().unwrap().name.expose_secret() == test_dir.expose_secret()
+                        })
+                        .count()
+                );
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_remove_file() {
+    run_test(
+        TestSetup {
+            key: "test_remove_file",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            for dir in ["test-dir", "test-dir_", "test-dir-clear"] {
+                let test_file = SecretString::from_str(dir).unwrap();
+                let _ = fs
+                    .create(
+                        ROOT_INODE,
+                        &test_file,
+                        create_attr(FileType::RegularFile),
+                        OpenFlags::new(false, false),
+                    )
+                    .await
+                    .unwrap();
+
+                assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+                fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
+                assert!(!fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+                assert_eq!(None, fs.find_by_name(ROOT_INODE, &test_file).await.unwrap());
+                assert_eq!(
+                    0,
+                    fs.read_dir(ROOT_INODE)
+                        .await
+                        .unwrap()
+                        .filter(|entry| {
+                            entry.as_ref().unwrap().name.expose_secret()
+                                == test_file.expose_secret()
+                        })
+                        .count()
+                );
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_symlink() {
+    run_test(
+        TestSetup {
+            key: "test_symlink",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_link = SecretString::from_str("test-link").unwrap();
+            let target = SecretString::from_str("../some/target-file").unwrap();
+            let attr = fs
+                .create_symlink(
+                    ROOT_INODE,
+                    &test_link,
+                    &target,
+                    create_attr(FileType::Symlink),
+                )
+                .await
+                .unwrap();
+            assert_eq!(FileType::Symlink, attr.kind);
+            assert_eq!(target.expose_secret().len() as u64, attr.size);
+            assert_eq!(
+                target.expose_secret().as_str(),
+                fs.read_link(attr.ino)
+                    .await
+                    .unwrap()
+                    .expose_secret()
+                    .as_str()
+            );
+            // target is not stored in plaintext
+            let raw =
+                std::fs::read(fs.data_dir.join(CONTENTS_DIR).join(attr.ino.to_string())).unwrap();
+            assert!(!raw
+                .windows(target.expose_secret().len())
+                .any(|w| w == target.expose_secret().as_bytes()));
+
+            let found = fs.find_by_name(ROOT_INODE, &test_link).await.unwrap();
+            assert_eq!(Some(attr), found);
+            assert_eq!(
+                1,
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .filter(|entry| {
+                        let entry = entry.as_ref().unwrap();
+                        entry.kind == FileType::Symlink
+                            && entry.name.expose_secret() == test_link.expose_secret()
+                    })
+                    .count()
+            );
+
+            // symlinks cannot be opened and regular files cannot be read as links
+            assert!(matches!(
+                fs.open(attr.ino, OpenFlags::new(true, false)).await,
+                Err(FsError::InvalidInodeType)
+            ));
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (_, file_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+            assert!(matches!(
+                fs.read_link(file_attr.ino).await,
+                Err(FsError::InvalidInodeType)
+            ));
+            assert!(matches!(
+                fs.create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-link-2").unwrap(),
+                    create_attr(FileType::Symlink),
+                    OpenFlags::new(false, false)
+                )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+            assert!(matches!(
+                fs.create_symlink(
+                    ROOT_INODE,
+                    &test_link,
+                    &target,
+                    create_attr(FileType::Symlink),
+                )
+                .await,
+                Err(FsError::AlreadyExists)
+            ));
+
+            fs.remove_file(ROOT_INODE, &test_link).await.unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &test_link).unwrap());
+            assert!(!fs.exists(attr.ino));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_link() {
+    run_test(
+        TestSetup {
+            key: "test_link",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true),
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(1, attr.nlink);
+
+            let test_dir = SecretString::from_str("test-dir").unwrap();
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_dir,
+                    create_attr(FileType::Directory),
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+            let test_link = SecretString::from_str("test-link").unwrap();
+            let link_attr = fs.link(attr.ino, dir_attr.ino, &test_link).await.unwrap();
+            assert_eq!(attr.ino, link_attr.ino);
+            assert_eq!(2, link_attr.nlink);
+            assert_eq!(2, fs.get_attr(attr.ino).await.unwrap().nlink);
+            let found = fs
+                .find_by_name(dir_attr.ino, &test_link)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(attr.ino, found.ino);
+            assert_eq!("test-42", test_common::read_to_string(found.ino, &fs).await);
+
+            assert!(matches!(
+                fs.link(attr.ino, dir_attr.ino, &test_link).await,
+                Err(FsError::AlreadyExists)
+            ));
+            assert!(matches!(
+                fs.link(
+                    dir_attr.ino,
+                    ROOT_INODE,
+                    &SecretString::from_str("test-dir-link").unwrap()
+                )
+                .await,
+                Err(FsError::InvalidInodeType)
+            ));
+
+            // removing one link keeps the inode and contents
+            fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+            assert!(fs.exists(attr.ino));
+            assert_eq!(1, fs.get_attr(attr.ino).await.unwrap().nlink);
+            assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+
+            // removing the last link drops the inode and contents
+            fs.remove_file(dir_attr.ino, &test_link).await.unwrap();
+            assert!(!fs.exists(attr.ino));
+            assert!(!fs
+                .data_dir
+                .join(CONTENTS_DIR)
+                .join(attr.ino.to_string())
+                .exists());
+
+            // renaming over an entry drops its link the same way
+            let (_, replaced_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+            fs.link(replaced_attr.ino, dir_attr.ino, &test_link)
+                .await
+                .unwrap();
+            let test_file_2 = SecretString::from_str("test-file-2").unwrap();
+            for (parent, name, nlink) in
+                [(ROOT_INODE, &test_file, 1), (dir_attr.ino, &test_link, 0)]
+            {
+                fs.create(
+                    ROOT_INODE,
+                    &test_file_2,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+                fs.rename(ROOT_INODE, &test_file_2, parent, name)
+                    .await
+                    .unwrap();
+                if nlink > 0 {
+                    assert_eq!(nlink, fs.get_attr(replaced_attr.ino).await.unwrap().nlink);
+                }
+            }
+            assert!(!fs.exists(replaced_attr.ino));
+            assert!(!fs
+                .data_dir
+                .join(CONTENTS_DIR)
+                .join(replaced_attr.ino.to_string())
+                .exists());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_xattr() {
+    run_test(
+        TestSetup {
+            key: "test_xattr",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+            assert!(fs.list_xattr(attr.ino).await.unwrap().is_empty());
+            assert!(matches!(
+                fs.get_xattr(attr.ino, "user.test").await,
+                Err(FsError::NotFound(_))
+            ));
+
+            fs.set_xattr(attr.ino, "user.test", b"value", SetXattrMode::Upsert)
+                .await
+                .unwrap();
+            fs.set_xattr(attr.ino, "user.empty", b"", SetXattrMode::Create)
+                .await
+                .unwrap();
+            assert_eq!(
+                b"value".to_vec(),
+                fs.get_xattr(attr.ino, "user.test").await.unwrap()
+            );
+            assert!(fs
+                .get_xattr(attr.ino, "user.empty")
+                .await
+                .unwrap()
+                .is_empty());
+            assert_eq!(
+                vec!["user.empty".to_string(), "user.test".to_string()],
+                fs.list_xattr(attr.ino).await.unwrap()
+            );
+            // values are not stored in plaintext
+            let raw = std::fs::read(
+                fs.data_dir
+                    .join(INODES_DIR)
+                    .join(format!("{}.{XATTR_EXT}", attr.ino)),
+            )
+            .unwrap();
+            assert!(!raw.windows(9).any(|w| w == b"user.test"));
+
+            assert!(matches!(
+                fs.set_xattr(attr.ino, "user.test", b"new", SetXattrMode::Create)
+                    .await,
+                Err(FsError::AlreadyExists)
+            ));
+            assert!(matches!(
+                fs.set_xattr(attr.ino, "user.missing", b"new", SetXattrMode::Replace)
+                    .await,
+                Err(FsError::NotFound(_))
+            ));
+            fs.set_xattr(attr.ino, "user.test", b"new", SetXattrMode::Replace)
+                .await
+                .unwrap();
+            assert_eq!(
+                b"new".to_vec(),
+                fs.get_xattr(attr.ino, "user.test").await.unwrap()
+            );
+
+            fs.remove_xattr(attr.ino, "user.test").await.unwrap();
+            assert!(matches!(
+                fs.remove_xattr(attr.ino, "user.test").await,
+                Err(FsError::NotFound(_))
+            ));
+            assert_eq!(
+                vec!["user.empty".to_string()],
+                fs.list_xattr(attr.ino).await.unwrap()
+            );
+
+            // removed together with the inode
+            fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
+            assert!(!fs
+                .data_dir
+                .join(INODES_DIR)
+                .join(format!("{}.{XATTR_EXT}", attr.ino))
+                .exists());
+            assert!(matches!(
+                fs.list_xattr(attr.ino).await,
+                Err(FsError::InodeNotFound)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_acl() {
+    run_test(
+        TestSetup {
+            key: "test_acl",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-dir").unwrap(),
+                    CreateFileAttr {
+                        perm: 0o755,
+                        ..create_attr(FileType::Directory)
+                    },
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+            let default_acl = Acl {
+                entries: vec![
+                    AclEntry {
+                        tag: AclTag::UserObj,
+                        perm: 0o7,
+                    },
+                    AclEntry {
+                        tag: AclTag::User(1000),
+                        perm: 0o5,
+                    },
+                    AclEntry {
+                        tag: AclTag::GroupObj,
+                        perm: 0o5,
+                    },
+                    AclEntry {
+                        tag: AclTag::Mask,
+                        perm: 0o5,
+                    },
+                    AclEntry {
+                        tag: AclTag::Other,
+                        perm: 0,
+                    },
+                ],
+            };
+            assert_eq!(
+                default_acl,
+                Acl::from_bytes(&default_acl.to_bytes()).unwrap()
+            );
+            assert!(matches!(
+                fs.set_xattr(
+                    dir_attr.ino,
+                    ACL_DEFAULT_XATTR,
+                    &[2, 0, 0, 0, 1],
+                    SetXattrMode::Upsert
+                )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+            fs.set_xattr(
+                dir_attr.ino,
+                ACL_DEFAULT_XATTR,
+                &default_acl.to_bytes(),
+                SetXattrMode::Upsert,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                Some(default_acl.clone()),
+                fs.get_default_acl(dir_attr.ino).await.unwrap()
+            );
+            assert_eq!(None, fs.get_access_acl(dir_attr.ino).await.unwrap());
+
+            // file inherits the default ACL limited by the requested mode
+            let (_, file_attr) = fs
+                .create(
+                    dir_attr.ino,
+                    &SecretString::from_str("test-file").unwrap(),
+                    CreateFileAttr {
+                        perm: 0o666,
+                        ..create_attr(FileType::RegularFile)
+                    },
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+            assert_eq!(0o640, file_attr.perm);
+            let acl = fs.get_access_acl(file_attr.ino).await.unwrap().unwrap();
+            assert!(acl.check(0, 0, 1000, &[1000], libc::R_OK));
+            assert!(!acl.check(0, 0, 1000, &[1000], libc::W_OK));
+            assert!(acl.check(0, 0, 2000, &[0], libc::R_OK));
+            assert!(!acl.check(0, 0, 2000, &[2000], libc::R_OK));
+            assert!(acl.check(0, 0, 0, &[0], libc::R_OK | libc::W_OK));
+            assert_eq!(None, fs.get_default_acl(file_attr.ino).await.unwrap());
+            assert!(matches!(
+                fs.set_xattr(
+                    file_attr.ino,
+                    ACL_DEFAULT_XATTR,
+                    &default_acl.to_bytes(),
+                    SetXattrMode::Upsert
+                )
+                .await,
+                Err(FsError::InvalidInodeType)
+            ));
+
+            // directory also inherits the default ACL
+            let (_, sub_dir_attr) = fs
+                .create(
+                    dir_attr.ino,
+                    &SecretString::from_str("test-sub-dir").unwrap(),
+                    CreateFileAttr {
+                        perm: 0o777,
+                        ..create_attr(FileType::Directory)
+                    },
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+            assert_eq!(0o750, sub_dir_attr.perm);
+            assert_eq!(
+                Some(default_acl.clone()),
+                fs.get_default_acl(sub_dir_attr.ino).await.unwrap()
+            );
+            assert_eq!(
+                Some(default_acl.clone()),
+                fs.get_access_acl(sub_dir_attr.ino).await.unwrap()
+            );
+
+            // chmod updates the access ACL
+            fs.set_attr(file_attr.ino, SetFileAttr::default().with_perm(0o600))
+                .await
+                .unwrap();
+            let acl = fs.get_access_acl(file_attr.ino).await.unwrap().unwrap();
+            assert!(!acl.check(0, 0, 1000, &[1000], libc::R_OK));
+            assert_eq!(0o600, acl.mode(0));
+
+            // setting the access ACL updates the mode
+            fs.set_xattr(
+                file_attr.ino,
+                ACL_ACCESS_XATTR,
+                &default_acl.to_bytes(),
+                SetXattrMode::Upsert,
+            )
+            .await
+            .unwrap();
+            assert_eq!(0o750, fs.get_attr(file_attr.ino).await.unwrap().perm);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_special_files() {
+    run_test(
+        TestSetup {
+            key: "test_special_files",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            for (name, kind, rdev) in [
+                ("test-fifo", FileType::NamedPipe, 0),
+                ("test-socket", FileType::Socket, 0),
+                ("test-char", FileType::CharDevice, 0x0103),
+                ("test-block", FileType::BlockDevice, 0x0801),
+            ] {
+                let name = SecretString::from_str(name).unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &name,
+                        CreateFileAttr {
+                            rdev,
+                            ..create_attr(kind)
+                        },
+                        OpenFlags::new(false, false),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(0, fh);
+                assert_eq!(kind, attr.kind);
+                assert_eq!(rdev, attr.rdev);
+
+                // persisted in the inode
+                fs.attr_cache.get().await.unwrap().write().await.clear();
+                let attr2 = fs.get_attr(attr.ino).await.unwrap();
+                assert_eq!(kind, attr2.kind);
+                assert_eq!(rdev, attr2.rdev);
+                assert_eq!(
+                    1,
+                    fs.read_dir(ROOT_INODE)
+                        .await
+                        .unwrap()
+                        .filter(|entry| {
+                            let entry = entry.as_ref().unwrap();
+                            entry.kind == kind && entry.name.expose_secret() == name.expose_secret()
+                        })
+                        .count()
+                );
+                assert!(matches!(
+                    fs.open(attr.ino, OpenFlags::new(true, false)).await,
+                    Err(FsError::InvalidInodeType)
+                ));
+
+                fs.remove_file(ROOT_INODE, &name).await.unwrap();
+                assert!(!fs.exists(attr.ino));
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_allocate() {
+    run_test(
+        TestSetup {
+            key: "test_allocate",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true),
+                )
+                .await
+                .unwrap();
+            let data = "x".repeat(250);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let mut expected = data.clone();
+
+            // preallocate extends with zeros
+            fs.allocate(attr.ino, 200, 100, AllocateMode::default())
+                .await
+                .unwrap();
+            expected.push_str(&"\0".repeat(50));
+            assert_eq!(300, fs.get_attr(attr.ino).await.unwrap().size);
+            assert_eq!(expected, test_common::read_to_string(attr.ino, &fs).await);
+
+            // keep size doesn't change anything
+            fs.allocate(
+                attr.ino,
+                1000,
+                100,
+                AllocateMode::default().with_keep_size(true),
+            )
+            .await
+            .unwrap();
+            assert_eq!(300, fs.get_attr(attr.ino).await.unwrap().size);
+
+            // punch hole inside the file, across blocks
+            fs.allocate(
+                attr.ino,
+                10,
+                150,
+                AllocateMode::default()
+                    .with_keep_size(true)
+                    .with_punch_hole(true),
+            )
+            .await
+            .unwrap();
+            expected.replace_range(10..160, &"\0".repeat(150));
+            assert_eq!(300, fs.get_attr(attr.ino).await.unwrap().size);
+            assert_eq!(expected, test_common::read_to_string(attr.ino, &fs).await);
+
+            // zero range extending the file
+            fs.allocate(
+                attr.ino,
+                180,
+                140,
+                AllocateMode::default().with_zero_range(true),
+            )
+            .await
+            .unwrap();
+            expected.replace_range(180..300, &"\0".repeat(120));
+            expected.push_str(&"\0".repeat(20));
+            assert_eq!(320, fs.get_attr(attr.ino).await.unwrap().size);
+            assert_eq!(expected, test_common::read_to_string(attr.ino, &fs).await);
+
+            assert!(matches!(
+                fs.allocate(
+                    attr.ino,
+                    0,
+                    10,
+                    AllocateMode::default().with_punch_hole(true)
+                )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+            assert!(matches!(
+                fs.allocate(attr.ino, 0, 0, AllocateMode::default()).await,
+                Err(FsError::InvalidInput(_))
+            ));
+            assert!(matches!(
+                fs.allocate(ROOT_INODE, 0, 10, AllocateMode::default())
+                    .await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_sparse_file() {
+    run_test(
+        TestSetup {
+            key: "test_sparse_file",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true),
+                )
+                .await
+                .unwrap();
+            let holes_file = fs
+                .data_dir
+                .join(INODES_DIR)
+                .join(format!("{}.{HOLES_EXT}", attr.ino));
+            let contents_file = fs.data_dir.join(CONTENTS_DIR).join(attr.ino.to_string());
+            let ciphertext_block_size = fs.cipher.ciphertext_block_size();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"start", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let mut expected = "start".to_string();
+
+            // extending leaves holes, only the last block is encrypted
+            fs.set_len(attr.ino, 1050).await.unwrap();
+            expected.push_str(&"\0".repeat(1045));
+            assert_eq!(1050, fs.get_attr(attr.ino).await.unwrap().size);
+            assert_eq!(expected, test_common::read_to_string(attr.ino, &fs).await);
+            assert!(holes_file.is_file());
+            let contents = std::fs::read(&contents_file).unwrap();
+            assert!(contents[ciphertext_block_size..ciphertext_block_size * 10]
+                .iter()
+                .all(|b| *b == 0));
+            let old_holes = std::fs::read(&holes_file).unwrap();
+
+            // writing after the end leaves holes also
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 320, b"middle", fh)
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 2030, b"end", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            expected.replace_range(320..326, "middle");
+            expected.push_str(&"\0".repeat(980));
+            expected.push_str("end");
+            assert_eq!(2033, fs.get_attr(attr.ino).await.unwrap().size);
+            assert_eq!(expected, test_common::read_to_string(attr.ino, &fs).await);
+            let contents = std::fs::read(&contents_file).unwrap();
+            assert!(
+                contents[ciphertext_block_size * 11..ciphertext_block_size * 20]
+                    .iter()
+                    .all(|b| *b == 0)
+            );
+
+            // punch hole over whole blocks
+            fs.allocate(
+                attr.ino,
+                250,
+                500,
+                AllocateMode::default()
+                    .with_keep_size(true)
+                    .with_punch_hole(true),
+            )
+            .await
+            .unwrap();
+            expected.replace_range(250..750, &"\0".repeat(500));
+            assert_eq!(expected, test_common::read_to_string(attr.ino, &fs).await);
+
+            // the holes are bound to the file and to when they were saved
+            let holes = std::fs::read(&holes_file).unwrap();
+            std::fs::write(&holes_file, &old_holes).unwrap();
+            assert!(matches!(
+                fs.create_content_read_seek(attr.ino).await,
+                Err(FsError::Tampered(_))
+            ));
+            let (fh, other_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file-2").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true),
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            fs.set_len(other_attr.ino, 1050).await.unwrap();
+            std::fs::copy(fs.holes_file(other_attr.ino), &holes_file).unwrap();
+            assert!(matches!(
+                fs.create_content_read_seek(attr.ino).await,
+                Err(FsError::Tampered(_))
+            ));
+            std::fs::write(&holes_file, &holes).unwrap();
+            assert_eq!(expected, test_common::read_to_string(attr.ino, &fs).await);
+
+            // shrinking inside a hole
+            fs.set_len(attr.ino, 1510).await.unwrap();
+            expected.truncate(1510);
+            assert_eq!(expected, test_common::read_to_string(attr.ino, &fs).await);
+            fs.set_len(attr.ino, 200).await.unwrap();
+            expected.truncate(200);
+            assert_eq!(expected, test_common::read_to_string(attr.ino, &fs).await);
+            assert!(!holes_file.exists());
+
+            fs.set_len(attr.ino, 1000).await.unwrap();
+            assert!(holes_file.is_file());
+
+            // the holes are saved before a block after them is written, not only when flushing
+            fs.set_len(attr.ino, 200).await.unwrap();
+            assert!(!holes_file.exists());
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
+            fs.write(attr.ino, 1000, &[1; BLOCK_SIZE], fh)
+                .await
+                .unwrap();
+            assert!(
+                std::fs::metadata(&contents_file).unwrap().len()
+                    > 10 * ciphertext_block_size as u64
+            );
+            assert!(holes_file.is_file());
+            fs.release(fh).await.unwrap();
+
+            fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
+            assert!(!holes_file.exists());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_seek_data_hole() {
+    run_test(
+        TestSetup {
+            key: "test_seek_data_hole",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true),
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"start", fh)
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 520, b"middle", fh)
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 1010, b"end", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            // holes are blocks 1..5 and 6..10
+            assert_eq!(Some(3), fs.seek_data(attr.ino, 3).await.unwrap());
+            assert_eq!(Some(500), fs.seek_data(attr.ino, 150).await.unwrap());
+            assert_eq!(Some(1000), fs.seek_data(attr.ino, 600).await.unwrap());
+            assert_eq!(None, fs.seek_data(attr.ino, 1013).await.unwrap());
+            assert_eq!(Some(100), fs.seek_hole(attr.ino, 3).await.unwrap());
+            assert_eq!(Some(150), fs.seek_hole(attr.ino, 150).await.unwrap());
+            assert_eq!(Some(600), fs.seek_hole(attr.ino, 520).await.unwrap());
+            assert_eq!(Some(1013), fs.seek_hole(attr.ino, 1005).await.unwrap());
+            assert_eq!(None, fs.seek_hole(attr.ino, 2000).await.unwrap());
+
+            assert!(matches!(
+                fs.seek_data(ROOT_INODE, 0).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_locks() {
+    run_test(
+        TestSetup {
+            key: "test_locks",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true),
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let pid = std::process::id();
+
+            // shared locks don't conflict
+            fs.set_lock(attr.ino, 1, pid, 0, 99, Some(LockType::Read), false)
+                .await
+                .unwrap();
+            fs.set_lock(attr.ino, 2, pid, 50, 149, Some(LockType::Read), false)
+                .await
+                .unwrap();
+            assert!(matches!(
+                fs.set_lock(attr.ino, 3, pid, 90, 200, Some(LockType::Write), false)
+                    .await,
+                Err(FsError::WouldBlock)
+            ));
+            let lock = fs
+                .get_lock(attr.ino, 3, 120, 200, LockType::Write)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!((2, 50, 149), (lock.owner, lock.start, lock.end));
+            assert!(fs
+                .get_lock(attr.ino, 3, 150, u64::MAX, LockType::Write)
+                .await
+                .unwrap()
+                .is_none());
+
+            // unlocking part of a range keeps the rest
+            fs.set_lock(attr.ino, 2, pid, 50, 99, None, false)
+                .await
+                .unwrap();
+            fs.set_lock(attr.ino, 3, pid, 100, 120, Some(LockType::Read), false)
+                .await
+                .unwrap();
+            assert!(fs
+                .get_lock(attr.ino, 3, 0, 99, LockType::Read)
+                .await
+                .unwrap()
+                .is_none());
+            assert_eq!(
+                Some(2),
+                fs.get_lock(attr.ino, 3, 100, 200, LockType::Write)
+                    .await
+                    .unwrap()
+                    .map(|lock| lock.owner)
+            );
+
+            // blocking waits until the conflicting locks are released
+            let fs2 = fs.clone();
+            let ino = attr.ino;
+            let waiter = tokio::spawn(async move {
+                fs2.set_lock(ino, 4, pid, 0, u64::MAX, Some(LockType::Write), true)
+                    .await
+            });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert!(!waiter.is_finished());
+            fs.release_locks(attr.ino, 1).await;
+            fs.release_locks(attr.ino, 2).await;
+            fs.release_locks(attr.ino, 3).await;
+            waiter.await.unwrap().unwrap();
+            assert_eq!(
+                Some(4),
+                fs.get_lock(attr.ino, 1, 10, 10, LockType::Read)
+                    .await
+                    .unwrap()
+                    .map(|lock| lock.owner)
+            );
+
+            // flock is over the whole file, apart from the byte-range locks
+            fs.set_flock(attr.ino, 5, pid, Some(LockType::Write), false)
+                .await
+                .unwrap();
+            assert!(matches!(
+                fs.set_flock(attr.ino, 6, pid, Some(LockType::Read), false)
+                    .await,
+                Err(FsError::WouldBlock)
+            ));
+            fs.set_flock(attr.ino, 5, pid, Some(LockType::Read), false)
+                .await
+                .unwrap();
+            fs.set_flock(attr.ino, 6, pid, Some(LockType::Read), false)
+                .await
+                .unwrap();
+            fs.release_locks(attr.ino, 5).await;
+            fs.release_locks(attr.ino, 6).await;
+
+            // a holder which dies without releasing doesn't block the waiters for long
+            let mut child = std::process::Command::new("sleep")
+                .arg("60")
+                .spawn()
+                .unwrap();
+            fs.set_flock(attr.ino, 7, child.id(), Some(LockType::Write), false)
+                .await
+                .unwrap();
+            let fs2 = fs.clone();
+            let waiter = tokio::spawn(async move {
+                fs2.set_flock(ino, 8, pid, Some(LockType::Write), true)
+                    .await
+            });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert!(!waiter.is_finished());
+            child.kill().unwrap();
+            child.wait().unwrap();
+            tokio::time::timeout(Duration::from_secs(5), waiter)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_statfs() {
+    run_test(
+        TestSetup {
+            key: "test_statfs",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let stat = fs.statfs().unwrap();
+            assert_eq!(BLOCK_SIZE as u32, stat.bsize);
+            assert!(stat.blocks > 0);
+            assert!(stat.bfree <= stat.blocks);
+            assert!(stat.bavail <= stat.bfree);
+            assert!(stat.ffree <= stat.files);
+
+            // encryption overhead makes the volume smaller than the underlying filesystem
+            let mut underlying: libc::statvfs = unsafe { std::mem::zeroed() };
+            let path = std::ffi::CString::new(fs.data_dir.to_str().unwrap()).unwrap();
+            assert_eq!(0, unsafe { libc::statvfs(path.as_ptr(), &mut underlying) });
+            #[allow(clippy::unnecessary_cast)]
+            let underlying_bytes = underlying.f_blocks as u64 * underlying.f_frsize as u64;
+            assert!(stat.blocks * u64::from(stat.bsize) < underlying_bytes);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_concurrent_writers() {
+    run_test(
+        TestSetup {
+            key: "test_concurrent_writers",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true),
+                )
+                .await
+                .unwrap();
+            let fh_2 = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
+            let fh_read = fs
+                .open(attr.ino, OpenFlags::new(true, false))
+                .await
+                .unwrap();
+
+            // different blocks in parallel, from both handles
+            let mut tasks = vec![];
+            for i in 0..8_u8 {
+                let fs = fs.clone();
+                let fh = if i < 4 { fh } else { fh_2 };
+                tasks.push(tokio::spawn(async move {
+                    let data = vec![b'a' + i; BLOCK_SIZE];
+                    write_all_bytes_to_fs(
+                        &fs,
+                        attr.ino,
+                        u64::from(i) * BLOCK_SIZE as u64,
+                        &data,
+                        fh,
+                    )
+                    .await
+                    .unwrap();
+                }));
+            }
+            for task in tasks {
+                task.await.unwrap();
+            }
+            let mut expected: Vec<u8> =
+                (0..8_u8).flat_map(|i| vec![b'a' + i; BLOCK_SIZE]).collect();
+            assert_eq!(
+                expected.len() as u64,
+                fs.get_attr(attr.ino).await.unwrap().size
+            );
+
+            // the same block from both handles, the last write wins
+            write_all_bytes_to_fs(&fs, attr.ino, 10, b"first", fh)
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 12, b"second", fh_2)
+                .await
+                .unwrap();
+            expected[10..15].copy_from_slice(b"first");
+            expected[12..18].copy_from_slice(b"second");
+            // visible to readers before flushing
+            let mut buf = vec![0; expected.len()];
+            let mut read = 0;
+            while read < buf.len() {
+                let len = fs
+                    .read(attr.ino, read as u64, &mut buf[read..], fh_read)
+                    .await
+                    .unwrap();
+                assert_ne!(len, 0);
+                read += len;
+            }
+            assert_eq!(expected, buf);
+
+            // extending from one handle while the other is still opened
+            write_all_bytes_to_fs(&fs, attr.ino, expected.len() as u64 + 5, b"end", fh_2)
+                .await
+                .unwrap();
+            expected.extend_from_slice(b"\0\0\0\0\0end");
+            // reading doesn't write the last chunk to the content file
+            let contents_file = fs.data_dir.join(CONTENTS_DIR).join(attr.ino.to_string());
+            let contents_len = std::fs::metadata(&contents_file).unwrap().len();
+            let mut buf = vec![0; 8];
+            let len = fs
+                .read(attr.ino, 8 * BLOCK_SIZE as u64, &mut buf, fh_read)
+                .await
+                .unwrap();
+            assert_eq!(&expected[8 * BLOCK_SIZE..], &buf[..len]);
+            assert_eq!(
+                contents_len,
+                std::fs::metadata(&contents_file).unwrap().len()
+            );
+            fs.release(fh).await.unwrap();
+            assert_eq!(
+                expected.len() as u64,
+                fs.get_attr(attr.ino).await.unwrap().size
+            );
+            fs.release(fh_2).await.unwrap();
+            fs.release(fh_read).await.unwrap();
+            assert_eq!(
+                expected.len() as u64,
+                fs.get_attr(attr.ino).await.unwrap().size
+            );
+            assert_eq!(
+                String::from_utf8(expected).unwrap(),
+                test_common::read_to_string(attr.ino, &fs).await
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_rename_mode() {
+    run_test(
+        TestSetup {
+            key: "test_rename_mode",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let file_1 = SecretString::from_str("file-1").unwrap();
+            let (_, file_1_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &file_1,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+            let dir_1 = SecretString::from_str("dir-1").unwrap();
+            let (_, dir_1_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &dir_1,
+                    create_attr(FileType::Directory),
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+            let dir_2 = SecretString::from_str("dir-2").unwrap();
+            let (_, dir_2_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &dir_2,
+                    create_attr(FileType::Directory),
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+            let file_2 = SecretString::from_str("file-2").unwrap();
+            let (_, file_2_attr) = fs
+                .create(
+                    dir_2_attr.ino,
+                    &file_2,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+            let find = |parent: u64, name: &SecretString| {
+                let fs = fs.clone();
+                let name = name.clone();
+                async move { fs.find_by_name(parent, &name).await.unwrap().unwrap().ino }
+            };
+            let count = |parent: u64, name: &SecretString| {
+                let fs = fs.clone();
+                let name = name.clone();
+                async move {
+                    fs.read_dir(parent)
+                        .await
+                        .unwrap()
+                        .filter(|entry| {
+                            entry.as_ref().unwrap().name.expose_secret() == name.expose_secret()
+                        })
+                        .count()
+                }
+            };
+
+            // no replace
+            assert!(matches!(
+                fs.rename2(
+                    ROOT_INODE,
+                    &file_1,
+                    ROOT_INODE,
+                    &dir_1,
+                    RenameMode::NoReplace
+                )
+                .await,
+                Err(FsError::AlreadyExists)
+            ));
+            assert!(matches!(
+                fs.rename2(
+                    ROOT_INODE,
+                    &file_1,
+                    ROOT_INODE,
+                    &file_1,
+                    RenameMode::NoReplace
+                )
+                .await,
+                Err(FsError::AlreadyExists)
+            ));
+            assert_eq!(file_1_attr.ino, find(ROOT_INODE, &file_1).await);
+            assert_eq!(dir_1_attr.ino, find(ROOT_INODE, &dir_1).await);
+            let file_1_new = SecretString::from_str("file-1-new").unwrap();
+            fs.rename2(
+                ROOT_INODE,
+                &file_1,
+                ROOT_INODE,
+                &file_1_new,
+                RenameMode::NoReplace,
+            )
+            .await
+            .unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
+            assert_eq!(file_1_attr.ino, find(ROOT_INODE, &file_1_new).await);
+
+            // exchange needs both entries
+            assert!(matches!(
+                fs.rename2(
+                    ROOT_INODE,
+                    &file_1_new,
+                    ROOT_INODE,
+                    &file_1,
+                    RenameMode::Exchange
+                )
+                .await,
+                Err(FsError::NotFound(_))
+            ));
+            assert_eq!(file_1_attr.ino, find(ROOT_INODE, &file_1_new).await);
+
+            // exchange a file and a directory in the same parent
+            fs.rename2(
+                ROOT_INODE,
+                &file_1_new,
+                ROOT_INODE,
+                &dir_1,
+                RenameMode::Exchange,
+            )
+            .await
+            .unwrap();
+            assert_eq!(dir_1_attr.ino, find(ROOT_INODE, &file_1_new).await);
+            assert_eq!(file_1_attr.ino, find(ROOT_INODE, &dir_1).await);
+            assert_eq!(1, count(ROOT_INODE, &file_1_new).await);
+            assert_eq!(1, count(ROOT_INODE, &dir_1).await);
+            let dot_dot = SecretString::from_str("..").unwrap();
+            assert_eq!(ROOT_INODE, find(dir_1_attr.ino, &dot_dot).await);
+
+            // exchange across parents, the directory gets the new parent link
+            fs.rename2(
+                ROOT_INODE,
+                &file_1_new,
+                dir_2_attr.ino,
+                &file_2,
+                RenameMode::Exchange,
+            )
+            .await
+            .unwrap();
+            assert_eq!(file_2_attr.ino, find(ROOT_INODE, &file_1_new).await);
+            assert_eq!(dir_1_attr.ino, find(dir_2_attr.ino, &file_2).await);
+            assert_eq!(dir_2_attr.ino, find(dir_1_attr.ino, &dot_dot).await);
+            assert_eq!(1, count(ROOT_INODE, &file_1_new).await);
+            assert_eq!(1, count(dir_2_attr.ino, &file_2).await);
+
+            // concurrent renames to the same name without replacing, only one of them wins
+            let target = SecretString::from_str("target").unwrap();
+            let mut tasks = vec![];
+            for i in 0..8 {
+                let name = SecretString::from_str(&format!("source-{i}")).unwrap();
+                fs.create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+                let fs = fs.clone();
+                let target = target.clone();
+                tasks.push(tokio::spawn(async move {
+                    fs.rename2(
+                        ROOT_INODE,
+                        &name,
+                        ROOT_INODE,
+                        &target,
+                        RenameMode::NoReplace,
+                    )
+                    .await
+                }));
+            }
+            let mut renamed = 0;
+            for task in tasks {
+                match task.await.unwrap() {
+                    Ok(()) => renamed += 1,
+                    Err(FsError::AlreadyExists) => {}
+                    Err(err) => panic!("{err}"),
+                }
+            }
+            assert_eq!(1, renamed);
+            assert_eq!(1, count(ROOT_INODE, &target).await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_open_flags() {
+    run_test(
+        TestSetup {
+            key: "test_open_flags",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true).with_exclusive(true),
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"hello", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            // exclusive create fails if the file exists, else it's opened
+            assert!(matches!(
+                fs.create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true).with_exclusive(true),
+                )
+                .await,
+                Err(FsError::AlreadyExists)
+            ));
+            let (fh, existing_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(true, false),
+                )
+                .await
+                .unwrap();
+            assert_eq!(attr.ino, existing_attr.ino);
+            assert_eq!(5, existing_attr.size);
+            fs.release(fh).await.unwrap();
+
+            // truncate
+            assert!(matches!(
+                fs.open(attr.ino, OpenFlags::new(true, false).with_truncate(true))
+                    .await,
+                Err(FsError::InvalidInput(_))
+            ));
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(false, true).with_truncate(true))
+                .await
+                .unwrap();
+            assert_eq!(0, fs.get_attr(attr.ino).await.unwrap().size);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"start-", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            // append always writes at the end, from any handle
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(false, true).with_append(true))
+                .await
+                .unwrap();
+            let fh_2 = fs
+                .open(attr.ino, OpenFlags::new(false, true).with_append(true))
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"one-", fh)
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"two-", fh_2)
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 2, b"three", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            fs.release(fh_2).await.unwrap();
+            assert_eq!(
+                "start-one-two-three",
+                test_common::read_to_string(attr.ino, &fs).await
+            );
+
+            // sync writes are on disk before returning
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(false, true).with_sync(true))
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 19, b"-sync", fh)
+                .await
+                .unwrap();
+            assert_eq!(24, fs.get_inode_from_storage(attr.ino).await.unwrap().size);
+            // read the content file, not the cache of the handle
+            let mut reader = fs.create_content_read_seek(attr.ino).await.unwrap();
+            let mut content = String::new();
+            std::io::Read::read_to_string(&mut reader, &mut content).unwrap();
+            assert_eq!("start-one-two-three-sync", content);
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_fsync() {
+    run_test(
+        TestSetup {
+            key: "test_fsync",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true),
+                )
+                .await
+                .unwrap();
+            let data = b"test-42";
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data, fh)
+                .await
+                .unwrap();
+            assert_eq!(0, fs.get_inode_from_storage(attr.ino).await.unwrap().size);
+
+            // the size is needed to read the data back
+            fs.fsync(fh, true).await.unwrap();
+            assert_eq!(
+                data.len() as u64,
+                fs.get_inode_from_storage(attr.ino).await.unwrap().size
+            );
+            let mut buf = vec![0; data.len()];
+            let fh_read = fs
+                .open(attr.ino, OpenFlags::new(true, false))
+                .await
+                .unwrap();
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh_read).await;
+            assert_eq!(data, &buf[..]);
+
+            // write after a hole, the holes are saved too
+            let offset = 3 * BLOCK_SIZE as u64;
+            write_all_bytes_to_fs(&fs, attr.ino, offset, data, fh)
+                .await
+                .unwrap();
+            fs.fsync(fh, false).await.unwrap();
+            assert_eq!(
+                offset + data.len() as u64,
+                fs.get_inode_from_storage(attr.ino).await.unwrap().size
+            );
+            assert!(fs.holes_file(attr.ino).is_file());
+
+            // nothing to do for readers
+            fs.fsync(fh_read, false).await.unwrap();
+            assert!(matches!(
+                fs.fsync(42_u64, false).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+            fs.release(fh).await.unwrap();
+            fs.release(fh_read).await.unwrap();
+
+            // directories
+            let test_dir = SecretString::from_str("test-dir").unwrap();
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_dir,
+                    create_attr(FileType::Directory),
+                    OpenFlags::default(),
+                )
+                .await
+                .unwrap();
+            fs.remove_dir(ROOT_INODE, &test_dir).await.unwrap();
+            fs.fsync_dir(ROOT_INODE).await.unwrap();
+            assert!(matches!(
+                fs.fsync_dir(dir_attr.ino).await,
+                Err(FsError::InodeNotFound)
+            ));
+            assert!(matches!(
+                fs.fsync_dir(attr.ino).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_atime_mode() {
+    run_test(
+        TestSetup {
+            key: "test_atime_mode",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let data_dir = fs.data_dir.clone();
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true),
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let ino = attr.ino;
+
+            async fn read(fs: &EncryptedFs, ino: u64) -> SystemTime {
+                let fh = fs.open(ino, OpenFlags::new(true, false)).await.unwrap();
+                let mut buf = vec![0; 7];
+                test_common::read_exact(fs, ino, 0, &mut buf, fh).await;
+                fs.release(fh).await.unwrap();
+                fs.get_inode_from_storage(ino).await.unwrap().atime
+            }
+
+            for mode in AtimeMode::iter() {
+                let fs = EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                    mode,
+                )
+                .await
+                .unwrap();
+                // accessed before the last change
+                let mut attr = fs.get_inode_from_storage(ino).await.unwrap();
+                attr.atime = attr.mtime - Duration::from_secs(60);
+                fs.write_inode_to_storage(&attr).await.unwrap();
+
+                let atime = read(&fs, ino).await;
+                match mode {
+                    AtimeMode::NoAtime => assert_eq!(attr.atime, atime),
+                    AtimeMode::RelAtime | AtimeMode::StrictAtime => assert!(atime > attr.mtime),
+                }
+
+                // accessed after the last change, a moment ago
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                let atime_2 = read(&fs, ino).await;
+                match mode {
+                    AtimeMode::NoAtime | AtimeMode::RelAtime => assert_eq!(atime, atime_2),
+                    AtimeMode::StrictAtime => assert!(atime_2 > atime),
+                }
+
+                // accessed long ago
+                let mut attr = fs.get_inode_from_storage(ino).await.unwrap();
+                attr.atime = SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60);
+                attr.mtime = attr.atime - Duration::from_secs(60);
+                attr.ctime = attr.mtime;
+                fs.write_inode_to_storage(&attr).await.unwrap();
+                let atime = read(&fs, ino).await;
+                match mode {
+                    AtimeMode::NoAtime => assert_eq!(attr.atime, atime),
+                    AtimeMode::RelAtime | AtimeMode::StrictAtime => assert!(atime > attr.atime),
+                }
+
+                // listing a directory, the first one catches up with its creation
+                fs.read_dir(ROOT_INODE).await.unwrap();
+                let atime = fs.get_inode_from_storage(ROOT_INODE).await.unwrap().atime;
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                fs.read_dir(ROOT_INODE).await.unwrap();
+                let atime_2 = fs.get_inode_from_storage(ROOT_INODE).await.unwrap().atime;
+                match mode {
+                    AtimeMode::NoAtime | AtimeMode::RelAtime => assert_eq!(atime, atime_2),
+                    AtimeMode::StrictAtime => assert!(atime_2 > atime),
+                }
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_quota() {
+    run_test(
+        TestSetup {
+            key: "test_quota",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let block_size = BLOCK_SIZE as u64;
+            assert_eq!(None, fs.usage());
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(true, true),
+                )
+                .await
+                .unwrap();
+            let quota = Quota::default()
+                .with_max_bytes(3 * block_size)
+                .with_max_inodes(3);
+            fs.set_quota(quota).await.unwrap();
+            assert_eq!(quota, fs.quota());
+            assert_eq!(
+                Some(Usage {
+                    bytes: 0,
+                    inodes: 2
+                }),
+                fs.usage()
+            );
+
+            // inodes
+            let test_dir = SecretString::from_str("test-dir").unwrap();
+            fs.create(
+                ROOT_INODE,
+                &test_dir,
+                create_attr(FileType::Directory),
+                OpenFlags::default(),
+            )
+            .await
+            .unwrap();
+            let test_file_2 = SecretString::from_str("test-file-2").unwrap();
+            assert!(matches!(
+                fs.create(
+                    ROOT_INODE,
+                    &test_file_2,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true),
+                )
+                .await,
+                Err(FsError::QuotaExceeded)
+            ));
+            fs.remove_dir(ROOT_INODE, &test_dir).await.unwrap();
+            assert_eq!(2, fs.usage().unwrap().inodes);
+
+            // write
+            let data = vec![42_u8; 3 * BLOCK_SIZE];
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            assert!(matches!(
+                fs.write(attr.ino, 3 * block_size, b"a", fh).await,
+                Err(FsError::QuotaExceeded)
+            ));
+            // overwriting doesn't need more space
+            write_all_bytes_to_fs(&fs, attr.ino, block_size, b"test-42", fh)
+                .await
+                .unwrap();
+            let stat = fs.statfs().unwrap();
+            assert_eq!(3, stat.blocks);
+            assert_eq!(0, stat.bfree);
+            assert_eq!(3, stat.files);
+            assert_eq!(1, stat.ffree);
+
+            // set_len
+            assert!(matches!(
+                fs.set_len(attr.ino, 4 * block_size).await,
+                Err(FsError::QuotaExceeded)
+            ));
+            fs.set_len(attr.ino, block_size).await.unwrap();
+            assert_eq!(block_size, fs.usage().unwrap().bytes);
+
+            // copy_file_range
+            let (fh_2, attr_2) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file_2,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true),
+                )
+                .await
+                .unwrap();
+            let copy = |dest_offset| {
+                CopyFileRangeReq::builder()
+                    .src_ino(attr.ino)
+                    .src_offset(0)
+                    .dest_ino(attr_2.ino)
+                    .dest_offset(dest_offset)
+                    .src_fh(fh)
+                    .dest_fh(fh_2)
+                    .build()
+            };
+            assert!(matches!(
+                fs.copy_file_range(&copy(2 * block_size), BLOCK_SIZE).await,
+                Err(FsError::QuotaExceeded)
+            ));
+            assert_eq!(
+                BLOCK_SIZE,
+                fs.copy_file_range(&copy(block_size), BLOCK_SIZE)
+                    .await
+                    .unwrap()
+            );
+            assert_eq!(
+                Some(Usage {
+                    bytes: 3 * block_size,
+                    inodes: 3
+                }),
+                fs.usage()
+            );
+            fs.release(fh).await.unwrap();
+            fs.release(fh_2).await.unwrap();
+
+            // it's kept in the volume and usage is counted again when opened
+            let fs_2 = EncryptedFs::new(
+                fs.data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                AtimeMode::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(quota, fs_2.quota());
+            assert_eq!(fs.usage(), fs_2.usage());
+
+            // the file replaced by a rename frees its space
+            fs.rename(ROOT_INODE, &test_file_2, ROOT_INODE, &test_file)
+                .await
+                .unwrap();
+            assert_eq!(
+                Some(Usage {
+                    bytes: 2 * block_size,
+                    inodes: 2
+                }),
+                fs.usage()
+            );
+
+            fs.set_quota(Quota::default()).await.unwrap();
+            assert_eq!(None, fs.usage());
+            assert!(!fs.data_dir.join(SECURITY_DIR).join(QUOTA_FILENAME).exists());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_dir_from() {
+    run_test(
+        TestSetup {
+            key: "test_read_dir_from",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            for i in 0..20 {
+                let test_file = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+                fs.create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+            }
+            let names = |entries: Vec<String>| {
+                let mut entries = entries;
+                entries.sort();
+                entries
+            };
+            let all = names(
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .map(|entry| entry.unwrap().name.expose_secret().to_string())
+                    .collect(),
+            );
+            assert_eq!(21, all.len());
+
+            // continue after the first entries
+            let mut iter = fs.read_dir(ROOT_INODE).await.unwrap();
+            let mut listed: Vec<String> = iter
+                .by_ref()
+                .take(7)
+                .map(|entry| entry.unwrap().name.expose_secret().to_string())
+                .collect();
+            let cookie = iter.cookie();
+            drop(iter);
+            listed.extend(
+                fs.read_dir_from(ROOT_INODE, cookie)
+                    .await
+                    .unwrap()
+                    .map(|entry| entry.unwrap().name.expose_secret().to_string()),
+            );
+            assert_eq!(all, names(listed));
+
+            let mut iter = fs.read_dir_plus(ROOT_INODE).await.unwrap();
+            let mut listed: Vec<String> = iter
+                .by_ref()
+                .take(13)
+                .map(|entry| entry.unwrap().name.expose_secret().to_string())
+                .collect();
+            let cookie = iter.cookie();
+            drop(iter);
+            listed.extend(
+                fs.read_dir_plus_from(ROOT_INODE, cookie)
+                    .await
+                    .unwrap()
+                    .map(|entry| entry.unwrap().name.expose_secret().to_string()),
+            );
+            assert_eq!(all, names(listed));
+
+            // entries added or removed before continuing don't move the others
+            let mut iter = fs.read_dir(ROOT_INODE).await.unwrap();
+            let mut listed: Vec<String> = iter
+                .by_ref()
+                .take(10)
+                .map(|entry| entry.unwrap().name.expose_secret().to_string())
+                .collect();
+            let cookie = iter.cookie();
+            drop(iter);
+            for name in listed
+                .iter()
+                .filter(|name| name.starts_with("test-file-"))
+                .take(3)
+            {
+                fs.remove_file(ROOT_INODE, &SecretString::from_str(name).unwrap())
+                    .await
+                    .unwrap();
+            }
+            let new_file = SecretString::from_str("new-file").unwrap();
+            fs.create(
+                ROOT_INODE,
+                &new_file,
+                create_attr(FileType::RegularFile),
+                OpenFlags::new(false, false),
+            )
+            .await
+            .unwrap();
+            listed.extend(
+                fs.read_dir_from(ROOT_INODE, cookie)
+                    .await
+                    .unwrap()
+                    .map(|entry| entry.unwrap().name.expose_secret().to_string()),
+            );
+            // the new one is either before or after where it continued
+            listed.retain(|name| name != "new-file");
+            assert_eq!(all, names(listed));
+
+            // after the end
+            assert_eq!(
+                0,
+                fs.read_dir_from(ROOT_INODE, u64::MAX)
+                    .await
+                    .unwrap()
+                    .count()
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_dir_batches() {
+    run_test(
+        TestSetup {
+            key: "test_read_dir_batches",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            // more than two batches
+            let count = DIR_BATCH * 2 + 10;
+            for i in 0..count {
+                let test_file = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+                fs.create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+            }
+
+            let mut iter = fs.read_dir(ROOT_INODE).await.unwrap();
+            let mut cookies = vec![];
+            let mut names = HashSet::new();
+            while let Some(entry) = iter.next() {
+                names.insert(entry.unwrap().name.expose_secret().to_string());
+                cookies.push(iter.cookie());
+            }
+            // with ".", and in the order of their cookies
+            assert_eq!(count + 1, names.len());
+            assert!(cookies.windows(2).all(|w| w[0] < w[1]));
+
+            // continue in the middle of the second batch
+            let cookie = cookies[DIR_BATCH + 100];
+            let rest: Vec<u64> = {
+                let mut iter = fs.read_dir_from(ROOT_INODE, cookie).await.unwrap();
+                let mut rest = vec![];
+                while let Some(entry) = iter.next() {
+                    entry.unwrap();
+                    rest.push(iter.cookie());
+                }
+                rest
+            };
+            assert_eq!(cookies[DIR_BATCH + 101..], rest[..]);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_long_file_name() {
+    run_test(
+        TestSetup {
+            key: "test_long_file_name",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let contents_dir = fs.data_dir.join(CONTENTS_DIR).join(ROOT_INODE.to_string());
+            let count = |dir: &str| {
+                std::fs::read_dir(contents_dir.join(dir))
+                    .map(Iterator::count)
+                    .unwrap_or(0)
+            };
+
+            // too long for the ls entry once encrypted
+            let test_file = SecretString::from_str(&"a".repeat(NAME_MAX)).unwrap();
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+            assert_eq!(1, count(LONG_NAMES_DIR));
+            assert!(std::fs::read_dir(contents_dir.join(LS_DIR))
+                .unwrap()
+                .all(|entry| entry.unwrap().file_name().len() <= NAME_MAX));
+            assert_eq!(
+                attr.ino,
+                fs.find_by_name(ROOT_INODE, &test_file)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            assert!(fs.read_dir(ROOT_INODE).await.unwrap().any(|entry| {
+                *entry.unwrap().name.expose_secret() == *test_file.expose_secret()
+            }));
+
+            let new_name = SecretString::from_str(&"b".repeat(NAME_MAX - 1)).unwrap();
+            fs.rename(ROOT_INODE, &test_file, ROOT_INODE, &new_name)
+                .await
+                .unwrap();
+            assert_eq!(1, count(LONG_NAMES_DIR));
+            assert!(fs.read_dir(ROOT_INODE).await.unwrap().any(|entry| {
+                *entry.unwrap().name.expose_secret() == *new_name.expose_secret()
+            }));
+            fs.remove_file(ROOT_INODE, &new_name).await.unwrap();
+            assert_eq!(0, count(LONG_NAMES_DIR));
+
+            let test_file = SecretString::from_str(&"a".repeat(NAME_MAX + 1)).unwrap();
+            assert!(matches!(
+                fs.create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, false),
+                )
+                .await,
+                Err(FsError::NameTooLong)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_trash() {
+    run_test(
+        TestSetup {
+            key: "test_trash",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            fs.set_trash(Some(Trash::default())).await.unwrap();
+            assert_eq!(Some(Trash::default()), fs.trash());
+
+            let test_dir = SecretString::from_str("test-dir").unwrap();
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_dir,
+                    create_attr(FileType::Directory),
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    dir_attr.ino,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true),
+                )
+                .await
+                .unwrap();
+            fs.write(attr.ino, 0, b"test-data", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            // removed entries are kept
+            fs.remove_file(dir_attr.ino, &test_file).await.unwrap();
+            assert!(!fs.exists_by_name(dir_attr.ino, &test_file).unwrap());
+            assert!(fs.exists(attr.ino));
+            let entries = fs.trash_entries().await.unwrap();
+            assert_eq!(1, entries.len());
+            assert_eq!(attr.ino, entries[0].ino);
+            assert_eq!(FileType::RegularFile, entries[0].kind);
+            assert_eq!("/test-dir/test-file", *entries[0].path.expose_secret());
+
+            fs.restore_from_trash(attr.ino).await.unwrap();
+            assert!(fs.trash_entries().await.unwrap().is_empty());
+            assert_eq!(
+                9,
+                fs.find_by_name(dir_attr.ino, &test_file)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .size
+            );
+
+            // can't restore into a removed directory
+            fs.remove_file(dir_attr.ino, &test_file).await.unwrap();
+            fs.remove_dir(ROOT_INODE, &test_dir).await.unwrap();
+            assert_eq!(2, fs.trash_entries().await.unwrap().len());
+            assert!(matches!(
+                fs.restore_from_trash(attr.ino).await,
+                Err(FsError::NotFound(_))
+            ));
+            fs.restore_from_trash(dir_attr.ino).await.unwrap();
+            fs.restore_from_trash(attr.ino).await.unwrap();
+            assert!(fs.exists_by_name(dir_attr.ino, &test_file).unwrap());
+
+            fs.remove_file(dir_attr.ino, &test_file).await.unwrap();
+            fs.purge_from_trash(attr.ino).await.unwrap();
+            assert!(!fs.exists(attr.ino));
+            assert!(fs.trash_entries().await.unwrap().is_empty());
+
+            // the file replaced by a rename is kept too
+            let (_, replaced_attr) = fs
+                .create(
+                    dir_attr.ino,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+            let other_file = SecretString::from_str("other-file").unwrap();
+            let (_, other_attr) = fs
+                .create(
+                    dir_attr.ino,
+                    &other_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, false),
+                )
+                .await
+                .unwrap();
+            fs.rename(dir_attr.ino, &other_file, dir_attr.ino, &test_file)
+                .await
+                .unwrap();
+            let entries = fs.trash_entries().await.unwrap();
+            assert_eq!(1, entries.len());
+            assert_eq!(replaced_attr.ino, entries[0].ino);
+            assert_eq!("/test-dir/test-file", *entries[0].path.expose_secret());
+            fs.purge_from_trash(replaced_attr.ino).await.unwrap();
+            fs.remove_file(dir_attr.ino, &test_file).await.unwrap();
+            fs.purge_from_trash(other_attr.ino).await.unwrap();
+
+            // expired entries are purged
+            fs.remove_dir(ROOT_INODE, &test_dir).await.unwrap();
+            fs.set_trash(Some(
+                Trash::default().with_retention(Duration::from_secs(60)),
+            ))
+            .await
+            .unwrap();
+            fs.expire_trash().await.unwrap();
+            assert_eq!(1, fs.trash_entries().await.unwrap().len());
+            fs.set_trash(Some(Trash::default().with_retention(Duration::ZERO)))
+                .await
+                .unwrap();
+            fs.expire_trash().await.unwrap();
+            assert!(fs.trash_entries().await.unwrap().is_empty());
+            assert!(!fs.exists(dir_attr.ino));
+
+            // without the trash entries are removed
+            fs.set_trash(None).await.unwrap();
+            fs.create(
+                ROOT_INODE,
+                &test_file,
+                create_attr(FileType::RegularFile),
+                OpenFlags::new(false, false),
+            )
+            .await
+            .unwrap();
+            fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
+            assert!(fs.trash_entries().await.unwrap().is_empty());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_snapshot() {
+    run_test(
+        TestSetup {
+            key: "test_snapshot",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let read_all = |fs: Arc<EncryptedFs>, ino: u64| async move {
+                let size = fs.get_attr(ino).await.unwrap().size;
+                let fh = fs.open(ino, OpenFlags::new(true, false)).await.unwrap();
+                #[allow(clippy::cast_possible_truncation)]
+                let mut buf = vec![0; size as usize];
+                test_common::read_exact(&fs, ino, 0, &mut buf, fh).await;
+                fs.release(fh).await.unwrap();
+                buf
+            };
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true),
+                )
+                .await
+                .unwrap();
+            #[allow(clippy::cast_possible_truncation)]
+            let data: Vec<u8> = (0..BLOCK_SIZE * 3 + 100).map(|i| (i % 251) as u8).collect();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            fs.create_snapshot("snapshot-1").await.unwrap();
+            assert!(matches!(
+                fs.create_snapshot("snapshot-1").await,
+                Err(FsError::AlreadyExists)
+            ));
+            assert!(matches!(
+                fs.create_snapshot("../snapshot").await,
+                Err(FsError::InvalidInput(_))
+            ));
+            assert_eq!(vec!["snapshot-1".to_string()], fs.snapshots().unwrap());
+
+            // later changes
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, BLOCK_SIZE as u64 + 5, b"changed", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let mut changed = data.clone();
+            changed[BLOCK_SIZE + 5..BLOCK_SIZE + 12].copy_from_slice(b"changed");
+            assert_eq!(changed, read_all(fs.clone(), attr.ino).await);
+            let new_file = SecretString::from_str("new-file").unwrap();
+            fs.create(
+                ROOT_INODE,
+                &new_file,
+                create_attr(FileType::RegularFile),
+                OpenFlags::new(false, false),
+            )
+            .await
+            .unwrap();
+
+            let data_dir = snapshot_data_dir(&fs.data_dir, "snapshot-1");
+            // only the changed block is kept aside
+            let cow_file = data_dir.join(COW_DIR).join(attr.ino.to_string());
+            let mut cow = vec![];
+            File::open(&cow_file)
+                .unwrap()
+                .read_to_end(&mut cow)
+                .unwrap();
+            let block_size = Cipher::ChaCha20Poly1305.ciphertext_block_size();
+            assert_eq!(2 * block_size, cow.len());
+            assert!(cow[..block_size].iter().all(|b| *b == 0));
+
+            fs.set_len(attr.ino, 10).await.unwrap();
+            assert!(matches!(
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                    AtimeMode::default(),
+                )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+            let snapshot = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                true,
+                AtimeMode::default(),
+            )
+            .await
+            .unwrap();
+            let snapshot_attr = snapshot
+                .find_by_name(ROOT_INODE, &test_file)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(data.len() as u64, snapshot_attr.size);
+            assert_eq!(data, read_all(snapshot.clone(), attr.ino).await);
+            assert!(!snapshot.exists_by_name(ROOT_INODE, &new_file).unwrap());
+            assert_eq!(10, fs.get_attr(attr.ino).await.unwrap().size);
+            drop(snapshot);
+
+            fs.remove_snapshot("snapshot-1").unwrap();
+            assert!(fs.snapshots().unwrap().is_empty());
+            assert!(!data_dir.exists());
+
+            // taken while a file is being written, it has whole writes
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &[255; BLOCK_SIZE * 2], fh)
+                .await
+                .unwrap();
+            let writer = {
+                let fs = fs.clone();
+                tokio::spawn(async move {
+                    for i in 0..50_u8 {
+                        let data = vec![i; BLOCK_SIZE * 2];
+                        write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                            .await
+                            .unwrap();
+                    }
+                })
+            };
+            fs.create_snapshot("snapshot-2").await.unwrap();
+            writer.await.unwrap();
+            fs.release(fh).await.unwrap();
+            let snapshot = EncryptedFs::new(
+                snapshot_data_dir(&fs.data_dir, "snapshot-2"),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                true,
+                AtimeMode::default(),
+            )
+            .await
+            .unwrap();
+            let snapshot_data = read_all(snapshot.clone(), attr.ino).await;
+            assert_eq!(BLOCK_SIZE * 2, snapshot_data.len());
+            for block in snapshot_data.chunks(BLOCK_SIZE) {
+                assert!(block.iter().all(|b| *b == block[0]));
+            }
+            drop(snapshot);
+
+            // taken while files are created and renamed, it has none half done
+            let changer = {
+                let fs = fs.clone();
+                tokio::spawn(async move {
+                    for i in 0..30 {
+                        let name = SecretString::from_str(&format!("created-{i}")).unwrap();
+                        fs.create(
+                            ROOT_INODE,
+                            &name,
+                            create_attr(FileType::RegularFile),
+                            OpenFlags::new(false, false),
+                        )
+                        .await
+                        .unwrap();
+                        fs.rename(
+                            ROOT_INODE,
+                            &name,
+                            ROOT_INODE,
+                            &SecretString::from_str(&format!("renamed-{i}")).unwrap(),
+                        )
+                        .await
+                        .unwrap();
+                    }
+                })
+            };
+            tokio::task::yield_now().await;
+            fs.create_snapshot("snapshot-3").await.unwrap();
+            changer.await.unwrap();
+            let snapshot = EncryptedFs::new(
+                snapshot_data_dir(&fs.data_dir, "snapshot-3"),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                true,
+                AtimeMode::default(),
+            )
+            .await
+            .unwrap();
+            let entries: Vec<_> = snapshot
+                .read_dir_plus(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .collect();
+            for i in 0..30 {
+                let names = [format!("created-{i}"), format!("renamed-{i}")];
+                let found = entries
+                    .iter()
+                    .filter(|entry| names.contains(&entry.name.expose_secret().to_string()))
+                    .count();
+                assert!(found <= 1);
+            }
+            drop(snapshot);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_versions() {
+    run_test(
+        TestSetup {
+            key: "test_versions",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            fs.set_versioning(Some(Versioning::default().with_max_versions(2)))
+                .await
+                .unwrap();
+            assert_eq!(2, fs.versioning().unwrap().max_versions);
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true),
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"v1", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(1, fs.list_versions(attr.ino).await.unwrap().len());
+
+            // nothing changed
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(1, fs.list_versions(attr.ino).await.unwrap().len());
+
+            for content in [b"v2--", b"v3--"] {
+                let fh = fs
+                    .open(attr.ino, OpenFlags::new(false, true))
+                    .await
+                    .unwrap();
+                write_all_bytes_to_fs(&fs, attr.ino, 0, content, fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+            }
+            // the oldest is pruned
+            let versions = fs.list_versions(attr.ino).await.unwrap();
+            assert_eq!(
+                vec![2, 3],
+                versions
+                    .iter()
+                    .map(|version| version.id)
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(4, versions[0].size);
+
+            let mut buf = [0; 10];
+            assert_eq!(4, fs.read_version(attr.ino, 2, 0, &mut buf).await.unwrap());
+            assert_eq!(b"v2--", &buf[..4]);
+            assert_eq!(2, fs.read_version(attr.ino, 2, 2, &mut buf).await.unwrap());
+            assert_eq!(b"--", &buf[..2]);
+            assert_eq!(0, fs.read_version(attr.ino, 2, 4, &mut buf).await.unwrap());
+            assert!(matches!(
+                fs.read_version(attr.ino, 1, 0, &mut buf).await,
+                Err(FsError::NotFound(_))
+            ));
+
+            fs.restore_version(attr.ino, 2).await.unwrap();
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(true, false))
+                .await
+                .unwrap();
+            let mut buf = [0; 4];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            fs.release(fh).await.unwrap();
+            assert_eq!(b"v2--", &buf);
+
+            fs.set_versioning(Some(Versioning::default().with_max_age(Duration::ZERO)))
+                .await
+                .unwrap();
+            fs.expire_versions().await.unwrap();
+            assert!(fs.list_versions(attr.ino).await.unwrap().is_empty());
+
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"v4", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            fs.set_versioning(None).await.unwrap();
+            fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
+            assert!(!fs
+                .data_dir
+                .join(INODES_DIR)
+                .join(format!("{}.versions", attr.ino))
+                .exists());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_clone() {
+    run_test(
+        TestSetup {
+            key: "test_clone",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let block_size = BLOCK_SIZE as u64;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true),
+                )
+                .await
+                .unwrap();
+            let data: Vec<u8> = (0..3 * BLOCK_SIZE + 42).map(|i| (i % 251) as u8).collect();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let test_file_2 = SecretString::from_str("test-file-2").unwrap();
+            let (fh, attr_2) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file_2,
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true),
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            fs.clone_file(attr.ino, attr_2.ino).await.unwrap();
+            assert_eq!(
+                data.len() as u64,
+                fs.get_attr(attr_2.ino).await.unwrap().size
+            );
+            // the blocks are in a layer shared by both
+            let contents_dir = fs.data_dir.join(CONTENTS_DIR);
+            let layer = contents_dir.join(format!("{}.base.0", attr.ino));
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                assert_eq!(2, std::fs::metadata(&layer).unwrap().nlink());
+            }
+            let layer_2 = contents_dir.join(format!("{}.base.0", attr_2.ino));
+            assert!(layer_2.is_file());
+
+            let read_file = |ino| {
+                let fs = fs.clone();
+                async move {
+                    let fh = fs.open(ino, OpenFlags::new(true, false)).await.unwrap();
+                    let mut buf = vec![0; fs.get_attr(ino).await.unwrap().size as usize];
+                    test_common::read_exact(&fs, ino, 0, &mut buf, fh).await;
+                    fs.release(fh).await.unwrap();
+                    buf
+                }
+            };
+            assert_eq!(data, read_file(attr_2.ino).await);
+
+            // writes change only one side
+            let fh = fs
+                .open(attr_2.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr_2.ino, block_size + 1, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-37", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let mut expected = data.clone();
+            expected[..7].copy_from_slice(b"test-37");
+            assert_eq!(expected, read_file(attr.ino).await);
+            let mut expected_2 = data.clone();
+            expected_2[BLOCK_SIZE + 1..BLOCK_SIZE + 8].copy_from_slice(b"test-42");
+            assert_eq!(expected_2, read_file(attr_2.ino).await);
+
+            // truncate and extend reads the last block through the layer
+            fs.set_len(attr_2.ino, block_size + 4).await.unwrap();
+            fs.set_len(attr_2.ino, 2 * block_size).await.unwrap();
+            let mut buf = read_file(attr_2.ino).await;
+            assert_eq!(&expected_2[..BLOCK_SIZE + 4], &buf[..BLOCK_SIZE + 4]);
+            assert!(buf.split_off(BLOCK_SIZE + 4).iter().all(|b| *b == 0));
+
+            // the layer is freed with the last file
+            fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
+            assert!(!layer.is_file());
+            assert!(layer_2.is_file());
+            assert_eq!(
+                &expected_2[..BLOCK_SIZE + 4],
+                &read_file(attr_2.ino).await[..BLOCK_SIZE + 4]
+            );
+            fs.set_len(attr_2.ino, 0).await.unwrap();
+            assert!(!layer_2.is_file());
+
+            // copying the whole file clones it
+            let fh = fs
+                .open(attr_2.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr_2.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let test_file_3 = SecretString::from_str("test-file-3").unwrap();
+            let (fh_3, attr_3) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file_3,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, true),
                 )
                 .await
                 .unwrap();
-
-            assert_eq!(
-                Some(file_attr),
-                fs.find_by_name(ROOT_INODE, &test_file).await.unwrap()
-            );
+            let fh_2 = fs
+                .open(attr_2.ino, OpenFlags::new(true, false))
+                .await
+                .unwrap();
+            let file_range_req = CopyFileRangeReq::builder()
+                .src_ino(attr_2.ino)
+                .src_offset(0)
+                .dest_ino(attr_3.ino)
+                .dest_offset(0)
+                .src_fh(fh_2)
+                .dest_fh(fh_3)
+                .build();
+            // the handles are checked first
+            let invalid_req = CopyFileRangeReq::builder()
+                .src_ino(attr_2.ino)
+                .src_offset(0)
+                .dest_ino(attr_3.ino)
+                .dest_offset(0)
+                .src_fh(fh_3)
+                .dest_fh(fh_2)
+                .build();
+            assert!(matches!(
+                fs.copy_file_range(&invalid_req, 2 * data.len()).await,
+                Err(FsError::InvalidFileHandle)
+            ));
             assert_eq!(
-                None,
-                fs.find_by_name(ROOT_INODE, &SecretString::from_str("42").unwrap())
+                data.len(),
+                fs.copy_file_range(&file_range_req, 2 * data.len())
                     .await
                     .unwrap()
             );
+            fs.release(fh_2).await.unwrap();
+            fs.release(fh_3).await.unwrap();
+            assert!(contents_dir
+                .join(format!("{}.base.0", attr_3.ino))
+                .is_file());
+            assert_eq!(data, read_file(attr_3.ino).await);
+
+            assert!(matches!(
+                fs.clone_file(attr_3.ino, attr_3.ino).await,
+                Err(FsError::InvalidInput(_))
+            ));
+            assert!(matches!(
+                fs.clone_file(attr_3.ino, ROOT_INODE).await,
+                Err(FsError::InvalidInodeType)
+            ));
+
+            // cloning many times keeps the layers to look into limited
+            for _ in 0..content_file::MAX_LAYERS + 2 {
+                fs.clone_file(attr_3.ino, attr_2.ino).await.unwrap();
+            }
+            let contents_file_3 = contents_dir.join(attr_3.ino.to_string());
+            assert!(content_file::layers(&contents_file_3).len() <= content_file::MAX_LAYERS);
+            assert_eq!(data, read_file(attr_3.ino).await);
+            assert_eq!(data, read_file(attr_2.ino).await);
         },
     )
     .await;
@@ -972,34 +3832,127 @@ async fn test_find_by_name() {
 #[tokio::test]
 #[traced_test]
 #[allow(clippy::too_many_lines)]
-async fn test_exists_by_name() {
+async fn test_block_dedup() {
     run_test(
         TestSetup {
-            key: "test_exists_by_name",
+            key: "test_block_dedup",
             read_only: false,
         },
         async {
             let fs = get_fs().await;
+            fs.set_block_dedup(true).await.unwrap();
+            assert!(fs.block_dedup());
+            let chunks_dir = fs.data_dir.join(CHUNKS_DIR);
+            let chunks = || {
+                std::fs::read_dir(&chunks_dir)
+                    .map(|dir| dir.count())
+                    .unwrap_or(0)
+            };
 
-            for file in ["test-file", "test--file"] {
-                let test_file = SecretString::from_str(file).unwrap();
-                let _ = fs
+            // two identical blocks and the last one
+            let block: Vec<u8> = (0..BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+            let mut data = block.repeat(2);
+            data.extend_from_slice(b"test-42");
+            let read_file = |ino| {
+                let fs = fs.clone();
+                async move {
+                    let fh = fs.open(ino, OpenFlags::new(true, false)).await.unwrap();
+                    let mut buf = vec![0; fs.get_attr(ino).await.unwrap().size as usize];
+                    test_common::read_exact(&fs, ino, 0, &mut buf, fh).await;
+                    fs.release(fh).await.unwrap();
+                    buf
+                }
+            };
+            let mut inodes = vec![];
+            for name in ["test-file", "test-file-2"] {
+                let (fh, attr) = fs
                     .create(
                         ROOT_INODE,
-                        &test_file,
+                        &SecretString::from_str(name).unwrap(),
                         create_attr(FileType::RegularFile),
-                        false,
-                        false,
+                        OpenFlags::new(false, true),
                     )
                     .await
                     .unwrap();
-
-                assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
-                assert!(
-                    !(fs.exists_by_name(ROOT_INODE, &SecretString::from_str("42").unwrap())
-                        .unwrap())
-                );
+                write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                assert_eq!(2, chunks());
+                assert_eq!(data, read_file(attr.ino).await);
+                inodes.push(attr.ino);
             }
+            let stat = fs.statfs().unwrap();
+            assert_eq!(Some(2 * data.len() as u64), stat.logical_bytes);
+            // counted as the files change, the same as measuring them again
+            let physical_bytes = || fs.statfs().unwrap().physical_bytes.unwrap();
+            let measured = || dedup::measure(&fs.data_dir).unwrap();
+            assert!(physical_bytes() > 0);
+            assert_eq!(measured(), physical_bytes());
+
+            // a changed block is stored apart, the other file keeps the old one
+            let refs_file = fs.chunk_refs_file(inodes[0]);
+            let old_refs = std::fs::read(&refs_file).unwrap();
+            let fh = fs
+                .open(inodes[0], OpenFlags::new(false, true))
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, inodes[0], 1, b"test-37", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(3, chunks());
+            let mut expected = data.clone();
+            expected[1..8].copy_from_slice(b"test-37");
+            assert_eq!(expected, read_file(inodes[0]).await);
+            assert_eq!(data, read_file(inodes[1]).await);
+
+            // the references are bound to the file and to when they were saved
+            let refs = std::fs::read(&refs_file).unwrap();
+            std::fs::write(&refs_file, &old_refs).unwrap();
+            assert!(matches!(
+                fs.create_content_read_seek(inodes[0]).await,
+                Err(FsError::Tampered(_))
+            ));
+            std::fs::copy(fs.chunk_refs_file(inodes[1]), &refs_file).unwrap();
+            assert!(matches!(
+                fs.create_content_read_seek(inodes[0]).await,
+                Err(FsError::Tampered(_))
+            ));
+            std::fs::write(&refs_file, &refs).unwrap();
+            assert_eq!(expected, read_file(inodes[0]).await);
+
+            // truncating and extending reads the last block from the store
+            fs.set_len(inodes[1], BLOCK_SIZE as u64 + 3).await.unwrap();
+            fs.set_len(inodes[1], 2 * BLOCK_SIZE as u64).await.unwrap();
+            let buf = read_file(inodes[1]).await;
+            assert_eq!(&data[..BLOCK_SIZE + 3], &buf[..BLOCK_SIZE + 3]);
+            assert!(buf[BLOCK_SIZE + 3..].iter().all(|b| *b == 0));
+            assert_eq!(measured(), physical_bytes());
+
+            // the chunks are freed with the last reference
+            fs.create_snapshot("snapshot").await.unwrap();
+            fs.remove_file(ROOT_INODE, &SecretString::from_str("test-file").unwrap())
+                .await
+                .unwrap();
+            assert_eq!(measured(), physical_bytes());
+            fs.remove_snapshot("snapshot").unwrap();
+            assert_eq!(1, chunks());
+            assert_eq!(measured(), physical_bytes());
+            fs.remove_file(ROOT_INODE, &SecretString::from_str("test-file-2").unwrap())
+                .await
+                .unwrap();
+            assert_eq!(0, chunks());
+            assert_eq!(measured(), physical_bytes());
+
+            // kept between mounts, and measured again when turned back on
+            let path = fs.data_dir.join(SECURITY_DIR).join(PHYSICAL_SIZE_FILENAME);
+            assert!(path.is_file());
+
+            fs.set_block_dedup(false).await.unwrap();
+            assert!(!fs.block_dedup());
+            assert_eq!(None, fs.statfs().unwrap().logical_bytes);
+            assert!(!path.exists());
         },
     )
     .await;
@@ -1007,43 +3960,69 @@ async fn test_exists_by_name() {
 
 #[tokio::test]
 #[traced_test]
-#[allow(clippy::too_many_lines)]
-async fn test_remove_dir() {
+async fn test_compression() {
     run_test(
         TestSetup {
-            key: "test_remove_dir",
+            key: "test_compression",
             read_only: false,
         },
         async {
             let fs = get_fs().await;
-            for dir in ["test-dir", "test-dir_", "test-dir-"] {
-                let test_dir = SecretString::from_str(dir).unwrap();
-                let _ = fs
-                    .create(
-                        ROOT_INODE,
-                        &test_dir,
-                        create_attr(FileType::Directory),
-                        false,
-                        false,
-                    )
-                    .await
-                    .unwrap();
+            fs.set_compression(Some(Compression::Lz4)).await.unwrap();
+            assert_eq!(Some(Compression::Lz4), fs.compression());
 
-                assert!(fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
-                fs.remove_dir(ROOT_INODE, &test_dir).await.unwrap();
-                assert!(!fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
-                assert_eq!(None, fs.find_by_name(ROOT_INODE, &test_dir).await.unwrap());
-                assert_eq!(
-                    0,
-                    fs.read_dir(ROOT_INODE)
-                        .await
-                        .unwrap()
-                        .filter(|entry| {
-                            entry.as_ref().unwrap().name.expose_secret() == test_dir.expose_secret()
-                        })
-                        .count()
-                );
-            }
+            let data: Vec<u8> = (0..BLOCK_SIZE * 5 + 17).map(|i| (i % 7) as u8).collect();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true),
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            // the blocks keep their place, only their start is written
+            let ciphertext = std::fs::read(fs.contents_path(attr.ino)).unwrap();
+            let ciphertext_block_size = fs.cipher.ciphertext_block_size();
+            assert_eq!(
+                5 * ciphertext_block_size + 17 + ciphertext_block_size - BLOCK_SIZE,
+                ciphertext.len()
+            );
+            assert!(ciphertext[ciphertext_block_size / 2..ciphertext_block_size]
+                .iter()
+                .all(|b| *b == 0));
+
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(true, false))
+                .await
+                .unwrap();
+            let mut buf = vec![0; data.len()];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(data, buf);
+            let offset = BLOCK_SIZE * 3 + 42;
+            let mut buf = vec![0; data.len() - offset];
+            test_common::read_exact(&fs, attr.ino, offset as u64, &mut buf, fh).await;
+            assert_eq!(&data[offset..], buf);
+            fs.release(fh).await.unwrap();
+
+            // blocks written before turning it off are still read
+            fs.set_compression(None).await.unwrap();
+            let fh = fs.open(attr.ino, OpenFlags::new(true, true)).await.unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, BLOCK_SIZE as u64 + 3, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            let mut expected = data.clone();
+            expected[BLOCK_SIZE + 3..BLOCK_SIZE + 10].copy_from_slice(b"test-42");
+            let mut buf = vec![0; data.len()];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(expected, buf);
+            fs.release(fh).await.unwrap();
         },
     )
     .await;
@@ -1051,45 +4030,148 @@ async fn test_remove_dir() {
 
 #[tokio::test]
 #[traced_test]
-#[allow(clippy::too_many_lines)]
-async fn test_remove_file() {
+async fn test_replayed_blocks() {
     run_test(
         TestSetup {
-            key: "test_remove_file",
+            key: "test_replayed_blocks",
             read_only: false,
         },
         async {
             let fs = get_fs().await;
 
-            for dir in ["test-dir", "test-dir_", "test-dir-clear"] {
-                let test_file = SecretString::from_str(dir).unwrap();
-                let _ = fs
+            let data: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| (i % 7) as u8).collect();
+            let mut files = vec![];
+            for name in ["test-file-1", "test-file-2"] {
+                let (fh, attr) = fs
                     .create(
                         ROOT_INODE,
-                        &test_file,
+                        &SecretString::from_str(name).unwrap(),
                         create_attr(FileType::RegularFile),
-                        false,
-                        false,
+                        OpenFlags::new(false, true),
                     )
                     .await
                     .unwrap();
-
-                assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
-                fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
-                assert!(!fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
-                assert_eq!(None, fs.find_by_name(ROOT_INODE, &test_file).await.unwrap());
-                assert_eq!(
-                    0,
-                    fs.read_dir(ROOT_INODE)
-                        .await
-                        .unwrap()
-                        .filter(|entry| {
-                            entry.as_ref().unwrap().name.expose_secret()
-                                == test_file.expose_secret()
-                        })
-                        .count()
-                );
+                write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                files.push(attr.ino);
             }
+            let (ino, other_ino) = (files[0], files[1]);
+            let read_block = |block_index: u64| {
+                let fs = &fs;
+                async move {
+                    let mut reader = fs.create_content_read_seek(ino).await.unwrap();
+                    reader.seek(SeekFrom::Start(block_index * BLOCK_SIZE as u64))?;
+                    let mut buf = vec![0; BLOCK_SIZE];
+                    reader.read_exact(&mut buf).map(|()| buf)
+                }
+            };
+            let ciphertext = std::fs::read(fs.contents_path(ino)).unwrap();
+            let counters = std::fs::read(fs.counters_file(ino)).unwrap();
+
+            // the previous content of a block written again doesn't open anymore
+            let fh = fs.open(ino, OpenFlags::new(false, true)).await.unwrap();
+            write_all_bytes_to_fs(&fs, ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let mut expected = data[..BLOCK_SIZE].to_vec();
+            expected[..7].copy_from_slice(b"test-42");
+            assert_eq!(expected, read_block(0).await.unwrap());
+            std::fs::write(fs.contents_path(ino), &ciphertext).unwrap();
+            assert!(read_block(0).await.is_err());
+            assert_eq!(
+                data[BLOCK_SIZE..BLOCK_SIZE * 2],
+                read_block(1).await.unwrap()
+            );
+
+            // nor the blocks of another file with the same content
+            std::fs::copy(fs.contents_path(other_ino), fs.contents_path(ino)).unwrap();
+            assert!(read_block(1).await.is_err());
+
+            // nor with the write counters put back as they were, the inode knows they were synced further
+            std::fs::write(fs.contents_path(ino), &ciphertext).unwrap();
+            std::fs::write(fs.counters_file(ino), &counters).unwrap();
+            assert!(matches!(
+                fs.create_content_read_seek(ino).await,
+                Err(FsError::Tampered(_))
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_blocks_without_counters() {
+    run_test(
+        TestSetup {
+            key: "test_blocks_without_counters",
+            read_only: false,
+        },
+        async {
+            use crate::crypto::write::CryptoWrite;
+
+            let fs = get_fs().await;
+
+            let data: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| (i % 7) as u8).collect();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    OpenFlags::new(false, true),
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let read_all = || {
+                let fs = &fs;
+                async move {
+                    let fh = fs.open(attr.ino, OpenFlags::new(true, false)).await?;
+                    let mut buf = vec![0; BLOCK_SIZE * 3];
+                    let res = fs.read(attr.ino, 0, &mut buf, fh).await;
+                    fs.release(fh).await?;
+                    res.map(|len| buf[..len].to_vec())
+                }
+            };
+            // bound to their index only, like before the counters were kept
+            let mut writer = crypto::create_write(
+                File::create(fs.contents_path(attr.ino)).unwrap(),
+                fs.cipher,
+                &fs.key.get().await.unwrap(),
+            );
+            std::io::Write::write_all(&mut writer, &data).unwrap();
+            writer.finish().unwrap();
+            // the blocks of a file with an id don't open so
+            assert!(read_all().await.is_err());
+            // nor without its counters, they were removed
+            std::fs::remove_file(fs.counters_file(attr.ino)).unwrap();
+            assert!(matches!(read_all().await, Err(FsError::Tampered(_))));
+
+            // a file created before the ids were kept has none, its blocks open with their index only
+            let mut legacy_attr = fs.get_inode_from_storage(attr.ino).await.unwrap();
+            legacy_attr.file_id = None;
+            legacy_attr.counters_synced = 0;
+            fs.write_inode_to_storage(&legacy_attr).await.unwrap();
+            assert_eq!(data, read_all().await.unwrap());
+            // so are the ones written again
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, BLOCK_SIZE as u64, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let mut expected = data.clone();
+            expected[BLOCK_SIZE..BLOCK_SIZE + 7].copy_from_slice(b"test-42");
+            assert_eq!(expected, read_all().await.unwrap());
+            assert!(!fs.counters_file(attr.ino).exists());
         },
     )
     .await;
@@ -1114,8 +4196,7 @@ async fn test_find_by_name_exists_by_name100files() {
                         ROOT_INODE,
                         &test_file,
                         create_attr(FileType::RegularFile),
-                        false,
-                        false,
+                        OpenFlags::new(false, false),
                     )
                     .await
                     .unwrap();
@@ -1127,8 +4208,7 @@ async fn test_find_by_name_exists_by_name100files() {
                     ROOT_INODE,
                     &special_test_file,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1207,8 +4287,7 @@ async fn test_create() {
                     ROOT_INODE,
                     &test_file,
                     create_attr(FileType::RegularFile),
-                    true,
-                    false,
+                    OpenFlags::new(true, false),
                 )
                 .await
                 .unwrap();
@@ -1257,8 +4336,7 @@ async fn test_create() {
                     ROOT_INODE,
                     &test_dir,
                     create_attr(FileType::Directory),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1309,8 +4387,7 @@ async fn test_create() {
                     parent,
                     &test_dir_2,
                     create_attr(FileType::Directory),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1355,8 +4432,7 @@ async fn test_create() {
                     ROOT_INODE,
                     &test_file,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false
+                    OpenFlags::new(false, false)
                 )
                 .await,
                 Err(FsError::AlreadyExists)
@@ -1368,8 +4444,7 @@ async fn test_create() {
                     ROOT_INODE,
                     &test_dir,
                     create_attr(FileType::Directory),
-                    false,
-                    false
+                    OpenFlags::new(false, false)
                 )
                 .await,
                 Err(FsError::AlreadyExists)
@@ -1399,8 +4474,7 @@ async fn test_rename() {
                     ROOT_INODE,
                     &file_1,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1445,8 +4519,7 @@ async fn test_rename() {
                     ROOT_INODE,
                     &dir_1,
                     create_attr(FileType::Directory),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1521,8 +4594,7 @@ async fn test_rename() {
                     ROOT_INODE,
                     &dir_new_parent,
                     create_attr(FileType::Directory),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1534,8 +4606,7 @@ async fn test_rename() {
                     ROOT_INODE,
                     &file_1,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1586,8 +4657,7 @@ async fn test_rename() {
                     ROOT_INODE,
                     &dir_1,
                     create_attr(FileType::Directory),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1670,8 +4740,7 @@ async fn test_rename() {
                     ROOT_INODE,
                     &file_1,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1680,8 +4749,7 @@ async fn test_rename() {
                     new_parent,
                     &file_2,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1720,8 +4788,7 @@ async fn test_rename() {
                     ROOT_INODE,
                     &dir_1,
                     create_attr(FileType::Directory),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1730,8 +4797,7 @@ async fn test_rename() {
                     new_parent,
                     &dir_2,
                     create_attr(FileType::Directory),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1802,8 +4868,7 @@ async fn test_rename() {
                     ROOT_INODE,
                     &file_1,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1812,8 +4877,7 @@ async fn test_rename() {
                     new_parent,
                     &file_1,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1852,8 +4916,7 @@ async fn test_rename() {
                     ROOT_INODE,
                     &dir_1,
                     create_attr(FileType::Directory),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1862,8 +4925,7 @@ async fn test_rename() {
                     new_parent,
                     &dir_1,
                     create_attr(FileType::Directory),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1934,8 +4996,7 @@ async fn test_rename() {
                     ROOT_INODE,
                     &file_1,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1944,8 +5005,7 @@ async fn test_rename() {
                     new_parent,
                     &dir_1,
                     create_attr(FileType::Directory),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1985,8 +5045,7 @@ async fn test_rename() {
                     ROOT_INODE,
                     &dir_3,
                     create_attr(FileType::Directory),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -1995,8 +5054,7 @@ async fn test_rename() {
                     new_parent,
                     &file_1,
                     create_attr(FileType::Directory),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -2067,8 +5125,7 @@ async fn test_rename() {
                     ROOT_INODE,
                     &dir_3,
                     create_attr(FileType::Directory),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -2149,8 +5206,7 @@ async fn test_rename() {
                     ROOT_INODE,
                     &file_3,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -2180,8 +5236,7 @@ async fn test_rename() {
                     ROOT_INODE,
                     &dir_5,
                     create_attr(FileType::Directory),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -2247,8 +5302,7 @@ async fn test_rename() {
                     ROOT_INODE,
                     &existing_file,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
@@ -2291,24 +5345,35 @@ async fn test_open() {
                     ROOT_INODE,
                     &test_file,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    OpenFlags::new(false, false),
                 )
                 .await
                 .unwrap();
             // single read
-            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let fh = fs
+                .open(attr.ino, OpenFlags::new(true, false))
+                .await
+                .unwrap();
             assert_ne!(fh, 0);
             // multiple read
-            let fh_2 = fs.open(attr.ino, true, false).await.unwrap();
+            let fh_2 = fs
+                .open(attr.ino, OpenFlags::new(true, false))
+                .await
+                .unwrap();
             assert_ne!(fh_2, 0);
             // write and read
-            let _ = fs.open(attr.ino, false, true).await.unwrap();
-            // ensure cannot open multiple write
-            assert!(matches!(
-                fs.open(attr.ino, false, true).await,
-                Err(FsError::AlreadyOpenForWrite)
-            ));
+            let fh_3 = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
+            assert_ne!(fh_3, 0);
+            // multiple write
+            let fh_4 = fs
+                .open(attr.ino, OpenFlags::new(false, true))
+                .await
+                .unwrap();
+            assert_ne!(fh_4, 0);
+            assert_ne!(fh_3, fh_4);
         },
     )
     .await;
@@ -2349,8 +5414,7 @@ async fn test_read_only_create() {
                     ROOT_INODE,
                     &test_file,
                     create_attr(FileType::RegularFile),
-                    true,
-                    false,
+                    OpenFlags::new(true, false),
                 )
                 .await;
             assert!(matches!(create_file_result, Err(FsError::ReadOnly)));
@@ -2382,8 +5446,7 @@ async fn test_read_only_write() {
                     ROOT_INODE,
                     &file1,
                     create_attr(FileType::RegularFile),
-                    true,
-                    true,
+                    OpenFlags::new(true, true),
                 )
                 .await
                 .expect("read_only_test_create: Error creating file.");
@@ -2392,8 +5455,7 @@ async fn test_read_only_write() {
                     ROOT_INODE,
                     &file_dest,
                     create_attr(FileType::RegularFile),
-                    true,
-                    true,
+                    OpenFlags::new(true, true),
                 )
                 .await
                 .expect("read_only_test_create: Error creating file.");
@@ -2402,8 +5464,7 @@ async fn test_read_only_write() {
                     ROOT_INODE,
                     &dir1,
                     create_attr(FileType::Directory),
-                    false,
-                    true,
+                    OpenFlags::new(false, true),
                 )
                 .await
                 .expect("read_only_test_create: Error creating dir.");
@@ -2415,11 +5476,17 @@ async fn test_read_only_write() {
             fs_rw.flush(fh).await.unwrap();
             fs_rw.release(fh).await.unwrap();
             drop(fs_rw);
-            let fs_ro = EncryptedFs::new(data_dir, Box::new(PasswordProviderImpl {}), cipher, true)
-                .await
-                .expect("test_read_only_write: Error creating rw fs.");
+            let fs_ro = EncryptedFs::new(
+                data_dir,
+                Box::new(PasswordProviderImpl {}),
+                cipher,
+                true,
+                AtimeMode::default(),
+            )
+            .await
+            .expect("test_read_only_write: Error creating rw fs.");
             let fh = fs_ro
-                .open(attr.ino, true, false)
+                .open(attr.ino, OpenFlags::new(true, false))
                 .await
                 .expect("read_only_test_create: Error opening file in ro.");
 
@@ -2435,8 +5502,7 @@ async fn test_read_only_write() {
                     ROOT_INODE,
                     &file2,
                     create_attr(FileType::RegularFile),
-                    true,
-                    true,
+                    OpenFlags::new(true, true),
                 )
                 .await;
             assert!(matches!(create_file_result, Err(FsError::ReadOnly)));
@@ -0,0 +1,48 @@
+//! Removed files and directories kept aside, so they can be restored.
+//!
+//! While the trash is on, removing the last link to an inode only takes it out of its parent directory. The inode
+//! and its contents stay where they are and a record of it is kept encrypted in the `.trash` directory, named by the
+//! inode. Purging the record removes the inode like a normal remove would have.
+
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use shush_rs::SecretString;
+
+use crate::encryptedfs::FileType;
+
+/// Settings of the trash, see [`crate::encryptedfs::EncryptedFs::set_trash`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Trash {
+    /// How long entries are kept before they are purged. [None] keeps them until purged explicitly.
+    pub retention: Option<Duration>,
+}
+
+impl Trash {
+    #[must_use]
+    pub const fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+}
+
+/// A removed file or directory.
+#[derive(Debug, Clone)]
+pub struct TrashEntry {
+    /// Inode of the entry, used to restore or purge it.
+    pub ino: u64,
+    pub kind: FileType,
+    /// Path it had, from the root of the filesystem.
+    pub path: SecretString,
+    pub deleted: SystemTime,
+}
+
+/// What is kept for each entry.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TrashRecord {
+    pub parent: u64,
+    pub name: String,
+    pub path: String,
+    pub kind: FileType,
+    pub deleted: SystemTime,
+}
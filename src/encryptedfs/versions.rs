@@ -0,0 +1,55 @@
+//! Previous contents of regular files, see [`crate::encryptedfs::EncryptedFs::set_versioning`].
+//!
+//! When a write handle is released, the content of the file is copied, still encrypted, to
+//! `inodes/<ino>.versions/<id>`, next to its holes and a record of its size and times. Ids increase with each version.
+
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+/// Versions kept by default for each file.
+const DEFAULT_MAX_VERSIONS: usize = 10;
+
+/// How many versions are kept, see [`crate::encryptedfs::EncryptedFs::set_versioning`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Versioning {
+    /// Number of versions kept for each file, the oldest are removed first.
+    pub max_versions: usize,
+    /// Versions older than this are removed. [None] keeps them until there are too many.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for Versioning {
+    fn default() -> Self {
+        Self {
+            max_versions: DEFAULT_MAX_VERSIONS,
+            max_age: None,
+        }
+    }
+}
+
+impl Versioning {
+    #[must_use]
+    pub const fn with_max_versions(mut self, max_versions: usize) -> Self {
+        self.max_versions = max_versions;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+}
+
+/// A previous content of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileVersion {
+    /// Used to read or restore it.
+    pub id: u64,
+    pub size: u64,
+    /// When the content was last modified.
+    pub mtime: SystemTime,
+    /// When the version was saved.
+    pub saved: SystemTime,
+}
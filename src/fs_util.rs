@@ -36,6 +36,11 @@ pub async fn rename_dir_content(src: &Path, dst: &Path) -> io::Result<()> {
     Ok(())
 }
 
+/// Make the entries of a directory durable, needed after creating, renaming or removing files in it.
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
 pub fn open_atomic_write(file: &Path) -> io::Result<AtomicWriteFile> {
     let mut opt = AtomicWriteFile::options();
     opt.read(true);
@@ -28,7 +28,7 @@
 //! use anyhow::Result;
 //! use shush_rs::SecretString;
 //!
-//! use rencfs::encryptedfs::PasswordProvider;
+//! use rencfs::encryptedfs::{AtimeMode, PasswordProvider};
 //! use rencfs::mount::create_mount_point;
 //! use rencfs::mount::MountPoint;
 //!
@@ -59,6 +59,7 @@
 //!         false,
 //!         false,
 //!         false,
+//!         AtimeMode::default(),
 //!     );
 //!     let handle = mount_point.mount().await?;
 //!     let mut buffer = String::new();
@@ -86,7 +87,7 @@
 //! #![allow(unused_imports)]
 //! use std::fs;
 //! use shush_rs::SecretString;
-//! use rencfs::encryptedfs::{EncryptedFs, FileType, PasswordProvider, CreateFileAttr};
+//! use rencfs::encryptedfs::{AtimeMode, EncryptedFs, FileType, PasswordProvider, CreateFileAttr, OpenFlags};
 //! use rencfs::crypto::Cipher;
 //! use anyhow::Result;
 //! use std::path::Path;
@@ -109,15 +110,15 @@
 //!     let data_dir = Path::new("/tmp/rencfs_data_test").to_path_buf();
 //!     let  _ = fs::remove_dir_all(data_dir.to_str().unwrap());
 //!     let cipher = Cipher::ChaCha20Poly1305;
-//!     let mut fs = EncryptedFs::new(data_dir.clone(), Box::new(PasswordProviderImpl{}), cipher, false).await?;
+//!     let mut fs = EncryptedFs::new(data_dir.clone(), Box::new(PasswordProviderImpl{}), cipher, false, AtimeMode::default()).await?;
 //!
 //!     let  file1 = SecretString::new(Box::new(String::from("file-1")));
-//!     let (fh, attr) = fs.create(ROOT_INODE, &file1, file_attr(), false, true).await?;
+//!     let (fh, attr) = fs.create(ROOT_INODE, &file1, file_attr(), OpenFlags::new(false, true)).await?;
 //!     let data = "Hello, world!";
 //!     write_all_string_to_fs( &fs, attr.ino, 0,data, fh).await?;
 //!     fs.flush(fh).await?;
 //!     fs.release(fh).await?;
-//!     let fh = fs.open(attr.ino, true, false).await?;
+//!     let fh = fs.open(attr.ino, OpenFlags::new(true, false)).await?;
 //!     let mut buf = vec![0; data.len()];
 //!     fs.read(attr.ino, 0, &mut buf, fh).await?;
 //!     fs.release(fh).await?;
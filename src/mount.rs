@@ -1,5 +1,5 @@
 use crate::crypto::Cipher;
-use crate::encryptedfs::{FsResult, PasswordProvider};
+use crate::encryptedfs::{AtimeMode, FsResult, PasswordProvider};
 use async_trait::async_trait;
 use futures_util::FutureExt;
 use std::future::Future;
@@ -35,6 +35,7 @@ pub trait MountPoint {
         allow_root: bool,
         allow_other: bool,
         read_only: bool,
+        atime_mode: AtimeMode,
     ) -> Self
     where
         Self: Sized;
@@ -66,7 +67,8 @@ pub(crate) trait MountHandleInner: Future<Output = io::Result<()>> {
 /// Available arguments
 ///
 /// **`mountpoint`** where it wil mount the filesystem
-/// **`data_dir`** the directory where the encrypted files will be stored  
+/// **`data_dir`** the directory where the encrypted files will be stored, or of a snapshot of them,
+/// see [`crate::encryptedfs::snapshot::snapshot_data_dir`], which needs `read_only`  
 /// **`password_provider`** the password provider  
 /// **`cipher`** The encryption algorithm to use.
 /// Currently, it supports these ciphers [`Cipher`]
@@ -74,6 +76,7 @@ pub(crate) trait MountHandleInner: Future<Output = io::Result<()>> {
 /// **`allow_root`** allow root to access the file system  
 /// **`allow_other`** allow other users to access the file system  
 /// **`read_only`** Set FUSE filesystem read-only mount option, default is disabled.
+/// **`atime_mode`** When reads update the access time, see [`AtimeMode`].
 #[must_use]
 #[allow(clippy::fn_params_excessive_bools)]
 #[allow(clippy::too_long_first_doc_paragraph)]
@@ -85,6 +88,7 @@ pub fn create_mount_point(
     allow_root: bool,
     allow_other: bool,
     read_only: bool,
+    atime_mode: AtimeMode,
 ) -> impl MountPoint {
     MountPointImpl::new(
         mountpoint.to_path_buf(),
@@ -94,6 +98,7 @@ pub fn create_mount_point(
         allow_root,
         allow_other,
         read_only,
+        atime_mode,
     )
 }
 
@@ -7,7 +7,7 @@ use std::task::{Context, Poll};
 use tracing::error;
 
 use crate::crypto::Cipher;
-use crate::encryptedfs::{FsError, FsResult, PasswordProvider};
+use crate::encryptedfs::{AtimeMode, FsError, FsResult, PasswordProvider};
 use crate::mount;
 use crate::mount::{MountHandleInner, MountPoint};
 
@@ -21,6 +21,7 @@ pub struct MountPointImpl {
     allow_root: bool,
     allow_other: bool,
     read_only: bool,
+    atime_mode: AtimeMode,
 }
 
 #[async_trait]
@@ -33,6 +34,7 @@ impl MountPoint for MountPointImpl {
         allow_root: bool,
         allow_other: bool,
         read_only: bool,
+        atime_mode: AtimeMode,
     ) -> Self {
         Self {
             mountpoint,
@@ -42,6 +44,7 @@ impl MountPoint for MountPointImpl {
             allow_root,
             allow_other,
             read_only,
+            atime_mode,
         }
     }
 
@@ -1,14 +1,15 @@
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::future::Future;
 use std::io;
 use std::io::{BufRead, BufReader};
-use std::iter::Skip;
 use std::num::NonZeroU32;
 use std::os::raw::c_int;
 use std::path::PathBuf;
 use std::pin::Pin;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
@@ -18,7 +19,8 @@ use async_trait::async_trait;
 use bytes::Bytes;
 use fuse3::raw::prelude::{
     DirectoryEntry, DirectoryEntryPlus, ReplyAttr, ReplyCopyFileRange, ReplyCreated, ReplyData,
-    ReplyDirectory, ReplyDirectoryPlus, ReplyEntry, ReplyInit, ReplyOpen, ReplyStatFs, ReplyWrite,
+    ReplyDirectory, ReplyDirectoryPlus, ReplyEntry, ReplyInit, ReplyLSeek, ReplyLock, ReplyOpen,
+    ReplyStatFs, ReplyWrite, ReplyXAttr,
 };
 use fuse3::raw::{Filesystem, MountHandle, Request, Session};
 use fuse3::{Errno, Inode, MountOptions, Result, SetAttr, Timestamp};
@@ -30,30 +32,20 @@ use tracing::{debug, error, instrument, trace, warn};
 use tracing::{info, Level};
 
 use crate::crypto::Cipher;
+use crate::encryptedfs::acl::{ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR};
+use crate::encryptedfs::lock::LockType;
 use crate::encryptedfs::{
-    CopyFileRangeReq, CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsResult,
-    PasswordProvider, SetFileAttr,
+    AllocateMode, AtimeMode, CopyFileRangeReq, CreateFileAttr, EncryptedFs, FileAttr, FileType,
+    FsError, FsResult, OpenFlags, PasswordProvider, RenameMode, SetFileAttr, SetXattrMode,
+    NAME_MAX,
 };
 use crate::mount;
 use crate::mount::{MountHandleInner, MountPoint};
 
 const TTL: Duration = Duration::from_secs(1);
-const STATFS: ReplyStatFs = ReplyStatFs {
-    blocks: 1,
-    bfree: 0,
-    bavail: 0,
-    files: 1,
-    ffree: 0,
-    bsize: 4096,
-    namelen: u32::MAX,
-    frsize: 0,
-};
-
 const FMODE_EXEC: i32 = 0x20;
 
-// const MAX_NAME_LENGTH: u32 = 255 - ENCRYPT_FILENAME_OVERHEAD_CHARS as u32;
-
-pub struct DirectoryEntryIterator(crate::encryptedfs::DirectoryEntryIterator, u64);
+pub struct DirectoryEntryIterator(crate::encryptedfs::DirectoryEntryIterator);
 
 impl Iterator for DirectoryEntryIterator {
     type Item = Result<DirectoryEntry>;
@@ -62,18 +54,13 @@ impl Iterator for DirectoryEntryIterator {
     fn next(&mut self) -> Option<Self::Item> {
         match self.0.next() {
             Some(Ok(entry)) => {
-                let kind = if entry.kind == FileType::Directory {
-                    fuse3::raw::prelude::FileType::Directory
-                } else {
-                    fuse3::raw::prelude::FileType::RegularFile
-                };
-                self.1 += 1;
+                let kind = entry.kind.into();
                 Some(Ok(DirectoryEntry {
                     inode: entry.ino,
                     kind,
                     name: OsString::from(&*entry.name.expose_secret()),
                     #[allow(clippy::cast_possible_wrap)]
-                    offset: self.1 as i64,
+                    offset: self.0.cookie() as i64,
                 }))
             }
             Some(Err(FsError::Io { source, .. })) => {
@@ -89,7 +76,7 @@ impl Iterator for DirectoryEntryIterator {
     }
 }
 
-pub struct DirectoryEntryPlusIterator(crate::encryptedfs::DirectoryEntryPlusIterator, u64);
+pub struct DirectoryEntryPlusIterator(crate::encryptedfs::DirectoryEntryPlusIterator);
 
 impl Iterator for DirectoryEntryPlusIterator {
     type Item = Result<DirectoryEntryPlus>;
@@ -97,19 +84,14 @@ impl Iterator for DirectoryEntryPlusIterator {
     fn next(&mut self) -> Option<Self::Item> {
         match self.0.next() {
             Some(Ok(entry)) => {
-                let kind = if entry.kind == FileType::Directory {
-                    fuse3::raw::prelude::FileType::Directory
-                } else {
-                    fuse3::raw::prelude::FileType::RegularFile
-                };
-                self.1 += 1;
+                let kind = entry.kind.into();
                 Some(Ok(DirectoryEntryPlus {
                     inode: entry.ino,
                     generation: 0,
                     kind,
                     name: OsString::from(&*entry.name.expose_secret()),
                     #[allow(clippy::cast_possible_wrap)]
-                    offset: self.1 as i64,
+                    offset: self.0.cookie() as i64,
                     attr: entry.attr.into(),
                     entry_ttl: TTL,
                     attr_ttl: TTL,
@@ -128,8 +110,90 @@ impl Iterator for DirectoryEntryPlusIterator {
     }
 }
 
+trait DirOffset {
+    fn offset(&self) -> i64;
+}
+
+impl DirOffset for DirectoryEntry {
+    fn offset(&self) -> i64 {
+        self.offset
+    }
+}
+
+impl DirOffset for DirectoryEntryPlus {
+    fn offset(&self) -> i64 {
+        self.offset
+    }
+}
+
+/// Listing of an opened directory, kept between `readdir` calls so they continue where the previous one stopped.
+struct DirCursor<T> {
+    entries: Option<Box<dyn Iterator<Item = Result<T>> + Send>>,
+    // offset after the last entry taken from `entries`
+    offset: i64,
+    // the last entry and the offset before it, the kernel might not have had room for it
+    last: Option<(i64, T)>,
+}
+
+impl<T: DirOffset + Clone> DirCursor<T> {
+    const fn new() -> Self {
+        Self {
+            entries: None,
+            offset: 0,
+            last: None,
+        }
+    }
+
+    /// Continue from `offset` if the listing is there. Returns the entry to send again, if any.
+    fn resume(&mut self, offset: i64) -> Option<Option<T>> {
+        self.entries.as_ref()?;
+        if offset == self.offset {
+            return Some(None);
+        }
+        match &self.last {
+            Some((before, entry)) if *before == offset => Some(Some(entry.clone())),
+            _ => None,
+        }
+    }
+
+    fn restart(&mut self, entries: Box<dyn Iterator<Item = Result<T>> + Send>, offset: i64) {
+        self.entries = Some(entries);
+        self.offset = offset;
+        self.last = None;
+    }
+}
+
+/// Entries sent in a `readdir` reply, they are taken from the shared cursor one by one.
+struct DirCursorIter<T> {
+    cursor: Arc<std::sync::Mutex<DirCursor<T>>>,
+    replay: Option<T>,
+}
+
+impl<T: DirOffset + Clone> Iterator for DirCursorIter<T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.replay.take() {
+            return Some(Ok(entry));
+        }
+        let mut cursor = self.cursor.lock().unwrap();
+        let entry = cursor.entries.as_mut()?.next()?;
+        if let Ok(entry) = &entry {
+            cursor.last = Some((cursor.offset, entry.clone()));
+            cursor.offset = entry.offset();
+        }
+        Some(entry)
+    }
+}
+
+type DirCursors<T> = std::sync::Mutex<HashMap<u64, Arc<std::sync::Mutex<DirCursor<T>>>>>;
+
 struct EncryptedFsFuse3 {
     fs: Arc<EncryptedFs>,
+    // fh -> listing of the opened directory
+    dir_cursors: DirCursors<DirectoryEntry>,
+    dir_plus_cursors: DirCursors<DirectoryEntryPlus>,
+    current_dir_handle: AtomicU64,
 }
 
 impl EncryptedFsFuse3 {
@@ -138,12 +202,32 @@ impl EncryptedFsFuse3 {
         password_provider: Box<dyn PasswordProvider>,
         cipher: Cipher,
         read_only: bool,
+        atime_mode: AtimeMode,
     ) -> FsResult<Self> {
         Ok(Self {
-            fs: EncryptedFs::new(data_dir, password_provider, cipher, read_only).await?,
+            fs: EncryptedFs::new(data_dir, password_provider, cipher, read_only, atime_mode)
+                .await?,
+            dir_cursors: std::sync::Mutex::default(),
+            dir_plus_cursors: std::sync::Mutex::default(),
+            current_dir_handle: AtomicU64::new(1),
         })
     }
 
+    /// Get the cursor of an opened directory, one not kept if `fh` is not from [`Filesystem::opendir`].
+    fn dir_cursor<T: DirOffset + Clone>(
+        cursors: &DirCursors<T>,
+        fh: u64,
+    ) -> Arc<std::sync::Mutex<DirCursor<T>>> {
+        let mut cursors = cursors.lock().unwrap();
+        if fh == 0 {
+            return Arc::new(std::sync::Mutex::new(DirCursor::new()));
+        }
+        cursors
+            .entry(fh)
+            .or_insert_with(|| Arc::new(std::sync::Mutex::new(DirCursor::new())))
+            .clone()
+    }
+
     fn get_fs(&self) -> Arc<EncryptedFs> {
         self.fs.clone()
     }
@@ -153,15 +237,65 @@ impl EncryptedFsFuse3 {
         (mode & !(libc::S_ISUID | libc::S_ISGID)) as u16
     }
 
+    /// Check `access_mask` (`R_OK`, `W_OK`, `X_OK`) for the requester, using the access ACL of the inode if it has one.
+    async fn has_access(&self, attr: &FileAttr, req: &Request, access_mask: i32) -> bool {
+        if access_mask != libc::F_OK && req.uid != 0 {
+            match self.get_fs().get_access_acl(attr.ino).await {
+                Ok(Some(acl)) => {
+                    let mut groups = get_groups(req.pid);
+                    groups.push(req.gid);
+                    return acl.check(attr.uid, attr.gid, req.uid, &groups, access_mask);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    error!(err = %err, "cannot read ACL");
+                    return false;
+                }
+            }
+        }
+        check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask)
+    }
+
+    /// Check `access_mask` on the inode for an extended attribute operation.
+    ///
+    /// Only root can access the `trusted` namespace and only the owner or root can change ACLs,
+    /// while anybody can read them.
+    async fn check_xattr_access(
+        &self,
+        req: &Request,
+        inode: Inode,
+        name: &str,
+        access_mask: i32,
+    ) -> Result<()> {
+        let attr = self.get_fs().get_attr(inode).await.map_err(|err| {
+            error!(err = %err);
+            ENOENT
+        })?;
+        if name.starts_with("trusted.") && req.uid != 0 {
+            return Err(EPERM.into());
+        }
+        if name == ACL_ACCESS_XATTR || name == ACL_DEFAULT_XATTR {
+            if access_mask == libc::W_OK && req.uid != 0 && req.uid != attr.uid {
+                return Err(EPERM.into());
+            }
+            return Ok(());
+        }
+        if !self.has_access(&attr, req, access_mask).await {
+            return Err(EACCES.into());
+        }
+        Ok(())
+    }
+
     #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    #[allow(clippy::too_many_arguments)]
     async fn create_nod(
         &self,
         parent: u64,
         mut mode: u32,
+        rdev: u32,
         req: &Request,
         name: &OsStr,
-        read: bool,
-        write: bool,
+        flags: OpenFlags,
     ) -> std::result::Result<(u64, FileAttr), c_int> {
         let parent_attr = match self.get_fs().get_attr(parent).await {
             Err(err) => {
@@ -171,14 +305,7 @@ impl EncryptedFsFuse3 {
             Ok(parent_attr) => parent_attr,
         };
 
-        if !check_access(
-            parent_attr.uid,
-            parent_attr.gid,
-            parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
+        if !self.has_access(&parent_attr, req, libc::W_OK).await {
             return Err(EACCES);
         }
 
@@ -192,6 +319,8 @@ impl EncryptedFsFuse3 {
         } else {
             file_attr()
         };
+        attr.kind = kind;
+        attr.rdev = rdev;
         attr.perm = self.creation_mode(mode);
         attr.uid = req.uid;
         attr.gid = creation_gid(&parent_attr, req.gid);
@@ -202,21 +331,15 @@ impl EncryptedFsFuse3 {
                 parent,
                 &SecretString::from_str(name.to_str().unwrap()).unwrap(),
                 attr,
-                read,
-                write,
+                flags,
             )
             .await
             .map_err(|err| {
                 error!(err = %err);
                 match err {
                     FsError::AlreadyExists => EEXIST,
-                    FsError::Io { source, .. } => {
-                        if source.to_string().to_lowercase().contains("too long") {
-                            ENAMETOOLONG
-                        } else {
-                            EIO
-                        }
-                    }
+                    FsError::QuotaExceeded => libc::EDQUOT,
+                    FsError::NameTooLong => ENAMETOOLONG,
                     _ => EIO,
                 }
             })?;
@@ -242,11 +365,7 @@ impl From<FileAttr> for fuse3::raw::prelude::FileAttr {
             atime: from.atime.into(),
             mtime: from.mtime.into(),
             ctime: from.ctime.into(),
-            kind: if from.kind == FileType::Directory {
-                fuse3::raw::prelude::FileType::Directory
-            } else {
-                fuse3::raw::prelude::FileType::RegularFile
-            },
+            kind: from.kind.into(),
             perm: from.perm,
             nlink: from.nlink,
             uid: from.uid,
@@ -257,6 +376,20 @@ impl From<FileAttr> for fuse3::raw::prelude::FileAttr {
     }
 }
 
+impl From<FileType> for fuse3::raw::prelude::FileType {
+    fn from(from: FileType) -> Self {
+        match from {
+            FileType::Directory => Self::Directory,
+            FileType::RegularFile => Self::RegularFile,
+            FileType::Symlink => Self::Symlink,
+            FileType::NamedPipe => Self::NamedPipe,
+            FileType::CharDevice => Self::CharDevice,
+            FileType::BlockDevice => Self::BlockDevice,
+            FileType::Socket => Self::Socket,
+        }
+    }
+}
+
 impl Filesystem for EncryptedFsFuse3 {
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::INFO))]
     async fn init(&self, req: Request) -> Result<ReplyInit> {
@@ -276,10 +409,10 @@ impl Filesystem for EncryptedFsFuse3 {
     async fn lookup(&self, req: Request, parent: u64, name: &OsStr) -> Result<ReplyEntry> {
         trace!("");
 
-        // if name.len() > MAX_NAME_LENGTH as usize {
-        //     warn!(name = %name.to_str().unwrap(), "name too long");
-        //     return Err(ENAMETOOLONG.into());
-        // }
+        if name.len() > NAME_MAX {
+            warn!(name = %name.to_str().unwrap(), "name too long");
+            return Err(ENAMETOOLONG.into());
+        }
 
         match self.get_fs().get_attr(parent).await {
             Err(err) => {
@@ -287,14 +420,7 @@ impl Filesystem for EncryptedFsFuse3 {
                 return Err(ENOENT.into());
             }
             Ok(parent_attr) => {
-                if !check_access(
-                    parent_attr.uid,
-                    parent_attr.gid,
-                    parent_attr.perm,
-                    req.uid,
-                    req.gid,
-                    libc::X_OK,
-                ) {
+                if !self.has_access(&parent_attr, &req, libc::X_OK).await {
                     return Err(EACCES.into());
                 }
             }
@@ -469,7 +595,10 @@ impl Filesystem for EncryptedFsFuse3 {
 
             self.get_fs().set_len(inode, size).await.map_err(|err| {
                 error!(err = %err);
-                Errno::from(EIO)
+                match err {
+                    FsError::QuotaExceeded => Errno::from(libc::EDQUOT),
+                    _ => Errno::from(EIO),
+                }
             })?;
             set_attr2 = set_attr2.with_size(size);
 
@@ -480,9 +609,7 @@ impl Filesystem for EncryptedFsFuse3 {
         if let Some(atime) = set_attr.atime {
             debug!(?atime, "utimens");
 
-            if attr.uid != req.uid
-                && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
-            {
+            if attr.uid != req.uid && !self.has_access(&attr, &req, libc::W_OK).await {
                 return Err(EACCES.into());
             }
 
@@ -493,9 +620,7 @@ impl Filesystem for EncryptedFsFuse3 {
         if let Some(mtime) = set_attr.mtime {
             debug!(?mtime, "utimens");
 
-            if attr.uid != req.uid
-                && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
-            {
+            if attr.uid != req.uid && !self.has_access(&attr, &req, libc::W_OK).await {
                 return Err(EACCES.into());
             }
 
@@ -522,6 +647,122 @@ impl Filesystem for EncryptedFsFuse3 {
         })
     }
 
+    #[instrument(skip(self), err(level = Level::WARN))]
+    async fn readlink(&self, req: Request, inode: Inode) -> Result<ReplyData> {
+        trace!("");
+
+        match self.get_fs().read_link(inode).await {
+            Ok(target) => Ok(ReplyData {
+                data: Bytes::copy_from_slice(target.expose_secret().as_bytes()),
+            }),
+            Err(FsError::InvalidInodeType) => Err(libc::EINVAL.into()),
+            Err(err) => {
+                error!(err = %err);
+                Err(ENOENT.into())
+            }
+        }
+    }
+
+    #[instrument(skip(self, name, link), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn symlink(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        link: &OsStr,
+    ) -> Result<ReplyEntry> {
+        trace!("");
+
+        let parent_attr = match self.get_fs().get_attr(parent).await {
+            Err(err) => {
+                error!(err = %err);
+                return Err(ENOENT.into());
+            }
+            Ok(parent_attr) => parent_attr,
+        };
+
+        if !self.has_access(&parent_attr, &req, libc::W_OK).await {
+            return Err(EACCES.into());
+        }
+
+        let mut attr = symlink_attr();
+        attr.uid = req.uid;
+        attr.gid = creation_gid(&parent_attr, req.gid);
+
+        let attr = self
+            .get_fs()
+            .create_symlink(
+                parent,
+                &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                &SecretString::from_str(link.to_str().unwrap()).unwrap(),
+                attr,
+            )
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                match err {
+                    FsError::AlreadyExists => EEXIST,
+                    FsError::InvalidInput(_) => libc::EINVAL,
+                    FsError::QuotaExceeded => libc::EDQUOT,
+                    FsError::NameTooLong => ENAMETOOLONG,
+                    _ => EIO,
+                }
+            })?;
+        Ok(ReplyEntry {
+            ttl: TTL,
+            attr: attr.into(),
+            generation: 0,
+        })
+    }
+
+    #[instrument(skip(self, new_name), fields(new_name = new_name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn link(
+        &self,
+        req: Request,
+        inode: Inode,
+        new_parent: Inode,
+        new_name: &OsStr,
+    ) -> Result<ReplyEntry> {
+        trace!("");
+
+        let parent_attr = match self.get_fs().get_attr(new_parent).await {
+            Err(err) => {
+                error!(err = %err);
+                return Err(ENOENT.into());
+            }
+            Ok(parent_attr) => parent_attr,
+        };
+
+        if !self.has_access(&parent_attr, &req, libc::W_OK).await {
+            return Err(EACCES.into());
+        }
+
+        let attr = self
+            .get_fs()
+            .link(
+                inode,
+                new_parent,
+                &SecretString::from_str(new_name.to_str().unwrap()).unwrap(),
+            )
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                match err {
+                    FsError::AlreadyExists => EEXIST,
+                    FsError::InvalidInodeType => EPERM,
+                    FsError::InvalidInput(_) => libc::EINVAL,
+                    FsError::InodeNotFound => ENOENT,
+                    FsError::NameTooLong => ENAMETOOLONG,
+                    _ => EIO,
+                }
+            })?;
+        Ok(ReplyEntry {
+            ttl: TTL,
+            attr: attr.into(),
+            generation: 0,
+        })
+    }
+
     #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn mknod(
         &self,
@@ -536,16 +777,29 @@ impl Filesystem for EncryptedFsFuse3 {
 
         let file_type = mode & libc::S_IFMT;
 
-        if file_type != libc::S_IFREG
-            // && file_type != libc::S_IFLNK as u32
-            && file_type != libc::S_IFDIR
+        if file_type == libc::S_IFLNK {
+            // symbolic links are created with symlink
+            return Err(libc::EINVAL.into());
+        }
+        if ![
+            libc::S_IFREG,
+            libc::S_IFDIR,
+            libc::S_IFIFO,
+            libc::S_IFCHR,
+            libc::S_IFBLK,
+            libc::S_IFSOCK,
+        ]
+        .contains(&file_type)
         {
-            // TODO
-            warn!("implementation is incomplete. Only supports regular files and directories. Got mode={mode:o}");
-            return Err(libc::ENOSYS.into());
+            warn!("unsupported file type, mode={mode:o}");
+            return Err(libc::EINVAL.into());
+        }
+        if (file_type == libc::S_IFCHR || file_type == libc::S_IFBLK) && req.uid != 0 {
+            // creating device nodes needs CAP_MKNOD
+            return Err(EPERM.into());
         }
 
-        self.create_nod(parent, mode, &req, name, false, false)
+        self.create_nod(parent, mode, rdev, &req, name, OpenFlags::default())
             .await
             .map_err(|err| {
                 error!(err = %err);
@@ -580,14 +834,7 @@ impl Filesystem for EncryptedFsFuse3 {
             Ok(parent_attr) => parent_attr,
         };
 
-        if !check_access(
-            parent_attr.uid,
-            parent_attr.gid,
-            parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
+        if !self.has_access(&parent_attr, &req, libc::W_OK).await {
             return Err(EACCES.into());
         }
 
@@ -612,13 +859,16 @@ impl Filesystem for EncryptedFsFuse3 {
                 parent,
                 &SecretString::from_str(name.to_str().unwrap()).unwrap(),
                 attr,
-                false,
-                false,
+                OpenFlags::default(),
             )
             .await
             .map_err(|err| {
                 error!(err = %err);
-                Errno::from(ENOENT)
+                match err {
+                    FsError::NameTooLong => Errno::from(ENAMETOOLONG),
+                    FsError::QuotaExceeded => Errno::from(libc::EDQUOT),
+                    _ => Errno::from(ENOENT),
+                }
             })?;
         Ok(ReplyEntry {
             ttl: TTL,
@@ -639,14 +889,7 @@ impl Filesystem for EncryptedFsFuse3 {
             Ok(attr) => attr,
         };
 
-        if !check_access(
-            parent_attr.uid,
-            parent_attr.gid,
-            parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
+        if !self.has_access(&parent_attr, &req, libc::W_OK).await {
             return Err(EACCES.into());
         }
 
@@ -701,14 +944,7 @@ impl Filesystem for EncryptedFsFuse3 {
             return Err(ENOENT.into());
         };
 
-        if !check_access(
-            parent_attr.uid,
-            parent_attr.gid,
-            parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
+        if !self.has_access(&parent_attr, &req, libc::W_OK).await {
             return Err(EACCES.into());
         }
 
@@ -768,6 +1004,31 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<()> {
         trace!("");
 
+        self.rename2(req, parent, name, new_parent, new_name, 0)
+            .await
+    }
+
+    #[instrument(skip(self, name, new_name), fields(name = name.to_str().unwrap(), new_name = new_name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn rename2(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        new_parent: Inode,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> Result<()> {
+        trace!("");
+        debug!("flags={flags:x}");
+
+        let mode = match flags {
+            0 => RenameMode::Replace,
+            libc::RENAME_NOREPLACE => RenameMode::NoReplace,
+            libc::RENAME_EXCHANGE => RenameMode::Exchange,
+            // RENAME_WHITEOUT is for overlay filesystems
+            _ => return Err(libc::EINVAL.into()),
+        };
+
         let Ok(Some(attr)) = self
             .get_fs()
             .find_by_name(
@@ -789,14 +1050,7 @@ impl Filesystem for EncryptedFsFuse3 {
             return Err(ENOENT.into());
         };
 
-        if !check_access(
-            parent_attr.uid,
-            parent_attr.gid,
-            parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
+        if !self.has_access(&parent_attr, &req, libc::W_OK).await {
             return Err(EACCES.into());
         }
 
@@ -815,14 +1069,7 @@ impl Filesystem for EncryptedFsFuse3 {
             return Err(ENOENT.into());
         };
 
-        if !check_access(
-            new_parent_attr.uid,
-            new_parent_attr.gid,
-            new_parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
+        if !self.has_access(&new_parent_attr, &req, libc::W_OK).await {
             return Err(EACCES.into());
         }
 
@@ -847,23 +1094,43 @@ impl Filesystem for EncryptedFsFuse3 {
         // because that will change the ".." link in it
         if attr.kind == FileType::Directory
             && parent != new_parent
-            && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
+            && !self.has_access(&attr, &req, libc::W_OK).await
         {
             return Err(EACCES.into());
         }
+        // when exchanging, the other entry moves too
+        if mode == RenameMode::Exchange && parent != new_parent {
+            if let Ok(Some(new_attr)) = self
+                .get_fs()
+                .find_by_name(
+                    new_parent,
+                    &SecretString::from_str(new_name.to_str().unwrap()).unwrap(),
+                )
+                .await
+            {
+                if new_attr.kind == FileType::Directory
+                    && !self.has_access(&new_attr, &req, libc::W_OK).await
+                {
+                    return Err(EACCES.into());
+                }
+            }
+        }
 
         match self
             .get_fs()
-            .rename(
+            .rename2(
                 parent,
                 &SecretString::from_str(name.to_str().unwrap()).unwrap(),
                 new_parent,
                 &SecretString::from_str(new_name.to_str().unwrap()).unwrap(),
+                mode,
             )
             .await
         {
             Ok(()) => Ok(()),
             Err(FsError::NotEmpty) => Err(ENOTEMPTY.into()),
+            Err(FsError::AlreadyExists) => Err(libc::EEXIST.into()),
+            Err(FsError::NameTooLong) => Err(ENAMETOOLONG.into()),
             _ => Err(ENOENT.into()),
         }
     }
@@ -894,25 +1161,15 @@ impl Filesystem for EncryptedFsFuse3 {
             }
         };
 
-        // let _create = flags & libc::O_CREAT as u32 != 0;
-        let truncate = flags & libc::O_TRUNC as u32 != 0;
-        // let _append = flags & libc::O_APPEND as u32 != 0;
-
         let attr = self.get_fs().get_attr(inode).await.map_err(|err| {
             error!(err = %err);
             EIO
         })?;
         //
-        if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
-            if truncate {
-                self.get_fs().set_len(attr.ino, 0).await.map_err(|err| {
-                    error!(err = %err);
-                    EIO
-                })?;
-            }
+        if self.has_access(&attr, &req, access_mask).await {
             let fh = self
                 .get_fs()
-                .open(inode, read, write)
+                .open(inode, open_flags(flags, read, write))
                 .await
                 .map_err(|err| {
                     error!(err = %err);
@@ -969,6 +1226,7 @@ impl Filesystem for EncryptedFsFuse3 {
                 error!(err = %err);
                 match err {
                     FsError::MaxFilesizeExceeded(_) => EFBIG,
+                    FsError::QuotaExceeded => libc::EDQUOT,
                     _ => EIO,
                 }
             })?;
@@ -982,8 +1240,21 @@ impl Filesystem for EncryptedFsFuse3 {
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn statfs(&self, req: Request, inode: u64) -> Result<ReplyStatFs> {
         trace!("");
-        warn!("implementation is a stub");
-        Ok(STATFS)
+
+        let stat = self.get_fs().statfs().map_err(|err| {
+            error!(err = %err);
+            Errno::from(EIO)
+        })?;
+        Ok(ReplyStatFs {
+            blocks: stat.blocks,
+            bfree: stat.bfree,
+            bavail: stat.bavail,
+            files: stat.files,
+            ffree: stat.ffree,
+            bsize: stat.bsize,
+            namelen: u32::MAX,
+            frsize: stat.bsize,
+        })
     }
 
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -999,6 +1270,7 @@ impl Filesystem for EncryptedFsFuse3 {
         trace!("");
 
         let fs = self.get_fs();
+        fs.release_locks(inode, lock_owner).await;
 
         if flush {
             if let Err(err) = fs.flush(fh).await {
@@ -1033,10 +1305,127 @@ impl Filesystem for EncryptedFsFuse3 {
         Ok(())
     }
 
+    #[instrument(skip(self, name, value), fields(name = name.to_str().unwrap(), len = value.len()), err(level = Level::WARN))]
+    async fn setxattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        _position: u32,
+    ) -> Result<()> {
+        trace!("");
+
+        let name = name.to_str().ok_or(libc::EINVAL)?;
+        self.check_xattr_access(&req, inode, name, libc::W_OK)
+            .await?;
+        #[allow(clippy::cast_possible_wrap)]
+        let mode = match flags as i32 {
+            0 => SetXattrMode::Upsert,
+            libc::XATTR_CREATE => SetXattrMode::Create,
+            libc::XATTR_REPLACE => SetXattrMode::Replace,
+            _ => return Err(libc::EINVAL.into()),
+        };
+
+        self.get_fs()
+            .set_xattr(inode, name, value, mode)
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                match err {
+                    FsError::AlreadyExists => EEXIST,
+                    FsError::NotFound(_) => libc::ENODATA,
+                    FsError::InodeNotFound => ENOENT,
+                    FsError::InvalidInput(_) => libc::EINVAL,
+                    // default ACLs are only allowed on directories
+                    FsError::InvalidInodeType => EACCES,
+                    FsError::ReadOnly => libc::EROFS,
+                    _ => EIO,
+                }
+                .into()
+            })
+    }
+
+    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::DEBUG))]
+    async fn getxattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        name: &OsStr,
+        size: u32,
+    ) -> Result<ReplyXAttr> {
+        trace!("");
+
+        let name = name.to_str().ok_or(libc::EINVAL)?;
+        self.check_xattr_access(&req, inode, name, libc::R_OK)
+            .await?;
+
+        let value = self
+            .get_fs()
+            .get_xattr(inode, name)
+            .await
+            .map_err(|err| match err {
+                FsError::NotFound(_) => libc::ENODATA,
+                FsError::InodeNotFound => ENOENT,
+                err => {
+                    error!(err = %err);
+                    EIO
+                }
+            })?;
+        xattr_reply(value, size)
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN))]
+    async fn listxattr(&self, req: Request, inode: Inode, size: u32) -> Result<ReplyXAttr> {
+        trace!("");
+
+        let names = self.get_fs().list_xattr(inode).await.map_err(|err| {
+            error!(err = %err);
+            match err {
+                FsError::InodeNotFound => ENOENT,
+                _ => EIO,
+            }
+        })?;
+        // names are null-terminated and concatenated
+        let mut data = vec![];
+        for name in names {
+            data.extend_from_slice(name.as_bytes());
+            data.push(0);
+        }
+        xattr_reply(data, size)
+    }
+
+    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN))]
+    async fn removexattr(&self, req: Request, inode: Inode, name: &OsStr) -> Result<()> {
+        trace!("");
+
+        let name = name.to_str().ok_or(libc::EINVAL)?;
+        self.check_xattr_access(&req, inode, name, libc::W_OK)
+            .await?;
+
+        self.get_fs()
+            .remove_xattr(inode, name)
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                match err {
+                    FsError::NotFound(_) => libc::ENODATA,
+                    FsError::InodeNotFound => ENOENT,
+                    FsError::ReadOnly => libc::EROFS,
+                    _ => EIO,
+                }
+                .into()
+            })
+    }
+
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn flush(&self, req: Request, inode: Inode, fh: u64, lock_owner: u64) -> Result<()> {
         trace!("");
 
+        // closing any descriptor of the file releases the POSIX locks of the process
+        self.get_fs().release_locks(inode, lock_owner).await;
+
         if let Err(err) = self.get_fs().flush(fh).await {
             error!(err = %err, fh);
             return Err(EIO.into());
@@ -1045,6 +1434,20 @@ impl Filesystem for EncryptedFsFuse3 {
         Ok(())
     }
 
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn fsync(&self, req: Request, inode: Inode, fh: u64, datasync: bool) -> Result<()> {
+        trace!("");
+
+        match self.get_fs().fsync(fh, datasync).await {
+            Ok(()) => Ok(()),
+            Err(FsError::InvalidFileHandle) => Err(libc::EBADF.into()),
+            Err(err) => {
+                error!(err = %err, fh);
+                Err(EIO.into())
+            }
+        }
+    }
+
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     #[allow(clippy::cast_possible_wrap)]
     async fn opendir(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
@@ -1074,9 +1477,10 @@ impl Filesystem for EncryptedFsFuse3 {
             Ok(attr) => attr,
         };
 
-        if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
+        if self.has_access(&attr, &req, access_mask).await {
             Ok(ReplyOpen {
-                fh: 0, // we don't use handles for directories
+                // only used to keep the listing between readdir calls
+                fh: self.current_dir_handle.fetch_add(1, Ordering::SeqCst),
                 flags: 0,
             })
         } else {
@@ -1085,7 +1489,7 @@ impl Filesystem for EncryptedFsFuse3 {
     }
 
     type DirEntryStream<'a>
-        = Iter<Skip<DirectoryEntryIterator>>
+        = Iter<DirCursorIter<DirectoryEntry>>
     where
         Self: 'a;
 
@@ -1099,20 +1503,26 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<ReplyDirectory<Self::DirEntryStream<'_>>> {
         trace!("");
 
-        #[allow(clippy::cast_sign_loss)]
-        let iter = match self.get_fs().read_dir(inode).await {
-            Err(err) => {
-                error!(err = %err);
-                return Err(EIO.into());
-            }
-            Ok(iter) => iter,
+        let cursor = Self::dir_cursor(&self.dir_cursors, fh);
+        let resumed = cursor.lock().unwrap().resume(offset);
+        let replay = if let Some(replay) = resumed {
+            replay
+        } else {
+            #[allow(clippy::cast_sign_loss)]
+            let iter = match self.get_fs().read_dir_from(inode, offset as u64).await {
+                Err(err) => {
+                    error!(err = %err);
+                    return Err(EIO.into());
+                }
+                Ok(iter) => iter,
+            };
+            let iter = DirectoryEntryIterator(iter);
+            cursor.lock().unwrap().restart(Box::new(iter), offset);
+            None
         };
-        let iter = DirectoryEntryIterator(iter, 0);
 
         Ok(ReplyDirectory {
-            #[allow(clippy::cast_possible_truncation)]
-            #[allow(clippy::cast_sign_loss)]
-            entries: stream::iter(iter.skip(offset as usize)),
+            entries: stream::iter(DirCursorIter { cursor, replay }),
         })
     }
 
@@ -1120,24 +1530,40 @@ impl Filesystem for EncryptedFsFuse3 {
     async fn releasedir(&self, req: Request, inode: Inode, fh: u64, flags: u32) -> Result<()> {
         trace!("");
 
+        self.dir_cursors.lock().unwrap().remove(&fh);
+        self.dir_plus_cursors.lock().unwrap().remove(&fh);
+
         Ok(())
     }
 
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn fsyncdir(&self, req: Request, inode: Inode, fh: u64, datasync: bool) -> Result<()> {
+        trace!("");
+
+        match self.get_fs().fsync_dir(inode).await {
+            Ok(()) => Ok(()),
+            Err(FsError::InodeNotFound) => Err(ENOENT.into()),
+            Err(FsError::InvalidInodeType) => Err(ENOTDIR.into()),
+            Err(err) => {
+                error!(err = %err, inode);
+                Err(EIO.into())
+            }
+        }
+    }
+
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn access(&self, req: Request, inode: u64, mask: u32) -> Result<()> {
         trace!("");
 
-        self.get_fs().get_attr(inode).await.map_or_else(
-            |_| Err(ENOENT.into()),
-            |attr| {
-                #[allow(clippy::cast_possible_wrap)]
-                if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, mask as i32) {
-                    Ok(())
-                } else {
-                    Err(EACCES.into())
-                }
-            },
-        )
+        let Ok(attr) = self.get_fs().get_attr(inode).await else {
+            return Err(ENOENT.into());
+        };
+        #[allow(clippy::cast_possible_wrap)]
+        if self.has_access(&attr, &req, mask as i32).await {
+            Ok(())
+        } else {
+            Err(EACCES.into())
+        }
     }
 
     #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -1163,11 +1589,11 @@ impl Filesystem for EncryptedFsFuse3 {
         };
 
         let (handle, attr) = self
-            .create_nod(parent, mode, &req, name, read, write)
+            .create_nod(parent, mode, 0, &req, name, open_flags(flags, read, write))
             .await
             .map_err(|err| {
                 error!(err = %err);
-                Errno::from(ENOENT)
+                Errno::from(err)
             })?;
         Ok(ReplyCreated {
             ttl: TTL,
@@ -1179,7 +1605,7 @@ impl Filesystem for EncryptedFsFuse3 {
     }
 
     type DirEntryPlusStream<'a>
-        = Iter<Skip<DirectoryEntryPlusIterator>>
+        = Iter<DirCursorIter<DirectoryEntryPlus>>
     where
         Self: 'a;
 
@@ -1194,20 +1620,195 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<ReplyDirectoryPlus<Self::DirEntryPlusStream<'_>>> {
         trace!("");
 
+        let cursor = Self::dir_cursor(&self.dir_plus_cursors, fh);
+        #[allow(clippy::cast_possible_wrap)]
+        let offset = offset as i64;
+        let resumed = cursor.lock().unwrap().resume(offset);
+        let replay = if let Some(replay) = resumed {
+            replay
+        } else {
+            #[allow(clippy::cast_sign_loss)]
+            let iter = match self
+                .get_fs()
+                .read_dir_plus_from(parent, offset as u64)
+                .await
+            {
+                Err(err) => {
+                    error!(err = %err);
+                    return Err(EIO.into());
+                }
+                Ok(iter) => iter,
+            };
+            let iter = DirectoryEntryPlusIterator(iter);
+            cursor.lock().unwrap().restart(Box::new(iter), offset);
+            None
+        };
+
+        Ok(ReplyDirectoryPlus {
+            entries: stream::iter(DirCursorIter { cursor, replay }),
+        })
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN))]
+    async fn fallocate(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        length: u64,
+        mode: u32,
+    ) -> Result<()> {
+        trace!("");
+        debug!("mode={mode:x}");
+
         #[allow(clippy::cast_sign_loss)]
-        let iter = match self.get_fs().read_dir_plus(parent).await {
+        let supported = (libc::FALLOC_FL_KEEP_SIZE
+            | libc::FALLOC_FL_PUNCH_HOLE
+            | libc::FALLOC_FL_ZERO_RANGE) as u32;
+        if mode & !supported != 0 {
+            return Err(libc::EOPNOTSUPP.into());
+        }
+        if !self.get_fs().is_write_handle(fh).await {
+            return Err(libc::EBADF.into());
+        }
+        #[allow(clippy::cast_sign_loss)]
+        let mode = AllocateMode::default()
+            .with_keep_size(mode & libc::FALLOC_FL_KEEP_SIZE as u32 != 0)
+            .with_punch_hole(mode & libc::FALLOC_FL_PUNCH_HOLE as u32 != 0)
+            .with_zero_range(mode & libc::FALLOC_FL_ZERO_RANGE as u32 != 0);
+
+        self.get_fs()
+            .allocate(inode, offset, length, mode)
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                match err {
+                    FsError::InvalidInput(_) => libc::EINVAL,
+                    FsError::InvalidInodeType => libc::ENODEV,
+                    FsError::ReadOnly => libc::EROFS,
+                    FsError::QuotaExceeded => libc::EDQUOT,
+                    _ => EIO,
+                }
+                .into()
+            })
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn getlk(
+        &self,
+        _req: Request,
+        inode: Inode,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        _pid: u32,
+    ) -> Result<ReplyLock> {
+        trace!("");
+
+        let lock_type = lock_type(r#type)?.ok_or(Errno::from(libc::EINVAL))?;
+        match self
+            .get_fs()
+            .get_lock(inode, lock_owner, start, end, lock_type)
+            .await
+        {
+            Ok(Some(lock)) => Ok(ReplyLock {
+                start: lock.start,
+                end: lock.end,
+                #[allow(clippy::cast_sign_loss)]
+                r#type: match lock.lock_type {
+                    LockType::Read => libc::F_RDLCK as u32,
+                    LockType::Write => libc::F_WRLCK as u32,
+                },
+                pid: lock.pid,
+            }),
+            #[allow(clippy::cast_sign_loss)]
+            Ok(None) => Ok(ReplyLock {
+                start,
+                end,
+                r#type: libc::F_UNLCK as u32,
+                pid: 0,
+            }),
             Err(err) => {
                 error!(err = %err);
-                return Err(EIO.into());
+                Err(ENOENT.into())
             }
-            Ok(iter) => iter,
-        };
-        let iter = DirectoryEntryPlusIterator(iter, 0);
+        }
+    }
 
-        Ok(ReplyDirectoryPlus {
-            #[allow(clippy::cast_possible_truncation)]
-            entries: stream::iter(iter.skip(offset as usize)),
-        })
+    /// `flock(2)` is not forwarded to us, `fuse3` doesn't negotiate `FUSE_FLOCK_LOCKS` nor pass the `FUSE_LK_FLOCK`
+    /// flag, so the kernel emulates it locally. It's enough as the mount is only accessible from this machine,
+    /// [`EncryptedFs::set_flock`](crate::encryptedfs::EncryptedFs::set_flock) is there for when it does.
+    #[instrument(skip(self), err(level = Level::WARN))]
+    async fn setlk(
+        &self,
+        _req: Request,
+        inode: Inode,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
+        block: bool,
+    ) -> Result<()> {
+        trace!("");
+
+        let lock_type = lock_type(r#type)?;
+        self.get_fs()
+            .set_lock(inode, lock_owner, pid, start, end, lock_type, block)
+            .await
+            .map_err(|err| {
+                match err {
+                    FsError::WouldBlock => libc::EAGAIN,
+                    FsError::InodeNotFound => ENOENT,
+                    FsError::InvalidInput(_) => libc::EINVAL,
+                    _ => {
+                        error!(err = %err);
+                        libc::EINTR
+                    }
+                }
+                .into()
+            })
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn lseek(
+        &self,
+        _req: Request,
+        inode: Inode,
+        _fh: u64,
+        offset: u64,
+        whence: u32,
+    ) -> Result<ReplyLSeek> {
+        trace!("");
+
+        // the kernel handles the other cases itself
+        #[allow(clippy::cast_sign_loss)]
+        let res = match whence {
+            whence if whence == libc::SEEK_DATA as u32 => {
+                self.get_fs().seek_data(inode, offset).await
+            }
+            whence if whence == libc::SEEK_HOLE as u32 => {
+                self.get_fs().seek_hole(inode, offset).await
+            }
+            _ => return Err(libc::EINVAL.into()),
+        };
+        match res {
+            Ok(Some(offset)) => Ok(ReplyLSeek { offset }),
+            Ok(None) => Err(libc::ENXIO.into()),
+            Err(err) => {
+                error!(err = %err);
+                Err(match err {
+                    FsError::InodeNotFound => ENOENT,
+                    FsError::InvalidInodeType => libc::EINVAL,
+                    _ => EIO,
+                }
+                .into())
+            }
+        }
     }
 
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -1238,6 +1839,7 @@ impl Filesystem for EncryptedFsFuse3 {
             .copy_file_range(&file_range_req, length as usize)
             .await
         {
+            Err(FsError::QuotaExceeded) => Err(libc::EDQUOT.into()),
             Err(err) => {
                 error!(err = %err);
                 return Err(EIO.into());
@@ -1282,10 +1884,18 @@ fn as_file_kind(mut mode: u32) -> FileType {
 
     if mode == libc::S_IFREG {
         FileType::RegularFile
-        // } else if mode == libc::S_IFLNK as u32 {
-        //     return FileType::Symlink;
+    } else if mode == libc::S_IFLNK {
+        FileType::Symlink
     } else if mode == libc::S_IFDIR {
         FileType::Directory
+    } else if mode == libc::S_IFIFO {
+        FileType::NamedPipe
+    } else if mode == libc::S_IFCHR {
+        FileType::CharDevice
+    } else if mode == libc::S_IFBLK {
+        FileType::BlockDevice
+    } else if mode == libc::S_IFSOCK {
+        FileType::Socket
     } else {
         unimplemented!("{mode}");
     }
@@ -1302,6 +1912,17 @@ const fn dir_attr() -> CreateFileAttr {
     }
 }
 
+const fn symlink_attr() -> CreateFileAttr {
+    CreateFileAttr {
+        kind: FileType::Symlink,
+        perm: 0o777,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+    }
+}
+
 const fn file_attr() -> CreateFileAttr {
     CreateFileAttr {
         kind: FileType::RegularFile,
@@ -1313,6 +1934,41 @@ const fn file_attr() -> CreateFileAttr {
     }
 }
 
+/// Reply with the size only when `size` is 0, as requested by the kernel, or with the data if it fits in `size`.
+fn xattr_reply(data: Vec<u8>, size: u32) -> Result<ReplyXAttr> {
+    #[allow(clippy::cast_possible_truncation)]
+    let len = data.len() as u32;
+    if size == 0 {
+        Ok(ReplyXAttr::Size(len))
+    } else if len > size {
+        Err(libc::ERANGE.into())
+    } else {
+        Ok(ReplyXAttr::Data(Bytes::from(data)))
+    }
+}
+
+/// Open flags from the `open(2)` flags, the access mode is already parsed into `read` and `write`.
+#[allow(clippy::cast_sign_loss)]
+const fn open_flags(flags: u32, read: bool, write: bool) -> OpenFlags {
+    OpenFlags::new(read, write)
+        .with_append(flags & libc::O_APPEND as u32 != 0)
+        .with_truncate(flags & libc::O_TRUNC as u32 != 0)
+        .with_exclusive(flags & libc::O_EXCL as u32 != 0)
+        // O_SYNC includes O_DSYNC
+        .with_sync(flags & libc::O_DSYNC as u32 != 0)
+}
+
+/// Lock type from `F_RDLCK`, `F_WRLCK` or `F_UNLCK`, which is [None].
+#[allow(clippy::cast_possible_wrap)]
+fn lock_type(r#type: u32) -> Result<Option<LockType>> {
+    match r#type as i32 {
+        libc::F_RDLCK => Ok(Some(LockType::Read)),
+        libc::F_WRLCK => Ok(Some(LockType::Write)),
+        libc::F_UNLCK => Ok(None),
+        _ => Err(libc::EINVAL.into()),
+    }
+}
+
 fn check_access(
     #[allow(clippy::similar_names)] file_uid: u32,
     #[allow(clippy::similar_names)] file_gid: u32,
@@ -1362,6 +2018,7 @@ pub struct MountPointImpl {
     allow_root: bool,
     allow_other: bool,
     read_only: bool,
+    atime_mode: AtimeMode,
 }
 
 #[async_trait]
@@ -1374,6 +2031,7 @@ impl MountPoint for MountPointImpl {
         allow_root: bool,
         allow_other: bool,
         read_only: bool,
+        atime_mode: AtimeMode,
     ) -> Self {
         Self {
             mountpoint,
@@ -1383,6 +2041,7 @@ impl MountPoint for MountPointImpl {
             allow_root,
             allow_other,
             read_only,
+            atime_mode,
         }
     }
 
@@ -1395,6 +2054,7 @@ impl MountPoint for MountPointImpl {
             self.allow_root,
             self.allow_other,
             self.read_only,
+            self.atime_mode,
         )
         .await?;
         Ok(mount::MountHandle {
@@ -1431,6 +2091,7 @@ async fn mount_fuse(
     allow_root: bool,
     allow_other: bool,
     read_only: bool,
+    atime_mode: AtimeMode,
 ) -> FsResult<MountHandle> {
     // create mount point if it doesn't exist
     if !mountpoint.exists() {
@@ -1453,7 +2114,8 @@ async fn mount_fuse(
     info!("Checking password and mounting FUSE filesystem");
     Ok(Session::new(mount_options)
         .mount_with_unprivileged(
-            EncryptedFsFuse3::new(data_dir, password_provider, cipher, read_only).await?,
+            EncryptedFsFuse3::new(data_dir, password_provider, cipher, read_only, atime_mode)
+                .await?,
             mount_path,
         )
         .await?)
@@ -17,7 +17,8 @@ use tracing::{error, info, warn, Level};
 
 use crate::keyring;
 use rencfs::crypto::Cipher;
-use rencfs::encryptedfs::{EncryptedFs, FsError, PasswordProvider};
+use rencfs::encryptedfs::snapshot::snapshot_data_dir;
+use rencfs::encryptedfs::{AtimeMode, EncryptedFs, FsError, PasswordProvider};
 use rencfs::mount::MountPoint;
 use rencfs::{log, mount};
 
@@ -179,6 +180,25 @@ fn get_cli_args() -> ArgMatches {
                         .requires("data-dir")
                         .help("Set FUSE filesystem read-only mount option, default is disabled.")
                 )
+                .arg(
+                    Arg::new("snapshot")
+                        .long("snapshot")
+                        .value_name("snapshot")
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Mount this snapshot of the data dir instead, always read-only")
+                )
+                .arg(
+                    Arg::new("atime")
+                        .long("atime")
+                        .value_name("atime")
+                        .default_value("relatime")
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help(format!("When reads update the access time, possible values: {}",
+                                      AtimeMode::iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ")),
+                        )
+                )
         ).subcommand(
         Command::new("passwd")
             .about("Change password for the master key used to encrypt the data")
@@ -266,7 +286,18 @@ async fn run_mount(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
         .unwrap()
         .to_string();
 
-    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let mut data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let snapshot = matches.get_one::<String>("snapshot");
+    if let Some(name) = snapshot {
+        data_dir = snapshot_data_dir(Path::new(&data_dir), name)
+            .to_string_lossy()
+            .to_string();
+    }
+
+    let Ok(atime_mode) = AtimeMode::from_str(matches.get_one::<String>("atime").unwrap()) else {
+        error!("Invalid atime");
+        return Err(ExitStatusError::Failure(1).into());
+    };
 
     // when running from IDE we can't read from stdin with rpassword, get it from env var
     let mut password = SecretString::from_str(
@@ -349,7 +380,8 @@ async fn run_mount(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
         cipher,
         matches.get_flag("allow-root"),
         matches.get_flag("allow-other"),
-        matches.get_flag("read-only"),
+        matches.get_flag("read-only") || snapshot.is_some(),
+        atime_mode,
     );
     let mount_handle = mount_point.mount().await.map_err(|err| {
         error!(err = %err);
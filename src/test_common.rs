@@ -12,7 +12,7 @@ use tokio::sync::Mutex;
 
 use crate::crypto::Cipher;
 use crate::encryptedfs::{
-    CopyFileRangeReq, CreateFileAttr, EncryptedFs, FileType, PasswordProvider,
+    AtimeMode, CopyFileRangeReq, CreateFileAttr, EncryptedFs, FileType, OpenFlags, PasswordProvider,
 };
 
 #[allow(dead_code)]
@@ -85,6 +85,7 @@ async fn setup(setup: TestSetup) -> SetupResult {
         Box::new(PasswordProviderImpl {}),
         Cipher::ChaCha20Poly1305,
         read_only,
+        AtimeMode::default(),
     )
     .await
     .unwrap();
@@ -123,7 +124,7 @@ where
 
 #[allow(dead_code)]
 pub async fn read_to_string(ino: u64, fs: &EncryptedFs) -> String {
-    let fh = fs.open(ino, true, false).await.unwrap();
+    let fh = fs.open(ino, OpenFlags::new(true, false)).await.unwrap();
     let buf = &mut [0; 4096];
     let buf2 = vec![];
     let mut cur = Cursor::new(buf2);
@@ -138,7 +139,7 @@ pub async fn read_to_string(ino: u64, fs: &EncryptedFs) -> String {
             fs.release(fh).await.unwrap();
             return String::from_utf8(cur.into_inner()).unwrap();
         }
-        cur.write_all(&buf[read..len]).unwrap();
+        cur.write_all(&buf[read..read + len]).unwrap();
         read += len;
         offset += len as u64;
     }
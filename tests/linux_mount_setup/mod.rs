@@ -8,7 +8,7 @@ use std::thread::sleep;
 use std::time::Duration;
 
 use rencfs::crypto::Cipher;
-use rencfs::encryptedfs::PasswordProvider;
+use rencfs::encryptedfs::{AtimeMode, PasswordProvider};
 use rencfs::mount::{create_mount_point, MountHandle, MountPoint};
 use shush_rs::SecretString;
 use tokio::runtime::Runtime;
@@ -31,6 +31,7 @@ impl TestResource {
             false,
             false,
             false,
+            AtimeMode::default(),
         );
         let runtime = tokio::runtime::Builder::new_multi_thread()
             .worker_threads(1)
@@ -4,7 +4,7 @@ use linux_mount_setup::{count_files, TestGuard, DATA_PATH, MOUNT_PATH};
 use std::{
     fs::{self, File},
     io::{Read, Write},
-    os::unix::fs::MetadataExt,
+    os::unix::fs::{FileTypeExt, MetadataExt},
     path::Path,
 };
 
@@ -147,3 +147,66 @@ fn it_create_empty_dir_check_attr() {
     let res = fs::remove_dir_all(Path::new(&test_folder));
     assert!(res.is_ok(), "failed to delete [{}]", &test_folder);
 }
+
+#[test]
+fn it_create_and_read_symlink() {
+    let _guard = TestGuard::setup();
+    let test_file = format!("{}{}", MOUNT_PATH, "/symlink_target.txt");
+    let test_link = format!("{}{}", MOUNT_PATH, "/symlink.txt");
+    {
+        let res = File::create_new(Path::new(&test_file));
+        assert!(res.is_ok(), "failed to create [{}]", &test_file);
+        res.unwrap().write_all(b"test").unwrap();
+        let res = std::os::unix::fs::symlink("symlink_target.txt", Path::new(&test_link));
+        assert!(res.is_ok(), "failed to symlink [{}]", res.err().unwrap());
+        let metadata = fs::symlink_metadata(Path::new(&test_link)).unwrap();
+        assert!(metadata.file_type().is_symlink());
+        let target = fs::read_link(Path::new(&test_link)).unwrap();
+        assert_eq!(target, Path::new("symlink_target.txt"));
+        assert_eq!(fs::read_to_string(Path::new(&test_link)).unwrap(), "test");
+    }
+    let res = fs::remove_file(Path::new(&test_link));
+    assert!(res.is_ok(), "failed to delete [{}]", res.err().unwrap());
+    let res = fs::remove_file(Path::new(&test_file));
+    assert!(res.is_ok(), "failed to delete [{}]", res.err().unwrap());
+}
+
+#[test]
+fn it_create_and_remove_hard_link() {
+    let _guard = TestGuard::setup();
+    let test_file = format!("{}{}", MOUNT_PATH, "/link_source.txt");
+    let test_link = format!("{}{}", MOUNT_PATH, "/link.txt");
+    {
+        let res = File::create_new(Path::new(&test_file));
+        assert!(res.is_ok(), "failed to create [{}]", &test_file);
+        res.unwrap().write_all(b"test").unwrap();
+        let res = fs::hard_link(Path::new(&test_file), Path::new(&test_link));
+        assert!(res.is_ok(), "failed to link [{}]", res.err().unwrap());
+        let metadata = fs::metadata(Path::new(&test_link)).unwrap();
+        assert_eq!(metadata.nlink(), 2);
+        assert_eq!(
+            metadata.ino(),
+            fs::metadata(Path::new(&test_file)).unwrap().ino()
+        );
+    }
+    let res = fs::remove_file(Path::new(&test_file));
+    assert!(res.is_ok(), "failed to delete [{}]", res.err().unwrap());
+    assert_eq!(fs::read_to_string(Path::new(&test_link)).unwrap(), "test");
+    assert_eq!(fs::metadata(Path::new(&test_link)).unwrap().nlink(), 1);
+    let res = fs::remove_file(Path::new(&test_link));
+    assert!(res.is_ok(), "failed to delete [{}]", res.err().unwrap());
+}
+
+#[test]
+fn it_create_unix_socket() {
+    let _guard = TestGuard::setup();
+    let test_socket = format!("{}{}", MOUNT_PATH, "/test.sock");
+    {
+        let res = std::os::unix::net::UnixListener::bind(Path::new(&test_socket));
+        assert!(res.is_ok(), "failed to bind [{}]", res.err().unwrap());
+        let metadata = fs::symlink_metadata(Path::new(&test_socket)).unwrap();
+        assert!(metadata.file_type().is_socket());
+    }
+    let res = fs::remove_file(Path::new(&test_socket));
+    assert!(res.is_ok(), "failed to delete [{}]", res.err().unwrap());
+}